@@ -0,0 +1,88 @@
+// Criterion benchmarks of the deposit and withdrawal hot path, over CSV generated in memory so nothing is read from disk.
+//
+// Each workload is timed three ways: parsing the CSV into rows, applying rows already parsed to an Engine, and both
+// together as a run reads its input. Run with `cargo bench --bench engine`, and compare two trees with
+// `--save-baseline` and `--baseline`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use payment_engine::input::{self, OptionalColumns};
+use payment_engine::{Engine, TransactionRow};
+use std::hint::black_box;
+
+// Rows per iteration, and the clients they are spread over
+const ROWS: u64 = 100_000;
+const CLIENTS: u64 = 1_000;
+
+// xorshift64*, so every run times the same rows
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
+
+// This function writes the CSV of a workload in which one row in `withdrawal_every` is a withdrawal and the rest are
+// deposits. Withdrawals are small enough that most of them are applied
+fn workload(withdrawal_every: u64) -> Vec<u8> {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        let client = rng.below(CLIENTS) + 1;
+        let cents = rng.below(100_000) + 1;
+        let kind = if withdrawal_every > 0 && tx % withdrawal_every == 0 { "withdrawal" } else { "deposit" };
+        let cents = if kind == "withdrawal" { cents / 10 + 1 } else { cents };
+        csv.push_str(&format!("{},{},{},{}.{:02}\n", kind, client, tx, cents / 100, cents % 100));
+    }
+    csv.into_bytes()
+}
+
+// This function parses every row of a workload as a run does, into one reused record, and hands each to `each`
+fn parse(csv: &[u8], mut each: impl FnMut(TransactionRow)) {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(csv);
+    let headers = reader.headers().expect("the workload has a header").clone();
+    let columns = OptionalColumns::find(&headers, false);
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record).expect("the workload is valid CSV") {
+        if let Some(row) = input::parse_row(&record, &headers, columns, |_| true, ',').expect("every row parses") {
+            each(row);
+        }
+    }
+}
+
+// This function applies a row. Withdrawals a client cannot cover are rejected, as in a real run
+fn apply(engine: &mut Engine, row: &TransactionRow) {
+    let _ = black_box(engine.process(row));
+}
+
+fn hot_path(c: &mut Criterion) {
+    for (name, withdrawal_every) in [("deposits", 0), ("deposits_and_withdrawals", 4)] {
+        let csv = workload(withdrawal_every);
+        let mut rows = Vec::new();
+        parse(&csv, |row| rows.push(row));
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(ROWS));
+        group.sample_size(20);
+        group.bench_function("parse", |b| b.iter(|| parse(black_box(&csv), |row| {
+            black_box(row);
+        })));
+        group.bench_function("apply", |b| b.iter_batched(Engine::new, |mut engine| {
+            for row in &rows {
+                apply(&mut engine, row);
+            }
+            engine
+        }, BatchSize::LargeInput));
+        group.bench_function("parse_and_apply", |b| b.iter_batched(Engine::new, |mut engine| {
+            parse(black_box(&csv), |row| apply(&mut engine, &row));
+            engine
+        }, BatchSize::LargeInput));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, hot_path);
+criterion_main!(benches);
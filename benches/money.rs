@@ -0,0 +1,58 @@
+// Criterion benchmarks of the Money type: parsing amounts, adding them up and writing them out. Money is Decimal by
+// default and Fixed with the fixed-point feature, so running this once without and once with
+// `--features fixed-point` compares the two. The engine benchmarks in engine.rs show what that does to a whole run.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use payment_engine::money::{self, Money};
+use std::fmt::Write;
+use std::hint::black_box;
+
+const AMOUNTS: u64 = 10_000;
+
+// This function returns amounts with up to four decimal places, as inputs give them
+fn amounts() -> Vec<String> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..AMOUNTS).map(|index| {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let units = state.wrapping_mul(0x2545_f491_4f6c_dd1d) % 100_000_000;
+        match index % 3 {
+            0 => format!("{}", units / 10_000),
+            1 => format!("{}.{:02}", units / 10_000, units % 100),
+            _ => format!("{}.{:04}", units / 10_000, units % 10_000),
+        }
+    }).collect()
+}
+
+fn money(c: &mut Criterion) {
+    let texts = amounts();
+    let values: Vec<Money> = texts.iter().map(|text| money::parse_amount(text, ',').expect("every amount parses")).collect();
+    let mut group = c.benchmark_group(if cfg!(feature = "fixed-point") { "fixed" } else { "decimal" });
+    group.throughput(Throughput::Elements(AMOUNTS));
+    group.bench_function("parse", |b| b.iter(|| {
+        for text in &texts {
+            black_box(money::parse_amount(black_box(text), ',').ok());
+        }
+    }));
+    group.bench_function("checked_add", |b| b.iter(|| {
+        values.iter().try_fold(Money::ZERO, |sum, &value| sum.checked_add(black_box(value)))
+    }));
+    group.bench_function("deposit_and_withdraw", |b| b.iter(|| {
+        // A balance going up and down, as deposits and withdrawals move it
+        values.iter().try_fold(Money::ZERO, |balance, &value| balance.checked_add(black_box(value))?.checked_add(-black_box(value).round_dp(2)))
+    }));
+    group.bench_function("display", |b| {
+        let mut out = String::with_capacity(32);
+        b.iter(|| {
+            for value in &values {
+                out.clear();
+                let _ = write!(out, "{}", black_box(value));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, money);
+criterion_main!(benches);
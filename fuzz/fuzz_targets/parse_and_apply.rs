@@ -0,0 +1,10 @@
+#![no_main]
+
+// Feeds arbitrary bytes through CSV row parsing and the engine. Run with `cargo fuzz run parse_and_apply`, seeding the
+// corpus from src/*.csv with a leading options byte, see payment_engine::fuzzing
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    payment_engine::fuzzing::parse_and_apply(data);
+});
@@ -0,0 +1,72 @@
+// API keys for `serve`, read with --api-keys-file. Each line of the file is a key, or a key and its role:
+//
+//   3f9c2a7e51d04b8a
+//   7d1e0b94c6a25f33:admin
+//
+// A key without a role has the read role, which may only GET. The admin role may also POST transactions, locks and
+// unlocks. Blank lines and lines starting with # are skipped. Keys are compared in constant time and never logged, so
+// errors about the file name the line, not the key.
+
+use payment_engine::EngineError;
+use std::fs;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    Read,
+    Admin,
+}
+
+pub(crate) struct ApiKeys {
+    keys: Vec<(Vec<u8>, Role)>,
+}
+
+impl ApiKeys {
+    // This function reads the keys file. A file without a single key is refused, as it would lock every caller out
+    pub(crate) fn read(path: &str) -> Result<ApiKeys, EngineError> {
+        let text = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("could not open API keys file {}: {}", path, e)))?;
+        let mut keys = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, role) = match line.rsplit_once(':') {
+                Some((key, "read")) => (key, Role::Read),
+                Some((key, "admin")) => (key, Role::Admin),
+                Some(_) => return Err(format!("{} line {}: the role must be read or admin", path, index + 1).into()),
+                None => (line, Role::Read),
+            };
+            if key.is_empty() {
+                return Err(format!("{} line {}: the key is empty", path, index + 1).into());
+            }
+            keys.push((key.as_bytes().to_vec(), role));
+        }
+        if keys.is_empty() {
+            return Err(format!("{} holds no API key", path).into());
+        }
+        Ok(ApiKeys { keys })
+    }
+
+    // This function returns the role of a key, None if it is not one of them. Every key is compared in full, so the time
+    // taken does not tell how much of a key was right or which key matched
+    pub(crate) fn role(&self, presented: &[u8]) -> Option<Role> {
+        let mut role = None;
+        for (key, key_role) in &self.keys {
+            if constant_time_eq(key, presented) {
+                role = role.max(Some(*key_role));
+            }
+        }
+        role
+    }
+}
+
+// This function compares two byte strings without stopping at the first difference. Only their lengths can be told
+// from the time taken
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut difference = u8::from(a.len() != b.len());
+    for (x, y) in a.iter().zip(b) {
+        difference |= x ^ y;
+    }
+    difference == 0
+}
@@ -0,0 +1,674 @@
+// The flags shared by every command that processes an input, and the values they take.
+//
+// ProcessingArgs changes how the input is read and applied, ReportArgs which reports are written once it is done. Both
+// are flattened into the command line of the payment_engine binary and filled in from its config file, and are what
+// run::process_input and the report writers take.
+
+use crate::money::{self, Money};
+use crate::{CurrencyScales, EngineError, EnginePolicy, LockPolicy, TransactionType};
+use clap::ArgEnum;
+use csv::Trim;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+
+// Flags that choose which reports are written and where, after the input has been processed
+#[derive(clap::Args, Default)]
+pub struct ReportArgs {
+    /// Format of the account report
+    #[clap(long, arg_enum, default_value = "csv")]
+    pub format: OutputFormat,
+
+    /// Write the account report to this file instead of stdout, creating missing directories. It only appears once the report is complete
+    #[clap(long)]
+    pub output: Option<String>,
+
+    /// Layout of the account report. v1 is the deprecated legacy layout, see ReportSchema
+    #[clap(long, arg_enum, default_value = "v2")]
+    pub report_schema: ReportSchema,
+
+    /// Decimal places written for every amount in the account report, zero-padded and rounded with Bankers Rounding
+    #[clap(long, default_value = "4")]
+    pub precision: u32,
+
+    /// After processing, charge back every open dispute on a copy of the accounts and write the worst case to this file
+    #[clap(long)]
+    pub simulate_chargebacks: Option<String>,
+
+    /// Write every failed assert row to this file (with --assertions lenient)
+    #[clap(long)]
+    pub assertion_report: Option<String>,
+
+    /// Write every dispute opened during the run to this file, with how many rows it stayed open
+    #[clap(long)]
+    pub dispute_aging: Option<String>,
+
+    /// Write one row per locked client to this file, naming the transaction that locked the account
+    #[clap(long)]
+    pub why_locked: Option<String>,
+
+    /// Write every dispute, resolve and chargeback whose tx id never appears in the input to this file
+    #[clap(long)]
+    pub dangling_refs: Option<String>,
+
+    /// Write the count and summed amount of the applied rows of each type, per client and in total, to this file
+    #[clap(long)]
+    pub volume_report: Option<String>,
+
+    /// Write every row skipped for not parsing or having an unknown type to this file, with its content and the reason
+    #[clap(long)]
+    pub skipped_rows: Option<String>,
+
+    /// Save the accounts and stored transactions to this file at the end of the run, to be continued with --snapshot-in
+    #[clap(long)]
+    pub snapshot_out: Option<String>,
+
+    /// Print every client whose available balance went below zero to stderr at the end of the run, with the lowest it reached
+    #[clap(long)]
+    pub report_anomalies: bool,
+
+    /// Print counts of applied and not applied rows, and totals over the accounts, to stderr at the end of the run
+    #[clap(long)]
+    pub summary: bool,
+
+    /// Only write the accounts of these clients to the report, e.g. 42 or 100-200 or 5,9000-. Can be given more than once.
+    /// Every row is still processed, so the accounts written are the same as in a full report
+    #[clap(long, multiple_occurrences = true)]
+    pub client: Vec<ClientFilter>,
+
+    /// Print the wall-clock time, rows per second and the most accounts and stored records held at once to stderr at the end of the run
+    #[clap(long)]
+    pub stats: bool,
+
+    /// Fail the run if more than this many rows reference tx ids that never appear in the input
+    #[clap(long)]
+    pub max_dangling_refs: Option<u64>,
+
+    /// Exit with status 4 after writing the report if a chargeback locked any account, listing the locked clients on stderr
+    #[clap(long)]
+    pub fail_on_locked: bool,
+
+    /// Append a TOTAL row summing available, held and total across all accounts. The JSON report puts it in a totals field next to an accounts array instead
+    #[clap(long)]
+    pub totals_row: bool,
+
+    /// Write each account as soon as the input moves on to the next client, in input order. Needs --assume-grouped-by client
+    #[clap(long)]
+    pub streaming_output: bool,
+
+    /// Check the input without writing the account report: list every row that would not be applied on stderr, and exit with status 5 if there are any
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+// Flags that change how the input is read and applied, shared by every command that processes an input
+#[derive(clap::Args, Default)]
+pub struct ProcessingArgs {
+    /// Format of the input file
+    #[clap(long, arg_enum, default_value = "csv")]
+    pub input_format: InputFormat,
+
+    /// Compression of CSV input. auto goes by the file extension, .gz or .zst, and reads standard input as is
+    #[clap(long, arg_enum, default_value = "auto")]
+    pub compression: Compression,
+
+    /// Only process rows for these clients, e.g. 17,42,9000-9100
+    #[clap(long)]
+    pub clients: Option<ClientFilter>,
+
+    /// What to do with amounts that have more than four decimal places: round them with Bankers Rounding, truncate them, or
+    /// reject the row. Builds with the fixed-point feature always reject them while parsing
+    #[clap(long, arg_enum, default_value = "round")]
+    pub input_precision: InputPrecision,
+
+    /// Character that may group the digits of CSV amounts before the decimal point, e.g. the comma in "1,234.50"
+    #[clap(long, default_value = ",")]
+    pub thousands_separator: ThousandsSeparator,
+
+    /// Treat rows with an amount above this like rows that do not parse: skip them or, with --strict, stop the run
+    #[clap(long)]
+    pub max_amount: Option<Money>,
+
+    /// Skip every row of these transaction types, e.g. chargeback,resolve
+    #[clap(long, use_value_delimiter = true, possible_values = TransactionType::NAMES)]
+    pub ignore_types: Vec<TransactionType>,
+
+    /// List every client mentioned in the input, with zero balances if none of their transactions were applied
+    #[clap(long)]
+    pub include_referenced_clients: bool,
+
+    /// Forget undisputed records once they are this far behind the current row, e.g. rows=1000000. References to a
+    /// forgotten record are rejected as expired for as many rows again, and as unknown after that
+    #[clap(long)]
+    pub record_retention: Option<RecordRetention>,
+
+    /// Keep only the most recently stored or changed records in memory and move older ones to a file in this directory.
+    /// Disputes of spilled records read them back from the file
+    #[clap(long)]
+    pub spill_dir: Option<String>,
+
+    /// How many records --spill-dir keeps in memory
+    #[clap(long, default_value = "1000000")]
+    pub spill_keep: usize,
+
+    /// Print a progress line to stderr every N rows and once more at the end of the input
+    #[clap(long)]
+    pub heartbeat: Option<NonZeroU64>,
+
+    /// Write the accounts to --checkpoint-out every N rows, so a run that dies part way leaves the state of its last checkpoint
+    #[clap(long)]
+    pub checkpoint_every: Option<NonZeroU64>,
+
+    /// File the checkpoints of --checkpoint-every are written to, each one replacing the last once it is complete
+    #[clap(long)]
+    pub checkpoint_out: Option<String>,
+
+    /// Write every accepted transaction to a binary log that can be replayed with --input-format binlog
+    #[clap(long)]
+    pub export_binlog: Option<String>,
+
+    /// Write a replay file with the policy and every decision taken, to be checked later with `replay`
+    #[clap(long)]
+    pub record: Option<String>,
+
+    /// Write a copy of the CSV input with outcome and detail columns for every row
+    #[clap(long)]
+    pub annotate_out: Option<String>,
+
+    /// Write one CSV row per input row to this file as it is processed, with its outcome and the client's balances after it
+    #[clap(long)]
+    pub audit_log: Option<String>,
+
+    /// Write one JSON object per line to this file, or - for stderr, for every row that is not applied and for the error that stops the run
+    #[clap(long)]
+    pub diagnostics_json: Option<String>,
+
+    /// Stop the run at the first row that cannot be parsed or has an unknown type, instead of skipping it
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Stop the run at the first row with an unknown type, but keep skipping rows that do not parse for other reasons
+    #[clap(long)]
+    pub strict_types: bool,
+
+    /// Ignore CSV rows whose first field starts with #, also before the header, counting them in the summary
+    #[clap(long)]
+    pub allow_comments: bool,
+
+    /// Keep the parsed input in this file and read it from there on later runs, as long as the input is unchanged
+    #[clap(long)]
+    pub cache: Option<String>,
+
+    /// Start from the accounts and stored transactions saved by an earlier run with --snapshot-out
+    #[clap(long)]
+    pub snapshot_in: Option<String>,
+
+    /// Fee debited from the client's account on every successful chargeback
+    #[clap(long)]
+    pub chargeback_fee: Option<Money>,
+
+    /// Fee debited when a calendar month of the row timestamps ends, from every account that is not locked and had a
+    /// positive total during the month. Audit lines of the fees use tx ids from 4293918720 up, which rows may not store
+    #[clap(long)]
+    pub monthly_fee: Option<Money>,
+
+    /// Also charge --monthly-fee for the month the input ends in, although that month may not be over
+    #[clap(long)]
+    pub fee_final_partial_month: bool,
+
+    /// Which chargebacks lock the account: account locks it on every chargeback, threshold:N only on the Nth for the
+    /// same client, the ones before only charging back their transaction
+    #[clap(long, default_value = "account")]
+    pub lock_policy: LockPolicy,
+
+    /// Reject deposits and opening balances that would take a client's total above this amount
+    #[clap(long, alias = "max-client-total")]
+    pub max_balance: Option<Money>,
+
+    /// Per-client balance caps that replace --max-balance for those clients, e.g. 17=500,42=20000
+    #[clap(long)]
+    pub client_max_balance: Option<BalanceCaps>,
+
+    /// Decimal places amounts may have per currency, e.g. JPY=0,USD=2,DEFAULT=4. Finer amounts are rejected, fees are
+    /// rounded to them and the account report writes them instead of --precision. DEFAULT covers every other currency
+    #[clap(long)]
+    pub currency_scales: Option<CurrencyScales>,
+
+    /// CSV file of per-client balance caps with columns client,max_total. --client-max-balance wins for a client in both
+    #[clap(long)]
+    pub limits_file: Option<String>,
+
+    /// Move negative available balances into a debt that later deposits pay off first, and report it in a debt column
+    #[clap(long)]
+    pub track_debt: bool,
+
+    /// Reject every row for a client whose account was not created by an opening_balance row
+    #[clap(long)]
+    pub require_opening_balances: bool,
+
+    /// CSV file of balances with columns client,available,held,locked that accounts start from before the first row
+    #[clap(long)]
+    pub opening_balances: Option<String>,
+
+    /// Accept negative balances in --opening-balances
+    #[clap(long)]
+    pub allow_negative_opening: bool,
+
+    /// Let admin_hold rows freeze more than the client's available funds, taking available negative
+    #[clap(long)]
+    pub allow_forced_hold: bool,
+
+    /// Only hold a disputed deposit once available covers it. Until then the dispute waits and is held after a later deposit
+    #[clap(long)]
+    pub dispute_requires_funds: bool,
+
+    /// Stop the run if a resolve or chargeback leaves a client with negative held funds
+    #[clap(long)]
+    pub abort_on_negative_held: bool,
+
+    /// Ignore rows whose effect is already in the state, like a deposit whose tx id is stored or a dispute of a disputed
+    /// transaction, so an input read again after loading its own snapshot changes nothing
+    #[clap(long)]
+    pub idempotent_replay: bool,
+
+    /// Promise that the input keeps all rows of a client together. A row that breaks the promise stops the run
+    #[clap(long, arg_enum)]
+    pub assume_grouped_by: Option<Grouping>,
+
+    /// Read the whole CSV input first and apply its rows in the order of their timestamp column, keeping the input order of rows with equal timestamps
+    #[clap(long)]
+    pub sort_by_timestamp: bool,
+
+    /// Merge several CSV input files or named pipes into one sequence by their timestamp column instead of reading them
+    /// one after another. Rows with equal timestamps are applied in the order the inputs were given
+    #[clap(long, arg_enum)]
+    pub merge_by: Option<MergeBy>,
+
+    /// With --merge-by, the most seconds to wait for an input that has no row ready before merging the others without
+    /// it. Without it the merge waits for every input, so rows are always applied in timestamp order
+    #[clap(long)]
+    pub merge_max_skew: Option<u64>,
+
+    /// Keep one account per client and currency when the CSV input has a currency column, and add a currency column to
+    /// the report. Disputes, resolves, chargebacks, corrections and releases may leave the currency empty
+    #[clap(long)]
+    pub multi_currency: bool,
+
+    /// Whether a failed assert row stops the run or is only recorded
+    #[clap(long, arg_enum, default_value = "strict")]
+    pub assertions: AssertionMode,
+
+    /// Apply the rows again from several threads on an engine split by client, compare the accounts both runs leave
+    /// field by field and stop the run if any differ. Meant for canary runs: it keeps every applied row in memory, so it
+    /// needs input files rather than standard input or a URL
+    #[clap(long)]
+    pub verify_parallel: bool,
+
+    /// Test hook for --verify-parallel: flip the locked flag of this client's accounts after the parallel run
+    #[clap(long, hide = true)]
+    pub verify_parallel_divergence: Option<u16>,
+
+    // Stop after this input row instead of at the end of the input
+    #[clap(skip)]
+    pub until_row: Option<u64>,
+
+    // Set when several files are read as one input, whose rows then no longer match the lines of any one file
+    #[clap(skip)]
+    pub multiple_inputs: bool,
+
+    // The inputs of the run, whose digests the --audit-log run header holds
+    #[clap(skip)]
+    pub inputs: Vec<String>,
+
+    // The --report-schema of the run, which a --snapshot-in has to have been written with. Unset for subcommands that
+    // write no account report
+    #[clap(skip)]
+    pub report_schema: Option<ReportSchema>,
+
+    // Set by --dry-run, which lists every row that was not applied. Rows that do not parse are skipped then, as with
+    // --annotate-out, so one run finds all of them
+    #[clap(skip)]
+    pub keep_outcomes: bool,
+}
+
+impl ProcessingArgs {
+    // This function builds the engine policy from the flags, reading the --limits-file if there is one
+    pub fn policy(&self) -> Result<EnginePolicy, EngineError> {
+        let mut policy = EnginePolicy::builder()
+            .track_debt(self.track_debt)
+            .allow_forced_hold(self.allow_forced_hold)
+            .dispute_requires_funds(self.dispute_requires_funds)
+            .lock_policy(self.lock_policy);
+        if let Some(fee) = self.chargeback_fee {
+            // Every chargeback would fail to debit a fee the engine cannot take, so it is refused up front
+            if fee < Money::ZERO || fee.round_dp(money::DECIMAL_PLACES) != fee {
+                return Err(EngineError::Invalid(format!("--chargeback-fee {} must be zero or more with at most {} decimal places", fee, money::DECIMAL_PLACES)));
+            }
+            policy = policy.chargeback_fee(fee);
+        }
+        if let Some(fee) = self.monthly_fee {
+            if fee < Money::ZERO || fee.round_dp(money::DECIMAL_PLACES) != fee {
+                return Err(EngineError::Invalid(format!("--monthly-fee {} must be zero or more with at most {} decimal places", fee, money::DECIMAL_PLACES)));
+            }
+            policy = policy.monthly_fee(fee);
+        }
+        if let Some(cap) = self.max_balance {
+            policy = policy.max_balance(cap);
+        }
+        if let Some(scales) = &self.currency_scales {
+            policy = policy.currency_scales(scales.clone());
+        }
+        for (client_id, cap) in self.client_caps()? {
+            policy = policy.client_max_balance(client_id, cap);
+        }
+        Ok(policy.build())
+    }
+
+    // This function returns the per-client balance caps of --limits-file and --client-max-balance together
+    pub(crate) fn client_caps(&self) -> Result<BTreeMap<u16, Money>, EngineError> {
+        let mut caps = match &self.limits_file {
+            Some(path) => read_limits(path)?,
+            None => BTreeMap::new(),
+        };
+        caps.extend(self.client_max_balance.iter().flat_map(|caps| &caps.caps));
+        Ok(caps)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    Binlog,
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputPrecision {
+    #[default]
+    Round,
+    Truncate,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AssertionMode {
+    // The first failed assertion aborts the run
+    #[default]
+    Strict,
+    // Failed assertions are recorded and processing continues
+    Lenient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeBy {
+    // The timestamp column of CSV inputs
+    Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grouping {
+    // Every row of a client comes before any row of the next client
+    Client,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // An array of account objects named like the CSV columns, amounts as strings under the v2 schema
+    Json,
+    // Columns aligned for reading in a terminal
+    Table,
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportSchema {
+    // The original layout, kept for parsers that depend on it: amounts go through f32 (so 2.0 is written as 2.0 and
+    // large balances lose precision) and accounts come out in no particular order
+    V1,
+    // Amounts are written exactly with four decimal places and accounts are sorted by client id. Columns may be
+    // added at the end in later versions, so readers should look them up by header
+    #[default]
+    V2,
+}
+
+// This function names a report schema as --report-schema takes it
+pub(crate) fn schema_name(schema: ReportSchema) -> &'static str {
+    match schema {
+        ReportSchema::V1 => "v1",
+        ReportSchema::V2 => "v2",
+    }
+}
+
+// A set of client ids and inclusive id ranges, parsed from a spec like "17,42,9000-9100". A range may leave out either
+// end, so "9000-" is every id from 9000 up and "-99" every id up to 99
+#[derive(Debug, Clone)]
+pub struct ClientFilter {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl ClientFilter {
+    pub fn single(client_id: u16) -> ClientFilter {
+        ClientFilter { ranges: vec![(client_id, client_id)] }
+    }
+
+    // This function combines filters given several times into one that lets through every client any of them does,
+    // None if there are none
+    pub fn union(filters: &[ClientFilter]) -> Option<ClientFilter> {
+        (!filters.is_empty()).then(|| ClientFilter { ranges: filters.iter().flat_map(|filter| filter.ranges.iter().copied()).collect() })
+    }
+
+    pub fn contains(&self, client_id: u16) -> bool {
+        self.ranges.iter().any(|&(start, end)| start <= client_id && client_id <= end)
+    }
+}
+
+impl FromStr for ClientFilter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<ClientFilter, String> {
+        let parse_id = |id: &str| id.trim().parse::<u16>().map_err(|_| format!("invalid client id {:?}", id.trim()));
+        // An empty end of a range is open, but a range of two empty ends is not a range
+        let parse_end = |id: &str, open: u16| if id.trim().is_empty() { Ok(open) } else { parse_id(id) };
+
+        let mut ranges = Vec::new();
+        for part in spec.split(',') {
+            let range = match part.split_once('-') {
+                Some((start, end)) if !(start.trim().is_empty() && end.trim().is_empty()) => (parse_end(start, u16::MIN)?, parse_end(end, u16::MAX)?),
+                Some(_) => return Err(format!("client range {:?} has no ends", part.trim())),
+                None => (parse_id(part)?, parse_id(part)?),
+            };
+            if range.0 > range.1 {
+                return Err(format!("client range {:?} is backwards", part.trim()));
+            }
+            ranges.push(range);
+        }
+
+        Ok(ClientFilter { ranges })
+    }
+}
+
+impl fmt::Display for ClientFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(start, end)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Balance caps for single clients, parsed from a spec like "17=500,42=20000"
+#[derive(Debug, Clone)]
+pub struct BalanceCaps {
+    pub(crate) caps: BTreeMap<u16, Money>,
+}
+
+impl FromStr for BalanceCaps {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<BalanceCaps, String> {
+        let mut caps = BTreeMap::new();
+        for part in spec.split(',') {
+            let (client_id, cap) = part.split_once('=').ok_or_else(|| format!("invalid balance cap {:?}, expected CLIENT=AMOUNT", part.trim()))?;
+            let client_id = client_id.trim().parse::<u16>().map_err(|_| format!("invalid client id {:?}", client_id.trim()))?;
+            let cap = cap.trim().parse::<Money>().map_err(|_| format!("invalid amount {:?}", cap.trim()))?;
+            if caps.insert(client_id, cap).is_some() {
+                return Err(format!("client {} has more than one balance cap", client_id));
+            }
+        }
+        Ok(BalanceCaps { caps })
+    }
+}
+
+impl fmt::Display for BalanceCaps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (client_id, cap)) in self.caps.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", client_id, cap)?;
+        }
+        Ok(())
+    }
+}
+
+// One row of a --limits-file. The cap is text so it is parsed exactly, like amounts in the input
+#[derive(Debug, Deserialize)]
+struct LimitRow {
+    client: u16,
+    max_total: String,
+}
+
+// This function reads per-client balance caps from a CSV file with a client,max_total header. A client listed twice is
+// an error, as in --client-max-balance
+fn read_limits(path: &str) -> Result<BTreeMap<u16, Money>, EngineError> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open limits file {}: {}", path, e)))?;
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(file);
+
+    let mut caps = BTreeMap::new();
+    for (index, row) in rdr.deserialize::<LimitRow>().enumerate() {
+        let line = index + 2;
+        let row = row.map_err(|e| format!("{} line {}: {}", path, line, e))?;
+        let cap = row.max_total.parse::<Money>().map_err(|_| format!("{} line {}: invalid amount {:?}", path, line, row.max_total))?;
+        if caps.insert(row.client, cap).is_some() {
+            return Err(format!("{} line {}: client {} has more than one limit", path, line, row.client).into());
+        }
+    }
+    Ok(caps)
+}
+
+// How long a record stays disputable. A record stored at row R can be referenced up to row R + rows
+#[derive(Debug, Clone, Copy)]
+pub struct RecordRetention {
+    pub(crate) rows: u64,
+}
+
+impl FromStr for RecordRetention {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<RecordRetention, String> {
+        match spec.split_once('=') {
+            Some(("rows", rows)) => rows.trim().parse::<u64>()
+                .map(|rows| RecordRetention { rows })
+                .map_err(|_| format!("invalid row count {:?}", rows.trim())),
+            _ => Err(format!("unsupported retention {:?}, expected rows=N", spec)),
+        }
+    }
+}
+
+impl fmt::Display for RecordRetention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rows={}", self.rows)
+    }
+}
+
+// The character that groups digits in CSV amounts. It cannot be one that is part of a number itself
+#[derive(Debug, Clone, Copy)]
+pub struct ThousandsSeparator(pub(crate) char);
+
+impl Default for ThousandsSeparator {
+    fn default() -> ThousandsSeparator {
+        ThousandsSeparator(',')
+    }
+}
+
+impl FromStr for ThousandsSeparator {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<ThousandsSeparator, String> {
+        let mut chars = spec.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E')) => Ok(ThousandsSeparator(c)),
+            _ => Err(format!("invalid thousands separator {:?}, expected a single character that is not part of a number", spec)),
+        }
+    }
+}
+
+impl fmt::Display for ThousandsSeparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_filters_take_ids_and_ranges() -> Result<(), String> {
+        let filter: ClientFilter = "1-5,9".parse()?;
+        let included: Vec<u16> = (0..=10).filter(|&client_id| filter.contains(client_id)).collect();
+        assert_eq!(included, [1, 2, 3, 4, 5, 9]);
+
+        let open: ClientFilter = " 65530- ,-2".parse()?;
+        assert!(open.contains(0) && open.contains(2) && !open.contains(3) && open.contains(u16::MAX));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_client_filters_are_refused() {
+        for (spec, error) in [
+            ("5-1", "client range \"5-1\" is backwards"),
+            ("1-5,9-7", "client range \"9-7\" is backwards"),
+            ("-", "client range \"-\" has no ends"),
+            ("1-x", "invalid client id \"x\""),
+            ("1,,2", "invalid client id \"\""),
+            ("1-2-3", "invalid client id \"2-3\""),
+            ("70000", "invalid client id \"70000\""),
+        ] {
+            assert_eq!(spec.parse::<ClientFilter>().err().as_deref(), Some(error), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,290 @@
+// Arrow IPC input and report output, enabled with the `arrow` feature.
+//
+// Input batches must have the columns type (utf8 or a dictionary of utf8), client (uint16), tx (uint64) and
+// amount (nullable decimal128). Both the IPC file and stream formats are accepted. Each batch is converted to
+// transactions and fed through the normal handlers before the next one is read.
+
+use crate::heartbeat::{ByteCounter, CountingReader};
+use crate::money;
+use crate::report::{ReportSink, RunMeta};
+use crate::{AccountSummary, EngineError, TransactionRow};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Decimal128Type, UInt16Type, UInt64Type};
+use arrow_array::{Array, ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, UInt16Array};
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const FILE_MAGIC: &[u8; 6] = b"ARROW1";
+
+// Amounts in the report are written with the widest precision Decimal128 allows, at the scale --precision asks for
+const REPORT_PRECISION: u8 = 38;
+
+type Batches = Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>;
+
+pub(crate) struct ArrowReader {
+    batches: Batches,
+    pending: std::vec::IntoIter<TransactionRow>,
+    failed: bool,
+}
+
+impl ArrowReader {
+    // This function opens an Arrow IPC file or stream and checks its schema before any batch is read.
+    // Bytes read from the file are added to `bytes`
+    pub(crate) fn open<P: AsRef<Path>>(path: P, bytes: &ByteCounter) -> Result<ArrowReader, EngineError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("could not open arrow input {}: {}", path.display(), e))?;
+        let mut file = CountingReader::new(file, bytes);
+
+        // The file format starts with a magic string, the stream format does not
+        let mut magic = [0u8; 6];
+        let is_file = file.read_exact(&mut magic).is_ok() && &magic == FILE_MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+
+        let (schema, batches): (_, Batches) = if is_file {
+            let reader = FileReader::try_new_buffered(file, None).map_err(arrow_error)?;
+            (reader.schema(), Box::new(reader))
+        } else {
+            let reader = StreamReader::try_new(BufReader::new(file), None).map_err(arrow_error)?;
+            (reader.schema(), Box::new(reader))
+        };
+        check_schema(&schema)?;
+
+        Ok(ArrowReader {
+            batches,
+            pending: Vec::new().into_iter(),
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for ArrowReader {
+    type Item = Result<TransactionRow, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.failed {
+                return None;
+            }
+            if let Some(transaction) = self.pending.next() {
+                return Some(Ok(transaction));
+            }
+
+            let batch = match self.batches.next()? {
+                Ok(batch) => batch,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                },
+            };
+            match batch_transactions(&batch) {
+                Ok(transactions) => self.pending = transactions.into_iter(),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                },
+            }
+        }
+    }
+}
+
+// This function keeps arrow's I/O failures apart from input it cannot read
+fn arrow_error(e: ArrowError) -> EngineError {
+    match e {
+        ArrowError::IoError(_, e) => EngineError::Io(e),
+        e => EngineError::parse(None, e),
+    }
+}
+
+// This function reports the first missing column or column with an unexpected type, naming the field and both types
+fn check_schema(schema: &Schema) -> Result<(), EngineError> {
+    let expect = |name: &str, ok: fn(&DataType) -> bool, expected: &str| -> Result<(), EngineError> {
+        let field = schema.field_with_name(name).map_err(|_| format!("arrow input is missing field \"{}\"", name))?;
+        if !ok(field.data_type()) {
+            return Err(format!("arrow field \"{}\" has type {}, expected {}", name, field.data_type(), expected).into());
+        }
+        Ok(())
+    };
+
+    expect("type", |t| match t {
+        DataType::Utf8 => true,
+        DataType::Dictionary(_, values) => values.as_ref() == &DataType::Utf8,
+        _ => false,
+    }, "Utf8 or Dictionary(_, Utf8)")?;
+    expect("client", |t| t == &DataType::UInt16, "UInt16")?;
+    expect("tx", |t| t == &DataType::UInt64, "UInt64")?;
+    expect("amount", |t| matches!(t, DataType::Decimal128(_, _)), "Decimal128")?;
+    Ok(())
+}
+
+// This function converts every row of a batch into a transaction
+fn batch_transactions(batch: &RecordBatch) -> Result<Vec<TransactionRow>, EngineError> {
+    let column = |name: &str| -> Result<&ArrayRef, EngineError> {
+        batch.column_by_name(name).ok_or_else(|| format!("arrow batch is missing field \"{}\"", name).into())
+    };
+    let mismatch = |name: &str| format!("arrow field \"{}\" changed type mid-stream", name);
+
+    let types = column("type")?;
+    let clients = column("client")?.as_primitive_opt::<UInt16Type>().ok_or_else(|| mismatch("client"))?;
+    let tx_ids = column("tx")?.as_primitive_opt::<UInt64Type>().ok_or_else(|| mismatch("tx"))?;
+    let amounts = column("amount")?.as_primitive_opt::<Decimal128Type>().ok_or_else(|| mismatch("amount"))?;
+    let scale = u32::try_from(amounts.scale()).map_err(|_| "arrow field \"amount\" has a negative scale")?;
+
+    // Dictionary encoded type columns are looked up through their keys
+    let (type_values, type_keys) = match types.as_any_dictionary_opt() {
+        Some(dict) => (dict.values().as_string_opt::<i32>(), Some(dict.normalized_keys())),
+        None => (types.as_string_opt::<i32>(), None),
+    };
+    let type_values = type_values.ok_or_else(|| mismatch("type"))?;
+
+    let mut transactions = Vec::with_capacity(batch.num_rows());
+    for row in 0..batch.num_rows() {
+        let null = |name: &str| format!("arrow row {} has a null \"{}\"", row, name);
+        if types.is_null(row) {
+            return Err(null("type").into());
+        }
+        if clients.is_null(row) {
+            return Err(null("client").into());
+        }
+        if tx_ids.is_null(row) {
+            return Err(null("tx").into());
+        }
+
+        let type_index = match &type_keys {
+            Some(keys) => *keys.get(row).ok_or_else(|| null("type"))?,
+            None => row,
+        };
+        if type_index >= type_values.len() {
+            return Err(format!("arrow row {} has an invalid dictionary key for \"type\"", row).into());
+        }
+
+        let transaction_id = u32::try_from(tx_ids.value(row))
+            .map_err(|_| format!("arrow row {} has tx {} which does not fit in 32 bits", row, tx_ids.value(row)))?;
+        let amount = if amounts.is_null(row) {
+            None
+        } else {
+            let amount = money::from_parts(amounts.value(row), scale)
+                .ok_or_else(|| format!("arrow row {} has an amount out of range", row))?;
+            Some(amount)
+        };
+
+        transactions.push(TransactionRow {
+            transaction_type: type_values.value(type_index).parse()
+                .map_err(|e| format!("arrow row {} has an {}", row, e))?,
+            client_id: clients.value(row),
+            transaction_id,
+            amount,
+            expected_held_total: None,
+            timestamp: None,
+            currency: None,
+        });
+    }
+
+    Ok(transactions)
+}
+
+// Writes the report as a single-batch Arrow IPC file. Accounts are buffered as columns and the file is written in
+// finish, with the number of input rows processed and the report schema kept in the schema metadata under "rows"
+// and "report_schema". A report with currencies has a nullable currency column after the client
+pub(crate) struct ArrowSink<W: Write> {
+    out: Option<W>,
+    metadata: HashMap<String, String>,
+    scale: u32,
+    ids: Vec<u16>,
+    currencies: Option<Vec<Option<String>>>,
+    available: Vec<i128>,
+    held: Vec<i128>,
+    total: Vec<i128>,
+    locked: Vec<bool>,
+}
+
+impl<W: Write> ArrowSink<W> {
+    pub(crate) fn new(out: W) -> ArrowSink<W> {
+        ArrowSink {
+            out: Some(out),
+            metadata: HashMap::new(),
+            scale: money::DECIMAL_PLACES,
+            ids: Vec::new(),
+            currencies: None,
+            available: Vec::new(),
+            held: Vec::new(),
+            total: Vec::new(),
+            locked: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> ReportSink for ArrowSink<W> {
+    fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+        if let Some(rows) = meta.rows {
+            self.metadata.insert("rows".to_string(), rows.to_string());
+        }
+        self.metadata.insert("report_schema".to_string(), format!("{:?}", meta.schema).to_lowercase());
+        self.scale = meta.precision;
+        let accounts = meta.accounts.unwrap_or_default();
+        self.ids.reserve(accounts);
+        if meta.currencies {
+            self.currencies = Some(Vec::with_capacity(accounts));
+        }
+        self.available.reserve(accounts);
+        self.held.reserve(accounts);
+        self.total.reserve(accounts);
+        self.locked.reserve(accounts);
+        Ok(())
+    }
+
+    fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+        let scaled = |value| money::to_scaled(value, self.scale).ok_or("balance does not fit in the arrow report");
+        self.ids.push(account.client);
+        if let Some(currencies) = self.currencies.as_mut() {
+            currencies.push(account.currency.map(|currency| currency.to_string()));
+        }
+        self.available.push(scaled(account.available)?);
+        self.held.push(scaled(account.held)?);
+        self.total.push(scaled(account.total)?);
+        self.locked.push(account.locked);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        let out = self.out.take().ok_or("arrow report already written")?;
+        // --precision is checked to be at most 28, so the scale always fits
+        let scale = i8::try_from(self.scale).map_err(|_| "--precision is too large for an arrow report")?;
+        let decimal = DataType::Decimal128(REPORT_PRECISION, scale);
+        let mut fields = vec![Field::new("client", DataType::UInt16, false)];
+        let mut columns: Vec<ArrayRef> = vec![Arc::new(UInt16Array::from(std::mem::take(&mut self.ids)))];
+        if let Some(currencies) = self.currencies.take() {
+            fields.push(Field::new("currency", DataType::Utf8, true));
+            columns.push(Arc::new(StringArray::from(currencies)));
+        }
+        fields.extend([
+            Field::new("available", decimal.clone(), false),
+            Field::new("held", decimal.clone(), false),
+            Field::new("total", decimal, false),
+            Field::new("locked", DataType::Boolean, false),
+        ]);
+        let schema = Arc::new(Schema::new(fields).with_metadata(std::mem::take(&mut self.metadata)));
+
+        let decimals = |values: Vec<i128>| -> Result<ArrayRef, ArrowError> {
+            Ok(Arc::new(Decimal128Array::from(values).with_precision_and_scale(REPORT_PRECISION, scale)?))
+        };
+        columns.extend([
+            decimals(std::mem::take(&mut self.available)).map_err(arrow_error)?,
+            decimals(std::mem::take(&mut self.held)).map_err(arrow_error)?,
+            decimals(std::mem::take(&mut self.total)).map_err(arrow_error)?,
+            Arc::new(BooleanArray::from(std::mem::take(&mut self.locked))),
+        ]);
+        let batch = RecordBatch::try_new(schema.clone(), columns).map_err(arrow_error)?;
+
+        let mut writer = FileWriter::try_new(out, &schema).map_err(arrow_error)?;
+        writer.write(&batch).map_err(arrow_error)?;
+        writer.finish().map_err(arrow_error)?;
+        Ok(())
+    }
+}
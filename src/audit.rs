@@ -0,0 +1,216 @@
+// Audit trail written with --audit-log: one CSV row for every input row, written and flushed as the row is processed.
+//
+//   row,line,type,client,tx,amount,outcome,reason,available,held,total,timestamp,prev_hash,hash
+//
+// outcome and reason are the ones --annotate-out uses. An applied row has the outcome applied and no reason, a
+// rejected one the snake case code of its Rejection, so the audit trail names reasons exactly like the engine does.
+// Rows that could not be parsed have no type, client or tx. The balances are the client's account after the row and
+// are empty when the client has no account. timestamp is the row's own, empty unless the input has a timestamp column.
+//
+// A monthly fee has a line of its own with the type monthly_fee, the outcome fee_charged or fee_failed and a tx id from
+// MONTHLY_FEE_TX_IDS. Its row and line are those of the row that ended the month.
+//
+// The lines form a hash chain, so an edit after the fact shows. hash is the SHA-256, in hex, of the line up to the comma
+// before it, which includes prev_hash, the hash of the line before. The first line after the header is the run header,
+// with row 0, the outcome run_header and a reason naming the engine version and the SHA-256 of every input file, e.g.
+//
+//   0,,,,,,run_header,engine=0.1.0 input=in.csv sha256=9f86d0...,,,,,0000...0000,3a7bd3...
+//
+// Its prev_hash is 64 zeros. Standard input and URLs are read once, so they are named without a digest.
+// `payment_engine audit verify` recomputes the chain and reports the first line that does not match.
+
+use crate::money::{Money, Places};
+use crate::run::STDIN_INPUT;
+use crate::{remote, Client, EngineError, MonthlyFee, TransactionRow};
+use chrono::SecondsFormat;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+#[derive(Debug, Serialize)]
+struct AuditRow<'a> {
+    row: u64,
+    line: Option<u64>,
+    #[serde(rename = "type")]
+    transaction_type: Option<&'static str>,
+    client: Option<u16>,
+    tx: Option<u32>,
+    amount: Option<String>,
+    outcome: &'a str,
+    reason: &'a str,
+    available: Option<String>,
+    held: Option<String>,
+    total: Option<String>,
+    timestamp: Option<String>,
+    prev_hash: &'a str,
+}
+
+const HEADER: &str = "row,line,type,client,tx,amount,outcome,reason,available,held,total,timestamp,prev_hash,hash";
+
+// The prev_hash of the run header, which has no line before it
+const SEED: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub(crate) struct AuditLog {
+    out: File,
+    // The line being encoded, so its hash can be taken before it is written. Kept to reuse its allocation
+    line: Vec<u8>,
+    prev_hash: String,
+}
+
+impl AuditLog {
+    // This function creates the log and writes its run header. `inputs` are the files the run reads, whose digests
+    // the header holds
+    pub(crate) fn create(path: &str, inputs: &[String]) -> Result<AuditLog, EngineError> {
+        let mut out = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("could not create audit log {}: {}", path, e)))?;
+        writeln!(out, "{}", HEADER)?;
+        let mut log = AuditLog { out, line: Vec::new(), prev_hash: SEED.to_string() };
+
+        let mut reason = format!("engine={}", env!("CARGO_PKG_VERSION"));
+        for input in inputs {
+            reason.push_str(&format!(" input={}", input));
+            if input != STDIN_INPUT && !remote::is_url(input) {
+                reason.push_str(&format!(" sha256={}", file_digest(input)?));
+            }
+        }
+        log.append(AuditRow {
+            row: 0, line: None, transaction_type: None, client: None, tx: None, amount: None, outcome: "run_header", reason: &reason,
+            available: None, held: None, total: None, timestamp: None, prev_hash: SEED,
+        })?;
+        Ok(log)
+    }
+
+    // This function writes one line with its hash and flushes it. The row's prev_hash is replaced by the last hash
+    fn append(&mut self, row: AuditRow) -> Result<(), EngineError> {
+        let mut encoder = csv::WriterBuilder::new().has_headers(false).buffer_capacity(256).from_writer(std::mem::take(&mut self.line));
+        encoder.serialize(AuditRow { prev_hash: &self.prev_hash, ..row })?;
+        let mut line = encoder.into_inner().map_err(|e| io::Error::other(e.error().to_string()))?;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        let hash = format!("{:x}", Sha256::digest(&line));
+        line.push(b',');
+        line.extend_from_slice(hash.as_bytes());
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.out.flush()?;
+        self.prev_hash = hash;
+        line.clear();
+        self.line = line;
+        Ok(())
+    }
+
+    // This function appends the event for one row. `account` is the client's account after the row
+    pub(crate) fn write(&mut self, row: u64, line: Option<u64>, transaction: Option<&TransactionRow>, outcome: &str, reason: &str, account: Option<&Client>) -> Result<(), EngineError> {
+        let amount = |value: Money| Places(value, 4).to_string();
+        self.append(AuditRow {
+            row,
+            line,
+            transaction_type: transaction.map(|t| t.transaction_type.name()),
+            client: transaction.map(|t| t.client_id),
+            tx: transaction.map(|t| t.transaction_id),
+            amount: transaction.and_then(|t| t.amount).map(amount),
+            outcome,
+            reason,
+            available: account.map(|c| amount(c.available)),
+            held: account.map(|c| amount(c.held)),
+            total: account.map(|c| amount(c.total)),
+            timestamp: transaction.and_then(|t| t.timestamp).map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            prev_hash: "",
+        })
+    }
+
+    // This function appends the event for a monthly fee, charged when row ended the month it is for
+    pub(crate) fn write_monthly_fee(&mut self, row: u64, line: Option<u64>, fee: &MonthlyFee) -> Result<(), EngineError> {
+        let amount = |value: Money| Places(value, 4).to_string();
+        let (outcome, reason) = match fee.result {
+            Ok(()) => ("fee_charged", ""),
+            Err(e) => ("fee_failed", e.code()),
+        };
+        self.append(AuditRow {
+            row,
+            line,
+            transaction_type: Some("monthly_fee"),
+            client: Some(fee.account.client_id),
+            tx: Some(fee.tx),
+            amount: Some(amount(fee.fee)),
+            outcome,
+            reason,
+            available: Some(amount(fee.available)),
+            held: Some(amount(fee.held)),
+            total: Some(amount(fee.total)),
+            timestamp: None,
+            prev_hash: "",
+        })
+    }
+}
+
+// This function returns the SHA-256 of a file in hex, reading it in blocks
+fn file_digest(path: &str) -> Result<String, EngineError> {
+    let mut file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open {}: {}", path, e)))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// The first line of an audit log whose hash chain does not hold, and why
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BrokenLink {
+    // Line of the file, the header being line 1
+    pub(crate) line: u64,
+    // The row column of that line, if it can still be read
+    pub(crate) row: Option<u64>,
+    pub(crate) reason: &'static str,
+}
+
+// This function recomputes the hash chain of an audit log. It returns the number of lines after the header, or the
+// first line that breaks the chain
+pub(crate) fn verify_chain(input: impl BufRead) -> Result<Result<u64, BrokenLink>, EngineError> {
+    let mut prev_hash = SEED.to_string();
+    let mut count = 0;
+    for (index, line) in input.split(b'\n').enumerate() {
+        let line = line?;
+        let number = index as u64 + 1;
+        if number == 1 {
+            if line != HEADER.as_bytes() {
+                return Ok(Err(BrokenLink { line: 1, row: None, reason: "the header is not an audit log header" }));
+            }
+            continue;
+        }
+        let row = line.split(|&byte| byte == b',').next().and_then(|row| std::str::from_utf8(row).ok()).and_then(|row| row.parse().ok());
+        let broken = |reason| Ok(Err(BrokenLink { line: number, row, reason }));
+        let Some(comma) = line.iter().rposition(|&byte| byte == b',') else {
+            return broken("the line has no hash");
+        };
+        let (hashed, hash) = line.split_at(comma);
+        if format!("{:x}", Sha256::digest(hashed)).as_bytes() != hash.get(1..).unwrap_or_default() {
+            return broken("the hash does not match the line");
+        }
+        if !hashed.ends_with(prev_hash.as_bytes()) || hashed.get(hashed.len().saturating_sub(SEED.len() + 1)) != Some(&b',') {
+            return broken("prev_hash is not the hash of the line before");
+        }
+        prev_hash = String::from_utf8_lossy(hash.get(1..).unwrap_or_default()).into_owned();
+        count += 1;
+    }
+    if count == 0 {
+        return Ok(Err(BrokenLink { line: 2, row: None, reason: "the run header is missing" }));
+    }
+    Ok(Ok(count))
+}
+
+// This function runs `audit verify` on the audit log at `path`. It prints the first broken link, or how many lines the
+// intact chain has, and returns whether the chain is intact
+pub fn run_verify(path: &str) -> Result<bool, EngineError> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open audit log {}: {}", path, e)))?;
+    match verify_chain(BufReader::new(file))? {
+        Ok(count) => {
+            println!("{}: the hash chain of {} lines is intact", path, count);
+            Ok(true)
+        },
+        Err(broken) => {
+            let row = broken.row.map(|row| format!(" (row {})", row)).unwrap_or_default();
+            println!("{}: broken link at line {}{}: {}", path, broken.line, row, broken.reason);
+            Ok(false)
+        },
+    }
+}
@@ -0,0 +1,183 @@
+// The `bench` subcommand: a one-shot throughput number for the machine it runs on.
+//
+// Transactions are generated in memory from a seeded generator, so nothing is read from disk, and applied with the
+// flags given to bench exactly as a normal run would apply them. The input is generated twice: once on its own, to
+// time the generator, and once fed straight into the engine, so the engine's share is the difference. Results are
+// key=value lines on stderr, like the heartbeat, because the engine's own diagnostics go to stdout; run it with
+// stdout sent to /dev/null to keep terminal output out of the numbers.
+
+use crate::BenchArgs;
+use payment_engine::heartbeat::ByteCounter;
+use payment_engine::money::Money;
+use payment_engine::report::{self, CsvSink, ReportOptions};
+use payment_engine::run::process_transactions;
+use payment_engine::{Client, EngineError, Record, TransactionRow, TransactionType};
+use clap::ArgEnum;
+use std::io;
+use std::mem::size_of;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+pub(crate) enum BenchProfile {
+    // Deposits only
+    Deposits,
+    // Mostly deposits with some withdrawals and the odd dispute
+    Mixed,
+    // A large share of deposits end up disputed
+    DisputeHeavy,
+}
+
+impl BenchProfile {
+    // Share of rows that are withdrawals, and the dispute rate used when --dispute-rate is not given
+    fn shape(self) -> (f64, f64) {
+        match self {
+            BenchProfile::Deposits => (0.0, 0.0),
+            BenchProfile::Mixed => (0.25, 0.01),
+            BenchProfile::DisputeHeavy => (0.10, 0.20),
+        }
+    }
+}
+
+// xorshift64*, good enough to shape a workload and the same on every platform
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    // This function seeds the generator. xorshift never leaves zero, so a zero seed is taken as 1
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+// Yields the generated rows. Disputes pick an earlier deposit, and every open dispute is eventually resolved or charged back
+struct Generator {
+    rng: Rng,
+    rows: u64,
+    row: u64,
+    accounts: u16,
+    withdrawal_rate: f64,
+    dispute_rate: f64,
+    deposits: Vec<(u16, u32)>,
+    open_disputes: Vec<(u16, u32)>,
+}
+
+impl Generator {
+    fn new(args: &BenchArgs) -> Generator {
+        let (withdrawal_rate, default_dispute_rate) = args.profile.shape();
+        Generator {
+            rng: Rng::new(args.seed),
+            rows: args.rows,
+            row: 0,
+            accounts: args.accounts,
+            withdrawal_rate,
+            dispute_rate: args.dispute_rate.unwrap_or(default_dispute_rate),
+            deposits: Vec::new(),
+            open_disputes: Vec::new(),
+        }
+    }
+
+    fn transaction(transaction_type: TransactionType, client_id: u16, transaction_id: u32, amount: Option<Money>) -> TransactionRow {
+        TransactionRow {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            expected_held_total: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    fn amount(&mut self) -> Option<Money> {
+        let cents = self.rng.below(100_000) + 1;
+        format!("{}.{:02}", cents / 100, cents % 100).parse().ok()
+    }
+}
+
+impl Iterator for Generator {
+    type Item = TransactionRow;
+
+    fn next(&mut self) -> Option<TransactionRow> {
+        if self.row >= self.rows {
+            return None;
+        }
+        self.row += 1;
+
+        if !self.deposits.is_empty() && self.rng.chance(self.dispute_rate) {
+            let index = self.rng.below(self.deposits.len() as u64) as usize;
+            let (client, tx) = self.deposits.swap_remove(index);
+            self.open_disputes.push((client, tx));
+            return Some(Generator::transaction(TransactionType::Dispute, client, tx, None));
+        }
+        if !self.open_disputes.is_empty() && self.rng.chance(self.dispute_rate) {
+            let index = self.rng.below(self.open_disputes.len() as u64) as usize;
+            let (client, tx) = self.open_disputes.swap_remove(index);
+            let settle = if self.rng.chance(0.25) { TransactionType::Chargeback } else { TransactionType::Resolve };
+            return Some(Generator::transaction(settle, client, tx, None));
+        }
+
+        // Row numbers start at 1 and run checks that they fit in a tx id
+        let tx = u32::try_from(self.row).unwrap_or(u32::MAX);
+        let client = self.rng.below(u64::from(self.accounts)) as u16 + 1;
+        let amount = self.amount();
+        if self.rng.chance(self.withdrawal_rate) {
+            Some(Generator::transaction(TransactionType::Withdrawal, client, tx, amount))
+        } else {
+            self.deposits.push((client, tx));
+            Some(Generator::transaction(TransactionType::Deposit, client, tx, amount))
+        }
+    }
+}
+
+// This function runs the benchmark and prints its results to stderr
+pub(crate) fn run(args: &BenchArgs) -> Result<(), EngineError> {
+    if args.rows > u64::from(u32::MAX) {
+        return Err(format!("--rows can be at most {}, one tx id per row", u32::MAX).into());
+    }
+    if args.accounts == 0 {
+        return Err("--accounts must be at least 1".into());
+    }
+
+    let started = Instant::now();
+    let generated = Generator::new(args).count();
+    let generate_secs = started.elapsed().as_secs_f64();
+
+    let started = Instant::now();
+    let transactions = Generator::new(args).map(|t| Ok(Some(t)));
+    let policy = args.processing.policy()?;
+    let state = process_transactions(Box::new(transactions), &ByteCounter::default(), &args.processing, &policy, None, None)?;
+    let process_secs = started.elapsed().as_secs_f64();
+    let engine_secs = (process_secs - generate_secs).max(0.0);
+
+    let started = Instant::now();
+    report::write_report(state.engine.accounts(), state.rows, &ReportOptions::default(), &mut CsvSink::new(io::sink()))?;
+    let report_secs = started.elapsed().as_secs_f64();
+
+    // The accounts and stored records dominate memory and are kept to the end of the run, hash table overhead aside
+    let peak_memory = state.engine.accounts().len() * size_of::<(u16, Client)>() + state.engine.transactions().len() * size_of::<(u32, Record)>();
+    let rate = |secs: f64| if secs > 0.0 { generated as f64 / secs } else { 0.0 };
+
+    let profile = args.profile.to_possible_value().map(|v| v.get_name()).unwrap_or_default();
+    eprintln!("bench profile={} rows={} accounts={} dispute_rate={}", profile, generated, args.accounts,
+        args.dispute_rate.unwrap_or(args.profile.shape().1));
+    eprintln!("phase name=generate secs={:.3}", generate_secs);
+    eprintln!("phase name=engine secs={:.3}", engine_secs);
+    eprintln!("phase name=report secs={:.3}", report_secs);
+    eprintln!("result rows_per_sec={:.0} engine_rows_per_sec={:.0} peak_memory_estimate_bytes={} accounts={} records={}",
+        rate(process_secs + report_secs), rate(engine_secs), peak_memory, state.engine.accounts().len(), state.engine.transactions().len());
+    Ok(())
+}
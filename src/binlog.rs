@@ -0,0 +1,208 @@
+// Compact binary log of accepted transactions, so experimental runs can skip CSV parsing entirely.
+//
+// The file starts with the magic bytes "PEBL" and a format version byte. Every transaction after that is a fixed-width
+// 29 byte record, all integers little endian:
+//
+//   type u8 | client u16 | tx u32 | has amount u8 | amount mantissa i128 | amount scale u8 | checksum u32
+//
+// The checksum is FNV-1a over the first 25 bytes of the record, so corruption and truncation are reported instead of
+// silently replaying a partial or garbled log.
+
+use crate::heartbeat::{ByteCounter, CountingReader};
+use crate::money;
+use crate::{EngineError, TransactionRow, TransactionType};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"PEBL";
+const VERSION: u8 = 1;
+const BODY_LEN: usize = 25;
+const RECORD_LEN: usize = BODY_LEN + 4;
+
+pub(crate) struct BinlogWriter {
+    out: BufWriter<File>,
+}
+
+impl BinlogWriter {
+    // This function creates the log file and writes its header
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> Result<BinlogWriter, EngineError> {
+        BinlogWriter::new(File::create(path)?)
+    }
+
+    // This function writes the header at the current position of an open file, for logs embedded in other files
+    pub(crate) fn new(file: File) -> Result<BinlogWriter, EngineError> {
+        let mut out = BufWriter::new(file);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(BinlogWriter { out })
+    }
+
+    // This function appends one transaction to the log
+    pub(crate) fn write(&mut self, transaction: &TransactionRow) -> Result<(), EngineError> {
+        let type_code = match transaction.transaction_type {
+            TransactionType::Deposit => 1,
+            TransactionType::Withdrawal => 2,
+            TransactionType::Dispute => 3,
+            TransactionType::Resolve => 4,
+            TransactionType::Chargeback => 5,
+            TransactionType::AdminHold => 6,
+            TransactionType::AdminRelease => 7,
+            TransactionType::Correction => 8,
+            TransactionType::OpeningBalance => 9,
+            TransactionType::AdminLock => 10,
+            TransactionType::AdminUnlock => 11,
+            other => return Err(format!("cannot write transaction type {} to a binlog", other).into()),
+        };
+        let (has_amount, mantissa, scale) = match transaction.amount {
+            Some(amount) => {
+                let (mantissa, scale) = money::to_parts(amount);
+                (1, mantissa, u8::try_from(scale).map_err(|_| format!("amount {} has too many decimal places for a binlog", amount))?)
+            },
+            None => (0, 0, 0),
+        };
+
+        let mut record = Vec::with_capacity(RECORD_LEN);
+        record.push(type_code);
+        record.extend_from_slice(&transaction.client_id.to_le_bytes());
+        record.extend_from_slice(&transaction.transaction_id.to_le_bytes());
+        record.push(has_amount);
+        record.extend_from_slice(&mantissa.to_le_bytes());
+        record.push(scale);
+        let checksum = fnv1a(&record);
+        record.extend_from_slice(&checksum.to_le_bytes());
+
+        self.out.write_all(&record)?;
+        Ok(())
+    }
+
+    // This function flushes the log to disk. Errors here would otherwise be lost when the writer is dropped
+    pub(crate) fn finish(mut self) -> Result<(), EngineError> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+pub(crate) struct BinlogReader {
+    input: BufReader<CountingReader<File>>,
+    index: u64,
+    failed: bool,
+}
+
+impl BinlogReader {
+    // This function opens a log and checks its header. Bytes read from the file are added to `bytes`
+    pub(crate) fn open<P: AsRef<Path>>(path: P, bytes: &ByteCounter) -> Result<BinlogReader, EngineError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("could not open binlog {}: {}", path.display(), e))?;
+        BinlogReader::from_reader(BufReader::new(CountingReader::new(file, bytes)), path)
+    }
+
+    // This function checks the header at the current position of `input`. `path` is only used in error messages
+    pub(crate) fn from_reader(mut input: BufReader<CountingReader<File>>, path: &Path) -> Result<BinlogReader, EngineError> {
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header).map_err(|_| format!("{} is not a binlog: header is missing", path.display()))?;
+        let [m0, m1, m2, m3, version] = header;
+        if &[m0, m1, m2, m3] != MAGIC {
+            return Err(format!("{} is not a binlog: bad magic bytes", path.display()).into());
+        }
+        if version != VERSION {
+            return Err(format!("binlog {} has format version {}, expected {}", path.display(), version, VERSION).into());
+        }
+
+        Ok(BinlogReader { input, index: 0, failed: false })
+    }
+
+    // This function reads the next record, returning None at a clean end of file
+    fn read_record(&mut self) -> Result<Option<TransactionRow>, EngineError> {
+        let mut buf = [0u8; RECORD_LEN];
+        let mut filled = 0;
+        while filled < RECORD_LEN {
+            let rest = buf.get_mut(filled..).unwrap_or_default();
+            match self.input.read(rest) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if filled == 0 {
+            return Ok(None);
+        }
+        if filled < RECORD_LEN {
+            return Err(format!("binlog is truncated: record {} has only {} of {} bytes", self.index, filled, RECORD_LEN).into());
+        }
+
+        let corrupt = |what: &str| format!("binlog record {} is corrupt: {}", self.index, what);
+        let mut rdr: &[u8] = &buf;
+        let mut body = [0u8; BODY_LEN];
+        rdr.read_exact(&mut body)?;
+        let mut checksum = [0u8; 4];
+        rdr.read_exact(&mut checksum)?;
+        if fnv1a(&body) != u32::from_le_bytes(checksum) {
+            return Err(corrupt("checksum mismatch").into());
+        }
+
+        let mut rdr: &[u8] = &body;
+        let type_code = read_array::<1>(&mut rdr)?;
+        let client_id = u16::from_le_bytes(read_array(&mut rdr)?);
+        let transaction_id = u32::from_le_bytes(read_array(&mut rdr)?);
+        let has_amount = read_array::<1>(&mut rdr)?;
+        let mantissa = i128::from_le_bytes(read_array(&mut rdr)?);
+        let [scale] = read_array::<1>(&mut rdr)?;
+
+        let transaction_type = match type_code {
+            [1] => TransactionType::Deposit,
+            [2] => TransactionType::Withdrawal,
+            [3] => TransactionType::Dispute,
+            [4] => TransactionType::Resolve,
+            [5] => TransactionType::Chargeback,
+            [6] => TransactionType::AdminHold,
+            [7] => TransactionType::AdminRelease,
+            [8] => TransactionType::Correction,
+            [9] => TransactionType::OpeningBalance,
+            [10] => TransactionType::AdminLock,
+            [11] => TransactionType::AdminUnlock,
+            _ => return Err(corrupt("unknown transaction type").into()),
+        };
+        let amount = match has_amount {
+            [0] => None,
+            [1] => Some(money::from_parts(mantissa, u32::from(scale)).ok_or_else(|| corrupt("amount out of range"))?),
+            _ => return Err(corrupt("bad amount flag").into()),
+        };
+
+        self.index += 1;
+        Ok(Some(TransactionRow {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            expected_held_total: None,
+            timestamp: None,
+            currency: None,
+        }))
+    }
+}
+
+impl Iterator for BinlogReader {
+    type Item = Result<TransactionRow, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.read_record();
+        self.failed = result.is_err();
+        result.map_err(Into::into).transpose()
+    }
+}
+
+fn read_array<const N: usize>(rdr: &mut &[u8]) -> Result<[u8; N], EngineError> {
+    let mut bytes = [0u8; N];
+    rdr.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5_u32, |hash, &b| (hash ^ u32::from(b)).wrapping_mul(0x0100_0193))
+}
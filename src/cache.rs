@@ -0,0 +1,163 @@
+// Parsed-input cache for CSV runs, enabled with --cache <path>.
+//
+// The cache holds every row of the input as parsed, before any filter or policy is applied, so runs with different
+// flags can share it. It is a binlog behind a key identifying the input it was built from:
+//
+//   "PECA" | version u8 | input size u64 | modified secs u64 | modified nanos u32 | content hash u64 | separator u32 | binlog
+//
+// all integers little endian, the hash being FNV-1a over the whole input and the separator the --thousands-separator
+// the amounts were read with. A cache whose key does not match the input
+// is ignored and rebuilt. The new cache is written next to the old one and only renamed over it once every row has
+// been read, so a run that stops early never leaves a partial cache behind. An input with timestamps is not cached,
+// since a binlog cannot hold them. Each decision is logged to stderr.
+
+use crate::binlog::{BinlogReader, BinlogWriter};
+use crate::heartbeat::{ByteCounter, CountingReader};
+use crate::run::Rows;
+use crate::{EngineError, TransactionRow};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 4] = b"PECA";
+const VERSION: u8 = 2;
+const KEY_LEN: usize = 4 + 1 + 8 + 8 + 4 + 8 + 4;
+
+// This function computes the key of the input as it is on disk now, read with the given separator
+fn input_key(input: &Path, separator: char) -> Result<[u8; KEY_LEN], EngineError> {
+    let metadata = fs::metadata(input)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_err(io::Error::other)?;
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut file = File::open(input)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for &byte in buf.get(..n).unwrap_or_default() {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    let mut key = Vec::with_capacity(KEY_LEN);
+    key.extend_from_slice(MAGIC);
+    key.push(VERSION);
+    key.extend_from_slice(&metadata.len().to_le_bytes());
+    key.extend_from_slice(&modified.as_secs().to_le_bytes());
+    key.extend_from_slice(&modified.subsec_nanos().to_le_bytes());
+    key.extend_from_slice(&hash.to_le_bytes());
+    key.extend_from_slice(&u32::from(separator).to_le_bytes());
+    Ok(key.try_into().map_err(|_| "cache key has the wrong length")?)
+}
+
+// This function returns the rows of the input, from the cache when it matches the input and from `parse` otherwise.
+// Parsed rows go into a new cache as they are read. Bytes read from the cache are added to `bytes`
+pub(crate) fn rows<'a>(cache: &str, input: &Path, separator: char, bytes: &ByteCounter, parse: impl FnOnce() -> Rows<'a>) -> Result<Rows<'a>, EngineError> {
+    let cache = Path::new(cache);
+    let key = input_key(input, separator)?;
+
+    let reason = match File::open(cache) {
+        Ok(file) => {
+            let mut file = BufReader::new(CountingReader::new(file, bytes));
+            let mut found = [0u8; KEY_LEN];
+            match file.read_exact(&mut found) {
+                Ok(()) if found == key => {
+                    note(format_args!("reading parsed input from {}", cache.display()));
+                    let reader = BinlogReader::from_reader(file, cache)?;
+                    return Ok(Box::new(reader.map(|t| t.map(Some))));
+                },
+                Ok(()) if found.starts_with(MAGIC) && found.get(MAGIC.len()) != Some(&VERSION) => "it has a different cache version",
+                Ok(()) if found.starts_with(MAGIC) => "the input or --thousands-separator changed since it was written",
+                _ => "it is not a cache file",
+            }
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => "it does not exist yet",
+        Err(e) => return Err(format!("could not open cache {}: {}", cache.display(), e).into()),
+    };
+    note(format_args!("parsing {} and rebuilding {}, because {}", input.display(), cache.display(), reason));
+
+    let mut partial = cache.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let mut file = File::create(&partial)?;
+    file.write_all(&key)?;
+    Ok(Box::new(CacheWriter {
+        rows: parse(),
+        log: Some(BinlogWriter::new(file)?),
+        partial,
+        cache: cache.to_path_buf(),
+    }))
+}
+
+// Passes the parsed rows through, copying each one into the new cache
+struct CacheWriter<'a> {
+    rows: Rows<'a>,
+    log: Option<BinlogWriter>,
+    partial: PathBuf,
+    cache: PathBuf,
+}
+
+impl CacheWriter<'_> {
+    // This function gives up on the new cache, leaving any old one in place
+    fn abandon(&mut self, reason: &dyn std::fmt::Display) {
+        if self.log.take().is_some() {
+            note(format_args!("not writing {}: {}", self.cache.display(), reason));
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+
+    // This function moves the complete cache into place
+    fn complete(&mut self) {
+        let Some(log) = self.log.take() else {
+            return;
+        };
+        match log.finish().and_then(|_| Ok(fs::rename(&self.partial, &self.cache)?)) {
+            Ok(()) => note(format_args!("wrote {}", self.cache.display())),
+            Err(e) => {
+                note(format_args!("not writing {}: {}", self.cache.display(), e));
+                let _ = fs::remove_file(&self.partial);
+            },
+        }
+    }
+}
+
+impl Iterator for CacheWriter<'_> {
+    type Item = Result<Option<TransactionRow>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.next();
+        match &row {
+            // A binlog has no room for timestamps, so caching them would lose them
+            Some(Ok(Some(transaction))) if transaction.timestamp.is_some() => self.abandon(&"the input has timestamps, which a cache cannot hold"),
+            Some(Ok(Some(transaction))) => {
+                if let Some(Err(e)) = self.log.as_mut().map(|log| log.write(transaction)) {
+                    self.abandon(&e);
+                }
+            },
+            // Skipped rows cannot be cached, the cache is always built from the unfiltered input
+            Some(Ok(None)) => self.abandon(&"the input had rows skipped while parsing"),
+            Some(Err(e)) => self.abandon(e),
+            None => self.complete(),
+        }
+        row
+    }
+}
+
+impl Drop for CacheWriter<'_> {
+    fn drop(&mut self) {
+        self.abandon(&"the run stopped before the end of the input");
+    }
+}
+
+// This function writes one line about the cache to stderr, left out with -q like the warnings
+fn note(message: std::fmt::Arguments) {
+    if log::log_enabled!(log::Level::Warn) {
+        eprintln!("cache: {}", message);
+    }
+}
@@ -0,0 +1,98 @@
+// Account checkpoints for long runs, enabled with --checkpoint-every N and --checkpoint-out PATH.
+//
+// Every N input rows the accounts as they are at that point are written to PATH as a CSV account report in the default
+// layout. The file is written under a temporary name and renamed into place, so PATH always holds one complete
+// checkpoint, and a run that dies part way leaves the accounts as of its last one. Each checkpoint also writes a
+// progress line with the rows processed and the rate to stderr.
+
+use crate::money::Money;
+use crate::report::{self, AtomicSink, CsvSink, ReportOptions};
+use crate::{Client, Engine, EngineError};
+use std::num::NonZeroU64;
+use std::time::Instant;
+
+pub(crate) struct Checkpoint {
+    every: NonZeroU64,
+    path: String,
+    // Rows processed when the last checkpoint was written
+    written: u64,
+    started: Instant,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(every: NonZeroU64, path: &str) -> Checkpoint {
+        Checkpoint {
+            every,
+            path: path.to_string(),
+            written: 0,
+            started: Instant::now(),
+        }
+    }
+
+    // This function writes a checkpoint when `rows` is a multiple of N that has not been written yet
+    pub(crate) fn rows_done(&mut self, rows: u64, engine: &Engine) -> Result<(), EngineError> {
+        if rows == self.written || !rows.is_multiple_of(self.every.get()) {
+            return Ok(());
+        }
+
+        // The debt column is only filled in for every account at the end of the run, so it is done on a copy here
+        let mut clients: Vec<Client> = engine.accounts().cloned().collect();
+        if engine.policy().track_debt() {
+            for client in &mut clients {
+                client.debt.get_or_insert(Money::ZERO);
+            }
+        }
+
+        let mut sink = AtomicSink::create(&self.path, |out| Box::new(CsvSink::new(out)))?;
+        report::write_report(clients.iter(), rows, &ReportOptions::default(), &mut sink)
+            .map_err(|e| format!("could not write checkpoint {}: {}", self.path, e))?;
+        self.written = rows;
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { rows as f64 / elapsed } else { 0.0 };
+        eprintln!("checkpoint rows={} rows_per_sec={:.0} accounts={} path={}", rows, rate, clients.len(), self.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TransactionRow, TransactionType};
+    use std::fs;
+
+    fn amount(text: &str) -> Money {
+        text.parse().unwrap_or_else(|_| panic!("invalid amount {}", text))
+    }
+
+    // With N = 2 the file changes after rows 2 and 4 only, and always holds the accounts as they were after the last of those
+    #[test]
+    fn a_checkpoint_holds_the_accounts_after_every_nth_row() -> Result<(), EngineError> {
+        let path = std::env::temp_dir().join(format!("payment_engine_{}_checkpoint_every_2.csv", std::process::id()));
+        let name = path.to_string_lossy().into_owned();
+        let every = NonZeroU64::new(2).ok_or("2 is not zero")?;
+        let mut checkpoint = Checkpoint::new(every, &name);
+        let mut engine = Engine::new();
+        let rows = [
+            TransactionRow::new(TransactionType::Deposit, 1, 1, Some(amount("10"))),
+            TransactionRow::new(TransactionType::Deposit, 2, 2, Some(amount("5.5"))),
+            TransactionRow::new(TransactionType::Withdrawal, 1, 3, Some(amount("4.25"))),
+            TransactionRow::new(TransactionType::Dispute, 2, 2, None),
+            TransactionRow::new(TransactionType::Chargeback, 2, 2, None),
+        ];
+        let header = "client,available,held,total,locked\n";
+        let after_2 = format!("{}1,10.0000,0.0000,10.0000,false\n2,5.5000,0.0000,5.5000,false\n", header);
+        let after_4 = format!("{}1,5.7500,0.0000,5.7500,false\n2,0.0000,5.5000,5.5000,false\n", header);
+        let expected = [None, Some(&after_2), Some(&after_2), Some(&after_4), Some(&after_4)];
+
+        let mut written = Vec::new();
+        for (done, row) in (1..).zip(&rows) {
+            engine.process(row)?;
+            checkpoint.rows_done(done, &engine)?;
+            written.push(fs::read_to_string(&path).ok());
+        }
+        let _ = fs::remove_file(&path);
+        assert_eq!(written, expected.map(|text| text.cloned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+// TOML configuration file holding defaults for the command line flags.
+//
+// Keys are the long flag names with underscores, e.g. `chargeback_fee = "2.50"` or `why_locked = "locked.csv"`, and
+// unknown keys are rejected. A value from the file is only used when the flag was not given on the command line, so
+// explicit flags always win. Without --config, ./payment_engine.toml is read if it exists.
+
+use payment_engine::args::{AssertionMode, BalanceCaps, ClientFilter, Compression, Grouping, InputFormat, InputPrecision, MergeBy, OutputFormat, ProcessingArgs, RecordRetention, ReportArgs, ReportSchema, ThousandsSeparator};
+use payment_engine::money::Money;
+use payment_engine::{CurrencyScales, EngineError, LockPolicy, TransactionType};
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PATH: &str = "payment_engine.toml";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_format: Option<InputFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    clients: Option<ClientFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_precision: Option<InputPrecision>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    thousands_separator: Option<ThousandsSeparator>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_amount: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_types: Option<Vec<TransactionType>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_referenced_clients: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    record_retention: Option<RecordRetention>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spill_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spill_keep: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat: Option<NonZeroU64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checkpoint_every: Option<NonZeroU64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checkpoint_out: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    export_binlog: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotate_out: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audit_log: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diagnostics_json: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict_types: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_comments: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_in: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chargeback_fee: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monthly_fee: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee_final_partial_month: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    lock_policy: Option<LockPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_balance: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    client_max_balance: Option<BalanceCaps>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    currency_scales: Option<CurrencyScales>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limits_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_debt: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    require_opening_balances: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opening_balances: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_negative_opening: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_forced_hold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dispute_requires_funds: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abort_on_negative_held: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idempotent_replay: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assume_grouped_by: Option<Grouping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort_by_timestamp: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merge_by: Option<MergeBy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merge_max_skew: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multi_currency: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assertions: Option<AssertionMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verify_parallel: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<OutputFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report_schema: Option<ReportSchema>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    simulate_chargebacks: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assertion_report: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dispute_aging: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    why_locked: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dangling_refs: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume_report: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_rows: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_out: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report_anomalies: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<bool>,
+    // --client may be given several times, the file holds their union, e.g. client = "42,100-200"
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "as_string")]
+    client: Option<ClientFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_dangling_refs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fail_on_locked: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals_row: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    precision: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    streaming_output: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run: Option<bool>,
+}
+
+// Copies every field the file sets onto the flags, unless that flag was given on the command line.
+// `value` fields are plain flags, `optional` fields are flags that hold an Option. clap names arguments in kebab case
+macro_rules! merge {
+    ($matches:expr, $config:expr, $flags:expr, value: [$($value:ident),*], optional: [$($optional:ident),*]) => {
+        $(
+            if let Some(value) = $config.$value {
+                if $matches.occurrences_of(stringify!($value).replace('_', "-").as_str()) == 0 {
+                    $flags.$value = value;
+                }
+            }
+        )*
+        $(
+            if let Some(value) = $config.$optional {
+                if $matches.occurrences_of(stringify!($optional).replace('_', "-").as_str()) == 0 {
+                    $flags.$optional = Some(value);
+                }
+            }
+        )*
+    };
+}
+
+// This function reads the config file, if there is one, and fills in every flag that was not given on the command line
+pub(crate) fn resolve(path: Option<&str>, matches: &ArgMatches, processing: &mut ProcessingArgs, report: &mut ReportArgs) -> Result<(), EngineError> {
+    let config = load(path)?;
+
+    merge!(matches, config, processing,
+        value: [input_format, compression, input_precision, thousands_separator, ignore_types, include_referenced_clients, spill_keep, fee_final_partial_month, lock_policy, track_debt, require_opening_balances, allow_negative_opening, allow_forced_hold, dispute_requires_funds, abort_on_negative_held, idempotent_replay, sort_by_timestamp, multi_currency, assertions, verify_parallel, strict, strict_types, allow_comments],
+        optional: [clients, max_amount, record_retention, spill_dir, heartbeat, checkpoint_every, checkpoint_out, export_binlog, record, annotate_out, audit_log, diagnostics_json, cache, snapshot_in, chargeback_fee, monthly_fee, max_balance, client_max_balance, currency_scales, limits_file, opening_balances, assume_grouped_by, merge_by, merge_max_skew]);
+    merge!(matches, config, report,
+        value: [format, report_schema, precision, totals_row, streaming_output, report_anomalies, summary, stats, fail_on_locked, dry_run],
+        optional: [output, simulate_chargebacks, assertion_report, dispute_aging, why_locked, dangling_refs, volume_report, skipped_rows, snapshot_out, max_dangling_refs]);
+    if let Some(client) = config.client {
+        if matches.occurrences_of("client") == 0 {
+            report.client = vec![client];
+        }
+    }
+    Ok(())
+}
+
+// This function reads and checks the config file. A missing default file is an empty config, a missing explicit one is an error
+fn load(path: Option<&str>) -> Result<Config, EngineError> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None if Path::new(DEFAULT_PATH).exists() => PathBuf::from(DEFAULT_PATH),
+        None => return Ok(Config::default()),
+    };
+
+    let text = fs::read_to_string(&path).map_err(|e| format!("could not read config {}: {}", path.display(), e))?;
+    Ok(toml::from_str(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))?)
+}
+
+// This function renders the resolved flags as a config file that would reproduce them
+pub(crate) fn show(processing: &ProcessingArgs, report: &ReportArgs) -> Result<String, EngineError> {
+    let config = Config {
+        input_format: Some(processing.input_format),
+        compression: Some(processing.compression),
+        clients: processing.clients.clone(),
+        input_precision: Some(processing.input_precision),
+        thousands_separator: Some(processing.thousands_separator),
+        max_amount: processing.max_amount,
+        ignore_types: Some(processing.ignore_types.clone()),
+        include_referenced_clients: Some(processing.include_referenced_clients),
+        record_retention: processing.record_retention,
+        spill_dir: processing.spill_dir.clone(),
+        spill_keep: Some(processing.spill_keep),
+        heartbeat: processing.heartbeat,
+        checkpoint_every: processing.checkpoint_every,
+        checkpoint_out: processing.checkpoint_out.clone(),
+        export_binlog: processing.export_binlog.clone(),
+        record: processing.record.clone(),
+        annotate_out: processing.annotate_out.clone(),
+        audit_log: processing.audit_log.clone(),
+        diagnostics_json: processing.diagnostics_json.clone(),
+        strict: Some(processing.strict),
+        strict_types: Some(processing.strict_types),
+        allow_comments: Some(processing.allow_comments),
+        cache: processing.cache.clone(),
+        snapshot_in: processing.snapshot_in.clone(),
+        chargeback_fee: processing.chargeback_fee,
+        monthly_fee: processing.monthly_fee,
+        fee_final_partial_month: Some(processing.fee_final_partial_month),
+        lock_policy: Some(processing.lock_policy),
+        max_balance: processing.max_balance,
+        client_max_balance: processing.client_max_balance.clone(),
+        currency_scales: processing.currency_scales.clone(),
+        limits_file: processing.limits_file.clone(),
+        track_debt: Some(processing.track_debt),
+        require_opening_balances: Some(processing.require_opening_balances),
+        opening_balances: processing.opening_balances.clone(),
+        allow_negative_opening: Some(processing.allow_negative_opening),
+        allow_forced_hold: Some(processing.allow_forced_hold),
+        dispute_requires_funds: Some(processing.dispute_requires_funds),
+        abort_on_negative_held: Some(processing.abort_on_negative_held),
+        idempotent_replay: Some(processing.idempotent_replay),
+        assume_grouped_by: processing.assume_grouped_by,
+        sort_by_timestamp: Some(processing.sort_by_timestamp),
+        merge_by: processing.merge_by,
+        merge_max_skew: processing.merge_max_skew,
+        multi_currency: Some(processing.multi_currency),
+        assertions: Some(processing.assertions),
+        verify_parallel: Some(processing.verify_parallel),
+        format: Some(report.format),
+        output: report.output.clone(),
+        report_schema: Some(report.report_schema),
+        precision: Some(report.precision),
+        simulate_chargebacks: report.simulate_chargebacks.clone(),
+        assertion_report: report.assertion_report.clone(),
+        dispute_aging: report.dispute_aging.clone(),
+        why_locked: report.why_locked.clone(),
+        dangling_refs: report.dangling_refs.clone(),
+        volume_report: report.volume_report.clone(),
+        skipped_rows: report.skipped_rows.clone(),
+        snapshot_out: report.snapshot_out.clone(),
+        report_anomalies: Some(report.report_anomalies),
+        summary: Some(report.summary),
+        stats: Some(report.stats),
+        client: ClientFilter::union(&report.client),
+        max_dangling_refs: report.max_dangling_refs,
+        fail_on_locked: Some(report.fail_on_locked),
+        totals_row: Some(report.totals_row),
+        streaming_output: Some(report.streaming_output),
+        dry_run: Some(report.dry_run),
+    };
+    Ok(toml::to_string(&config).map_err(|e| e.to_string())?)
+}
+
+// Values that are written the same way as on the command line, e.g. clients = "17,42,9000-9100"
+mod as_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(value: &Option<T>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => s.collect_str(value),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(d)?
+            .map(|s| s.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
@@ -0,0 +1,73 @@
+// Machine-readable diagnostics written with --diagnostics-json: one JSON object per line for every row that was not
+// applied and for the error that stops a run, written and flushed as it happens.
+//
+//   {"severity":"warning","code":"CLIENT_MISMATCH","row":7,"tx":3,"client":2,"message":"client does not match transaction"}
+//
+// A rejected row's code is the code of its Rejection in upper case, so CLIENT_MISMATCH is what the audit log and
+// --annotate-out call client_mismatch, and the message is the Rejection's own text. Rows that could not be parsed are
+// PARSE_ERROR or UNKNOWN_TYPE and have no tx or client. The error that stops a run has the severity error and a code
+// for its exit status: IO_ERROR, INPUT_ERROR or INVARIANT_VIOLATION, with no row.
+
+use crate::{EngineError, TransactionRow};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+// Name that stands for standard error instead of a file
+const STDERR: &str = "-";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+struct Diagnostic<'a> {
+    severity: Severity,
+    code: String,
+    row: Option<u64>,
+    tx: Option<u32>,
+    client: Option<u16>,
+    message: &'a str,
+}
+
+pub struct Diagnostics {
+    out: Box<dyn Write>,
+}
+
+impl Diagnostics {
+    pub(crate) fn create(path: &str) -> Result<Diagnostics, EngineError> {
+        let out: Box<dyn Write> = match path {
+            STDERR => Box::new(io::stderr()),
+            path => Box::new(File::create(path).map_err(|e| format!("could not create {}: {}", path, e))?),
+        };
+        Ok(Diagnostics { out })
+    }
+
+    // This function reopens the diagnostics of a run to add to them, for the error that stopped it
+    pub fn append(path: &str) -> Result<Diagnostics, EngineError> {
+        let out: Box<dyn Write> = match path {
+            STDERR => Box::new(io::stderr()),
+            path => Box::new(OpenOptions::new().append(true).create(true).open(path).map_err(|e| format!("could not open {}: {}", path, e))?),
+        };
+        Ok(Diagnostics { out })
+    }
+
+    // This function writes one diagnostic. `code` is a snake case code like Rejection::code returns
+    pub fn write(&mut self, severity: Severity, code: &str, row: Option<u64>, transaction: Option<&TransactionRow>, message: &str) -> Result<(), EngineError> {
+        let diagnostic = Diagnostic {
+            severity,
+            code: code.to_ascii_uppercase(),
+            row,
+            tx: transaction.map(|t| t.transaction_id),
+            client: transaction.map(|t| t.client_id),
+            message,
+        };
+        serde_json::to_writer(&mut self.out, &diagnostic)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+// The `explain` subcommand: process an input and describe every row that touched one transaction id.
+//
+// For each such row (the original deposit or withdrawal and any dispute, resolve or chargeback referencing it) the
+// output shows where it was in the input, whether it was applied or why it was rejected, and the owning client's
+// balances immediately before and after it. The input is processed with the same flags as the main command, so the
+// explanation is of the run that was made.
+
+use crate::ExplainArgs;
+use payment_engine::money::Money;
+use payment_engine::run::{process_input, Outcome};
+use payment_engine::{Client, EngineError, TransactionType};
+use clap::ArgEnum;
+use serde::Serialize;
+
+#[derive(Clone, Copy, ArgEnum)]
+pub(crate) enum ExplainFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Balances {
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+}
+
+impl From<&Client> for Balances {
+    fn from(client: &Client) -> Balances {
+        Balances {
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Event {
+    row: u64,
+    line: Option<u64>,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    amount: Option<Money>,
+    applied: bool,
+    reason: Option<String>,
+    before: Option<Balances>,
+    after: Option<Balances>,
+}
+
+#[derive(Debug, Serialize)]
+struct Explanation {
+    tx: u32,
+    events: Vec<Event>,
+}
+
+// This function processes the input and prints the explanation for the requested transaction
+pub(crate) fn run(args: &ExplainArgs) -> Result<(), EngineError> {
+    let mut events = Vec::new();
+    let mut observe = |outcome: Outcome| {
+        if outcome.transaction.transaction_id != args.tx {
+            return;
+        }
+        events.push(Event {
+            row: outcome.row,
+            line: outcome.line,
+            transaction_type: outcome.transaction.transaction_type,
+            client: outcome.transaction.client_id,
+            amount: outcome.transaction.amount,
+            applied: outcome.result.is_ok(),
+            reason: outcome.result.err().map(|e| e.to_string()),
+            before: outcome.before.as_ref().map(Balances::from),
+            after: outcome.after.map(Balances::from),
+        });
+    };
+    process_input(std::slice::from_ref(&args.input), &args.processing, &args.processing.policy()?, Some(&mut observe), None)?;
+
+    let explanation = Explanation { tx: args.tx, events };
+    match args.format {
+        ExplainFormat::Json => println!("{}", serde_json::to_string_pretty(&explanation)?),
+        ExplainFormat::Text => print_text(&explanation),
+    }
+    Ok(())
+}
+
+// This function prints one paragraph per event
+fn print_text(explanation: &Explanation) {
+    if explanation.events.is_empty() {
+        println!("Transaction {} does not appear in the input.", explanation.tx);
+        return;
+    }
+
+    let balances = |b: &Option<Balances>| match b {
+        Some(b) => format!("available {} held {} total {} locked {}", b.available, b.held, b.total, b.locked),
+        None => "no account".to_string(),
+    };
+    for event in &explanation.events {
+        let place = match event.line {
+            Some(line) => format!("row {} (line {})", event.row, line),
+            None => format!("row {}", event.row),
+        };
+        let amount = event.amount.map(|a| format!(" of {}", a)).unwrap_or_default();
+        println!("{}: {} {}{} for client {}", place, event.transaction_type, explanation.tx, amount, event.client);
+        match &event.reason {
+            Some(reason) => println!("  rejected: {}", reason),
+            None => println!("  applied"),
+        }
+        println!("  before: {}", balances(&event.before));
+        println!("  after:  {}", balances(&event.after));
+    }
+}
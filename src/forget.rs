@@ -0,0 +1,118 @@
+// The `forget` subcommand: remove one client from a snapshot, for an erasure request.
+//
+// The client's accounts, stored transactions and admin holds are written to the export file as one JSON object, then
+// removed from the state, which is saved as a new snapshot. The new snapshot remembers the client, so a run that starts
+// from it rejects every later row for them as forgotten_client instead of opening a new account, e.g.
+//
+//   {"client":1234,"accounts":[{"currency":null,"available":"10.0000","held":"0.0000","total":"10.0000","locked":false,
+//    "chargebacks":0,"open_disputes":[]}],"transactions":[{"tx":7,"type":"deposit","amount":"10.0000","state":"normal",
+//    "currency":null}],"admin_holds":[]}
+
+use crate::ForgetArgs;
+use payment_engine::money::{Money, Places};
+use payment_engine::{snapshot, EngineError, EnginePolicy};
+use log::info;
+use payment_engine::{Client, ForgottenClient, RecordKind};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+#[derive(Serialize)]
+struct Export {
+    client: u16,
+    accounts: Vec<ExportedAccount>,
+    transactions: Vec<ExportedTransaction>,
+    admin_holds: Vec<ExportedHold>,
+}
+
+#[derive(Serialize)]
+struct ExportedAccount {
+    currency: Option<String>,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    chargebacks: u32,
+    // The tx ids of the disputes still open
+    open_disputes: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct ExportedTransaction {
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: String,
+    state: &'static str,
+    currency: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedHold {
+    tx: u32,
+    amount: String,
+    currency: Option<String>,
+}
+
+// Amounts are exported as text with four decimal places, like the reports, so no JSON reader rounds them
+fn amount(value: Money) -> String {
+    Places(value, 4).to_string()
+}
+
+impl From<&Client> for ExportedAccount {
+    fn from(client: &Client) -> ExportedAccount {
+        ExportedAccount {
+            currency: client.currency.map(|currency| currency.to_string()),
+            available: amount(client.available),
+            held: amount(client.held),
+            total: amount(client.total),
+            locked: client.locked,
+            chargebacks: client.chargebacks,
+            open_disputes: client.open_disputes().keys().copied().collect(),
+        }
+    }
+}
+
+impl From<&ForgottenClient> for Export {
+    fn from(forgotten: &ForgottenClient) -> Export {
+        Export {
+            client: forgotten.client_id,
+            accounts: forgotten.accounts.iter().map(ExportedAccount::from).collect(),
+            transactions: forgotten.records.iter().map(|(tx, record)| ExportedTransaction {
+                tx: *tx,
+                kind: match record.kind {
+                    RecordKind::Deposit => "deposit",
+                    RecordKind::Withdrawal => "withdrawal",
+                    RecordKind::OpeningBalance => "opening_balance",
+                },
+                amount: amount(record.amount),
+                state: record.state.name(),
+                currency: record.currency.map(|currency| currency.to_string()),
+            }).collect(),
+            admin_holds: forgotten.admin_holds.iter().map(|(tx, hold)| ExportedHold {
+                tx: *tx,
+                amount: amount(hold.amount),
+                currency: hold.currency.map(|currency| currency.to_string()),
+            }).collect(),
+        }
+    }
+}
+
+// This function exports the client from the snapshot and writes the snapshot without them. The export is complete before
+// the new snapshot is written, so the client's data is never only gone
+pub(crate) fn run(args: &ForgetArgs) -> Result<(), EngineError> {
+    let (mut engine, schema) = snapshot::load(&args.state, &EnginePolicy::default())?;
+    if engine.is_forgotten(args.client) {
+        info!("client {} was already forgotten in {}.", args.client, args.state);
+    }
+    let forgotten = engine.forget_client(args.client);
+
+    let mut out = BufWriter::new(File::create(&args.export).map_err(|e| format!("could not create export {}: {}", args.export, e))?);
+    serde_json::to_writer(&mut out, &Export::from(&forgotten)).map_err(|e| e.to_string())?;
+    writeln!(out)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    snapshot::save(&engine, schema, &args.state_out)?;
+    info!("client {}: exported {} accounts and {} transactions to {}, wrote {} without them.", args.client, forgotten.accounts.len(), forgotten.records.len(), args.export, args.state_out);
+    Ok(())
+}
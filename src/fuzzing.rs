@@ -0,0 +1,71 @@
+// Entry point for the cargo-fuzz target in fuzz/, built with the `fuzzing` feature.
+//
+// The first byte picks the engine policy and parsing options, so the fuzzer reaches the handlers each flag turns on.
+// The rest is read as a CSV input, its first row as the header, and every row that parses is applied to the engine
+// before the report is rendered. Whatever the bytes are, none of it may panic: bad rows and rejections are passed over
+// as the command line tool passes over them.
+
+use crate::input::{self, OptionalColumns};
+use crate::money::{Money, Places};
+use crate::{Engine, EnginePolicy, LockPolicy};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use std::num::NonZeroU32;
+
+pub fn parse_and_apply(data: &[u8]) {
+    let Some((&options, csv)) = data.split_first() else {
+        return;
+    };
+    let flag = |bit: u8| options & (1 << bit) != 0;
+
+    let mut policy = EnginePolicy::builder()
+        .track_debt(flag(0))
+        .allow_forced_hold(flag(1))
+        .dispute_requires_funds(flag(2));
+    if let (true, Ok(fee)) = (flag(3), "2.5".parse::<Money>()) {
+        policy = policy.chargeback_fee(fee);
+    }
+    if let (true, Ok(cap)) = (flag(4), "1000".parse::<Money>()) {
+        policy = policy.max_balance(cap);
+    }
+    if let (true, Some(threshold)) = (flag(5), NonZeroU32::new(2)) {
+        policy = policy.lock_policy(LockPolicy::Threshold(threshold));
+    }
+    let currencies = flag(6);
+    let separator = if flag(7) { '_' } else { ',' };
+    let mut engine = Engine::with_policy(policy.build());
+
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(csv);
+    let mut record = StringRecord::new();
+    let headers = match rdr.read_record(&mut record) {
+        Ok(true) => record.iter().map(str::to_ascii_lowercase).collect::<StringRecord>(),
+        _ => return,
+    };
+    if input::missing_column(&headers).is_some() {
+        return;
+    }
+    let columns = OptionalColumns::find(&headers, currencies);
+
+    loop {
+        match rdr.read_record(&mut record) {
+            Ok(true) => {},
+            Ok(false) => break,
+            // A row that is not valid UTF-8 or cannot be split into fields still leaves the reader at the next row
+            Err(e) if !e.is_io_error() => continue,
+            Err(_) => break,
+        }
+        if let Ok(Some(transaction)) = input::parse_row(&record, &headers, columns, |_| true, separator) {
+            let _ = engine.process(&transaction);
+        }
+    }
+
+    // Balances at the edge of the range have to be written out as well as computed
+    let report = engine.complete();
+    for account in &report.accounts {
+        let _ = format!("{} {} {} {}", Places(account.available, 4), Places(account.held, 4), Places(account.total, 4), account.locked);
+    }
+    let _ = serde_json::to_vec(&report);
+}
@@ -0,0 +1,147 @@
+// The `generate` subcommand: write a synthetic transaction file for load tests.
+//
+// The file is consistent, so processing it never rejects a row. Tx ids are unique, amounts are positive with two decimal
+// places, withdrawals never take more than the client has available, disputes only name an earlier deposit of the same
+// client that is still covered by the available funds, and resolves and chargebacks only settle open disputes. A client
+// that has been charged back is locked and gets no further rows. The same flags and seed always produce the same bytes.
+//
+// Each row is, in this order of checks: a chargeback of an open dispute with --chargeback-rate, a resolve of an open
+// dispute with --dispute-rate, a dispute of an earlier deposit with --dispute-rate, else a deposit or, one time in four,
+// a withdrawal.
+
+use crate::bench::Rng;
+use crate::GenerateArgs;
+use payment_engine::{EngineError, TransactionType};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Share of plain rows that are withdrawals
+const WITHDRAWAL_RATE: f64 = 0.25;
+
+// A deposit that can be disputed, with its amount in cents
+#[derive(Clone, Copy)]
+struct Deposit {
+    client: u16,
+    tx: u32,
+    cents: u64,
+}
+
+struct Generator {
+    rng: Rng,
+    dispute_rate: f64,
+    chargeback_rate: f64,
+    // Clients that are not locked, in the order they are drawn from
+    open_clients: Vec<u16>,
+    locked: HashSet<u16>,
+    // Each client's available funds in cents
+    available: HashMap<u16, u64>,
+    deposits: Vec<Deposit>,
+    open_disputes: Vec<Deposit>,
+    next_tx: u32,
+}
+
+impl Generator {
+    // This function takes a random entry out of the pool, dropping entries of clients locked since they went in
+    fn take(rng: &mut Rng, pool: &mut Vec<Deposit>, locked: &HashSet<u16>) -> Option<Deposit> {
+        while !pool.is_empty() {
+            let index = rng.below(pool.len() as u64) as usize;
+            let deposit = pool.swap_remove(index);
+            if !locked.contains(&deposit.client) {
+                return Some(deposit);
+            }
+        }
+        None
+    }
+
+    fn available(&mut self, client: u16) -> &mut u64 {
+        self.available.entry(client).or_default()
+    }
+
+    // This function returns the next row as type, client, tx and amount in cents
+    fn row(&mut self) -> (TransactionType, u16, u32, Option<u64>) {
+        // The last open client is never charged back, so there is always someone left to write rows for
+        if self.open_clients.len() > 1 && !self.open_disputes.is_empty() && self.rng.chance(self.chargeback_rate) {
+            if let Some(dispute) = Generator::take(&mut self.rng, &mut self.open_disputes, &self.locked) {
+                self.locked.insert(dispute.client);
+                self.open_clients.retain(|&client| client != dispute.client);
+                return (TransactionType::Chargeback, dispute.client, dispute.tx, None);
+            }
+        }
+        if !self.open_disputes.is_empty() && self.rng.chance(self.dispute_rate) {
+            if let Some(dispute) = Generator::take(&mut self.rng, &mut self.open_disputes, &self.locked) {
+                *self.available(dispute.client) += dispute.cents;
+                self.deposits.push(dispute);
+                return (TransactionType::Resolve, dispute.client, dispute.tx, None);
+            }
+        }
+        if !self.deposits.is_empty() && self.rng.chance(self.dispute_rate) {
+            if let Some(deposit) = Generator::take(&mut self.rng, &mut self.deposits, &self.locked) {
+                let available = self.available(deposit.client);
+                if *available >= deposit.cents {
+                    *available -= deposit.cents;
+                    self.open_disputes.push(deposit);
+                    return (TransactionType::Dispute, deposit.client, deposit.tx, None);
+                }
+                // Not covered any more, so it stays undisputed and can be picked again later
+                self.deposits.push(deposit);
+            }
+        }
+
+        let client = self.open_clients.get(self.rng.below(self.open_clients.len() as u64) as usize).copied().unwrap_or(1);
+        let tx = self.next_tx;
+        self.next_tx = self.next_tx.saturating_add(1);
+        let cents = self.rng.below(100_000) + 1;
+        let withdraw = self.rng.chance(WITHDRAWAL_RATE);
+        let available = self.available(client);
+        if withdraw && *available > 0 {
+            let cents = cents.min(*available);
+            *available -= cents;
+            return (TransactionType::Withdrawal, client, tx, Some(cents));
+        }
+        *available += cents;
+        self.deposits.push(Deposit { client, tx, cents });
+        (TransactionType::Deposit, client, tx, Some(cents))
+    }
+}
+
+// This function writes the generated file to --out, or to stdout without it
+pub(crate) fn run(args: &GenerateArgs) -> Result<(), EngineError> {
+    if args.rows > u64::from(u32::MAX) {
+        return Err(format!("--rows can be at most {}, one tx id per row", u32::MAX).into());
+    }
+    if args.clients == 0 {
+        return Err("--clients must be at least 1".into());
+    }
+    for (name, rate) in [("--dispute-rate", args.dispute_rate), ("--chargeback-rate", args.chargeback_rate)] {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("{} must be between 0 and 1, got {}", name, rate).into());
+        }
+    }
+
+    let out: Box<dyn Write> = match &args.out {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+
+    let mut generator = Generator {
+        rng: Rng::new(args.seed),
+        dispute_rate: args.dispute_rate,
+        chargeback_rate: args.chargeback_rate,
+        open_clients: (1..=args.clients).collect(),
+        locked: HashSet::new(),
+        available: HashMap::new(),
+        deposits: Vec::new(),
+        open_disputes: Vec::new(),
+        next_tx: 1,
+    };
+    for _ in 0..args.rows {
+        let (transaction_type, client, tx, cents) = generator.row();
+        let amount = cents.map(|cents| format!("{}.{:02}", cents / 100, cents % 100)).unwrap_or_default();
+        wtr.write_record([transaction_type.name(), &client.to_string(), &tx.to_string(), &amount])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Liveness lines for batch runs, enabled with --heartbeat N.
+//
+// Every N rows a single line with the rows processed, rejects so far, the current rate and the input bytes consumed
+// is written to stderr, plus one last line at the end of the input. Each line goes out in one write so it cannot
+// be split by other output.
+
+use std::cell::Cell;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::num::NonZeroU64;
+use std::rc::Rc;
+use std::time::Instant;
+
+// Number of input bytes read so far, shared between a reader and whoever reports on it
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounter(Rc<Cell<u64>>);
+
+impl ByteCounter {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.get()
+    }
+
+    // This function counts bytes read elsewhere, e.g. by the threads that read merged inputs
+    pub(crate) fn add(&self, bytes: u64) {
+        self.0.set(self.0.get().saturating_add(bytes));
+    }
+}
+
+// A reader that adds every byte it reads to a ByteCounter
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    counter: ByteCounter,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R, counter: &ByteCounter) -> CountingReader<R> {
+        CountingReader { inner, counter: counter.clone() }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counter.0.set(self.counter.0.get().saturating_add(n as u64));
+        Ok(n)
+    }
+}
+
+// Seeking does not count as consuming input, only the bytes actually read do
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+pub(crate) struct Heartbeat {
+    every: NonZeroU64,
+    rows: u64,
+    rejects: u64,
+    started: Instant,
+    bytes: ByteCounter,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(every: NonZeroU64, bytes: &ByteCounter) -> Heartbeat {
+        Heartbeat {
+            every,
+            rows: 0,
+            rejects: 0,
+            started: Instant::now(),
+            bytes: bytes.clone(),
+        }
+    }
+
+    // This function records one processed row and emits a heartbeat when a multiple of N is reached
+    pub(crate) fn row(&mut self, rejected: bool) {
+        self.rows += 1;
+        if rejected {
+            self.rejects += 1;
+        }
+        if self.rows.is_multiple_of(self.every.get()) {
+            self.emit(false);
+        }
+    }
+
+    // This function emits the final heartbeat once the input is exhausted
+    pub(crate) fn finish(&self) {
+        self.emit(true);
+    }
+
+    fn emit(&self, done: bool) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.rows as f64 / elapsed } else { 0.0 };
+        eprintln!(
+            "heartbeat rows={} rejects={} rows_per_sec={:.0} bytes={} done={}",
+            self.rows, self.rejects, rate, self.bytes.get(), done
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// The `history` subcommand: process an input and list every row that touched one client.
+//
+//   tx,type,amount,outcome,available,held,total,timestamp
+//
+// The rows are the client's own, in input order, plus disputes, resolves and chargebacks by other clients that name
+// one of the client's transactions and were refused for it. outcome is applied or the snake case code of the
+// rejection, and the balances are the client's account after the row, empty while it has none. A last row with the
+// outcome final gives the account at the end of the input. timestamp is empty unless the input has a timestamp column.
+// The input is processed with the same flags as the main command, so the history is the one the client had in that run.
+
+use crate::HistoryArgs;
+use payment_engine::money::{Money, Places};
+use payment_engine::run::{process_input, Outcome};
+use payment_engine::{Client, EngineError, TransactionRow, TransactionType};
+use chrono::SecondsFormat;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io;
+
+#[derive(Debug, Clone, Copy)]
+struct Balances {
+    available: Money,
+    held: Money,
+    total: Money,
+}
+
+impl From<&Client> for Balances {
+    fn from(client: &Client) -> Balances {
+        Balances { available: client.available, held: client.held, total: client.total }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryRow {
+    tx: Option<u32>,
+    #[serde(rename = "type")]
+    transaction_type: Option<TransactionType>,
+    amount: Option<String>,
+    outcome: &'static str,
+    available: Option<String>,
+    held: Option<String>,
+    total: Option<String>,
+    timestamp: Option<String>,
+}
+
+impl HistoryRow {
+    fn new(transaction: Option<&TransactionRow>, outcome: &'static str, balances: Option<Balances>) -> HistoryRow {
+        let text = |value: Money| Places(value, 4).to_string();
+        HistoryRow {
+            tx: transaction.map(|t| t.transaction_id),
+            transaction_type: transaction.map(|t| t.transaction_type),
+            amount: transaction.and_then(|t| t.amount).map(text),
+            outcome,
+            available: balances.map(|b| text(b.available)),
+            held: balances.map(|b| text(b.held)),
+            total: balances.map(|b| text(b.total)),
+            timestamp: transaction.and_then(|t| t.timestamp).map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        }
+    }
+}
+
+// This function processes the input and writes the client's history to stdout
+pub(crate) fn run(args: &HistoryArgs) -> Result<(), EngineError> {
+    let mut rows = Vec::new();
+    // The client's deposits and withdrawals, which rows of other clients may still name
+    let mut own_transactions = HashSet::new();
+    // Only rows of the client itself change its account, so rows of others are shown with the last balances seen
+    let mut balances: Option<Balances> = None;
+    let mut observe = |outcome: Outcome| {
+        let transaction = outcome.transaction;
+        let own = transaction.client_id == args.client;
+        if own {
+            balances = outcome.after.map(Balances::from);
+            if outcome.result.is_ok() && matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+                own_transactions.insert(transaction.transaction_id);
+            }
+        } else if !(transaction.transaction_type.is_dispute_lifecycle() && own_transactions.contains(&transaction.transaction_id)) {
+            return;
+        }
+        let result = match outcome.result {
+            Ok(()) => "applied",
+            Err(e) => e.code(),
+        };
+        rows.push(HistoryRow::new(Some(transaction), result, balances));
+    };
+    let state = process_input(std::slice::from_ref(&args.input), &args.processing, &args.processing.policy()?, Some(&mut observe), None)?;
+
+    let mut wtr = csv::Writer::from_writer(io::stdout().lock());
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    let last = state.engine.account(args.client).map(Balances::from);
+    wtr.serialize(HistoryRow::new(None, "final", last))?;
+    wtr.flush()?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// Parsing of CSV input rows into transactions.
+//
+// Columns are looked up by their lowercased header name, so their order does not matter and unknown columns are
+// ignored. Reading the file, finding the header and reporting bad rows is left to the caller, this only turns one
+// row into a TransactionRow or says why it cannot.
+
+use crate::money;
+use crate::{Currency, TransactionRow, TransactionType};
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use csv::StringRecord;
+use serde::Deserialize;
+use std::error::Error;
+
+// Columns every CSV input must have. The amount is left out by dispute, resolve and chargeback rows
+pub const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+// One CSV row as named by the lowercased header. Every column but the amount is required
+#[derive(Debug, Deserialize)]
+struct InputRow<'a> {
+    #[serde(rename = "type")]
+    transaction_type: &'a str,
+    client: u16,
+    tx: u32,
+    // Left empty or out entirely by dispute, resolve and chargeback rows. Kept as text and parsed as Money, since serde
+    // would hand Decimal numbers beyond u64 or with a fraction through u128 and f64
+    amount: Option<&'a str>,
+}
+
+// This function returns the first required column the lowercased header does not have
+pub fn missing_column(headers: &StringRecord) -> Option<&'static str> {
+    REQUIRED_COLUMNS.into_iter().find(|&column| !headers.iter().any(|name| name == column))
+}
+
+// This function reads an ISO 8601 timestamp such as 2022-01-03T09:00:00Z or 2022-01-03T10:00:00.5+01:00. One without
+// an offset is taken to be UTC
+pub fn parse_timestamp(text: &str) -> Result<DateTime<FixedOffset>, String> {
+    DateTime::parse_from_rfc3339(text)
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f").map(|time| time.and_utc().fixed_offset()))
+        .map_err(|_| format!("invalid timestamp {:?}, expected ISO 8601 like 2022-01-03T09:00:00Z", text))
+}
+
+// Where the columns a CSV input may leave out are, if it has them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptionalColumns {
+    pub timestamp: Option<usize>,
+    // Only looked for when the caller keeps accounts per currency
+    pub currency: Option<usize>,
+}
+
+impl OptionalColumns {
+    // This function finds the optional columns in the lowercased header once, rather than matching them by name on
+    // every row, which costs more
+    pub fn find(headers: &StringRecord, currencies: bool) -> OptionalColumns {
+        let column = |wanted: &str| headers.iter().position(|name| name == wanted);
+        OptionalColumns {
+            timestamp: column("timestamp"),
+            currency: if currencies { column("currency") } else { None },
+        }
+    }
+}
+
+// This function parses a CSV row into a transaction, with its timestamp and currency when the input has columns for them. Only rows that move funds keep their amount, and a missing one is
+// left for the engine to reject, so one bad row does not stop the file. Rows for clients `keep_client` turns down are dropped.
+// Amounts that cannot be read are reported with the line they are on
+pub fn parse_row(record: &StringRecord, headers: &StringRecord, columns: OptionalColumns, keep_client: impl Fn(u16) -> bool, separator: char) -> Result<Option<TransactionRow>, Box<dyn Error>> {
+    let row: InputRow = record.deserialize(Some(headers))?;
+    if !keep_client(row.client) {
+        return Ok(None);
+    }
+
+    let line = record.position().map_or(0, |p| p.line());
+    let transaction_type = row.transaction_type.parse::<TransactionType>()?;
+    let parse_amount = |text: &str| money::parse_amount(text, separator).map_err(|e| format!("line {}: {}", line, e));
+    let parsed = row.amount.map(parse_amount).transpose()?;
+    let amount = match transaction_type {
+        TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Correction | TransactionType::OpeningBalance | TransactionType::AdminHold => parsed,
+        TransactionType::Assert => Some(parsed.ok_or_else(|| format!("line {} is an assert without an expected available balance", line))?),
+        _ => None,
+    };
+
+    // An assert row may go on to give the expected held and total balances, in the two columns after the header's
+    let extra = |index: usize| headers.len().checked_add(index).and_then(|index| record.get(index)).filter(|value| !value.is_empty());
+    let expected_held_total = match (transaction_type, extra(0), extra(1)) {
+        (TransactionType::Assert, Some(held), Some(total)) => Some((parse_amount(held)?, parse_amount(total)?)),
+        (TransactionType::Assert, Some(_), None) => return Err(format!("line {} gives an expected held balance without a total", line).into()),
+        _ => None,
+    };
+
+    let timestamp = columns.timestamp.and_then(|index| record.get(index)).filter(|text| !text.is_empty()).map(parse_timestamp).transpose().map_err(|e| format!("line {}: {}", line, e))?;
+
+    // A row that refers to a stored transaction or admin hold may leave the currency out and takes that one's, every
+    // other row opens or moves the account of the currency it names
+    let currency = columns.currency.and_then(|index| record.get(index)).filter(|text| !text.is_empty())
+        .map(str::parse::<Currency>).transpose().map_err(|e| format!("line {}: {}", line, e))?;
+    let refers_back = transaction_type.is_dispute_lifecycle() || matches!(transaction_type, TransactionType::Correction | TransactionType::AdminRelease);
+    if columns.currency.is_some() && currency.is_none() && !refers_back {
+        return Err(format!("line {} is a {} without a currency", line, transaction_type).into());
+    }
+
+    Ok(Some(TransactionRow {
+        transaction_type,
+        client_id: row.client,
+        transaction_id: row.tx,
+        amount,
+        expected_held_total,
+        timestamp,
+        currency,
+    }))
+}
@@ -0,0 +1,3014 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)]
+
+// The transaction engine, and the input and report handling of the command line tool built on it.
+//
+// An Engine owns the accounts and the stored transactions. Transactions are fed to it one at a time with
+// Engine::process_transaction, or as rows read from an input with Engine::process, and the resulting balances are read
+// back with Engine::accounts, or all at once as a serializable Report with Engine::report:
+//
+//     let mut engine = Engine::new();
+//     engine.process_transaction(Transaction::Deposit { client: 1, tx: 1, amount })?;
+//     for client in engine.accounts() { ... }
+//     let json = serde_json::to_string(&engine.report())?;
+//
+// An Observer passed to Engine::with_observer is told about every transaction as process applies or rejects it, and
+// about the final Report when the caller ends the run with Engine::complete.
+//
+// An Engine is Send and Sync but takes &mut self to process, so it is used from one thread at a time. A ShardedEngine
+// splits one by client so that threads can process rows for different clients at once, see sharded.rs.
+//
+// An engine whose state must survive restarts starts from a Storage with Engine::load and writes every applied row to
+// it with Engine::process_stored, see storage.rs.
+//
+// run::process_input reads whole inputs the way the command line tool does, with the flags in args.rs, and returns a
+// run::State that the account report and the other end of run reports are written from, see run.rs.
+
+pub mod args;
+#[cfg(feature = "arrow")]
+mod arrow_io;
+pub mod audit;
+mod binlog;
+mod cache;
+mod checkpoint;
+pub mod diagnostics;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod heartbeat;
+pub mod input;
+mod merge;
+pub mod money;
+mod parallel;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod remote;
+pub mod replay;
+pub mod report;
+pub mod run;
+mod sharded;
+pub mod snapshot;
+mod spill;
+pub mod storage;
+
+use chrono::{DateTime, Datelike, FixedOffset, Utc};
+use log::{info, warn};
+use money::Money;
+use serde::{Deserialize, Serialize};
+use sharded::Foreign;
+pub use sharded::ShardedEngine;
+use spill::Spill;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::num::NonZeroU32;
+use std::ops::RangeInclusive;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+
+// What a transaction does. Every input format names it in snake case, e.g. opening_balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Correction,
+    OpeningBalance,
+    AdminHold,
+    AdminRelease,
+    // Locks or unlocks the account by hand, e.g. for a support case. The tx id is not stored
+    AdminLock,
+    AdminUnlock,
+    // Checks the client's balances without changing them
+    Assert,
+}
+
+impl TransactionType {
+    pub const ALL: [TransactionType; 12] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Correction,
+        TransactionType::OpeningBalance,
+        TransactionType::AdminHold,
+        TransactionType::AdminRelease,
+        TransactionType::AdminLock,
+        TransactionType::AdminUnlock,
+        TransactionType::Assert,
+    ];
+
+    pub const NAMES: &'static [&'static str] = &[
+        "deposit", "withdrawal", "dispute", "resolve", "chargeback", "correction", "opening_balance", "admin_hold", "admin_release", "admin_lock", "admin_unlock", "assert",
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Correction => "correction",
+            TransactionType::OpeningBalance => "opening_balance",
+            TransactionType::AdminHold => "admin_hold",
+            TransactionType::AdminRelease => "admin_release",
+            TransactionType::AdminLock => "admin_lock",
+            TransactionType::AdminUnlock => "admin_unlock",
+            TransactionType::Assert => "assert",
+        }
+    }
+
+    // Whether the type refers back to a stored deposit or withdrawal by its tx id
+    pub fn is_dispute_lifecycle(self) -> bool {
+        matches!(self, TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback)
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = EngineError;
+
+    fn from_str(name: &str) -> Result<TransactionType, EngineError> {
+        TransactionType::ALL.into_iter()
+            .find(|transaction_type| transaction_type.name() == name)
+            .ok_or_else(|| EngineError::UnknownTransactionType(name.to_string()))
+    }
+}
+
+// A three letter currency code like USD, always in upper case. Inputs that name currencies keep one account per client
+// and currency, and a balance never mixes two of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters are ever stored
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+
+    // This function returns the code as three bytes, for storage formats with a fixed layout
+    pub fn to_bytes(self) -> [u8; 3] {
+        self.0
+    }
+
+    // This function reads a code written by to_bytes, None unless it is three ASCII upper case letters
+    pub fn from_bytes(bytes: [u8; 3]) -> Option<Currency> {
+        bytes.iter().all(u8::is_ascii_uppercase).then_some(Currency(bytes))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCurrencyError(String);
+
+impl fmt::Display for ParseCurrencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid currency {:?}, expected a three letter code like USD", self.0)
+    }
+}
+
+impl Error for ParseCurrencyError {}
+
+// Reads a code in any case, e.g. usd is USD
+impl FromStr for Currency {
+    type Err = ParseCurrencyError;
+
+    fn from_str(code: &str) -> Result<Currency, ParseCurrencyError> {
+        let bytes = <[u8; 3]>::try_from(code.as_bytes()).map_err(|_| ParseCurrencyError(code.to_string()))?;
+        Currency::from_bytes(bytes.map(|byte| byte.to_ascii_uppercase())).ok_or_else(|| ParseCurrencyError(code.to_string()))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Currency, D::Error> {
+        let code = String::deserialize(d)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Decimal places amounts may have in each currency, parsed from a spec like "JPY=0,USD=2,DEFAULT=4". DEFAULT applies to
+// every currency not listed, and to accounts without a currency. Without a scale an amount may have four places
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CurrencyScales {
+    scales: BTreeMap<Currency, u32>,
+    default: Option<u32>,
+}
+
+impl CurrencyScales {
+    // This function returns the places of a currency, None if neither it nor DEFAULT is listed
+    pub fn places(&self, currency: Option<Currency>) -> Option<u32> {
+        currency.and_then(|currency| self.scales.get(&currency).copied()).or(self.default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scales.is_empty() && self.default.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCurrencyScalesError(String);
+
+impl fmt::Display for ParseCurrencyScalesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ParseCurrencyScalesError {}
+
+impl FromStr for CurrencyScales {
+    type Err = ParseCurrencyScalesError;
+
+    fn from_str(spec: &str) -> Result<CurrencyScales, ParseCurrencyScalesError> {
+        let mut scales = CurrencyScales::default();
+        for part in spec.split(',') {
+            let (currency, places) = part.split_once('=').ok_or_else(|| ParseCurrencyScalesError(format!("invalid currency scale {:?}, expected CURRENCY=PLACES", part.trim())))?;
+            let (currency, places) = (currency.trim(), places.trim());
+            let places = places.parse::<u32>().ok().filter(|&places| places <= money::DECIMAL_PLACES)
+                .ok_or_else(|| ParseCurrencyScalesError(format!("invalid scale {:?} for {}, expected 0 to {} places", places, currency, money::DECIMAL_PLACES)))?;
+            let duplicate = if currency.eq_ignore_ascii_case("DEFAULT") {
+                scales.default.replace(places).is_some()
+            } else {
+                let currency = currency.parse::<Currency>().map_err(|e| ParseCurrencyScalesError(e.to_string()))?;
+                scales.scales.insert(currency, places).is_some()
+            };
+            if duplicate {
+                return Err(ParseCurrencyScalesError(format!("{} has more than one scale", currency.to_ascii_uppercase())));
+            }
+        }
+        Ok(scales)
+    }
+}
+
+impl fmt::Display for CurrencyScales {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let listed = self.scales.iter().map(|(currency, places)| (currency.as_str(), *places));
+        for (i, (currency, places)) in listed.chain(self.default.map(|places| ("DEFAULT", places))).enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", currency, places)?;
+        }
+        Ok(())
+    }
+}
+
+// Which account a transaction moves: the client's only one, or with currencies the client's account in that currency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AccountKey {
+    pub client_id: u16,
+    pub currency: Option<Currency>,
+}
+
+impl From<u16> for AccountKey {
+    fn from(client_id: u16) -> AccountKey {
+        AccountKey { client_id, currency: None }
+    }
+}
+
+// Hashed by the client id alone, which is all IdHasher has to take in for inputs without currencies. A client's accounts
+// in a few currencies share a hash, and the map tells them apart by comparing keys
+impl std::hash::Hash for AccountKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u16(self.client_id);
+    }
+}
+
+// Saved as the client id alone when there is no currency, so state saved before currencies existed still loads
+impl Serialize for AccountKey {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.currency {
+            None => s.serialize_u16(self.client_id),
+            Some(currency) => (self.client_id, currency).serialize(s),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountKey {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<AccountKey, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Saved {
+            Client(u16),
+            InCurrency(u16, Currency),
+        }
+        Ok(match Saved::deserialize(d)? {
+            Saved::Client(client_id) => AccountKey::from(client_id),
+            Saved::InCurrency(client_id, currency) => AccountKey { client_id, currency: Some(currency) },
+        })
+    }
+}
+
+// A transaction with exactly the fields its type needs. Embedders can build these directly and hand them to
+// Engine::process_transaction, and the engine dispatches on it for rows of every input format, see TransactionRow::transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transaction {
+    Deposit { client: u16, tx: u32, amount: Money },
+    Withdrawal { client: u16, tx: u32, amount: Money },
+    Dispute { client: u16, tx: u32 },
+    Resolve { client: u16, tx: u32 },
+    Chargeback { client: u16, tx: u32 },
+    // Replaces the amount of the stored deposit or withdrawal tx
+    Correction { client: u16, tx: u32, amount: Money },
+    OpeningBalance { client: u16, tx: u32, amount: Money },
+    AdminHold { client: u16, tx: u32, amount: Money },
+    AdminRelease { client: u16, tx: u32 },
+    AdminLock { client: u16, tx: u32 },
+    AdminUnlock { client: u16, tx: u32 },
+    // The balances the client is expected to have: available, and held and total when given
+    Assert { client: u16, tx: u32, available: Money, held_total: Option<(Money, Money)> },
+}
+
+impl Transaction {
+    pub fn transaction_type(&self) -> TransactionType {
+        match self {
+            Transaction::Deposit { .. } => TransactionType::Deposit,
+            Transaction::Withdrawal { .. } => TransactionType::Withdrawal,
+            Transaction::Dispute { .. } => TransactionType::Dispute,
+            Transaction::Resolve { .. } => TransactionType::Resolve,
+            Transaction::Chargeback { .. } => TransactionType::Chargeback,
+            Transaction::Correction { .. } => TransactionType::Correction,
+            Transaction::OpeningBalance { .. } => TransactionType::OpeningBalance,
+            Transaction::AdminHold { .. } => TransactionType::AdminHold,
+            Transaction::AdminRelease { .. } => TransactionType::AdminRelease,
+            Transaction::AdminLock { .. } => TransactionType::AdminLock,
+            Transaction::AdminUnlock { .. } => TransactionType::AdminUnlock,
+            Transaction::Assert { .. } => TransactionType::Assert,
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        match *self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::Chargeback { client, .. }
+            | Transaction::Correction { client, .. }
+            | Transaction::OpeningBalance { client, .. }
+            | Transaction::AdminHold { client, .. }
+            | Transaction::AdminRelease { client, .. }
+            | Transaction::AdminLock { client, .. }
+            | Transaction::AdminUnlock { client, .. }
+            | Transaction::Assert { client, .. } => client,
+        }
+    }
+
+    pub fn tx(&self) -> u32 {
+        match *self {
+            Transaction::Deposit { tx, .. }
+            | Transaction::Withdrawal { tx, .. }
+            | Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. }
+            | Transaction::Correction { tx, .. }
+            | Transaction::OpeningBalance { tx, .. }
+            | Transaction::AdminHold { tx, .. }
+            | Transaction::AdminRelease { tx, .. }
+            | Transaction::AdminLock { tx, .. }
+            | Transaction::AdminUnlock { tx, .. }
+            | Transaction::Assert { tx, .. } => tx,
+        }
+    }
+
+    // This function returns the amount the transaction moves, or for an assert the available balance it expects
+    pub fn amount(&self) -> Option<Money> {
+        match *self {
+            Transaction::Deposit { amount, .. }
+            | Transaction::Withdrawal { amount, .. }
+            | Transaction::Correction { amount, .. }
+            | Transaction::OpeningBalance { amount, .. }
+            | Transaction::AdminHold { amount, .. } => Some(amount),
+            Transaction::Assert { available, .. } => Some(available),
+            Transaction::Dispute { .. } | Transaction::Resolve { .. } | Transaction::Chargeback { .. } | Transaction::AdminRelease { .. }
+            | Transaction::AdminLock { .. } | Transaction::AdminUnlock { .. } => None,
+        }
+    }
+}
+
+// A row carrying a transaction, as parsed from a CSV row or read back from a binlog, along with what the input says
+// about it besides. Its amount is optional because inputs may leave it out, which the engine rejects as MissingAmount.
+// For assert rows, amount is the expected available balance
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionRow {
+    pub transaction_type: TransactionType,
+    pub client_id: u16,
+    pub transaction_id: u32,
+    pub amount: Option<Money>,
+    // Held and total an assert row expects, when it gives them
+    pub expected_held_total: Option<(Money, Money)>,
+    // When the transaction happened, for CSV inputs with a timestamp column. The engine itself never looks at it
+    pub timestamp: Option<DateTime<FixedOffset>>,
+    // The currency the amount is in, for CSV inputs with a currency column read with currencies. Rows that refer to a
+    // stored transaction may leave it out and move the account of the transaction they refer to
+    pub currency: Option<Currency>,
+}
+
+impl TransactionRow {
+    // This function creates a transaction of the given type. Only rows that move funds carry an amount
+    pub fn new(transaction_type: TransactionType, client_id: u16, transaction_id: u32, amount: Option<Money>) -> TransactionRow {
+        TransactionRow {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            expected_held_total: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    // This function returns the account the row names itself, which for rows that refer to a stored transaction may
+    // not be the one they move, see Engine::account_for
+    pub fn account(&self) -> AccountKey {
+        AccountKey { client_id: self.client_id, currency: self.currency }
+    }
+
+    // This function returns the typed transaction the row carries. A row that moves funds without an amount has none.
+    // Rows that refer to a stored transaction carry no amount, and one they are given is not part of the transaction
+    pub fn transaction(&self) -> Result<Transaction, Rejection> {
+        let (client, tx) = (self.client_id, self.transaction_id);
+        let amount = || self.amount.ok_or(Rejection::MissingAmount);
+        Ok(match self.transaction_type {
+            TransactionType::Deposit => Transaction::Deposit { client, tx, amount: amount()? },
+            TransactionType::Withdrawal => Transaction::Withdrawal { client, tx, amount: amount()? },
+            TransactionType::Dispute => Transaction::Dispute { client, tx },
+            TransactionType::Resolve => Transaction::Resolve { client, tx },
+            TransactionType::Chargeback => Transaction::Chargeback { client, tx },
+            TransactionType::Correction => Transaction::Correction { client, tx, amount: amount()? },
+            TransactionType::OpeningBalance => Transaction::OpeningBalance { client, tx, amount: amount()? },
+            TransactionType::AdminHold => Transaction::AdminHold { client, tx, amount: amount()? },
+            TransactionType::AdminRelease => Transaction::AdminRelease { client, tx },
+            TransactionType::AdminLock => Transaction::AdminLock { client, tx },
+            TransactionType::AdminUnlock => Transaction::AdminUnlock { client, tx },
+            TransactionType::Assert => Transaction::Assert { client, tx, available: amount()?, held_total: self.expected_held_total },
+        })
+    }
+}
+
+// A row without a timestamp or currency
+impl From<Transaction> for TransactionRow {
+    fn from(transaction: Transaction) -> TransactionRow {
+        let mut row = TransactionRow::new(transaction.transaction_type(), transaction.client(), transaction.tx(), transaction.amount());
+        if let Transaction::Assert { held_total, .. } = transaction {
+            row.expected_held_total = held_total;
+        }
+        row
+    }
+}
+
+// The kinds of transaction that are stored as records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    Deposit,
+    Withdrawal,
+    OpeningBalance,
+}
+
+// A stored transaction that later rows can refer to by its tx id. Every applied deposit and withdrawal is kept, so this
+// holds only what the dispute lifecycle and corrections need
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Record {
+    pub kind: RecordKind,
+    pub client_id: u16,
+    pub amount: Money,
+    pub state: RecordState,
+    // Saved by position like Client, so it goes last and may be missing in older state
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+impl Record {
+    // This function creates a record that has never been disputed
+    pub fn new(kind: RecordKind, client_id: u16, amount: Money) -> Record {
+        Record {
+            kind,
+            client_id,
+            amount,
+            state: RecordState::Normal,
+            currency: None,
+        }
+    }
+
+    // This function returns the account the record moved
+    pub fn account(&self) -> AccountKey {
+        AccountKey { client_id: self.client_id, currency: self.currency }
+    }
+}
+
+// Where a record is in the dispute lifecycle. A resolved record can be disputed again, a charged back one is final, so a
+// record can go dispute, resolve, dispute, ... and end in one chargeback. Each dispute holds the amount and each resolve
+// releases it, so only the chargeback moves it out of the account, once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordState {
+    Normal,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl RecordState {
+    // This function returns the state a dispute, resolve or chargeback row moves the record to, or why it may not.
+    // Every legal transition is listed here
+    pub fn next(self, step: TransactionType) -> Result<RecordState, Rejection> {
+        match (self, step) {
+            (RecordState::Normal | RecordState::Resolved, TransactionType::Dispute) => Ok(RecordState::Disputed),
+            (RecordState::Disputed, TransactionType::Resolve) => Ok(RecordState::Resolved),
+            (RecordState::Disputed, TransactionType::Chargeback) => Ok(RecordState::ChargedBack),
+            (RecordState::Disputed | RecordState::ChargedBack, TransactionType::Dispute) => Err(Rejection::AlreadyDisputed),
+            (RecordState::ChargedBack, TransactionType::Resolve | TransactionType::Chargeback) => Err(Rejection::AlreadyDisputed),
+            (RecordState::Normal | RecordState::Resolved, TransactionType::Resolve | TransactionType::Chargeback) => Err(Rejection::NotDisputed),
+            _ => Err(Rejection::NotDisputable),
+        }
+    }
+
+    pub fn is_disputed(self) -> bool {
+        self == RecordState::Disputed
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordState::Normal => "normal",
+            RecordState::Disputed => "disputed",
+            RecordState::Resolved => "resolved",
+            RecordState::ChargedBack => "charged_back",
+        }
+    }
+}
+
+// The stored transactions by tx id, with the tx ids of every client's records in the order they were stored, see
+// ClientIds. Only the records themselves are saved, the index by client is built again when a store is loaded.
+//
+// After spill_to, only the records stored or changed most recently stay in memory and the older ones are moved to a
+// file, see spill.rs. Lookups read spilled records back transparently, which is why records are returned as copies
+#[derive(Debug, Default, Deserialize)]
+#[serde(from = "IdMap<u32, Record>")]
+pub struct TransactionStore {
+    records: IdMap<u32, Record>,
+    by_client: IdMap<u16, ClientIds>,
+    #[serde(skip)]
+    spill: Option<Box<Spill>>,
+}
+
+impl TransactionStore {
+    pub fn new() -> TransactionStore {
+        TransactionStore::default()
+    }
+
+    // This function keeps at most `keep` records in memory from now on, moving older ones to a new file in `dir`. The
+    // file is removed when the store is dropped. Records already spilled by an earlier call are kept in the old file
+    pub fn spill_to(&mut self, dir: &Path, keep: usize) -> io::Result<()> {
+        let mut spill = Spill::create(dir, keep)?;
+        let mut ids: Vec<u32> = self.records.keys().copied().collect();
+        ids.sort_unstable();
+        for transaction_id in ids {
+            spill.touch(transaction_id);
+        }
+        if let Some(old) = self.spill.take() {
+            for transaction_id in old.ids() {
+                if let Some(record) = old.read(transaction_id) {
+                    spill.write(transaction_id, &record);
+                }
+            }
+            if let Some(failure) = old.take_failure().or_else(|| spill.take_failure()) {
+                return Err(io::Error::other(failure));
+            }
+        }
+        self.spill = Some(Box::new(spill));
+        self.evict();
+        self.take_failure().map_or(Ok(()), |failure| Err(io::Error::other(failure)))
+    }
+
+    // This function returns how many records are held in memory, which is all of them unless the store spills
+    pub fn in_memory(&self) -> usize {
+        self.records.len()
+    }
+
+    // This function returns the first failure to read or write the spill file since the last call, if any. A record
+    // that could not be read is treated as unknown and one that could not be written stays in memory
+    pub fn take_failure(&self) -> Option<String> {
+        self.spill.as_ref().and_then(|spill| spill.take_failure())
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len() + self.spill.as_ref().map_or(0, |spill| spill.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, transaction_id: u32) -> bool {
+        self.records.contains_key(&transaction_id) || self.spill.as_ref().is_some_and(|spill| spill.contains(transaction_id))
+    }
+
+    pub fn get(&self, transaction_id: u32) -> Option<Record> {
+        match self.records.get(&transaction_id) {
+            Some(record) => Some(*record),
+            None => self.spill.as_ref().and_then(|spill| spill.read(transaction_id)),
+        }
+    }
+
+    // This function returns the record a client refers to, or why the client may not refer to it
+    pub fn get_for_client(&self, transaction_id: u32, client_id: u16) -> Result<Record, Rejection> {
+        let record = self.get(transaction_id).ok_or(Rejection::UnknownTransaction)?;
+        if record.client_id != client_id {
+            return Err(Rejection::ClientMismatch);
+        }
+        Ok(record)
+    }
+
+    // This function stores a record under a tx id that is not taken yet
+    pub fn insert(&mut self, transaction_id: u32, record: Record) -> Result<(), Rejection> {
+        if self.spill.as_ref().is_some_and(|spill| spill.contains(transaction_id)) {
+            return Err(Rejection::DuplicateTransaction);
+        }
+        let Entry::Vacant(slot) = self.records.entry(transaction_id) else {
+            return Err(Rejection::DuplicateTransaction);
+        };
+        self.by_client.entry(record.client_id).or_default().push(transaction_id);
+        slot.insert(record);
+        self.touch(transaction_id);
+        Ok(())
+    }
+
+    // This function moves a record along the dispute lifecycle. The caller has already checked the step with RecordState::next
+    pub fn set_state(&mut self, transaction_id: u32, state: RecordState) {
+        if let Some(record) = self.get_mut(transaction_id) {
+            record.state = state;
+        }
+        self.touch(transaction_id);
+    }
+
+    // This function replaces the amount of a record, for corrections
+    pub fn set_amount(&mut self, transaction_id: u32, amount: Money) {
+        if let Some(record) = self.get_mut(transaction_id) {
+            record.amount = amount;
+        }
+        self.touch(transaction_id);
+    }
+
+    pub fn remove(&mut self, transaction_id: u32) -> Option<Record> {
+        let record = match self.records.remove(&transaction_id) {
+            Some(record) => {
+                if let Some(spill) = self.spill.as_mut() {
+                    spill.forget(transaction_id);
+                }
+                record
+            },
+            None => self.spill.as_mut()?.take(transaction_id)?,
+        };
+        if let Entry::Occupied(mut ids) = self.by_client.entry(record.client_id) {
+            ids.get_mut().remove(transaction_id);
+            if ids.get().is_empty() {
+                ids.remove();
+            }
+        }
+        Some(record)
+    }
+
+    // This function returns every record with its tx id, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Record)> + '_ {
+        let spilled = self.spill.iter().flat_map(|spill| spill.ids().filter_map(|transaction_id| spill.read(transaction_id).map(|record| (transaction_id, record))));
+        self.records.iter().map(|(transaction_id, record)| (*transaction_id, *record)).chain(spilled)
+    }
+
+    // This function returns a client's records with their tx ids, in the order they were stored, or by tx id in a loaded store
+    pub fn for_client(&self, client_id: u16) -> impl Iterator<Item = (u32, Record)> + '_ {
+        self.by_client.get(&client_id).into_iter().flat_map(ClientIds::iter)
+            .filter_map(|&transaction_id| self.get(transaction_id).map(|record| (transaction_id, record)))
+    }
+
+    // This function returns a record to change, first moving it back into memory if it was spilled
+    fn get_mut(&mut self, transaction_id: u32) -> Option<&mut Record> {
+        if !self.records.contains_key(&transaction_id) {
+            let record = self.spill.as_mut()?.take(transaction_id)?;
+            self.records.insert(transaction_id, record);
+        }
+        self.records.get_mut(&transaction_id)
+    }
+
+    // This function marks a record in memory as the most recently used and spills the oldest ones beyond the limit
+    fn touch(&mut self, transaction_id: u32) {
+        if let Some(spill) = self.spill.as_mut() {
+            if self.records.contains_key(&transaction_id) {
+                spill.touch(transaction_id);
+            }
+            self.evict();
+        }
+    }
+
+    fn evict(&mut self) {
+        let Some(spill) = self.spill.as_mut() else {
+            return;
+        };
+        while self.records.len() > spill.keep() {
+            let Some(transaction_id) = spill.oldest() else {
+                return;
+            };
+            let Some(record) = self.records.get(&transaction_id) else {
+                continue;
+            };
+            if !spill.write(transaction_id, record) {
+                // The file cannot be written, so the record stays in memory and the failure ends the run
+                spill.untake(transaction_id);
+                return;
+            }
+            self.records.remove(&transaction_id);
+        }
+    }
+}
+
+// The tx ids of one client's records in the order they were stored. Removing a record only notes its tx id, and the
+// list drops the removed ones once they are half of it, so removing every record of a client with many takes time in
+// proportion to their number rather than its square
+#[derive(Debug, Clone, Default)]
+struct ClientIds {
+    ids: Vec<u32>,
+    removed: HashSet<u32, BuildHasherDefault<IdHasher>>,
+}
+
+impl ClientIds {
+    // This function adds a tx id at the end. One the client had removed before is taken out of its old place first,
+    // which is rare enough to pay for the scan
+    fn push(&mut self, transaction_id: u32) {
+        if self.removed.remove(&transaction_id) {
+            self.ids.retain(|&id| id != transaction_id);
+        }
+        self.ids.push(transaction_id);
+    }
+
+    // This function removes a tx id the client holds
+    fn remove(&mut self, transaction_id: u32) {
+        self.removed.insert(transaction_id);
+        if self.removed.len() * 2 > self.ids.len() {
+            let removed = std::mem::take(&mut self.removed);
+            self.ids.retain(|id| !removed.contains(id));
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ids.len() == self.removed.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &u32> + '_ {
+        self.ids.iter().filter(|id| !self.removed.contains(id))
+    }
+}
+
+// A copy of a store keeps every record in memory, so that changes to the copy never reach the original's spill file
+impl Clone for TransactionStore {
+    fn clone(&self) -> TransactionStore {
+        TransactionStore { records: self.iter().collect(), by_client: self.by_client.clone(), spill: None }
+    }
+}
+
+impl From<IdMap<u32, Record>> for TransactionStore {
+    fn from(records: IdMap<u32, Record>) -> TransactionStore {
+        // Saved records come back in no particular order, so each client's tx ids are put in ascending order
+        let mut by_client: IdMap<u16, ClientIds> = IdMap::default();
+        for (&transaction_id, record) in &records {
+            by_client.entry(record.client_id).or_default().ids.push(transaction_id);
+        }
+        for ids in by_client.values_mut() {
+            ids.ids.sort_unstable();
+        }
+        TransactionStore { records, by_client, spill: None }
+    }
+}
+
+impl Serialize for TransactionStore {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.spill.is_none() {
+            return self.records.serialize(s);
+        }
+        let saved = s.collect_map(self.iter())?;
+        match self.take_failure() {
+            Some(failure) => Err(serde::ser::Error::custom(failure)),
+            None => Ok(saved),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Client {
+    pub client_id: u16,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+    // What the client owes, only tracked with --track-debt. Total is available + held - debt
+    pub debt: Option<Money>,
+    // The open disputes by tx id, with the amount each one holds. Held is their sum plus the client's admin holds
+    open_disputes: BTreeMap<u32, Money>,
+    // Disputes of deposits that available could not cover under dispute_requires_funds, oldest first. They hold
+    // nothing until a later row leaves enough available, and then become open disputes
+    #[serde(default)]
+    pending_disputes: Vec<(u32, Money)>,
+    // Held funds the account was opened with by Engine::open_with_balances, for disputes from before the run that no row
+    // can resolve or charge back. Counted in held along with the open disputes.
+    // Snapshots save fields by position, so fields added at the end are always written and may be missing when read
+    #[serde(default)]
+    carried_held: Option<Money>,
+    // The currency of the account, for inputs that name currencies. A client has one account per currency then
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    // Chargebacks the account has taken, which decide when it locks under LockPolicy::Threshold
+    #[serde(default)]
+    pub chargebacks: u32,
+}
+
+// An amount frozen in held by an admin_hold row. Holds are not disputes and cannot be resolved or charged back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminHold {
+    pub client_id: u16,
+    pub amount: Money,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+impl AdminHold {
+    // This function returns the account the hold is in
+    pub fn account(&self) -> AccountKey {
+        AccountKey { client_id: self.client_id, currency: self.currency }
+    }
+}
+
+// Business rules the transaction handlers consult. Built through EnginePolicy::builder() so new knobs can be added without touching every caller
+#[derive(Debug, Clone, Default)]
+pub struct EnginePolicy {
+    chargeback_fee: Option<Money>,
+    track_debt: bool,
+    allow_forced_hold: bool,
+    dispute_requires_funds: bool,
+    max_balance: Option<Money>,
+    client_max_balance: HashMap<u16, Money>,
+    lock_policy: LockPolicy,
+    monthly_fee: Option<Money>,
+    currency_scales: CurrencyScales,
+}
+
+// When a chargeback locks the account it is taken from. Written and read as `account` or `threshold:N`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    // Every chargeback locks the account
+    #[default]
+    Account,
+    // Only the Nth chargeback on an account locks it. The ones before only charge back their transaction
+    Threshold(NonZeroU32),
+}
+
+impl LockPolicy {
+    // This function tells whether an account that has now taken `chargebacks` chargebacks is to be locked
+    fn locks_after(self, chargebacks: u32) -> bool {
+        match self {
+            LockPolicy::Account => true,
+            LockPolicy::Threshold(threshold) => chargebacks >= threshold.get(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLockPolicyError(String);
+
+impl fmt::Display for ParseLockPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid lock policy {:?}, expected account or threshold:N with N at least 1", self.0)
+    }
+}
+
+impl Error for ParseLockPolicyError {}
+
+impl FromStr for LockPolicy {
+    type Err = ParseLockPolicyError;
+
+    fn from_str(policy: &str) -> Result<LockPolicy, ParseLockPolicyError> {
+        let invalid = || ParseLockPolicyError(policy.to_string());
+        match policy.split_once(':') {
+            None if policy == "account" => Ok(LockPolicy::Account),
+            Some(("threshold", threshold)) => threshold.parse().map(LockPolicy::Threshold).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for LockPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockPolicy::Account => f.write_str("account"),
+            LockPolicy::Threshold(threshold) => write!(f, "threshold:{}", threshold),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EnginePolicyBuilder {
+    policy: EnginePolicy,
+}
+
+impl EnginePolicy {
+    pub fn builder() -> EnginePolicyBuilder {
+        EnginePolicyBuilder::default()
+    }
+
+    // This function tells whether negative available balances are turned into debt
+    pub fn track_debt(&self) -> bool {
+        self.track_debt
+    }
+
+    // This function returns the fee debited on every successful chargeback, if there is one
+    pub fn chargeback_fee(&self) -> Option<Money> {
+        self.chargeback_fee
+    }
+
+    // This function tells when a chargeback locks the account
+    pub fn lock_policy(&self) -> LockPolicy {
+        self.lock_policy
+    }
+
+    // This function returns the fee debited at the end of every calendar month, if there is one
+    pub fn monthly_fee(&self) -> Option<Money> {
+        self.monthly_fee
+    }
+
+    // This function returns the decimal places amounts may have per currency
+    pub fn currency_scales(&self) -> &CurrencyScales {
+        &self.currency_scales
+    }
+
+    // This function returns the decimal places amounts in a currency may have, four unless the scales say otherwise
+    pub fn scale_for(&self, currency: Option<Currency>) -> u32 {
+        self.currency_scales.places(currency).unwrap_or(money::DECIMAL_PLACES)
+    }
+
+    // This function returns the balance cap that applies to a client, their own if they have one
+    fn max_balance_for(&self, client_id: u16) -> Option<Money> {
+        self.client_max_balance.get(&client_id).copied().or(self.max_balance)
+    }
+
+    // This function refuses to raise a client's total by `amount` if that would take it above their balance cap. Landing
+    // exactly on the cap is allowed. `client` is the client's account, None if they do not have one yet
+    fn check_balance_cap(&self, client_id: u16, client: Option<&Client>, amount: Money) -> Result<(), Rejection> {
+        let Some(cap) = self.max_balance_for(client_id) else {
+            return Ok(());
+        };
+        if amount <= Money::ZERO {
+            return Ok(());
+        }
+        let total = client.map_or(Money::ZERO, |client| client.total);
+        if total.checked_add(amount).ok_or(Rejection::Overflow)? > cap {
+            return Err(Rejection::BalanceCapExceeded);
+        }
+        Ok(())
+    }
+}
+
+impl EnginePolicyBuilder {
+    // Debit this fee from the client on every successful chargeback
+    pub fn chargeback_fee(mut self, fee: Money) -> EnginePolicyBuilder {
+        self.policy.chargeback_fee = Some(fee);
+        self
+    }
+
+    // Turn negative available balances into debt that deposits repay before crediting available
+    pub fn track_debt(mut self, track_debt: bool) -> EnginePolicyBuilder {
+        self.policy.track_debt = track_debt;
+        self
+    }
+
+    // Let administrative holds exceed the available funds
+    pub fn allow_forced_hold(mut self, allow_forced_hold: bool) -> EnginePolicyBuilder {
+        self.policy.allow_forced_hold = allow_forced_hold;
+        self
+    }
+
+    // Only hold a disputed deposit once available covers it. Until then the dispute waits, and later rows retry it
+    pub fn dispute_requires_funds(mut self, dispute_requires_funds: bool) -> EnginePolicyBuilder {
+        self.policy.dispute_requires_funds = dispute_requires_funds;
+        self
+    }
+
+    // Refuse anything that would take a client's total above this amount
+    pub fn max_balance(mut self, cap: Money) -> EnginePolicyBuilder {
+        self.policy.max_balance = Some(cap);
+        self
+    }
+
+    // Use this cap for one client instead of the global one
+    pub fn client_max_balance(mut self, client_id: u16, cap: Money) -> EnginePolicyBuilder {
+        self.policy.client_max_balance.insert(client_id, cap);
+        self
+    }
+
+    // Decide with this policy which chargebacks lock the account
+    pub fn lock_policy(mut self, lock_policy: LockPolicy) -> EnginePolicyBuilder {
+        self.policy.lock_policy = lock_policy;
+        self
+    }
+
+    // Debit this fee at the end of every calendar month of the row timestamps from each open account that had a positive
+    // total at some point during the month
+    pub fn monthly_fee(mut self, fee: Money) -> EnginePolicyBuilder {
+        self.policy.monthly_fee = Some(fee);
+        self
+    }
+
+    // Refuse amounts with more decimal places than their currency has, and round fees to them
+    pub fn currency_scales(mut self, scales: CurrencyScales) -> EnginePolicyBuilder {
+        self.policy.currency_scales = scales;
+        self
+    }
+
+    pub fn build(self) -> EnginePolicy {
+        self.policy
+    }
+}
+
+// Reasons an account operation can be refused. A rejected operation never changes the account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    AccountLocked,
+    InsufficientFunds,
+    Overflow,
+    MissingAmount,
+    UnknownClient,
+    UnknownTransaction,
+    ClientMismatch,
+    NotDisputable,
+    AlreadyDisputed,
+    NotDisputed,
+    ExpiredReference,
+    InDebt,
+    DuplicateTransaction,
+    InvalidAmount,
+    NotCorrectable,
+    NotFirstTransaction,
+    MissingOpeningBalance,
+    BalanceCapExceeded,
+    CurrencyMismatch,
+    ReservedTransactionId,
+    ForgottenClient,
+}
+
+impl Rejection {
+    // This function names the reason in snake case, for files that other tools read
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::AccountLocked => "account_locked",
+            Rejection::InsufficientFunds => "insufficient_funds",
+            Rejection::Overflow => "overflow",
+            Rejection::MissingAmount => "missing_amount",
+            Rejection::UnknownClient => "unknown_client",
+            Rejection::UnknownTransaction => "unknown_transaction",
+            Rejection::ClientMismatch => "client_mismatch",
+            Rejection::NotDisputable => "not_disputable",
+            Rejection::AlreadyDisputed => "already_disputed",
+            Rejection::NotDisputed => "not_disputed",
+            Rejection::ExpiredReference => "expired_reference",
+            Rejection::InDebt => "in_debt",
+            Rejection::DuplicateTransaction => "duplicate_transaction",
+            Rejection::InvalidAmount => "invalid_amount",
+            Rejection::NotCorrectable => "not_correctable",
+            Rejection::NotFirstTransaction => "not_first_transaction",
+            Rejection::MissingOpeningBalance => "missing_opening_balance",
+            Rejection::BalanceCapExceeded => "balance_cap_exceeded",
+            Rejection::CurrencyMismatch => "currency_mismatch",
+            Rejection::ReservedTransactionId => "reserved_transaction_id",
+            Rejection::ForgottenClient => "forgotten_client",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::AccountLocked => write!(f, "account is locked"),
+            Rejection::InsufficientFunds => write!(f, "insufficient funds"),
+            Rejection::Overflow => write!(f, "balance would overflow"),
+            Rejection::MissingAmount => write!(f, "transaction has no amount"),
+            Rejection::UnknownClient => write!(f, "client does not exist"),
+            Rejection::UnknownTransaction => write!(f, "transaction does not exist"),
+            Rejection::ClientMismatch => write!(f, "client does not match transaction"),
+            Rejection::NotDisputable => write!(f, "transaction type cannot be disputed"),
+            Rejection::AlreadyDisputed => write!(f, "transaction is already being disputed or has been charged back"),
+            Rejection::NotDisputed => write!(f, "transaction is not being disputed"),
+            Rejection::ExpiredReference => write!(f, "transaction is past the retention horizon"),
+            Rejection::InDebt => write!(f, "account has outstanding debt"),
+            Rejection::DuplicateTransaction => write!(f, "transaction id is already in use"),
+            Rejection::InvalidAmount => write!(f, "amount is not valid for this transaction"),
+            Rejection::NotCorrectable => write!(f, "transaction type cannot be corrected"),
+            Rejection::NotFirstTransaction => write!(f, "opening balance is not the client's first transaction"),
+            Rejection::MissingOpeningBalance => write!(f, "client has no opening balance"),
+            Rejection::BalanceCapExceeded => write!(f, "balance would exceed the client's cap"),
+            Rejection::CurrencyMismatch => write!(f, "currency does not match transaction"),
+            Rejection::ReservedTransactionId => write!(f, "transaction id is reserved for monthly fees"),
+            Rejection::ForgottenClient => write!(f, "client has been forgotten"),
+        }
+    }
+}
+
+impl Error for Rejection {}
+
+// Why a transaction, a row or a whole run could not be completed. A rejected transaction is part of normal
+// processing: it comes back as one of the variants naming the client and tx it was refused for, or as Rejected for
+// the rarer reasons. Everything else stops the run, and the command line tool picks its exit status by the variant
+#[derive(Debug)]
+pub enum EngineError {
+    // An input, output or report file could not be read or written
+    Io(io::Error),
+    // An input could not be read, at this line of it when it is read by lines
+    Parse { line: Option<u64>, source: Box<dyn Error + Send + Sync> },
+    // A type name did not parse into a TransactionType
+    UnknownTransactionType(String),
+    InsufficientFunds { client: u16, tx: u32 },
+    AccountLocked { client: u16 },
+    // A dispute, resolve, chargeback, correction or admin release named a tx that is not stored
+    TxNotFound(u32),
+    // A row named a tx that belongs to another client
+    ClientMismatch { tx: u32, expected: u16, got: u16 },
+    // A transaction was refused for any other reason
+    Rejected(Rejection),
+    // The file of spilled records could not be read or written, after which the engine should not be trusted with more
+    // rows, or a Storage could not be reached
+    Storage(String),
+    // The flags or a file other than the input could not be used
+    Invalid(String),
+    // The engine's own bookkeeping went wrong, as opposed to bad input
+    Invariant(String),
+}
+
+impl EngineError {
+    // This function wraps an error reading the input, e.g. a row that does not parse
+    pub fn parse(line: Option<u64>, source: impl Into<Box<dyn Error + Send + Sync>>) -> EngineError {
+        EngineError::Parse { line, source: source.into() }
+    }
+
+    // This function returns why a transaction was refused, or None for an error that is not a rejection
+    pub fn rejection(&self) -> Option<Rejection> {
+        match self {
+            EngineError::InsufficientFunds { .. } => Some(Rejection::InsufficientFunds),
+            EngineError::AccountLocked { .. } => Some(Rejection::AccountLocked),
+            EngineError::TxNotFound(_) => Some(Rejection::UnknownTransaction),
+            EngineError::ClientMismatch { .. } => Some(Rejection::ClientMismatch),
+            EngineError::Rejected(rejection) => Some(*rejection),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Io(e) => write!(f, "{}", e),
+            // Parse errors already say where they are
+            EngineError::Parse { source, .. } => write!(f, "{}", source),
+            EngineError::UnknownTransactionType(transaction_type) => write!(f, "invalid transaction type {:?}", transaction_type),
+            EngineError::Storage(message) | EngineError::Invalid(message) => write!(f, "{}", message),
+            EngineError::Invariant(message) => write!(f, "internal invariant violated: {}", message),
+            // The rejection alone, which is what every row warning and diagnostic shows
+            rejected => match rejected.rejection() {
+                Some(rejection) => write!(f, "{}", rejection),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+impl Error for EngineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EngineError::Io(e) => Some(e),
+            EngineError::Parse { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Rejection> for EngineError {
+    fn from(rejection: Rejection) -> EngineError {
+        EngineError::Rejected(rejection)
+    }
+}
+
+impl From<io::Error> for EngineError {
+    fn from(e: io::Error) -> EngineError {
+        EngineError::Io(e)
+    }
+}
+
+// A CSV error is an I/O error when reading the bytes failed, and otherwise one in the CSV itself
+impl From<csv::Error> for EngineError {
+    fn from(e: csv::Error) -> EngineError {
+        if !e.is_io_error() {
+            return EngineError::parse(e.position().map(csv::Position::line), e);
+        }
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => EngineError::Io(e),
+            kind => EngineError::Io(io::Error::other(format!("{:?}", kind))),
+        }
+    }
+}
+
+impl From<serde_json::Error> for EngineError {
+    fn from(e: serde_json::Error) -> EngineError {
+        if e.is_io() {
+            return EngineError::Io(e.into());
+        }
+        EngineError::parse(None, e)
+    }
+}
+
+impl From<String> for EngineError {
+    fn from(message: String) -> EngineError {
+        EngineError::Invalid(message)
+    }
+}
+
+impl From<&str> for EngineError {
+    fn from(message: &str) -> EngineError {
+        EngineError::Invalid(message.to_string())
+    }
+}
+
+impl Client {
+    // This function creates an empty, unlocked account
+    pub fn new(client_id: u16) -> Client {
+        Client::for_account(AccountKey::from(client_id))
+    }
+
+    // This function creates an empty, unlocked account in the key's currency, if it has one
+    pub fn for_account(key: AccountKey) -> Client {
+        let AccountKey { client_id, currency } = key;
+        Client {
+            client_id,
+            available: Money::ZERO,
+            held: Money::ZERO,
+            total: Money::ZERO,
+            locked: false,
+            debt: None,
+            open_disputes: BTreeMap::new(),
+            pending_disputes: Vec::new(),
+            carried_held: None,
+            currency,
+            chargebacks: 0,
+        }
+    }
+
+    pub fn account(&self) -> AccountKey {
+        AccountKey { client_id: self.client_id, currency: self.currency }
+    }
+
+    // This function returns the disputes that are open, by tx id, with the amount each one holds
+    pub fn open_disputes(&self) -> &BTreeMap<u32, Money> {
+        &self.open_disputes
+    }
+
+    // This function returns the disputes waiting for available funds to hold, oldest first, with their amounts
+    pub fn pending_disputes(&self) -> &[(u32, Money)] {
+        &self.pending_disputes
+    }
+
+    // This function returns the held funds the account was opened with, zero for an account opened by a row
+    pub fn carried_held(&self) -> Money {
+        self.carried_held.unwrap_or(Money::ZERO)
+    }
+
+    // This function returns the sum held by the open disputes
+    pub fn disputed(&self) -> Option<Money> {
+        self.open_disputes.values().try_fold(Money::ZERO, |sum, &amount| sum.checked_add(amount))
+    }
+
+    // This function moves a negative available balance into debt, or pays debt off from a positive one.
+    // Total is left as it is, since it already counts debt as owed
+    fn settle_debt(&mut self) {
+        let debt = self.debt.get_or_insert(Money::ZERO);
+
+        // A negative available "repays" a negative amount, which grows the debt by the shortfall
+        let repaid = std::cmp::min(self.available, *debt);
+        if repaid == Money::ZERO {
+            return;
+        }
+        if let (Some(new_debt), Some(new_available)) = (money::exact_add(*debt, -repaid), money::exact_add(self.available, -repaid)) {
+            *debt = new_debt;
+            self.available = new_available;
+        }
+    }
+
+    /// Credits available and total.
+    ///
+    /// # Errors
+    ///
+    /// [`Rejection::InvalidAmount`] if the amount is not positive or has more than four decimal places, and
+    /// [`Rejection::Overflow`] if a balance would no longer fit. The account is left as it was on any error.
+    ///
+    /// A locked account is refused by [`Engine::process`], not here, so an embedder driving accounts directly checks
+    /// [`Client::locked`] itself.
+    ///
+    /// ```
+    /// use payment_engine::{Client, Rejection};
+    ///
+    /// let mut client = Client::new(1);
+    /// client.deposit("10.5".parse().unwrap()).unwrap();
+    /// assert_eq!(client.available, "10.5".parse().unwrap());
+    /// assert_eq!(client.deposit("-1".parse().unwrap()), Err(Rejection::InvalidAmount));
+    /// ```
+    pub fn deposit(&mut self, amount: Money) -> Result<(), Rejection> {
+        check_amount(amount)?;
+        self.adjust(amount, Money::ZERO)
+    }
+
+    /// Debits available and total.
+    ///
+    /// # Errors
+    ///
+    /// [`Rejection::InsufficientFunds`] if available does not cover the amount, [`Rejection::InvalidAmount`] if the
+    /// amount is not positive or has more than four decimal places, and [`Rejection::Overflow`] if a balance would no
+    /// longer fit. The account is left as it was on any error.
+    ///
+    /// ```
+    /// use payment_engine::{Client, Rejection};
+    ///
+    /// let mut client = Client::new(1);
+    /// client.deposit("10".parse().unwrap()).unwrap();
+    /// assert_eq!(client.withdraw("10.0001".parse().unwrap()), Err(Rejection::InsufficientFunds));
+    /// client.withdraw("10".parse().unwrap()).unwrap();
+    /// assert_eq!(client.total, "0".parse().unwrap());
+    /// ```
+    pub fn withdraw(&mut self, amount: Money) -> Result<(), Rejection> {
+        check_amount(amount)?;
+        if self.available < amount {
+            return Err(Rejection::InsufficientFunds);
+        }
+        self.adjust(-amount, Money::ZERO)
+    }
+
+    /// Moves funds from available to held, which is what a dispute of a deposit does. Available may go negative,
+    /// since the funds being disputed may already have been spent.
+    ///
+    /// # Errors
+    ///
+    /// [`Rejection::InvalidAmount`] if the amount is not positive or has more than four decimal places, and
+    /// [`Rejection::Overflow`] if a balance would no longer fit. The account is left as it was on any error.
+    ///
+    /// A dispute lifecycle driven through the account alone, without an engine or any rows:
+    ///
+    /// ```
+    /// use payment_engine::Client;
+    ///
+    /// let amount = |text: &str| text.parse().unwrap();
+    /// let mut client = Client::new(1);
+    /// client.deposit(amount("10")).unwrap();
+    /// client.deposit(amount("5")).unwrap();
+    ///
+    /// // The first deposit is disputed, then the dispute is resolved
+    /// client.hold(amount("10")).unwrap();
+    /// assert_eq!((client.available, client.held, client.total), (amount("5"), amount("10"), amount("15")));
+    /// client.release(amount("10")).unwrap();
+    /// assert_eq!((client.available, client.held, client.total), (amount("15"), amount("0"), amount("15")));
+    ///
+    /// // The second is disputed and charged back
+    /// client.hold(amount("5")).unwrap();
+    /// client.charge_back(amount("5")).unwrap();
+    /// assert_eq!((client.available, client.held, client.total), (amount("10"), amount("0"), amount("10")));
+    /// ```
+    pub fn hold(&mut self, amount: Money) -> Result<(), Rejection> {
+        check_amount(amount)?;
+        self.adjust(-amount, amount)
+    }
+
+    /// Moves held funds back to available, which is what resolving a dispute of a deposit does.
+    ///
+    /// # Errors
+    ///
+    /// [`Rejection::InsufficientFunds`] if held does not cover the amount, [`Rejection::InvalidAmount`] if the amount
+    /// is not positive or has more than four decimal places, and [`Rejection::Overflow`] if a balance would no longer
+    /// fit. The account is left as it was on any error.
+    ///
+    /// ```
+    /// use payment_engine::{Client, Rejection};
+    ///
+    /// let mut client = Client::new(1);
+    /// client.deposit("10".parse().unwrap()).unwrap();
+    /// client.hold("4".parse().unwrap()).unwrap();
+    /// assert_eq!(client.release("5".parse().unwrap()), Err(Rejection::InsufficientFunds));
+    /// client.release("4".parse().unwrap()).unwrap();
+    /// ```
+    pub fn release(&mut self, amount: Money) -> Result<(), Rejection> {
+        check_amount(amount)?;
+        if self.held < amount {
+            return Err(Rejection::InsufficientFunds);
+        }
+        self.adjust(amount, -amount)
+    }
+
+    /// Removes held funds from the account entirely, which is what a chargeback of a disputed deposit does. It does
+    /// not lock the account, that is up to the engine's [`LockPolicy`].
+    ///
+    /// # Errors
+    ///
+    /// [`Rejection::InsufficientFunds`] if held does not cover the amount, [`Rejection::InvalidAmount`] if the amount
+    /// is not positive or has more than four decimal places, and [`Rejection::Overflow`] if a balance would no longer
+    /// fit. The account is left as it was on any error.
+    ///
+    /// ```
+    /// use payment_engine::{Client, Rejection};
+    ///
+    /// let mut client = Client::new(1);
+    /// client.deposit("10".parse().unwrap()).unwrap();
+    /// assert_eq!(client.charge_back("10".parse().unwrap()), Err(Rejection::InsufficientFunds));
+    /// client.hold("10".parse().unwrap()).unwrap();
+    /// client.charge_back("10".parse().unwrap()).unwrap();
+    /// assert_eq!(client.total, "0".parse().unwrap());
+    /// ```
+    pub fn charge_back(&mut self, amount: Money) -> Result<(), Rejection> {
+        check_amount(amount)?;
+        if self.held < amount {
+            return Err(Rejection::InsufficientFunds);
+        }
+        self.adjust(Money::ZERO, -amount)
+    }
+
+    // This function takes a deposit whose dispute never held its funds out of available and total.
+    // Available may go negative, the deposit is reversed either way
+    fn charge_back_pending(&mut self, amount: Money) -> Result<(), Rejection> {
+        self.adjust(-amount, Money::ZERO)
+    }
+
+    // This function holds pending disputes, oldest first, for as long as available covers the next one. A dispute
+    // that does not fit keeps the ones after it waiting too. A locked account holds nothing more
+    fn hold_pending_disputes(&mut self) {
+        if self.locked {
+            return;
+        }
+        while let Some(&(transaction_id, amount)) = self.pending_disputes.first() {
+            if self.available < amount || self.hold(amount).is_err() {
+                return;
+            }
+            self.pending_disputes.remove(0);
+            self.open_disputes.insert(transaction_id, amount);
+        }
+    }
+
+    // This function adds a disputed withdrawal to held and total, since the money may be coming back
+    fn hold_returned(&mut self, amount: Money) -> Result<(), Rejection> {
+        self.adjust(Money::ZERO, amount)
+    }
+
+    // This function drops a disputed withdrawal from held and total again once the dispute is resolved
+    fn release_returned(&mut self, amount: Money) -> Result<(), Rejection> {
+        self.adjust(Money::ZERO, -amount)
+    }
+
+    // This function gives a charged back withdrawal back to the client, moving it from held to available
+    fn charge_back_returned(&mut self, amount: Money) -> Result<(), Rejection> {
+        self.adjust(amount, -amount)
+    }
+
+    /// Debits a fee from available and total. Unlike [`Client::withdraw`], a fee may take the account negative.
+    ///
+    /// # Errors
+    ///
+    /// [`Rejection::InvalidAmount`] if the fee is negative or has more than four decimal places, and
+    /// [`Rejection::Overflow`] if a balance would no longer fit. The account is left as it was on any error.
+    ///
+    /// ```
+    /// use payment_engine::Client;
+    ///
+    /// let mut client = Client::new(1);
+    /// client.charge_fee("2.5".parse().unwrap()).unwrap();
+    /// assert_eq!(client.available, "-2.5".parse().unwrap());
+    /// ```
+    pub fn charge_fee(&mut self, fee: Money) -> Result<(), Rejection> {
+        if fee < Money::ZERO {
+            return Err(Rejection::InvalidAmount);
+        }
+        check_precision(fee)?;
+        self.adjust(-fee, Money::ZERO)
+    }
+
+    // This function shifts available and held by the given amounts, keeping total in step.
+    // Nothing is changed if any of the new balances would overflow or could not be kept exactly
+    fn adjust(&mut self, available: Money, held: Money) -> Result<(), Rejection> {
+        let new_available = money::exact_add(self.available, available).ok_or(Rejection::Overflow)?;
+        let new_held = money::exact_add(self.held, held).ok_or(Rejection::Overflow)?;
+        let new_total = money::exact_add(available, held)
+            .and_then(|delta| money::exact_add(self.total, delta))
+            .ok_or(Rejection::Overflow)?;
+
+        self.available = new_available;
+        self.held = new_held;
+        self.total = new_total;
+        Ok(())
+    }
+}
+
+// One account as it appears in a Report and in every report the command line tool writes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountSummary {
+    pub client: u16,
+    // Only present for inputs that name currencies, with one summary per client and currency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+    // Only present when debt is tracked, so the column only appears then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debt: Option<Money>,
+    // Chargebacks the account has taken
+    #[serde(default)]
+    pub chargebacks: u32,
+}
+
+impl From<&Client> for AccountSummary {
+    fn from(client: &Client) -> AccountSummary {
+        AccountSummary {
+            client: client.client_id,
+            currency: client.currency,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+            debt: client.debt,
+            chargebacks: client.chargebacks,
+        }
+    }
+}
+
+// The accounts of an engine, sorted by client id, and counts of the transactions it was given since it was created or
+// loaded. Built by Engine::report for callers that want the results without parsing a report file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub accounts: Vec<AccountSummary>,
+    // Transactions passed to Engine::process
+    pub rows: u64,
+    // Of those, the ones that were refused
+    pub rejected: u64,
+    // The applied ones by type
+    pub applied: BTreeMap<TransactionType, u64>,
+    // Clients whose account a chargeback locked, in ascending order
+    #[serde(default)]
+    pub locked: Vec<u16>,
+    // The chargeback fees debited and the ones that could not be
+    #[serde(default)]
+    pub fees: FeeCounts,
+}
+
+// How many chargeback fees an engine debited, and how many it could not because a balance would have overflowed. The
+// chargebacks themselves stay applied either way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeCounts {
+    pub charged: u64,
+    pub failed: u64,
+}
+
+// Everything an engine held about a client that Engine::forget_client removed
+#[derive(Debug, Clone)]
+pub struct ForgottenClient {
+    pub client_id: u16,
+    // One account, or one per currency for inputs that name currencies
+    pub accounts: Vec<Client>,
+    // The stored transactions with their tx ids, in the order they were stored
+    pub records: Vec<(u32, Record)>,
+    // The admin holds not released yet, by the tx id of the admin_hold row
+    pub admin_holds: Vec<(u32, AdminHold)>,
+}
+
+// The tx ids the audit lines of monthly fees are written with. Fees are not stored as records, and while a monthly fee is
+// set no input row may store a record under one of these ids
+pub const MONTHLY_FEE_TX_IDS: RangeInclusive<u32> = 0xFFF0_0000..=u32::MAX;
+
+// A monthly fee the engine debited or could not debit, handed out by Engine::take_monthly_fees
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonthlyFee {
+    pub account: AccountKey,
+    // The synthetic id of the fee, from MONTHLY_FEE_TX_IDS
+    pub tx: u32,
+    pub fee: Money,
+    // The calendar month the fee is for, as year and month in UTC
+    pub month: (i32, u32),
+    pub result: Result<(), Rejection>,
+    // The account's balances right after the fee
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+}
+
+// The calendar month that monthly fees are being collected for, with the accounts that had a positive total during it.
+// Saved with the state, so a run that resumes from a snapshot mid-month charges the month as one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FeeMonth {
+    // None until the first row with a timestamp
+    month: Option<(i32, u32)>,
+    positive: BTreeSet<AccountKey>,
+    next_tx: u32,
+    // Fees charged since the caller last took them
+    #[serde(skip)]
+    charged: Vec<MonthlyFee>,
+}
+
+impl Default for FeeMonth {
+    fn default() -> FeeMonth {
+        FeeMonth { month: None, positive: BTreeSet::new(), next_tx: *MONTHLY_FEE_TX_IDS.start(), charged: Vec::new() }
+    }
+}
+
+// This function returns the month after the given one
+fn next_month((year, month): (i32, u32)) -> (i32, u32) {
+    if month == 12 { (year + 1, 1) } else { (year, month + 1) }
+}
+
+// Counts kept by Engine::process for the Report. They describe one run, so they are not part of saved state
+#[derive(Debug, Clone, Default)]
+struct RunCounts {
+    rows: u64,
+    rejected: u64,
+    applied: BTreeMap<TransactionType, u64>,
+    locked: BTreeSet<u16>,
+    fees: FeeCounts,
+}
+
+// Receives what an engine does, as it does it, e.g. to keep metrics in a service that embeds the engine. Every method
+// does nothing unless it is overridden, so an observer only implements the events it wants
+pub trait Observer: Send {
+    // Called after process applied a transaction. `client` is the account after it, None only for an assert row of a
+    // client that has no account
+    fn on_applied(&mut self, _transaction: &TransactionRow, _client: Option<&Client>) {}
+
+    // Called after process refused a transaction, which left every account as it was
+    fn on_rejected(&mut self, _transaction: &TransactionRow, _rejection: &Rejection) {}
+
+    // Called by Engine::complete with the report it returns
+    fn on_complete(&mut self, _report: &Report) {}
+}
+
+// The engine's observer, if it has one. A clone of the engine has none, so what-if runs on a copy, like the worst case
+// chargeback report, are not reported as if they had happened. Observers only need to be Send, the mutex makes the
+// engine Sync without ever being locked, as the observer is only reached through &mut self
+#[derive(Default)]
+struct ObserverSlot(Option<Mutex<Box<dyn Observer>>>);
+
+impl ObserverSlot {
+    fn new(observer: Box<dyn Observer>) -> ObserverSlot {
+        ObserverSlot(Some(Mutex::new(observer)))
+    }
+
+    fn get(&mut self) -> Option<&mut Box<dyn Observer>> {
+        self.0.as_mut().map(|observer| observer.get_mut().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl Clone for ObserverSlot {
+    fn clone(&self) -> ObserverSlot {
+        ObserverSlot(None)
+    }
+}
+
+impl fmt::Debug for ObserverSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(Observer)" } else { "None" })
+    }
+}
+
+// Hasher for the engine's maps, which are keyed by client and tx ids. Every row looks up at least two of them, and the
+// default SipHash costs more than the lookup itself. The ids come from the operator's own inputs or from trusted
+// callers of `serve`, so there is no need to guard against keys chosen to collide
+#[derive(Default, Clone, Copy)]
+struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(u64::from(byte));
+        }
+    }
+
+    fn write_u16(&mut self, id: u16) {
+        self.write_u64(u64::from(id));
+    }
+
+    fn write_u32(&mut self, id: u32) {
+        self.write_u64(u64::from(id));
+    }
+
+    fn write_u64(&mut self, id: u64) {
+        self.0 = (self.0.rotate_left(5) ^ id).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+}
+
+type IdMap<K, V> = HashMap<K, V, BuildHasherDefault<IdHasher>>;
+
+// The accounts, the stored transactions they can be disputed by and the open administrative holds, updated by one
+// transaction at a time under a fixed policy. Serializing an engine saves its state without the policy, which belongs
+// to whoever loads it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Engine {
+    // The accounts by client, and by currency for inputs that name currencies
+    clients: IdMap<AccountKey, Client>,
+    records: TransactionStore,
+    // Administrative holds that have not been released yet, by the tx id of the admin_hold row
+    admin_holds: IdMap<u32, AdminHold>,
+    fee_month: FeeMonth,
+    // Clients removed with forget_client, whose rows are all rejected. Missing in state saved before clients could be forgotten
+    #[serde(default)]
+    forgotten: BTreeSet<u16>,
+    #[serde(skip)]
+    policy: EnginePolicy,
+    #[serde(skip)]
+    counts: RunCounts,
+    #[serde(skip)]
+    observer: ObserverSlot,
+}
+
+impl Engine {
+    // This function creates an engine with no accounts and the default policy
+    pub fn new() -> Engine {
+        Engine::default()
+    }
+
+    // This function creates an engine with no accounts that applies the given business rules
+    pub fn with_policy(policy: EnginePolicy) -> Engine {
+        Engine { policy, ..Engine::default() }
+    }
+
+    // This function creates an engine with no accounts and the default policy that tells the observer what it does
+    pub fn with_observer(observer: Box<dyn Observer>) -> Engine {
+        Engine { observer: ObserverSlot::new(observer), ..Engine::default() }
+    }
+
+    // This function sets the observer, replacing any earlier one, e.g. for an engine built with a policy or loaded
+    pub fn set_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observer = ObserverSlot::new(observer);
+    }
+
+    pub fn policy(&self) -> &EnginePolicy {
+        &self.policy
+    }
+
+    // This function replaces the business rules, e.g. for an engine loaded from saved state
+    pub fn set_policy(&mut self, policy: EnginePolicy) {
+        self.policy = policy;
+    }
+
+    // This function returns every account, in no particular order
+    pub fn accounts(&self) -> impl ExactSizeIterator<Item = &Client> {
+        self.clients.values()
+    }
+
+    // This function returns every account for changes that are not transactions, like filling in report columns
+    pub fn accounts_mut(&mut self) -> impl ExactSizeIterator<Item = &mut Client> {
+        self.clients.values_mut()
+    }
+
+    // This function returns an account by client id, or by client id and currency as an AccountKey
+    pub fn account(&self, key: impl Into<AccountKey>) -> Option<&Client> {
+        self.clients.get(&key.into())
+    }
+
+    // This function returns the account a transaction moves. A row that refers to a stored transaction or admin hold
+    // without naming a currency moves the account that one is in
+    pub fn account_for(&self, transaction: &TransactionRow) -> Option<&Client> {
+        self.account_key(transaction).ok().and_then(|key| self.clients.get(&key))
+    }
+
+    // This function returns the key of the account a transaction moves. A row without a currency that refers to a
+    // record or admin hold of its client moves the account that one is in, and a row that names a different currency
+    // than the one it refers to is refused. Every other row names its account itself, which is decided here without a
+    // call since it is asked for every row
+    #[inline]
+    pub fn account_key(&self, transaction: &TransactionRow) -> Result<AccountKey, Rejection> {
+        match transaction.transaction_type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback | TransactionType::Correction | TransactionType::AdminRelease => {
+                self.referred_account(transaction)
+            },
+            _ => Ok(transaction.account()),
+        }
+    }
+
+    fn referred_account(&self, transaction: &TransactionRow) -> Result<AccountKey, Rejection> {
+        let key = transaction.account();
+        let referred = match transaction.transaction_type {
+            TransactionType::AdminRelease => self.admin_holds.get(&transaction.transaction_id).map(AdminHold::account),
+            _ => self.records.get(transaction.transaction_id).map(|record| record.account()),
+        };
+        match referred.filter(|referred| referred.client_id == key.client_id) {
+            Some(referred) if key.currency.is_some() && key.currency != referred.currency => Err(Rejection::CurrencyMismatch),
+            Some(referred) => Ok(referred),
+            None => Ok(key),
+        }
+    }
+
+    // This function returns a client's account, creating an empty one if the client has none yet
+    pub fn account_or_new(&mut self, key: impl Into<AccountKey>) -> &mut Client {
+        account_or_new(&mut self.clients, key.into())
+    }
+
+    // This function takes a client's account out of the engine. Later transactions for the client start a new account
+    pub fn remove_account(&mut self, key: impl Into<AccountKey>) -> Option<Client> {
+        self.clients.remove(&key.into())
+    }
+
+    // This function creates a client's account with balances carried over from before this run, e.g. the closing balances
+    // of last month. Held funds are for disputes the engine never saw, so they stay held: no row can resolve or charge
+    // them back. It fails with NotFirstTransaction for a client that already has an account
+    pub fn open_with_balances(&mut self, key: impl Into<AccountKey>, available: Money, held: Money, locked: bool) -> Result<(), Rejection> {
+        let key = key.into();
+        if self.forgotten.contains(&key.client_id) {
+            return Err(Rejection::ForgottenClient);
+        }
+        let Entry::Vacant(slot) = self.clients.entry(key) else {
+            return Err(Rejection::NotFirstTransaction);
+        };
+        let total = available.checked_add(held).ok_or(Rejection::Overflow)?;
+        let client = slot.insert(Client::for_account(key));
+        client.available = available;
+        client.held = held;
+        client.total = total;
+        client.locked = locked;
+        client.carried_held = (held != Money::ZERO).then_some(held);
+        Ok(())
+    }
+
+    // This function returns every stored transaction with its tx id, in no particular order
+    pub fn records(&self) -> impl Iterator<Item = (u32, Record)> + '_ {
+        self.records.iter()
+    }
+
+    pub fn record(&self, transaction_id: u32) -> Option<Record> {
+        self.records.get(transaction_id)
+    }
+
+    // This function keeps at most `keep` stored transactions in memory from now on and moves older ones to a file in
+    // `dir`, see TransactionStore::spill_to. Reading or writing the file failing stops process with EngineError::Storage
+    pub fn spill_records(&mut self, dir: &Path, keep: usize) -> io::Result<()> {
+        self.records.spill_to(dir, keep)
+    }
+
+    // This function returns the stored transactions, for lookups by client without going through process
+    pub fn transactions(&self) -> &TransactionStore {
+        &self.records
+    }
+
+    // This function forgets a stored transaction, after which rows referring to it are rejected as unknown
+    pub fn remove_record(&mut self, transaction_id: u32) -> Option<Record> {
+        self.records.remove(transaction_id)
+    }
+
+    pub fn admin_hold(&self, transaction_id: u32) -> Option<&AdminHold> {
+        self.admin_holds.get(&transaction_id)
+    }
+
+    // This function removes everything the engine holds about a client and returns it: their accounts, their stored
+    // transactions and their admin holds. The client is remembered, so every later row for them is rejected with
+    // ForgottenClient rather than opening a new account, and rows of other clients that refer to their transactions find
+    // nothing to refer to
+    pub fn forget_client(&mut self, client_id: u16) -> ForgottenClient {
+        let mut accounts: Vec<Client> = Vec::new();
+        let keys: Vec<AccountKey> = self.clients.keys().filter(|key| key.client_id == client_id).copied().collect();
+        for key in keys {
+            accounts.extend(self.clients.remove(&key));
+            self.fee_month.positive.remove(&key);
+        }
+        accounts.sort_unstable_by_key(|client| client.currency);
+
+        let ids: Vec<u32> = self.records.for_client(client_id).map(|(transaction_id, _)| transaction_id).collect();
+        let records = ids.into_iter().filter_map(|transaction_id| self.records.remove(transaction_id).map(|record| (transaction_id, record))).collect();
+
+        let mut holds: Vec<u32> = self.admin_holds.iter().filter(|(_, hold)| hold.client_id == client_id).map(|(&transaction_id, _)| transaction_id).collect();
+        holds.sort_unstable();
+        let admin_holds = holds.into_iter().filter_map(|transaction_id| self.admin_holds.remove(&transaction_id).map(|hold| (transaction_id, hold))).collect();
+
+        self.forgotten.insert(client_id);
+        ForgottenClient { client_id, accounts, records, admin_holds }
+    }
+
+    // This function tells whether a client was removed with forget_client
+    pub fn is_forgotten(&self, client_id: u16) -> bool {
+        self.forgotten.contains(&client_id)
+    }
+
+    // This function returns the clients whose account a chargeback passed to process has locked, in ascending order.
+    // Accounts that were already locked in a loaded engine are not included
+    pub fn locked_clients(&self) -> impl ExactSizeIterator<Item = u16> + '_ {
+        self.counts.locked.iter().copied()
+    }
+
+    // This function returns how many chargeback fees were debited so far and how many could not be
+    pub fn fee_counts(&self) -> FeeCounts {
+        self.counts.fees
+    }
+
+    // This function returns the accounts and the counts of transactions processed so far
+    pub fn report(&self) -> Report {
+        let mut accounts: Vec<AccountSummary> = self.clients.values().map(AccountSummary::from).collect();
+        accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+        Report {
+            accounts,
+            rows: self.counts.rows,
+            rejected: self.counts.rejected,
+            applied: self.counts.applied.clone(),
+            locked: self.counts.locked.iter().copied().collect(),
+            fees: self.counts.fees,
+        }
+    }
+
+    // This function ends a run: it returns the report and hands it to the observer
+    pub fn complete(&mut self) -> Report {
+        let report = self.report();
+        if let Some(observer) = self.observer.get() {
+            observer.on_complete(&report);
+        }
+        report
+    }
+
+    // This function performs a transaction's action type. Deposits and withdrawals that are applied are stored so they can be disputed later.
+    // It is inlined into the command line tool's row loop, which is measurably faster
+    #[inline]
+    pub fn process(&mut self, transaction: &TransactionRow) -> Result<(), EngineError> {
+        self.process_row(transaction, None)
+    }
+
+    // This function is process for one shard of a ShardedEngine, which says who holds the row's tx id when another
+    // shard does. Without one it compiles down to process as it always was
+    #[inline]
+    fn process_row(&mut self, transaction: &TransactionRow, foreign: Option<Foreign>) -> Result<(), EngineError> {
+        if self.policy.monthly_fee.is_some() {
+            if let Some(timestamp) = transaction.timestamp {
+                self.start_month(timestamp);
+            }
+        }
+        let mut result = self.apply(transaction, foreign);
+        if let Err(EngineError::Rejected(rejection)) = result {
+            result = Err(self.rejected(transaction, rejection, foreign));
+        }
+        if self.records.spill.is_some() {
+            if let Some(failure) = self.records.take_failure() {
+                result = Err(EngineError::Storage(failure));
+            }
+        }
+        self.counts.rows += 1;
+        match result {
+            Ok(()) => *self.counts.applied.entry(transaction.transaction_type).or_default() += 1,
+            Err(_) => self.counts.rejected += 1,
+        }
+        // Rows for a locked account are refused, so a chargeback that is applied and leaves the account locked is the one
+        // that locked it. Under a lock threshold the ones before it leave the account open
+        if result.is_ok() && transaction.transaction_type == TransactionType::Chargeback && self.account_for(transaction).is_some_and(|client| client.locked) {
+            self.counts.locked.insert(transaction.client_id);
+        }
+        if self.policy.monthly_fee.is_some() && result.is_ok() {
+            if let Some(key) = self.account_key(transaction).ok().filter(|key| self.clients.get(key).is_some_and(|client| client.total > Money::ZERO)) {
+                self.fee_month.positive.insert(key);
+            }
+        }
+        if self.observer.0.is_some() {
+            self.notify(transaction, &result);
+        }
+        result
+    }
+
+    // This function charges the monthly fee for every month that ended before the one the timestamp is in. Rows whose
+    // timestamp is earlier than the month being collected count towards that month
+    fn start_month(&mut self, timestamp: DateTime<FixedOffset>) {
+        let utc = timestamp.with_timezone(&Utc);
+        let month = (utc.year(), utc.month());
+        let Some(mut current) = self.fee_month.month else {
+            // Accounts that were positive before the first timestamp, e.g. in a loaded snapshot, count for this month
+            self.fee_month.month = Some(month);
+            let positive: Vec<AccountKey> = self.clients.iter().filter(|(_, client)| client.total > Money::ZERO).map(|(&key, _)| key).collect();
+            self.fee_month.positive.extend(positive);
+            return;
+        };
+        while current < month {
+            self.close_month();
+            current = next_month(current);
+        }
+    }
+
+    // This function ends the month being collected: it debits the monthly fee from every account that is not locked and
+    // had a positive total during the month, and starts the next month with the accounts that are positive now. The
+    // command line tool calls it at the end of the input with --fee-final-partial-month
+    pub fn close_month(&mut self) {
+        let (Some(fee), Some(month)) = (self.policy.monthly_fee, self.fee_month.month) else {
+            return;
+        };
+        for key in std::mem::take(&mut self.fee_month.positive) {
+            let Some(client) = self.clients.get_mut(&key).filter(|client| !client.locked) else {
+                continue;
+            };
+            let tx = self.fee_month.next_tx;
+            self.fee_month.next_tx = tx.saturating_add(1);
+            let fee = fee.round_dp(self.policy.scale_for(key.currency));
+            let result = client.charge_fee(fee);
+            if let Err(e) = result {
+                warn!(client = key.client_id, tx, reason = e.code(); "monthly fee for client {} rejected: {}.", key.client_id, e);
+            }
+            self.fee_month.charged.push(MonthlyFee { account: key, tx, fee, month, result, available: client.available, held: client.held, total: client.total });
+        }
+        self.fee_month.positive = self.clients.iter().filter(|(_, client)| client.total > Money::ZERO).map(|(&key, _)| key).collect();
+        self.fee_month.month = Some(next_month(month));
+    }
+
+    // This function returns the monthly fees debited or refused since it was last called, in the order they were charged
+    pub fn take_monthly_fees(&mut self) -> Vec<MonthlyFee> {
+        std::mem::take(&mut self.fee_month.charged)
+    }
+
+    // This function processes a typed transaction the way process does a row, for callers that build transactions
+    // themselves rather than reading them from an input
+    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<(), EngineError> {
+        self.process(&TransactionRow::from(transaction))
+    }
+
+    // This function names the client and tx a rejection was for, for the rejections that have a variant of their own
+    #[cold]
+    #[inline(never)]
+    fn rejected(&self, transaction: &TransactionRow, rejection: Rejection, foreign: Option<Foreign>) -> EngineError {
+        let (client, tx) = (transaction.client_id, transaction.transaction_id);
+        match rejection {
+            Rejection::InsufficientFunds => EngineError::InsufficientFunds { client, tx },
+            Rejection::AccountLocked => EngineError::AccountLocked { client },
+            Rejection::UnknownTransaction => EngineError::TxNotFound(tx),
+            Rejection::ClientMismatch => {
+                let owner = match transaction.transaction_type {
+                    TransactionType::AdminRelease => foreign.and_then(|foreign| foreign.hold).or_else(|| self.admin_holds.get(&tx).map(|hold| hold.client_id)),
+                    _ => foreign.and_then(|foreign| foreign.record).or_else(|| self.records.get(tx).map(|record| record.client_id)),
+                };
+                match owner {
+                    Some(expected) => EngineError::ClientMismatch { tx, expected, got: client },
+                    None => EngineError::Rejected(rejection),
+                }
+            },
+            rejection => EngineError::Rejected(rejection),
+        }
+    }
+
+    // This function tells the observer how a transaction went. It is kept out of process, which runs for every row,
+    // so engines without an observer pay for no more than the check above
+    #[cold]
+    #[inline(never)]
+    fn notify(&mut self, transaction: &TransactionRow, result: &Result<(), EngineError>) {
+        let key = self.account_key(transaction);
+        let Some(observer) = self.observer.get() else {
+            return;
+        };
+        match result {
+            Ok(()) => observer.on_applied(transaction, key.ok().and_then(|key| self.clients.get(&key))),
+            Err(e) => {
+                if let Some(rejection) = e.rejection() {
+                    observer.on_rejected(transaction, &rejection);
+                }
+            },
+        }
+    }
+
+    fn apply(&mut self, transaction: &TransactionRow, foreign: Option<Foreign>) -> Result<(), EngineError> {
+        if !self.forgotten.is_empty() && self.forgotten.contains(&transaction.client_id) {
+            return Err(EngineError::Rejected(Rejection::ForgottenClient));
+        }
+        let key = self.account_key(transaction)?;
+
+        // A client only gets an account once one of its rows is valid, so rejected rows leave no empty accounts behind for
+        // the report. The handlers below create the account when they move funds into it
+        let locked = self.clients.get(&key).is_some_and(|client| client.locked);
+
+        // Amounts finer than their currency's decimal places, four unless --currency-scales says otherwise, are refused
+        // rather than rounded, so no row moves a fraction that can never be shown or withdrawn
+        if transaction.amount.is_some_and(|amount| amount.round_dp(self.policy.scale_for(key.currency)) != amount) {
+            return Err(EngineError::Rejected(Rejection::InvalidAmount));
+        }
+
+        // A locked account is frozen. Every row for it is refused whatever its type, so its balances cannot move again.
+        // This is the only place the rule is applied, the handlers below can assume the account is open. Only an
+        // administrator can unlock it again
+        if locked && !matches!(transaction.transaction_type, TransactionType::Assert | TransactionType::AdminLock | TransactionType::AdminUnlock) {
+            return Err(EngineError::Rejected(Rejection::AccountLocked));
+        }
+
+        if self.policy.monthly_fee.is_some() && MONTHLY_FEE_TX_IDS.contains(&transaction.transaction_id)
+            && matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::OpeningBalance) {
+            return Err(EngineError::Rejected(Rejection::ReservedTransactionId));
+        }
+
+        let typed = transaction.transaction()?;
+        if let Some(rejection) = foreign.and_then(|foreign| foreign.rejection(&typed, self.clients.contains_key(&key), self.admin_holds.contains_key(&transaction.transaction_id))) {
+            return Err(EngineError::Rejected(rejection));
+        }
+        let result = match typed {
+            // Holds have their own release path, the dispute lifecycle must not touch them
+            Transaction::Dispute { tx, .. } | Transaction::Resolve { tx, .. } | Transaction::Chargeback { tx, .. } if self.admin_holds.contains_key(&tx) => {
+                Err(Rejection::NotDisputable)
+            },
+            Transaction::Deposit { tx, amount, .. } => self.move_funds(RecordKind::Deposit, key, tx, amount),
+            Transaction::Withdrawal { tx, amount, .. } => self.move_funds(RecordKind::Withdrawal, key, tx, amount),
+            Transaction::Dispute { client, tx } => self.submit_dispute(tx, client),
+            Transaction::Resolve { client, tx } => self.resolve_dispute(tx, client),
+            Transaction::Chargeback { client, tx } => self.issue_chargeback(tx, client),
+            Transaction::Correction { client, tx, amount } => self.correct_transaction(tx, client, amount),
+            Transaction::OpeningBalance { tx, amount, .. } => self.open_account(key, tx, amount),
+            Transaction::AdminHold { tx, amount, .. } => self.place_admin_hold(key, tx, amount),
+            Transaction::AdminRelease { client, tx } => self.release_admin_hold(tx, client),
+            Transaction::AdminLock { .. } => self.set_locked(key, true),
+            Transaction::AdminUnlock { .. } => self.set_locked(key, false),
+            // Checking the expected balances is up to the caller, the engine has nothing to apply
+            Transaction::Assert { .. } => Ok(()),
+        };
+
+        // With debt tracking, every change to available is settled against the client's debt straight away
+        if self.policy.track_debt && result.is_ok() {
+            if let Some(client) = self.clients.get_mut(&key) {
+                client.settle_debt();
+            }
+        }
+        // Waiting disputes are held as soon as available covers them, usually after a deposit
+        if self.policy.dispute_requires_funds && result.is_ok() {
+            if let Some(client) = self.clients.get_mut(&key) {
+                client.hold_pending_disputes();
+            }
+        }
+        debug_assert!(self.held_is_accounted_for(key), "held of client {} is not the sum of its open disputes and admin holds", transaction.client_id);
+        result.map_err(EngineError::Rejected)
+    }
+
+    // This function locks or unlocks an account by hand. Locking a locked account or unlocking an open one changes
+    // nothing. The chargebacks counted against the account stay, so the lock policy still sees them
+    fn set_locked(&mut self, key: AccountKey, locked: bool) -> Result<(), Rejection> {
+        let client = self.clients.get_mut(&key).ok_or(Rejection::UnknownClient)?;
+        client.locked = locked;
+        Ok(())
+    }
+
+    // This function replaces the amount of a stored deposit or withdrawal, moving the client's balances by the difference.
+    // Records under dispute or charged back cannot be corrected, and a correction may not flip the amount's sign, leave available
+    // negative or take the total over the balance cap
+    fn correct_transaction(&mut self, transaction_id: u32, client_id: u16, new_amount: Money) -> Result<(), Rejection> {
+        let record = self.records.get_for_client(transaction_id, client_id)?;
+        if record.kind == RecordKind::OpeningBalance {
+            return Err(Rejection::NotCorrectable);
+        }
+        if matches!(record.state, RecordState::Disputed | RecordState::ChargedBack) {
+            return Err(Rejection::AlreadyDisputed);
+        }
+        if (record.amount < Money::ZERO) != (new_amount < Money::ZERO) {
+            return Err(Rejection::InvalidAmount);
+        }
+
+        let x = account_or_new(&mut self.clients, record.account());
+
+        // A larger deposit adds to available, a larger withdrawal takes from it
+        let delta = money::exact_add(new_amount, -record.amount).ok_or(Rejection::Overflow)?;
+        let delta = if record.kind == RecordKind::Withdrawal { -delta } else { delta };
+        if x.available.checked_add(delta).ok_or(Rejection::Overflow)? < Money::ZERO {
+            return Err(Rejection::InsufficientFunds);
+        }
+        if let Some(cap) = self.policy.max_balance_for(record.client_id) {
+            if delta > Money::ZERO && x.total.checked_add(delta).ok_or(Rejection::Overflow)? > cap {
+                return Err(Rejection::BalanceCapExceeded);
+            }
+        }
+        x.adjust(delta, Money::ZERO)?;
+        self.records.set_amount(transaction_id, new_amount);
+        Ok(())
+    }
+
+    // This function checks that an account's held funds are exactly its open disputes plus its admin holds
+    fn held_is_accounted_for(&self, key: AccountKey) -> bool {
+        let Some(client) = self.clients.get(&key) else {
+            return true;
+        };
+        let mut holds = self.admin_holds.values().filter(|hold| hold.account() == key).map(|hold| hold.amount);
+        let expected = client.disputed().and_then(|disputed| money::exact_add(disputed, client.carried_held()))
+            .and_then(|disputed| holds.try_fold(disputed, money::exact_add));
+        // Holds are summed in no particular order, and a sum that overflows along the way says nothing about held
+        expected.is_none_or(|expected| expected == client.held)
+    }
+
+    // This function creates a client's account with an opening balance carried over from another system.
+    // The balance is stored so its tx id stays taken, but it cannot be disputed or corrected
+    fn open_account(&mut self, key: AccountKey, transaction_id: u32, amount: Money) -> Result<(), Rejection> {
+        if self.clients.contains_key(&key) {
+            return Err(Rejection::NotFirstTransaction);
+        }
+        if self.records.contains(transaction_id) {
+            return Err(Rejection::DuplicateTransaction);
+        }
+        self.policy.check_balance_cap(key.client_id, None, amount)?;
+
+        let mut client = Client::for_account(key);
+        client.adjust(amount, Money::ZERO)?;
+        self.clients.insert(key, client);
+        let record = Record { currency: key.currency, ..Record::new(RecordKind::OpeningBalance, key.client_id, amount) };
+        self.records.insert(transaction_id, record)
+    }
+
+    // This function freezes an amount of a client's available funds under an administrative hold.
+    // Unless forced holds are allowed, the available funds must cover the amount
+    fn place_admin_hold(&mut self, key: AccountKey, transaction_id: u32, amount: Money) -> Result<(), Rejection> {
+        // The hold is released by its tx id later, so the id must not already mean something else
+        if self.admin_holds.contains_key(&transaction_id) || self.records.contains(transaction_id) {
+            return Err(Rejection::DuplicateTransaction);
+        }
+
+        let available = self.clients.get(&key).map_or(Money::ZERO, |client| client.available);
+        if available < amount && !self.policy.allow_forced_hold {
+            return Err(Rejection::InsufficientFunds);
+        }
+        account_or_new(&mut self.clients, key).hold(amount)?;
+
+        self.admin_holds.insert(transaction_id, AdminHold {
+            client_id: key.client_id,
+            amount,
+            currency: key.currency,
+        });
+        Ok(())
+    }
+
+    // This function releases an administrative hold, moving its amount from held back to available
+    fn release_admin_hold(&mut self, transaction_id: u32, client_id: u16) -> Result<(), Rejection> {
+        let hold = self.admin_holds.get(&transaction_id).ok_or(Rejection::UnknownTransaction)?;
+        if client_id != hold.client_id {
+            return Err(Rejection::ClientMismatch);
+        }
+
+        let x = account_or_new(&mut self.clients, hold.account());
+        x.release(hold.amount)?;
+        self.admin_holds.remove(&transaction_id);
+        Ok(())
+    }
+
+    // This function applies a deposit or withdrawal and stores it so it can be disputed later
+    fn move_funds(&mut self, kind: RecordKind, key: AccountKey, transaction_id: u32, amount: Money) -> Result<(), Rejection> {
+        // A reused tx id would replace the stored record, leaving later disputes to hold the wrong amount
+        if self.admin_holds.contains_key(&transaction_id) {
+            return Err(Rejection::DuplicateTransaction);
+        }
+        if self.records.contains(transaction_id) {
+            return Err(Rejection::DuplicateTransaction);
+        }
+
+        if amount <= Money::ZERO {
+            return Err(Rejection::InvalidAmount);
+        }
+
+        if kind == RecordKind::Deposit {
+            self.policy.check_balance_cap(key.client_id, self.clients.get(&key), amount)?;
+            account_or_new(&mut self.clients, key).deposit(amount)?;
+        } else {
+            // A client without an account has nothing to withdraw
+            let x = self.clients.get_mut(&key).ok_or(Rejection::InsufficientFunds)?;
+
+            // Clients who owe money cannot take any out until the debt is repaid
+            if x.debt.is_some_and(|debt| debt > Money::ZERO) {
+                return Err(Rejection::InDebt);
+            }
+
+            // Subtract amount from client. A withdrawal larger than the available funds is refused and the account stays open,
+            // only a chargeback locks it
+            x.withdraw(amount)?;
+        }
+        self.records.insert(transaction_id, Record { currency: key.currency, ..Record::new(kind, key.client_id, amount) })
+    }
+
+    // This function submits a dispute onto the client. A disputed deposit moves from available to held, a disputed
+    // withdrawal is added to held and total. Under dispute_requires_funds, a deposit that available does not cover is
+    // disputed without holding anything and waits in the client's pending disputes
+    pub fn submit_dispute(&mut self, transaction_id: u32, client_id: u16) -> Result<(), Rejection> {
+        let (record, next) = find_disputable(&self.records, transaction_id, client_id, TransactionType::Dispute)?;
+        let x = account_or_new(&mut self.clients, record.account());
+
+        if record.kind == RecordKind::Deposit && self.policy.dispute_requires_funds && x.available < record.amount {
+            x.pending_disputes.push((transaction_id, record.amount));
+            self.records.set_state(transaction_id, next);
+            return Ok(());
+        }
+        if record.kind == RecordKind::Deposit {
+            x.hold(record.amount)?;
+        } else {
+            x.hold_returned(record.amount)?;
+        }
+        x.open_disputes.insert(transaction_id, record.amount);
+        self.records.set_state(transaction_id, next);
+        Ok(())
+    }
+
+    // This function resolves a record under dispute. A deposit goes back from held to available, a withdrawal leaves held and total.
+    // A dispute still pending held nothing, so resolving it only drops it
+    pub fn resolve_dispute(&mut self, transaction_id: u32, client_id: u16) -> Result<(), Rejection> {
+        let (record, next) = find_disputable(&self.records, transaction_id, client_id, TransactionType::Resolve)?;
+        let x = account_or_new(&mut self.clients, record.account());
+
+        if let Some(index) = x.pending_disputes.iter().position(|&(id, _)| id == transaction_id) {
+            x.pending_disputes.remove(index);
+        } else if record.kind == RecordKind::Deposit {
+            x.release(record.amount)?;
+        } else {
+            x.release_returned(record.amount)?;
+        }
+        x.open_disputes.remove(&transaction_id);
+        self.records.set_state(transaction_id, next);
+        Ok(())
+    }
+
+    // This function issues a chargeback on a record, locks the record and counts the chargeback against the client, who
+    // is locked as well unless the lock policy waits for more chargebacks. A deposit is taken away from held and total,
+    // a withdrawal is given back by moving it from held to available.
+    // A deposit whose dispute is still pending was never held, so it is taken from available and total instead.
+    // If a chargeback fee is configured it is debited from available and total on top of the disputed amount, even if that leaves the account negative.
+    // Called directly, it does not refuse locked accounts the way process does, so every open dispute can be charged back to see the worst case
+    pub fn issue_chargeback(&mut self, transaction_id: u32, client_id: u16) -> Result<(), Rejection> {
+        let (record, next) = find_disputable(&self.records, transaction_id, client_id, TransactionType::Chargeback)?;
+        let x = account_or_new(&mut self.clients, record.account());
+
+        if let Some(index) = x.pending_disputes.iter().position(|&(id, _)| id == transaction_id) {
+            x.charge_back_pending(record.amount)?;
+            x.pending_disputes.remove(index);
+        } else if record.kind == RecordKind::Deposit {
+            x.charge_back(record.amount)?;
+        } else {
+            x.charge_back_returned(record.amount)?;
+        }
+        x.open_disputes.remove(&transaction_id);
+        self.records.set_state(transaction_id, next);
+        x.chargebacks = x.chargebacks.saturating_add(1);
+        if self.policy.lock_policy.locks_after(x.chargebacks) {
+            x.locked = true;
+        }
+
+        // Pass the network's chargeback fee on to the client. A fee that cannot be debited leaves the chargeback applied
+        // and is counted, so the caller can report it
+        if let Some(fee) = self.policy.chargeback_fee {
+            match x.charge_fee(fee.round_dp(self.policy.scale_for(record.currency))) {
+                Ok(()) => self.counts.fees.charged += 1,
+                Err(e) => {
+                    warn!(client = record.client_id, tx = transaction_id, reason = e.code(); "chargeback fee for client {} rejected: {}.", record.client_id, e);
+                    self.counts.fees.failed += 1;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+// This function refuses an amount for the Client operations that is not positive or finer than the report shows
+fn check_amount(amount: Money) -> Result<(), Rejection> {
+    if amount <= Money::ZERO {
+        return Err(Rejection::InvalidAmount);
+    }
+    check_precision(amount)
+}
+
+fn check_precision(amount: Money) -> Result<(), Rejection> {
+    if amount.round_dp(money::DECIMAL_PLACES) != amount {
+        return Err(Rejection::InvalidAmount);
+    }
+    Ok(())
+}
+
+// This function returns an account, creating an empty one if there is none yet. It takes the accounts alone, so a
+// handler can hold one while reading the rest of the engine
+fn account_or_new(clients: &mut IdMap<AccountKey, Client>, key: AccountKey) -> &mut Client {
+    clients.entry(key).or_insert_with(|| Client::for_account(key))
+}
+
+// This function finds the record a dispute, resolve or chargeback refers to and the state the step moves it to. It checks
+// in one place that the record exists, belongs to the client, is of a kind that can be disputed and allows the step.
+// The record is returned as a copy, and the caller only sets the new state in the store once the balances have moved
+fn find_disputable(records: &TransactionStore, transaction_id: u32, client_id: u16, step: TransactionType) -> Result<(Record, RecordState), Rejection> {
+    let record = records.get_for_client(transaction_id, client_id)?;
+    if record.kind == RecordKind::OpeningBalance {
+        return Err(Rejection::NotDisputable);
+    }
+    match record.state.next(step) {
+        Ok(next) => Ok((record, next)),
+        Err(rejection) => {
+            info!("transaction {} is {}, a {} is not allowed: {}.", transaction_id, record.state.name(), step, rejection);
+            Err(rejection)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn money(text: &str) -> Money {
+        text.parse().unwrap_or_else(|_| panic!("invalid amount {}", text))
+    }
+
+    // This function returns a client's available, held and total, panicking if it has no account
+    fn balances(engine: &Engine, client_id: u16) -> (Money, Money, Money) {
+        let client = engine.account(client_id).unwrap_or_else(|| panic!("client {} has no account", client_id));
+        (client.available, client.held, client.total)
+    }
+
+    fn deposit(client: u16, tx: u32, amount: &str) -> Transaction {
+        Transaction::Deposit { client, tx, amount: money(amount) }
+    }
+
+    #[test]
+    fn deposit_and_withdrawal_variants_move_available() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10.5"))?;
+        engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 2, amount: money("4") })?;
+        assert_eq!(balances(&engine, 1), (money("6.5"), money("0"), money("6.5")));
+        Ok(())
+    }
+
+    #[test]
+    fn dispute_resolve_and_chargeback_variants_move_held() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(deposit(1, 2, "5"))?;
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 })?;
+        assert_eq!(balances(&engine, 1), (money("5"), money("10"), money("15")));
+        engine.process_transaction(Transaction::Resolve { client: 1, tx: 1 })?;
+        assert_eq!(balances(&engine, 1), (money("15"), money("0"), money("15")));
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 2 })?;
+        engine.process_transaction(Transaction::Chargeback { client: 1, tx: 2 })?;
+        assert_eq!(balances(&engine, 1), (money("10"), money("0"), money("10")));
+        assert!(engine.account(1).is_some_and(|client| client.locked));
+        Ok(())
+    }
+
+    #[test]
+    fn correction_variant_moves_the_difference() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(Transaction::Correction { client: 1, tx: 1, amount: money("12") })?;
+        assert_eq!(balances(&engine, 1), (money("12"), money("0"), money("12")));
+        assert_eq!(engine.record(1).map(|record| record.amount), Some(money("12")));
+        Ok(())
+    }
+
+    #[test]
+    fn opening_balance_variant_opens_the_account() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(Transaction::OpeningBalance { client: 3, tx: 1, amount: money("40") })?;
+        assert_eq!(balances(&engine, 3), (money("40"), money("0"), money("40")));
+        let again = engine.process_transaction(Transaction::OpeningBalance { client: 3, tx: 2, amount: money("1") });
+        assert_eq!(again.map_err(|e| e.rejection()), Err(Some(Rejection::NotFirstTransaction)));
+        Ok(())
+    }
+
+    #[test]
+    fn admin_hold_and_release_variants_move_held() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(Transaction::AdminHold { client: 1, tx: 2, amount: money("3") })?;
+        assert_eq!(balances(&engine, 1), (money("7"), money("3"), money("10")));
+        engine.process_transaction(Transaction::AdminRelease { client: 1, tx: 2 })?;
+        assert_eq!(balances(&engine, 1), (money("10"), money("0"), money("10")));
+        Ok(())
+    }
+
+    #[test]
+    fn admin_lock_and_unlock_variants_freeze_the_account() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(Transaction::AdminLock { client: 1, tx: 0 })?;
+        let frozen = engine.process_transaction(deposit(1, 2, "5"));
+        assert_eq!(frozen.map_err(|e| e.rejection()), Err(Some(Rejection::AccountLocked)));
+        engine.process_transaction(Transaction::AdminUnlock { client: 1, tx: 0 })?;
+        engine.process_transaction(deposit(1, 3, "5"))?;
+        assert_eq!(balances(&engine, 1), (money("15"), money("0"), money("15")));
+
+        let unknown = engine.process_transaction(Transaction::AdminLock { client: 2, tx: 0 });
+        assert_eq!(unknown.map_err(|e| e.rejection()), Err(Some(Rejection::UnknownClient)));
+        Ok(())
+    }
+
+    #[test]
+    fn assert_variant_changes_nothing() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        let assert = Transaction::Assert { client: 1, tx: 9, available: money("99"), held_total: Some((money("0"), money("99"))) };
+        engine.process_transaction(assert)?;
+        assert_eq!(balances(&engine, 1), (money("10"), money("0"), money("10")));
+        Ok(())
+    }
+
+    #[test]
+    fn rows_convert_to_and_from_variants() {
+        let transactions = [
+            deposit(1, 1, "2.5"),
+            Transaction::Withdrawal { client: 1, tx: 2, amount: money("1") },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Correction { client: 1, tx: 1, amount: money("3") },
+            Transaction::OpeningBalance { client: 2, tx: 3, amount: money("4") },
+            Transaction::AdminHold { client: 1, tx: 4, amount: money("1") },
+            Transaction::AdminRelease { client: 1, tx: 4 },
+            Transaction::AdminLock { client: 1, tx: 0 },
+            Transaction::AdminUnlock { client: 1, tx: 0 },
+            Transaction::Assert { client: 1, tx: 5, available: money("1"), held_total: Some((money("0"), money("1"))) },
+        ];
+        let types: Vec<TransactionType> = transactions.iter().map(Transaction::transaction_type).collect();
+        assert_eq!(types, TransactionType::ALL);
+        for transaction in transactions {
+            let row = TransactionRow::from(transaction);
+            assert_eq!((row.client_id, row.transaction_id, row.amount), (transaction.client(), transaction.tx(), transaction.amount()));
+            assert_eq!(row.transaction(), Ok(transaction));
+        }
+    }
+
+    #[test]
+    fn rejections_name_their_client_and_tx() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(deposit(2, 2, "1"))?;
+
+        let overdraft = engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 3, amount: money("11") });
+        assert!(matches!(overdraft, Err(EngineError::InsufficientFunds { client: 1, tx: 3 })), "{:?}", overdraft);
+        let unknown = engine.process_transaction(Transaction::Dispute { client: 1, tx: 99 });
+        assert!(matches!(unknown, Err(EngineError::TxNotFound(99))), "{:?}", unknown);
+        let mismatch = engine.process_transaction(Transaction::Dispute { client: 2, tx: 1 });
+        assert!(matches!(mismatch, Err(EngineError::ClientMismatch { tx: 1, expected: 1, got: 2 })), "{:?}", mismatch);
+        let twice = engine.process_transaction(deposit(1, 1, "1"));
+        assert!(matches!(twice, Err(EngineError::Rejected(Rejection::DuplicateTransaction))), "{:?}", twice);
+
+        engine.process_transaction(Transaction::Dispute { client: 2, tx: 2 })?;
+        engine.process_transaction(Transaction::Chargeback { client: 2, tx: 2 })?;
+        let locked = engine.process_transaction(deposit(2, 4, "1"));
+        assert!(matches!(locked, Err(EngineError::AccountLocked { client: 2 })), "{:?}", locked);
+
+        for (error, rejection) in [
+            (overdraft, Rejection::InsufficientFunds),
+            (unknown, Rejection::UnknownTransaction),
+            (mismatch, Rejection::ClientMismatch),
+            (twice, Rejection::DuplicateTransaction),
+            (locked, Rejection::AccountLocked),
+        ] {
+            let error = error.err().unwrap_or_else(|| panic!("{:?} was not rejected", rejection));
+            assert_eq!(error.rejection(), Some(rejection));
+            assert_eq!(error.to_string(), rejection.to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn errors_that_are_not_rejections() {
+        let unknown_type = "transfer".parse::<TransactionType>();
+        assert!(matches!(&unknown_type, Err(EngineError::UnknownTransactionType(name)) if name == "transfer"), "{:?}", unknown_type);
+
+        let io = EngineError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert!(matches!(io, EngineError::Io(_)) && io.rejection().is_none());
+
+        let mut rdr = csv::ReaderBuilder::new().from_reader("a,b\n1,2\n3\n".as_bytes());
+        let bad = rdr.records().find_map(Result::err).unwrap_or_else(|| panic!("the short record was read"));
+        let parse = EngineError::from(bad);
+        assert!(matches!(parse, EngineError::Parse { line: Some(3), .. }), "{:?}", parse);
+        assert!(parse.rejection().is_none() && parse.source().is_some());
+
+        assert!(matches!(EngineError::from("no such flag"), EngineError::Invalid(_)));
+        assert!(EngineError::Invariant("held went negative".to_string()).to_string().starts_with("internal invariant violated"));
+    }
+
+    #[test]
+    fn withdrawal_refused_for_insufficient_funds() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "5"))?;
+        let result = engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 2, amount: money("5.0001") });
+        assert!(matches!(result, Err(EngineError::InsufficientFunds { client: 1, tx: 2 })), "{:?}", result);
+        assert_eq!(balances(&engine, 1), (money("5"), money("0"), money("5")));
+        // The refused tx id is not stored, so it can still be used
+        assert!(engine.record(2).is_none());
+        engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 2, amount: money("5") })?;
+        assert_eq!(balances(&engine, 1), (money("0"), money("0"), money("0")));
+        Ok(())
+    }
+
+    #[test]
+    fn withdrawals_down_to_zero_leave_the_account_open() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 2, amount: money("10") })?;
+        assert_eq!(balances(&engine, 1), (money("0"), money("0"), money("0")));
+        // Asking for more than is there is ignored and does not lock the account
+        let result = engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 3, amount: money("1") });
+        assert_eq!(result.map_err(|e| e.rejection()), Err(Some(Rejection::InsufficientFunds)));
+        assert_eq!(balances(&engine, 1), (money("0"), money("0"), money("0")));
+        assert!(engine.account(1).is_some_and(|client| !client.locked));
+        engine.process_transaction(deposit(1, 4, "2.5"))?;
+        assert_eq!(balances(&engine, 1), (money("2.5"), money("0"), money("2.5")));
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_rows_create_no_account() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Withdrawal { client: 7, tx: 1, amount: money("50") },
+            Transaction::Dispute { client: 7, tx: 2 },
+            Transaction::AdminHold { client: 7, tx: 3, amount: money("1") },
+            deposit(7, 4, "-1"),
+        ] {
+            assert!(engine.process_transaction(transaction).is_err(), "{:?}", transaction);
+            assert!(engine.account(7).is_none(), "{:?} created an account", transaction);
+        }
+        engine.process_transaction(deposit(7, 5, "1"))?;
+        assert_eq!(balances(&engine, 7), (money("1"), money("0"), money("1")));
+        Ok(())
+    }
+
+    // Every row type is tried against the locked account. Only an assert is let through, and it moves nothing
+    #[test]
+    fn locked_account_refuses_every_transaction()-> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(deposit(1, 2, "3"))?;
+        engine.process_transaction(Transaction::AdminHold { client: 1, tx: 5, amount: money("1") })?;
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 2 })?;
+        engine.process_transaction(Transaction::Chargeback { client: 1, tx: 2 })?;
+        let locked = balances(&engine, 1);
+        assert_eq!(locked, (money("9"), money("1"), money("10")));
+
+        let refused = [
+            deposit(1, 3, "1"),
+            Transaction::Withdrawal { client: 1, tx: 4, amount: money("1") },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Correction { client: 1, tx: 1, amount: money("12") },
+            Transaction::OpeningBalance { client: 1, tx: 6, amount: money("5") },
+            Transaction::AdminHold { client: 1, tx: 7, amount: money("1") },
+            Transaction::AdminRelease { client: 1, tx: 5 },
+        ];
+        for transaction in refused {
+            let result = engine.process_transaction(transaction);
+            assert!(matches!(result, Err(EngineError::AccountLocked { client: 1 })), "{:?} gave {:?}", transaction, result);
+            assert_eq!(balances(&engine, 1), locked, "{:?}", transaction);
+        }
+        engine.process_transaction(Transaction::Assert { client: 1, tx: 8, available: money("9"), held_total: Some((money("1"), money("10"))) })?;
+        engine.process_transaction(Transaction::AdminLock { client: 1, tx: 0 })?;
+        assert_eq!(balances(&engine, 1), locked);
+
+        // Unlocking is the one row that changes a locked account, see admin_lock_and_unlock_variants_freeze_the_account
+        let mut types: Vec<TransactionType> = refused.iter().map(Transaction::transaction_type).collect();
+        types.extend([TransactionType::Assert, TransactionType::AdminLock, TransactionType::AdminUnlock]);
+        types.sort_by_key(|transaction_type| transaction_type.name());
+        let mut all = TransactionType::ALL.to_vec();
+        all.sort_by_key(|transaction_type| transaction_type.name());
+        assert_eq!(types, all);
+        Ok(())
+    }
+
+    #[test]
+    fn dispute_lifecycle_refuses_the_wrong_client() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(deposit(2, 2, "10"))?;
+        for transaction in [Transaction::Dispute { client: 2, tx: 1 }, Transaction::Resolve { client: 2, tx: 1 }, Transaction::Chargeback { client: 2, tx: 1 }] {
+            let result = engine.process_transaction(transaction);
+            assert!(matches!(result, Err(EngineError::ClientMismatch { tx: 1, expected: 1, got: 2 })), "{:?} gave {:?}", transaction, result);
+        }
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 })?;
+        let result = engine.process_transaction(Transaction::Chargeback { client: 2, tx: 1 });
+        assert!(matches!(result, Err(EngineError::ClientMismatch { .. })), "{:?}", result);
+        assert_eq!(balances(&engine, 1), (money("0"), money("10"), money("10")));
+        assert_eq!(balances(&engine, 2), (money("10"), money("0"), money("10")));
+        Ok(())
+    }
+
+    #[test]
+    fn double_dispute_is_refused() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 })?;
+        let result = engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 });
+        assert_eq!(result.map_err(|e| e.rejection()), Err(Some(Rejection::AlreadyDisputed)));
+        // Only the first dispute holds the funds
+        assert_eq!(balances(&engine, 1), (money("0"), money("10"), money("10")));
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_and_chargeback_need_an_open_dispute() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        for transaction in [Transaction::Resolve { client: 1, tx: 1 }, Transaction::Chargeback { client: 1, tx: 1 }] {
+            let result = engine.process_transaction(transaction);
+            assert_eq!(result.map_err(|e| e.rejection()), Err(Some(Rejection::NotDisputed)), "{:?}", transaction);
+        }
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 })?;
+        engine.process_transaction(Transaction::Resolve { client: 1, tx: 1 })?;
+        let again = engine.process_transaction(Transaction::Resolve { client: 1, tx: 1 });
+        assert_eq!(again.map_err(|e| e.rejection()), Err(Some(Rejection::NotDisputed)));
+        assert_eq!(balances(&engine, 1), (money("10"), money("0"), money("10")));
+        Ok(())
+    }
+
+    // Every state against every row type, so a transition added to RecordState::next without a test shows up here
+    #[test]
+    fn record_state_transitions() {
+        use RecordState::{ChargedBack, Disputed, Normal, Resolved};
+        let lifecycle = [TransactionType::Dispute, TransactionType::Resolve, TransactionType::Chargeback];
+        let expected: [(RecordState, [Result<RecordState, Rejection>; 3]); 4] = [
+            (Normal, [Ok(Disputed), Err(Rejection::NotDisputed), Err(Rejection::NotDisputed)]),
+            (Disputed, [Err(Rejection::AlreadyDisputed), Ok(Resolved), Ok(ChargedBack)]),
+            (Resolved, [Ok(Disputed), Err(Rejection::NotDisputed), Err(Rejection::NotDisputed)]),
+            (ChargedBack, [Err(Rejection::AlreadyDisputed), Err(Rejection::AlreadyDisputed), Err(Rejection::AlreadyDisputed)]),
+        ];
+        for (state, results) in expected {
+            for (step, result) in lifecycle.into_iter().zip(results) {
+                assert_eq!(state.next(step), result, "{:?} on {:?}", step, state);
+            }
+            for step in TransactionType::ALL.into_iter().filter(|step| !lifecycle.contains(step)) {
+                assert_eq!(state.next(step), Err(Rejection::NotDisputable), "{:?} on {:?}", step, state);
+            }
+        }
+    }
+
+    #[test]
+    fn held_is_the_sum_of_the_open_disputes() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        for (tx, amount) in [(1, "1.5"), (2, "2"), (3, "4.25"), (4, "10")] {
+            engine.process_transaction(deposit(1, tx, amount))?;
+        }
+        for tx in 1..=3 {
+            engine.process_transaction(Transaction::Dispute { client: 1, tx })?;
+        }
+        engine.process_transaction(Transaction::Resolve { client: 1, tx: 2 })?;
+        engine.process_transaction(Transaction::Chargeback { client: 1, tx: 3 })?;
+
+        let client = engine.account(1).unwrap_or_else(|| panic!("client 1 has no account"));
+        assert_eq!(client.open_disputes().iter().map(|(&tx, &amount)| (tx, amount)).collect::<Vec<_>>(), [(1, money("1.5"))]);
+        assert_eq!(client.disputed(), Some(client.held));
+        assert_eq!(balances(&engine, 1), (money("12"), money("1.5"), money("13.5")));
+        Ok(())
+    }
+
+    // A resolved record can be disputed again as often as it is resolved, and ends with one chargeback
+    #[test]
+    fn resolved_records_can_be_disputed_again() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(deposit(1, 2, "5"))?;
+        for _ in 0..2 {
+            engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 })?;
+            assert_eq!(balances(&engine, 1), (money("5"), money("10"), money("15")));
+            engine.process_transaction(Transaction::Resolve { client: 1, tx: 1 })?;
+            assert_eq!(balances(&engine, 1), (money("15"), money("0"), money("15")));
+            let again = engine.process_transaction(Transaction::Resolve { client: 1, tx: 1 });
+            assert_eq!(again.map_err(|e| e.rejection()), Err(Some(Rejection::NotDisputed)));
+            assert_eq!(engine.record(1).map(|record| record.state), Some(RecordState::Resolved));
+        }
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 1 })?;
+        engine.process_transaction(Transaction::Chargeback { client: 1, tx: 1 })?;
+        assert_eq!(balances(&engine, 1), (money("5"), money("0"), money("5")));
+        assert_eq!(engine.record(1).map(|record| record.state), Some(RecordState::ChargedBack));
+        Ok(())
+    }
+
+    #[test]
+    fn pending_disputes_wait_for_funds() -> Result<(), EngineError> {
+        let mut engine = Engine::with_policy(EnginePolicy::builder().dispute_requires_funds(true).build());
+        for client in [1, 2] {
+            engine.process_transaction(deposit(client, u32::from(client) * 10, "10"))?;
+            engine.process_transaction(Transaction::Withdrawal { client, tx: u32::from(client) * 10 + 1, amount: money("8") })?;
+            engine.process_transaction(Transaction::Dispute { client, tx: u32::from(client) * 10 })?;
+            assert_eq!(balances(&engine, client), (money("2"), money("0"), money("2")));
+        }
+
+        // A deposit that covers the dispute has it held straight away
+        engine.process_transaction(deposit(1, 12, "9"))?;
+        assert_eq!(balances(&engine, 1), (money("1"), money("10"), money("11")));
+        let client = engine.account(1).unwrap_or_else(|| panic!("client 1 has no account"));
+        assert!(client.pending_disputes().is_empty() && client.open_disputes().contains_key(&10));
+
+        // A chargeback of a dispute still waiting takes the amount from available
+        engine.process_transaction(Transaction::Chargeback { client: 2, tx: 20 })?;
+        assert_eq!(balances(&engine, 2), (money("-8"), money("0"), money("-8")));
+        let client = engine.account(2).unwrap_or_else(|| panic!("client 2 has no account"));
+        assert!(client.locked && client.pending_disputes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn only_the_threshold_chargeback_locks() -> Result<(), EngineError> {
+        let threshold = NonZeroU32::new(3).unwrap_or_else(|| panic!("3 is not zero"));
+        let mut engine = Engine::with_policy(EnginePolicy::builder().lock_policy(LockPolicy::Threshold(threshold)).build());
+        for tx in 1..=4 {
+            engine.process_transaction(deposit(1, tx, "1"))?;
+        }
+        for tx in 1..=3 {
+            engine.process_transaction(Transaction::Dispute { client: 1, tx })?;
+            engine.process_transaction(Transaction::Chargeback { client: 1, tx })?;
+            assert_eq!(engine.account(1).map(|client| client.locked), Some(tx == 3), "after chargeback {}", tx);
+        }
+        assert_eq!(balances(&engine, 1), (money("1"), money("0"), money("1")));
+        assert_eq!(engine.locked_clients().collect::<Vec<_>>(), [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn disputed_withdrawal_is_returned_by_its_chargeback() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        engine.process_transaction(deposit(1, 1, "10"))?;
+        engine.process_transaction(Transaction::Withdrawal { client: 1, tx: 2, amount: money("4") })?;
+        engine.process_transaction(Transaction::Dispute { client: 1, tx: 2 })?;
+        assert_eq!(balances(&engine, 1), (money("6"), money("4"), money("10")));
+        engine.process_transaction(Transaction::Chargeback { client: 1, tx: 2 })?;
+        assert_eq!(balances(&engine, 1), (money("10"), money("0"), money("10")));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_deposits_are_refused() -> Result<(), EngineError> {
+        let mut engine = Engine::new();
+        for amount in INVALID {
+            let result = engine.process_transaction(deposit(1, 1, amount));
+            assert_eq!(result.map_err(|e| e.rejection()), Err(Some(Rejection::InvalidAmount)), "{}", amount);
+        }
+        engine.process_transaction(deposit(1, 1, "1"))?;
+        let duplicate = engine.process_transaction(deposit(1, 1, "1"));
+        assert_eq!(duplicate.map_err(|e| e.rejection()), Err(Some(Rejection::DuplicateTransaction)));
+        assert_eq!(balances(&engine, 1), (money("1"), money("0"), money("1")));
+        Ok(())
+    }
+
+    // Amounts the Client operations refuse. Fixed-point amounts cannot be finer than four places to begin with
+    #[cfg(not(feature = "fixed-point"))]
+    const INVALID: [&str; 3] = ["0", "-1", "0.00001"];
+    #[cfg(feature = "fixed-point")]
+    const INVALID: [&str; 2] = ["0", "-1"];
+
+    // The largest amount Money holds, which no balance can be added to
+    #[cfg(not(feature = "fixed-point"))]
+    const LARGEST: &str = "79228162514264337593543950335";
+    #[cfg(feature = "fixed-point")]
+    const LARGEST: &str = "922337203685477.5807";
+
+    // This function returns a client with 10 available and 4 held
+    fn funded_client() -> Client {
+        let mut client = Client::new(1);
+        client.available = money("10");
+        client.held = money("4");
+        client.total = money("14");
+        client
+    }
+
+    #[test]
+    fn client_operations_refuse_invalid_amounts() {
+        type Operation = fn(&mut Client, Money) -> Result<(), Rejection>;
+        let operations: [(&str, Operation); 5] = [
+            ("deposit", Client::deposit),
+            ("withdraw", Client::withdraw),
+            ("hold", Client::hold),
+            ("release", Client::release),
+            ("charge_back", Client::charge_back),
+        ];
+        for (name, operation) in operations {
+            for amount in INVALID {
+                let mut client = funded_client();
+                assert_eq!(operation(&mut client, money(amount)), Err(Rejection::InvalidAmount), "{} of {}", name, amount);
+                assert_eq!((client.available, client.held, client.total), (money("10"), money("4"), money("14")), "{} of {}", name, amount);
+            }
+        }
+        let mut client = funded_client();
+        assert_eq!(client.charge_fee(money("-1")), Err(Rejection::InvalidAmount));
+        assert_eq!(client.charge_fee(money("0")), Ok(()));
+    }
+
+    #[test]
+    fn client_operations_refuse_what_the_balances_do_not_cover() {
+        let mut client = funded_client();
+        assert_eq!(client.withdraw(money("10.0001")), Err(Rejection::InsufficientFunds));
+        assert_eq!(client.release(money("4.0001")), Err(Rejection::InsufficientFunds));
+        assert_eq!(client.charge_back(money("4.0001")), Err(Rejection::InsufficientFunds));
+        assert_eq!((client.available, client.held, client.total), (money("10"), money("4"), money("14")));
+
+        // A hold and a fee may take available negative
+        assert_eq!(client.hold(money("11")), Ok(()));
+        assert_eq!(client.charge_fee(money("1")), Ok(()));
+        assert_eq!((client.available, client.held, client.total), (money("-2"), money("15"), money("13")));
+    }
+
+    #[test]
+    fn client_operations_refuse_overflow() {
+        let mut client = Client::new(1);
+        assert_eq!(client.deposit(money(LARGEST)), Ok(()));
+        assert_eq!(client.deposit(money("1")), Err(Rejection::Overflow));
+        assert_eq!(client.available, money(LARGEST));
+        // Total is what overflows once the funds are held
+        assert_eq!(client.hold(money(LARGEST)), Ok(()));
+        assert_eq!(client.deposit(money("1")), Err(Rejection::Overflow));
+        assert_eq!((client.available, client.held, client.total), (money("0"), money(LARGEST), money(LARGEST)));
+    }
+
+    #[test]
+    fn rows_without_an_amount_are_rejected() {
+        let mut engine = Engine::new();
+        for transaction_type in [TransactionType::Deposit, TransactionType::Withdrawal, TransactionType::Correction, TransactionType::AdminHold] {
+            let row = TransactionRow::new(transaction_type, 1, 1, None);
+            assert_eq!(row.transaction(), Err(Rejection::MissingAmount));
+            assert_eq!(engine.process(&row).map_err(|e| e.rejection()), Err(Some(Rejection::MissingAmount)));
+        }
+    }
+
+    fn stored(client_id: u16, amount: &str) -> Record {
+        Record::new(RecordKind::Deposit, client_id, money(amount))
+    }
+
+    // This function returns a client's tx ids as the store lists them
+    fn client_ids(store: &TransactionStore, client_id: u16) -> Vec<u32> {
+        store.for_client(client_id).map(|(transaction_id, _)| transaction_id).collect()
+    }
+
+    #[test]
+    fn store_refuses_a_taken_tx_id_and_changes_records_in_place() {
+        let mut store = TransactionStore::new();
+        assert_eq!(store.insert(1, stored(1, "10")), Ok(()));
+        assert_eq!(store.insert(1, stored(2, "5")), Err(Rejection::DuplicateTransaction));
+        assert_eq!((store.len(), store.contains(1), store.contains(2)), (1, true, false));
+        assert_eq!(store.get_for_client(1, 2).err(), Some(Rejection::ClientMismatch));
+        assert_eq!(store.get_for_client(2, 1).err(), Some(Rejection::UnknownTransaction));
+
+        store.set_state(1, RecordState::Disputed);
+        store.set_amount(1, money("7"));
+        let record = store.get_for_client(1, 1).unwrap_or_else(|e| panic!("the record is not stored: {}", e));
+        assert_eq!((record.state, record.amount), (RecordState::Disputed, money("7")));
+        assert_eq!(store.remove(1).map(|record| record.amount), Some(money("7")));
+        assert!(store.remove(1).is_none());
+        assert!(store.is_empty() && store.by_client.is_empty());
+    }
+
+    #[test]
+    fn store_lists_a_client_in_the_order_stored_without_removed_records() {
+        let mut store = TransactionStore::new();
+        for (transaction_id, client_id) in [(5, 1), (2, 2), (9, 1), (1, 1), (7, 2)] {
+            assert_eq!(store.insert(transaction_id, stored(client_id, "1")), Ok(()));
+        }
+        assert_eq!(client_ids(&store, 1), [5, 9, 1]);
+        assert_eq!(client_ids(&store, 2), [2, 7]);
+
+        store.remove(9);
+        assert_eq!(client_ids(&store, 1), [5, 1]);
+        // A removed tx id stored again, by the same client or another, is listed where it was stored last
+        assert_eq!(store.insert(9, stored(1, "1")), Ok(()));
+        assert_eq!(client_ids(&store, 1), [5, 1, 9]);
+        store.remove(5);
+        assert_eq!(store.insert(5, stored(2, "1")), Ok(()));
+        assert_eq!((client_ids(&store, 1), client_ids(&store, 2)), (vec![1, 9], vec![2, 7, 5]));
+
+        for transaction_id in [1, 9] {
+            store.remove(transaction_id);
+        }
+        assert!(client_ids(&store, 1).is_empty());
+        assert!(!store.by_client.contains_key(&1));
+    }
+
+    #[test]
+    fn store_keeps_the_order_while_removing_most_of_a_client() {
+        let mut store = TransactionStore::new();
+        for transaction_id in 0..2000 {
+            assert_eq!(store.insert(transaction_id, stored(1, "1")), Ok(()));
+        }
+        // Every third record stays, so the removed ones are dropped from the list several times along the way
+        for transaction_id in (0..2000).filter(|transaction_id| transaction_id % 3 != 0) {
+            assert!(store.remove(transaction_id).is_some());
+        }
+        let left: Vec<u32> = (0..2000).filter(|transaction_id| transaction_id % 3 == 0).collect();
+        assert_eq!(client_ids(&store, 1), left);
+        let ids = store.by_client.get(&1).unwrap_or_else(|| panic!("client 1 has no records left"));
+        assert!(ids.ids.len() < left.len() * 2, "{} tx ids are kept for {} records", ids.ids.len(), left.len());
+    }
+
+    #[test]
+    fn loaded_store_lists_each_client_by_tx_id() {
+        let records: IdMap<u32, Record> = [(30, stored(1, "1")), (10, stored(1, "2")), (20, stored(2, "3")), (15, stored(1, "4"))].into_iter().collect();
+        let store = TransactionStore::from(records);
+        assert_eq!(client_ids(&store, 1), [10, 15, 30]);
+        assert_eq!(client_ids(&store, 2), [20]);
+        assert_eq!(client_ids(&store.clone(), 1), [10, 15, 30]);
+    }
+}
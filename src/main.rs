@@ -1,313 +1,735 @@
-use csv::WriterBuilder;
-use csv::Trim;
-use serde::{Serialize,Serializer,Deserialize};
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)]
+
+mod api_keys;
+mod bench;
+mod config;
+mod explain;
+mod forget;
+mod generate;
+mod history;
+mod serve;
+mod verify;
+
+use log::{error, warn};
+use payment_engine::args::{ClientFilter, InputFormat, ProcessingArgs, ReportArgs, ReportSchema};
+use payment_engine::diagnostics::{self, Severity};
+use payment_engine::report::{self, CsvSink, ReportOptions, ReportWriter, RunMeta};
+use payment_engine::run::{self, process_input, DONE_TARGET, STDIN_INPUT};
+use payment_engine::{audit, remote, replay, snapshot, EngineError};
 use std::process;
-use std::error::Error;
 use std::io;
-use clap::Parser;
-use rust_decimal_macros::dec;
-use rust_decimal::prelude::*;
-use std::collections::HashMap;
+use std::io::Write;
+use clap::{ArgEnum, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::num::{NonZeroU64, NonZeroUsize};
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true, after_help = EXIT_STATUS_HELP)]
 struct Args {
-    csv_file: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// CSV files to process in order, as one continuous input, - for standard input or an http:// URL to stream. Standard input is read when they are left out
+    input_files: Vec<String>,
+
+    /// Read defaults for any flag not given on the command line from this TOML file. Defaults to ./payment_engine.toml if it exists
+    #[clap(long)]
+    config: Option<String>,
+
+    /// Most detailed diagnostics written to stderr. debug shows every applied row, trace every row read. Defaults to -q or -v, then the RUST_LOG filters, then warn
+    #[clap(long, possible_values = LEVEL_NAMES)]
+    log_level: Option<log::Level>,
+
+    /// How diagnostics are written to stderr: text lines, or one JSON object per event with its level, timestamp,
+    /// message and the line, client, tx and reason it is about
+    #[clap(long, arg_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Write only errors that stop the run to stderr, leaving out warnings about rows and the closing done line
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Write more diagnostics to stderr, info with -v, every applied row with -vv and every row read with -vvv
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
+
+    #[clap(flatten)]
+    report: ReportArgs,
 }
 
-#[derive(Debug, Deserialize)]
-struct Record {
-    transaction_type: String,
-    client_id: u16,
-    amount: Decimal,
-    disputed: bool,
-    locked: bool,
+#[derive(Subcommand)]
+enum Command {
+    /// Process an input and show everything that happened to one transaction
+    Explain(Box<ExplainArgs>),
+    /// Process an input up to a row and print the accounts as they were at that point
+    At(Box<AtArgs>),
+    /// Process an input and list every row that touched one client, with its balances after each
+    History(Box<HistoryArgs>),
+    /// Re-run a file written with --record and report every decision that changed
+    Replay(Box<ReplayArgs>),
+    /// Process an input and check that an account report is what it yields
+    Verify(Box<VerifyArgs>),
+    /// Generate a workload in memory, run it through the engine and print the throughput
+    Bench(Box<BenchArgs>),
+    /// Keep the accounts in memory and apply transactions posted over HTTP
+    Serve(Box<ServeArgs>),
+    /// Write a synthetic transaction file that processes without a single rejected row
+    Generate(GenerateArgs),
+    /// Export one client from a snapshot and write the snapshot without them, rejecting their later rows
+    Forget(ForgetArgs),
+    /// Check an audit log written with --audit-log
+    #[clap(subcommand)]
+    Audit(AuditCommand),
+    /// Inspect the configuration file
+    #[clap(subcommand)]
+    Config(Box<ConfigCommand>),
 }
 
-#[derive(Debug, Serialize)]
-struct Client {
-    #[serde(rename = "client")]
-    client_id: u16,
-    #[serde(serialize_with = "round_serialize")]
-    available: Decimal,
-    #[serde(serialize_with = "round_serialize")]
-    held: Decimal,
-    #[serde(serialize_with = "round_serialize")]
-    total: Decimal,
-    locked: bool,
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the configuration that results from the config file and the given flags
+    Show(ConfigShowArgs),
 }
 
-// This macro rounds the Decimal units to 4 significance places in the Bankers Rounding method
-fn round_serialize<S>(x: &Decimal, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_f32(match x.round_dp(4).to_f32(){
-        Some(x) => x,
-        None => -1.0,
-    })
+#[derive(Subcommand)]
+enum AuditCommand {
+    /// Recompute the hash chain of an audit log and report the first line that was changed, added or removed
+    Verify(AuditVerifyArgs),
 }
 
-// This function is the main logic that handles opening and reading the CSV and delegating each transaction type
-fn open_and_read_csv(csv_file: String) -> Result<HashMap<u16,Client>, Box<dyn Error>> {
-    let mut records = HashMap::<u32,Record>::new();
-    let mut clients = HashMap::<u16,Client>::new();
-
-    // Set up path for CSV file
-    let mut path_abs = std::env::current_exe()?;
-    path_abs.pop();
-    path_abs.push(csv_file);
-
-    // Set up CSV reader
-    let mut rdr = match csv::ReaderBuilder::new()
-                    .trim(Trim::All)
-                    .from_path(&path_abs) {
-                        Ok(r) => r,
-                        Err(e) => {
-                            println!("ERR: could not find the file in path {}",&path_abs.display());
-                            process::exit(-1);
-                        }
-                    };
-
-
-    for result in rdr.records() {
-        let record = result?;
-
-        // DEBUG
-        //println!("{:?}",record);
-
-        // Parse CSV into hashmap
-        let transaction_id = record[2].parse::<u32>()?;
-        let transaction_type = (record[0]).to_string();
-        if (record[0]).to_string() == "deposit" || (record[0]).to_string() == "withdrawal" {
-            records.insert(transaction_id.clone(), Record {
-                transaction_type: transaction_type.clone(), 
-                client_id: record[1].parse::<u16>()?,
-                amount: record[3].parse::<Decimal>()?,
-                disputed: false,
-                locked: false,
-            });
-        }
+#[derive(clap::Args)]
+struct AuditVerifyArgs {
+    /// Audit log to check
+    path: String,
+}
 
-        // Perform action type
-        match transaction_type.as_str() {
-            "deposit" => deposit_to_account(&mut clients, records.get(&transaction_id).unwrap()),
-            "withdrawal" => withdraw_from_account(&mut clients, records.get(&transaction_id).unwrap()),
-            "dispute" => submit_dispute(&mut clients, &mut records, &transaction_id, &record[1].parse::<u16>()?),
-            "resolve" => resolve_dispute(&mut clients, &mut records, &transaction_id, &record[1].parse::<u16>()?),
-            "chargeback" => issue_chargeback(&mut clients, &mut records, &transaction_id, &record[1].parse::<u16>()?),
-            _  => {
-                println!("Error while parsing CSV: Invalid transaction type.");
-                process::exit(-1);
-            },
-        }
+#[derive(clap::Args)]
+struct ConfigShowArgs {
+    /// Configuration file to read. Defaults to ./payment_engine.toml if it exists
+    #[clap(long)]
+    config: Option<String>,
 
-        // DEBUG
-        //match records.get(&transaction_id) {
-        //    Some(r) => println!("{:?}",r),
-        //    None => println!("Entry does not exist."),
-        //};
+    #[clap(flatten)]
+    processing: ProcessingArgs,
 
-        //match clients.get(&records.get(&transaction_id).unwrap().client_id) {
-        //    Some(r) => println!("{:?}",r),
-        //    None => println!("Entry does not exist."),
-        //};
+    #[clap(flatten)]
+    report: ReportArgs,
+}
 
-        println!("\n\n");
-    }
 
-    Ok(clients)
+#[derive(clap::Args)]
+struct ExplainArgs {
+    /// Input file to process
+    #[clap(long)]
+    input: String,
+
+    /// Id of the transaction to explain
+    #[clap(long)]
+    tx: u32,
+
+    /// Format of the explanation
+    #[clap(long, arg_enum, default_value = "text")]
+    format: explain::ExplainFormat,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
 }
 
-// This function deposits money into a client's account
-fn deposit_to_account(clients: &mut HashMap::<u16,Client>, record: &Record) {
-    match clients.get_mut(&(record.client_id)) {
-        // Add amount to client
-        Some(x) => {
-            if x.locked != true {
-                x.available += record.amount;
-                x.total += record.amount;
-            }
-        },
-        // Create a new client if not already in list
-        None => drop(clients.insert(record.client_id, Client {
-                    client_id: record.client_id,
-                    available: record.amount,
-                    held: dec!(0),
-                    total: record.amount,
-                    locked: false,
-                })),
-    };
+#[derive(clap::Args)]
+struct ReplayArgs {
+    /// Replay file written with --record
+    path: String,
 
-    // DEBUG
-    println!("Deposit {:?} : {:?}",&(record.client_id),clients.get(&(record.client_id)).unwrap());
+    /// Flags given here replace the ones the run was recorded with, e.g. --chargeback-fee to see which decisions another
+    /// fee changes. The recorded value is used for every other flag
+    #[clap(flatten)]
+    processing: ProcessingArgs,
 }
 
-// This function withdraws money into a client's account
-fn withdraw_from_account(clients: &mut HashMap::<u16,Client>, record: &Record) {
-    match clients.get_mut(&(record.client_id)) {
-        // Subtract amount from client, error if insufficient funds are available
-        Some(x) => {
-            if x.available > record.amount && x.locked != true {
-                x.available -= record.amount;
-                x.total -= record.amount;
-            } else {
-                println!("Error: Insufficient funds for withdrawal.");
-                x.locked = true;
-            }
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// Number of rows to generate
+    #[clap(long, default_value = "1000000")]
+    rows: u64,
 
-        },
-        None => (),
-    };
+    /// Number of distinct clients the rows are spread over
+    #[clap(long, default_value = "1000")]
+    accounts: u16,
+
+    /// Shape of the generated workload
+    #[clap(long, arg_enum, default_value = "mixed")]
+    profile: bench::BenchProfile,
+
+    /// Chance that a row disputes an earlier deposit, and that a row settles an open dispute. Defaults to the profile's rate
+    #[clap(long)]
+    dispute_rate: Option<f64>,
 
-    // DEBUG
-    println!("Withdraw {:?} : {:?}",&(record.client_id),clients.get(&(record.client_id)).unwrap());
+    /// Seed for the generator, the same seed always produces the same rows
+    #[clap(long, default_value = "1")]
+    seed: u64,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
 }
 
-// This function submits a dispute onto the client and places funds from available to held
-fn submit_dispute(clients: &mut HashMap::<u16,Client>, records: &mut HashMap::<u32,Record>, transaction_id: &u32, client_id: &u16) {
-    // Get record associated with transaction id
-    let record = match records.get_mut(transaction_id) {
-        Some(x) => x,
-        None => {
-            println!("Error: transaction does not exist.");
-            return;
-        },
-    };
+#[derive(clap::Args)]
+struct GenerateArgs {
+    /// Number of rows to write
+    #[clap(long, default_value = "1000000")]
+    rows: u64,
 
-    // Check if client id's match
-    if client_id == &record.client_id {
-        match record.transaction_type.as_str() {
-            "deposit" => {
-                match clients.get_mut(&(record.client_id)) {
-                    // Check if client exists
-                    Some(x) => {
-                        // Check if record is already being disputed or chargeback has already occured (aka, account is locked)
-                        if record.disputed == false && record.locked == false {
-                            x.available -= record.amount;
-                            x.held += record.amount;
-                            record.disputed = true;
-                        }
-                        else {
-                            println!("Error: Transaction is already being disputed or has already been resolved.");
-                        }
-                    },
-                    None => println!("Error: Client {} does not exist.", &(record.client_id)),
-                }
-            },
-            _ => println!("Error: Transaction type {} cannot be disputed.", &record.transaction_type),
-        };
+    /// Number of distinct clients the rows are spread over
+    #[clap(long, default_value = "500")]
+    clients: u16,
+
+    /// Chance that a row disputes an earlier deposit, and that a row resolves an open dispute
+    #[clap(long, default_value = "0.01")]
+    dispute_rate: f64,
+
+    /// Chance that a row charges back an open dispute, locking the client
+    #[clap(long, default_value = "0.002")]
+    chargeback_rate: f64,
+
+    /// Seed for the generator, the same seed always produces the same file
+    #[clap(long, default_value = "1")]
+    seed: u64,
+
+    /// File to write, stdout if not given
+    #[clap(long)]
+    out: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct ForgetArgs {
+    /// Snapshot written with --snapshot-out to remove the client from
+    #[clap(long)]
+    state: String,
+
+    /// Client to forget
+    #[clap(long)]
+    client: u16,
+
+    /// JSON file to write the client's accounts, transactions and admin holds to
+    #[clap(long)]
+    export: String,
+
+    /// Snapshot to write without the client. May be the same file as --state
+    #[clap(long)]
+    state_out: String,
+}
+
+#[derive(clap::Args)]
+struct AtArgs {
+    /// Input file to process
+    #[clap(long)]
+    input: String,
+
+    /// Last input row to apply, counting the first row after the header as 1
+    #[clap(long)]
+    row: u64,
+
+    /// Only print this client's account
+    #[clap(long)]
+    client: Option<u16>,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
+}
+
+#[derive(clap::Args)]
+struct HistoryArgs {
+    /// Input file to process
+    input: String,
+
+    /// Client whose history is listed
+    #[clap(long)]
+    client: u16,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Input file to process
+    input: String,
+
+    /// Account report to check, as CSV
+    accounts: String,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Port to listen on
+    #[clap(long, default_value = "8080")]
+    port: u16,
+
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Answer 429 to new requests while this many are still queued or being handled
+    #[clap(long)]
+    max_pending: Option<NonZeroUsize>,
+
+    /// Requests read and applied at the same time, by as many worker threads
+    #[clap(long, default_value = "8")]
+    max_in_flight: NonZeroUsize,
+
+    /// Accepted connections that may wait for a worker. While the queue is full no more connections are accepted
+    #[clap(long, default_value = "64")]
+    queue_size: NonZeroUsize,
+
+    /// Shards the accounts are split into by client. Requests for clients in different shards are applied at the same time
+    #[clap(long, default_value = "16")]
+    shards: NonZeroUsize,
+
+    /// Seconds a shutdown waits for the requests in flight after SIGTERM or SIGINT
+    #[clap(long, default_value = "30")]
+    drain_timeout: u64,
+
+    /// Seconds a worker waits for a request to arrive and for its response to be taken before giving up on the connection
+    #[clap(long, default_value = "10")]
+    request_timeout: NonZeroU64,
+
+    /// Write the account report to this CSV file on shutdown
+    #[clap(long)]
+    output: Option<String>,
+
+    /// Write the engine state to this file on shutdown, for --snapshot-in
+    #[clap(long)]
+    snapshot_out: Option<String>,
+
+    /// File of API keys, one per line as KEY or KEY:ROLE with the role read or admin. Every request then needs an
+    /// Authorization: Bearer header with one of them, and POST needs an admin key
+    #[clap(long)]
+    api_keys_file: Option<String>,
+
+    /// Keep the accounts in this PostgreSQL database, as a URL or key=value connection string. The engine starts from
+    /// what it holds, and every applied request is written to it before it is answered
+    #[cfg(feature = "postgres")]
+    #[clap(long)]
+    postgres_url: Option<String>,
+
+    #[clap(flatten)]
+    processing: ProcessingArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ArgEnum)]
+enum LogFormat {
+    // "Warning: deposit 5 for client 1 rejected: insufficient funds."
+    Text,
+    // {"level":"warn","timestamp":"...","message":"...","line":6,"client":1,"tx":5,"reason":"insufficient_funds"}
+    Json,
+}
+
+// Exit statuses other than 0 for a completed run. Rejected rows are part of a completed run and never change it
+// An input, output or report file could not be read or written
+const EXIT_IO: i32 = 1;
+// The input or the flags could not be used, e.g. a row that does not parse under --strict. clap exits with the same
+// status for a command line it cannot parse
+const EXIT_INPUT: i32 = 2;
+// replay found decisions that changed since the recording, or verify found accounts that differ from the report
+const EXIT_MISMATCH: i32 = 3;
+// With --fail-on-locked, a chargeback locked at least one account
+const EXIT_LOCKED: i32 = 4;
+// With --dry-run, at least one row would not be applied
+const EXIT_ISSUES: i32 = 5;
+// The run was stopped because the engine's own bookkeeping went wrong, as opposed to bad input
+const EXIT_INVARIANT: i32 = 70;
+// serve got a second SIGTERM or SIGINT while draining and exited without writing its accounts
+#[cfg_attr(not(unix), allow(dead_code))]
+const EXIT_FORCED: i32 = 130;
+
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0     the run completed, even if some rows were rejected or skipped
+    1     an input, output or report file could not be read or written
+    2     the input or the flags could not be used
+    3     replay found decisions that changed, or verify found accounts that differ
+    4     with --fail-on-locked, a chargeback locked an account
+    5     with --dry-run, some rows would not be applied
+    70    an internal invariant was broken
+    130   serve was stopped by a second SIGTERM or SIGINT while draining";
+
+// This function picks the exit status for an error that stopped the run
+fn exit_status(e: &EngineError) -> i32 {
+    match e {
+        EngineError::Invariant(_) => EXIT_INVARIANT,
+        EngineError::Io(_) | EngineError::Storage(_) => EXIT_IO,
+        EngineError::Parse { .. }
+        | EngineError::UnknownTransactionType(_)
+        | EngineError::Invalid(_)
+        | EngineError::InsufficientFunds { .. }
+        | EngineError::AccountLocked { .. }
+        | EngineError::TxNotFound(_)
+        | EngineError::ClientMismatch { .. }
+        | EngineError::Rejected(_) => EXIT_INPUT,
     }
-    else {
-        println!("Error: Client does not match transaction.")
+}
+
+// This function names an exit status for --diagnostics-json, in snake case like the other diagnostic codes
+fn exit_code_name(status: i32) -> &'static str {
+    match status {
+        EXIT_INVARIANT => "invariant_violation",
+        EXIT_IO => "io_error",
+        _ => "input_error",
     }
 }
 
-// This function resolves a record under dispute and places funds from held back to available
-fn resolve_dispute(clients: &mut HashMap::<u16,Client>, records: &mut HashMap::<u32,Record>, transaction_id: &u32, client_id: &u16) {
-    // Get record associated with transaction id
-    let record = match records.get_mut(transaction_id) {
-        Some(x) => x,
+// Most decimal places --precision accepts, the scale limit of Decimal
+const MAX_PRECISION: u32 = 28;
+
+// This function prints the accounts as they were right after the given row, applying the rows up to it with the same
+// flags as the main command
+fn run_at(args: &mut AtArgs) -> Result<(), EngineError> {
+    args.processing.until_row = Some(args.row);
+    let state = process_input(std::slice::from_ref(&args.input), &args.processing, &args.processing.policy()?, None, None)?;
+
+    let options = ReportOptions { clients: args.client.map(ClientFilter::single), ..ReportOptions::default() };
+    report::write_report(state.engine.accounts(), state.rows, &options, &mut CsvSink::new(io::stdout()))
+}
+
+// This function prints the configuration that a run with the same config file and flags would use
+fn show_config(args: &mut ConfigShowArgs, matches: Option<&clap::ArgMatches>) -> Result<(), EngineError> {
+    let matches = matches.ok_or("config show was run without its arguments")?;
+    config::resolve(args.config.as_deref(), matches, &mut args.processing, &mut args.report)?;
+    print!("{}", config::show(&args.processing, &args.report)?);
+    Ok(())
+}
+
+// The names --log-level takes, least detailed first
+const LEVEL_NAMES: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+// This function sends the log macros to stderr as one line each, e.g. "Warning: deposit 5 for client 1 rejected: ...".
+// An explicit --log-level wins over -q and -v, and any of them over RUST_LOG, which takes env_logger filters such as
+// payment_engine=debug. Without any of them warnings and errors are written
+fn init_logging(args: &Args) {
+    const LEVELS: [log::Level; 5] = [log::Level::Error, log::Level::Warn, log::Level::Info, log::Level::Debug, log::Level::Trace];
+    let level = args.log_level
+        .or_else(|| args.quiet.then_some(log::Level::Error))
+        .or_else(|| (args.verbose > 0).then(|| LEVELS.get(usize::from(args.verbose) + 1).copied().unwrap_or(log::Level::Trace)));
+
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(log::LevelFilter::Warn);
+    match level {
+        Some(level) => {
+            logger.filter_level(level.to_level_filter());
+        },
         None => {
-            println!("Error: transaction does not exist.");
-            return;
+            if let Ok(filters) = std::env::var("RUST_LOG") {
+                logger.parse_filters(&filters);
+            }
         },
+    }
+    match args.log_format {
+        LogFormat::Text => logger.format(|out, record| {
+            if record.target() == DONE_TARGET {
+                return writeln!(out, "{}", record.args());
+            }
+            let label = match record.level() {
+                log::Level::Error => "Error",
+                log::Level::Warn => "Warning",
+                log::Level::Info => "Info",
+                log::Level::Debug => "Debug",
+                log::Level::Trace => "Trace",
+            };
+            writeln!(out, "{}: {}", label, record.args())
+        }),
+        LogFormat::Json => logger.format(|out, record| writeln!(out, "{}", json_event(record))),
     };
+    logger.init();
+}
 
-    // Check if client id's match    
-    if client_id == &record.client_id {
-        match record.transaction_type.as_str() {
-            "deposit" => {
-                // Check if client exists
-                match clients.get_mut(&(record.client_id)) {
-                    Some(x) => {
-                        // Check if record is under dispute
-                        if record.disputed == true {
-                            x.available += record.amount;
-                            x.held -= record.amount;
-                            record.disputed = false;
-                        }
-                        else {
-                            println!("Error: Transaction is not being disputed.");
-                        }
-                    },
-                    None => println!("Error: Client {} does not exist.", &(record.client_id)),
-                }
-            },
-            _ => println!("Error: Transaction type {} cannot be resolved.", &record.transaction_type),
-        };
+// This function turns one log event into a JSON object. The fields the event was logged with, like line, client, tx
+// and reason, follow level, timestamp and message. Numbers, booleans and missing values keep their JSON type and
+// everything else is written as a string
+fn json_event(record: &log::Record) -> serde_json::Value {
+    struct Fields(serde_json::Map<String, serde_json::Value>);
+    struct Field(serde_json::Value);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+            let mut field = Field(serde_json::Value::Null);
+            value.visit(&mut field)?;
+            self.0.insert(key.to_string(), field.0);
+            Ok(())
+        }
     }
-    else {
-        println!("Error: Client does not match transaction.")
+
+    impl<'v> log::kv::VisitValue<'v> for Field {
+        fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+            self.0 = value.to_string().into();
+            Ok(())
+        }
+
+        fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+            self.0 = serde_json::Value::Null;
+            Ok(())
+        }
+
+        fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
+            self.0 = value.into();
+            Ok(())
+        }
+
+        fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error> {
+            self.0 = value.into();
+            Ok(())
+        }
+
+        fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error> {
+            self.0 = value.into();
+            Ok(())
+        }
     }
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let timestamp = chrono::DateTime::from_timestamp(now.as_secs().try_into().unwrap_or(i64::MAX), now.subsec_nanos())
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+    let mut fields = Fields(serde_json::Map::new());
+    fields.0.insert("level".to_string(), record.level().as_str().to_ascii_lowercase().into());
+    fields.0.insert("timestamp".to_string(), timestamp.into());
+    fields.0.insert("message".to_string(), record.args().to_string().into());
+    // A source that stops visiting part way still leaves the fields before it
+    let _ = record.key_values().visit(&mut fields);
+    serde_json::Value::Object(fields.0)
 }
 
-// This function issues a chargeback on a record by taking the disputed amount away from held and total, and locks the record and client
-fn issue_chargeback(clients: &mut HashMap::<u16,Client>, records: &mut HashMap::<u32,Record>, transaction_id: &u32, client_id: &u16) {
-    // Get record associated with transaction id
-    let record = match records.get_mut(transaction_id) {
-        Some(x) => x,
-        None => {
-            println!("Error: transaction does not exist.");
-            return;
-        },
-    };
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    // Check if client id's match  
-    if client_id == &record.client_id {
-        match record.transaction_type.as_str() {
-            "deposit" => {
-                // Check if client exists
-                match clients.get_mut(&(record.client_id)) {
-                    Some(x) => {
-                        // Check if record is under dispute
-                        if record.disputed == true {
-                            x.total -= record.amount;
-                            x.held -= record.amount;
-                            x.locked = true;
-                            record.disputed = false;
-                            record.locked = true;
-                        }
-                        else {
-                            println!("Error: Transaction is not being disputed.");
-                        }
-                    },
-                    None => println!("Error: Client {} does not exist.", &(record.client_id)),
-                }
+    init_logging(&args);
+
+    if let Some(command) = &mut args.command {
+        let result = match command {
+            Command::Explain(explain_args) => explain::run(explain_args),
+            Command::At(at_args) => run_at(at_args),
+            Command::History(history_args) => history::run(history_args),
+            Command::Bench(bench_args) => bench::run(bench_args),
+            Command::Serve(serve_args) => serve::run(serve_args),
+            Command::Generate(generate_args) => generate::run(generate_args),
+            Command::Forget(forget_args) => forget::run(forget_args),
+            Command::Replay(replay_args) => match replay::run(&replay_args.path, &mut replay_args.processing, matches.subcommand_matches("replay")) {
+                Ok(true) => Ok(()),
+                Ok(false) => process::exit(EXIT_MISMATCH),
+                Err(e) => Err(e),
+            },
+            Command::Verify(verify_args) => match verify::run(verify_args) {
+                Ok(true) => Ok(()),
+                Ok(false) => process::exit(EXIT_MISMATCH),
+                Err(e) => Err(e),
+            },
+            Command::Audit(AuditCommand::Verify(verify_args)) => match audit::run_verify(&verify_args.path) {
+                Ok(true) => Ok(()),
+                Ok(false) => process::exit(EXIT_MISMATCH),
+                Err(e) => Err(e),
+            },
+            Command::Config(config_command) => {
+                let ConfigCommand::Show(show_args) = config_command.as_mut();
+                let show_matches = matches.subcommand_matches("config").and_then(|m| m.subcommand_matches("show"));
+                show_config(show_args, show_matches)
             },
-            _ => println!("Error: Transaction type {} cannot be resolved.", &record.transaction_type),
         };
+        if let Err(e) = result {
+            error!("could not process input: {}", e);
+            process::exit(exit_status(&e));
+        }
+        return;
     }
-    else {
-        println!("Error: Client does not match transaction.")
+    let mut inputs = std::mem::take(&mut args.input_files);
+    if inputs.is_empty() {
+        inputs.push(STDIN_INPUT.to_string());
     }
-}
 
-//  This function writes each client data struct to stdout in the CSV format
-fn write_to_csv(clients: HashMap::<u16,Client>) -> Result<(), Box<dyn Error>> {
-    let mut wtr = WriterBuilder::new().from_writer(io::stdout());
-    
-    for (id, data) in clients.iter() {
-        wtr.serialize(data);
-        wtr.flush()?;
+    if let Err(e) = config::resolve(args.config.as_deref(), &matches, &mut args.processing, &mut args.report) {
+        error!("{}", e);
+        process::exit(EXIT_INPUT);
     }
+    let report = args.report;
+    args.processing.multiple_inputs = inputs.len() > 1;
+    args.processing.inputs = inputs.clone();
+    args.processing.keep_outcomes = report.dry_run;
+    args.processing.report_schema = Some(report.report_schema);
 
-    Ok(())
-}
+    if report.report_schema == ReportSchema::V1 {
+        warn!("--report-schema v1 is deprecated and will be removed. It writes amounts through f32 and does not sort accounts; move to v2.");
+    }
 
-fn main() {
-    let args = Args::parse();
+    // Arrow is a binary format, so it cannot share stdout with the diagnostics
+    #[cfg(feature = "arrow")]
+    if report.format == payment_engine::args::OutputFormat::Arrow && (report.output.is_none() || report.totals_row) {
+        error!("--format arrow needs --output and does not support --totals-row.");
+        process::exit(EXIT_INPUT);
+    }
+
+    // The annotated copy is written by reading the input a second time
+    if args.processing.annotate_out.is_some() && (args.processing.input_format != InputFormat::Csv || inputs.len() > 1 || inputs.iter().any(|input| input == STDIN_INPUT || remote::is_url(input))) {
+        error!("--annotate-out needs a single CSV input file.");
+        process::exit(EXIT_INPUT);
+    }
+
+    if report.streaming_output && args.processing.assume_grouped_by.is_none() {
+        error!("--streaming-output needs --assume-grouped-by client.");
+        process::exit(EXIT_INPUT);
+    }
+
+    // Decimal carries at most 28 decimal places, so a finer precision could only ever add zeros
+    if report.precision > MAX_PRECISION {
+        error!("--precision can be at most {}.", MAX_PRECISION);
+        process::exit(EXIT_INPUT);
+    }
+
+    // A dry run writes no accounts, so asking for them somewhere is a mistake
+    if report.dry_run && (report.output.is_some() || report.streaming_output || report.snapshot_out.is_some()) {
+        error!("--dry-run writes no accounts, so it does not support --output, --streaming-output or --snapshot-out.");
+        process::exit(EXIT_INPUT);
+    }
+
+    // Streamed accounts are dropped from the state, so the snapshot would miss them
+    if report.streaming_output && report.snapshot_out.is_some() {
+        error!("--snapshot-out does not support --streaming-output.");
+        process::exit(EXIT_INPUT);
+    }
+
+    if args.processing.checkpoint_every.is_some() != args.processing.checkpoint_out.is_some() {
+        error!("--checkpoint-every and --checkpoint-out need each other.");
+        process::exit(EXIT_INPUT);
+    }
+    // Streaming writes out every account of a client at once, which does not know about currencies
+    if report.streaming_output && args.processing.multi_currency {
+        error!("--multi-currency does not support --streaming-output.");
+        process::exit(EXIT_INPUT);
+    }
+
+    // Like the snapshot, a checkpoint would miss the accounts already streamed out
+    if report.streaming_output && args.processing.checkpoint_every.is_some() {
+        error!("--checkpoint-every does not support --streaming-output.");
+        process::exit(EXIT_INPUT);
+    }
+
+    let policy = match args.processing.policy() {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(exit_status(&e));
+        }
+    };
 
-    let clients = match open_and_read_csv(args.csv_file) {
-        Ok(c) => c,
+    // Streamed accounts go out as the input moves past them, the rest follow once the input is done
+    let mut stream_sink = None;
+    if report.streaming_output {
+        match run::open_report(&report) {
+            Ok(sink) => stream_sink = Some(sink),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(exit_status(&e));
+            }
+        }
+    }
+    let meta = RunMeta { schema: report.report_schema, precision: report.precision, scales: policy.currency_scales().clone(), rows: None, accounts: None, currencies: false, totals: report.totals_row };
+    let mut stream = match stream_sink.as_deref_mut().map(|sink| ReportWriter::begin(sink, &run::report_options(&report, policy.currency_scales()), &meta)).transpose() {
+        Ok(stream) => stream,
         Err(e) => {
-            println!("Error while parsing CSV: {:?}", e);
-            process::exit(-1);
+            error!("{}", e);
+            process::exit(exit_status(&e));
         }
     };
-    
-    match write_to_csv(clients) {
-        Ok(_) => (),
-        Err(e) => println!("Error: {}",e),
+
+    let state = match process_input(&inputs, &args.processing, &policy, None, stream.as_mut()) {
+        Ok(s) => s,
+        Err(e) => {
+            if let EngineError::Invariant(_) = e {
+                error!("{}", e);
+            } else {
+                error!("could not process input: {}", e);
+            }
+            if let Some(path) = &args.processing.diagnostics_json {
+                let written = diagnostics::Diagnostics::append(path)
+                    .and_then(|mut out| out.write(Severity::Error, exit_code_name(exit_status(&e)), None, None, &e.to_string()));
+                if let Err(e) = written {
+                    error!("could not write diagnostics to {}: {}", path, e);
+                }
+            }
+            // process::exit skips destructors, so a partly streamed report file is removed here
+            drop(stream_sink);
+            process::exit(exit_status(&e));
+        }
+    };
+
+    // The next run depends on the snapshot, so failing to write it fails this one
+    if let Some(path) = &report.snapshot_out {
+        if let Err(e) = snapshot::save(&state.engine, report.report_schema, path) {
+            error!("could not write snapshot {}: {}", path, e);
+            process::exit(exit_status(&e));
+        }
+    }
+
+    run::write_reports(&state, &inputs, &args.processing, &report);
+    if let Some(max) = report.max_dangling_refs {
+        if state.dangling_refs() as u64 > max {
+            error!("{} rows reference unknown transactions, more than the --max-dangling-refs limit of {}.", state.dangling_refs(), max);
+            drop(stream_sink);
+            process::exit(EXIT_INPUT);
+        }
+    }
+
+    if report.dry_run {
+        let issues = run::print_issues(&state, &args.processing);
+        run::print_done(&state);
+        if issues > 0 {
+            process::exit(EXIT_ISSUES);
+        }
+        return;
+    }
+
+    // A report that could not be written in full fails the run
+    let result = match stream {
+        Some(mut writer) => report::write_accounts(&mut writer, state.engine.accounts(), report.report_schema).and_then(|_| writer.finish()),
+        None => run::write_report(&state, &report),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        drop(stream_sink);
+        process::exit(exit_status(&e));
+    }
+    run::print_done(&state);
+
+    // The report is complete either way, the status only tells a pipeline to stop before settling it
+    if report.fail_on_locked && state.engine.locked_clients().len() > 0 {
+        let clients: Vec<String> = state.engine.locked_clients().map(|client| client.to_string()).collect();
+        error!("chargebacks locked the accounts of clients {}.", clients.join(","));
+        process::exit(EXIT_LOCKED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use payment_engine::Rejection;
+
+    #[test]
+    fn exit_status_follows_the_error() {
+        let cases = [
+            (EngineError::Io(io::Error::new(io::ErrorKind::NotFound, "gone")), EXIT_IO),
+            (EngineError::Storage("spill file is gone".to_string()), EXIT_IO),
+            (EngineError::parse(Some(3), "bad row"), EXIT_INPUT),
+            (EngineError::UnknownTransactionType("transfer".to_string()), EXIT_INPUT),
+            (EngineError::Invalid("bad flag".to_string()), EXIT_INPUT),
+            (EngineError::InsufficientFunds { client: 1, tx: 2 }, EXIT_INPUT),
+            (EngineError::AccountLocked { client: 1 }, EXIT_INPUT),
+            (EngineError::TxNotFound(2), EXIT_INPUT),
+            (EngineError::ClientMismatch { tx: 2, expected: 1, got: 3 }, EXIT_INPUT),
+            (EngineError::Rejected(Rejection::DuplicateTransaction), EXIT_INPUT),
+            (EngineError::Invariant("held went negative".to_string()), EXIT_INVARIANT),
+        ];
+        for (error, status) in cases {
+            assert_eq!(exit_status(&error), status, "{:?}", error);
+        }
     }
-    
 }
@@ -0,0 +1,200 @@
+// Several CSV inputs merged into one sequence by their timestamp column, read with --merge-by timestamp.
+//
+// Each input is read on a thread of its own into a queue of at most LOOK_AHEAD rows, so an input that runs ahead cannot
+// fill memory while another catches up. The merge applies the earliest of the next rows of all inputs, and of equal
+// timestamps the one from the input given first. Rows without a timestamp, rows that do not parse and rows skipped by
+// the client filter go out as soon as they are next in their input, as --sort-by-timestamp puts them first.
+//
+// The merge waits for an input that has no row ready, since that row may be the earliest. With --merge-max-skew it waits
+// that many seconds at most: the input is then stalled and the others are merged without it until it has rows again.
+// A row of it that is older than rows already applied is applied late, with a warning.
+
+use crate::heartbeat::ByteCounter;
+use crate::args::ProcessingArgs;
+use crate::run::{decompress, input_error, open_input, read_csv, BadRow, IgnoredRow, Rows};
+use crate::EngineError;
+use chrono::{DateTime, FixedOffset};
+use log::warn;
+use crate::TransactionRow;
+use std::error::Error;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+// Rows read ahead of the merge from each input
+const LOOK_AHEAD: usize = 1024;
+
+// How long the merge sleeps on one stalled input when no input has a row ready
+const POLL: Duration = Duration::from_millis(10);
+
+// A row as the reading thread hands it over, with the bytes of its input read so far
+type Row = Result<Option<TransactionRow>, Box<dyn Error + Send>>;
+
+struct Source {
+    input: String,
+    rows: Receiver<(Row, u64)>,
+    // The next row of the input, once it has been received
+    next: Option<(Row, u64)>,
+    // Bytes of the input counted for the rows already merged
+    counted: u64,
+    open: bool,
+    stalled: bool,
+}
+
+impl Source {
+    // This function receives the input's next row if it has none yet, waiting as long as the merge waits for an input
+    fn fill(&mut self, max_skew: Option<Duration>) {
+        if self.next.is_some() || !self.open {
+            return;
+        }
+        let received = match max_skew {
+            None => self.rows.recv().map_err(|_| TryRecvError::Disconnected),
+            Some(_) if self.stalled => self.rows.try_recv(),
+            Some(wait) => self.rows.recv_timeout(wait).map_err(|e| match e {
+                RecvTimeoutError::Timeout => TryRecvError::Empty,
+                RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+            }),
+        };
+        self.receive(received, max_skew);
+    }
+
+    // This function waits a little for a stalled input, for when no input has a row ready
+    fn poll(&mut self, max_skew: Option<Duration>) {
+        if self.next.is_none() && self.open {
+            let received = self.rows.recv_timeout(POLL).map_err(|e| match e {
+                RecvTimeoutError::Timeout => TryRecvError::Empty,
+                RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+            });
+            self.receive(received, max_skew);
+        }
+    }
+
+    fn receive(&mut self, received: Result<(Row, u64), TryRecvError>, max_skew: Option<Duration>) {
+        match received {
+            Ok(row) => {
+                if self.stalled {
+                    warn!("merge: {} has rows again.", self.input);
+                    self.stalled = false;
+                }
+                self.next = Some(row);
+            },
+            Err(TryRecvError::Empty) => {
+                if !self.stalled {
+                    warn!("merge: {} had no row for {}s, merging the other inputs without it.", self.input, max_skew.unwrap_or_default().as_secs());
+                    self.stalled = true;
+                }
+            },
+            Err(TryRecvError::Disconnected) => self.open = false,
+        }
+    }
+
+    // This function returns the key the input's next row is merged by. Rows without a timestamp come first
+    fn key(&self) -> Option<Option<DateTime<FixedOffset>>> {
+        let (row, _) = self.next.as_ref()?;
+        Some(row.as_ref().ok().and_then(Option::as_ref).and_then(|transaction| transaction.timestamp))
+    }
+}
+
+struct Merge {
+    sources: Vec<Source>,
+    max_skew: Option<Duration>,
+    bytes: ByteCounter,
+    // The latest timestamp merged so far
+    latest: Option<DateTime<FixedOffset>>,
+}
+
+impl Iterator for Merge {
+    type Item = Result<Option<TransactionRow>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for source in &mut self.sources {
+                source.fill(self.max_skew);
+            }
+            // The earliest key wins, and min_by_key keeps the first of equal ones, so ties go to the input given first
+            let earliest = self.sources.iter().enumerate().filter_map(|(index, source)| source.key().map(|key| (key, index))).min_by_key(|&(key, _)| key);
+            let Some((timestamp, index)) = earliest else {
+                if !self.sources.iter().any(|source| source.open) {
+                    return None;
+                }
+                for source in &mut self.sources {
+                    source.poll(self.max_skew);
+                }
+                continue;
+            };
+            let source = self.sources.get_mut(index)?;
+            let (row, read) = source.next.take()?;
+            self.bytes.add(read.saturating_sub(source.counted));
+            source.counted = read;
+            if let Some(timestamp) = timestamp {
+                match self.latest {
+                    Some(latest) if timestamp < latest => warn!("merge: a row of {} at {} is older than rows already applied, applying it late.", source.input, timestamp),
+                    _ => self.latest = Some(timestamp),
+                }
+            }
+            return Some(row.map_err(|e| e as Box<dyn Error>));
+        }
+    }
+}
+
+// This function opens every input and starts reading each on its own thread, returning their rows merged by timestamp.
+// All inputs are opened before any row is read, so a missing one stops the run before any row is applied
+pub(crate) fn by_timestamp(inputs: &[String], args: &ProcessingArgs, bytes: &ByteCounter) -> Result<Rows<'static>, EngineError> {
+    let mut files = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        files.push((input.clone(), open_input(Path::new(input))?));
+    }
+
+    let mut sources = Vec::with_capacity(files.len());
+    for (input, file) in files {
+        let (sender, rows) = mpsc::sync_channel(LOOK_AHEAD);
+        // The reading thread parses with its own copy of the flags that parsing looks at
+        let options = ProcessingArgs {
+            compression: args.compression,
+            thousands_separator: args.thousands_separator,
+            allow_comments: args.allow_comments,
+            multi_currency: args.multi_currency,
+            clients: args.clients.clone(),
+            ..ProcessingArgs::default()
+        };
+        let path = input.clone();
+        thread::spawn(move || {
+            let read = ByteCounter::default();
+            let reader = match decompress(file, options.compression, Some(Path::new(&path))) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    let _ = sender.send((Err(Box::new(e) as Box<dyn Error + Send>), 0));
+                    return;
+                },
+            };
+            // The merge stops receiving when the run ends early, which ends this thread too
+            for row in read_csv(reader, options.clients.as_ref(), &options, &read) {
+                if sender.send((row.map_err(sendable), read.get())).is_err() {
+                    return;
+                }
+            }
+        });
+        sources.push(Source { input, rows, next: None, counted: 0, open: true, stalled: false });
+    }
+
+    Ok(Box::new(Merge {
+        sources,
+        max_skew: args.merge_max_skew.map(Duration::from_secs),
+        bytes: bytes.clone(),
+        latest: None,
+    }))
+}
+
+// This function turns an error reading a row into one that can be handed to the merge, keeping the kinds that let the
+// run skip the row
+fn sendable(e: Box<dyn Error>) -> Box<dyn Error + Send> {
+    let e = match e.downcast::<BadRow>() {
+        Ok(bad) => return bad,
+        Err(e) => e,
+    };
+    match e.downcast::<IgnoredRow>() {
+        Ok(ignored) => ignored,
+        Err(e) => Box::new(input_error(e, None)),
+    }
+}
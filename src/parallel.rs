@@ -0,0 +1,120 @@
+// The --verify-parallel check, a self-check of ShardedEngine for canary runs before it is trusted with production input.
+//
+// The serial run keeps every row it hands to its engine, so the input is parsed once. Those rows are then applied again
+// to a ShardedEngine started from the same snapshot and opening balances, from one thread per shard, each thread taking
+// the rows of the clients in its shard in input order. The accounts both runs leave are compared field by field, and
+// every client whose account differs, or that only one run has, is logged with what differs, e.g.
+//
+//   client 7: available is 12.5000 serially, 10.0000 in parallel
+//
+// before the run stops. Rows of different clients are applied in whatever order the threads reach them, so an input
+// in which clients race for the same tx id may fail the check although neither engine is wrong about its own order.
+
+use crate::money::Places;
+use crate::args::ProcessingArgs;
+use crate::run::start_engine;
+use log::{error, info};
+use crate::{AccountSummary, Currency, Engine, EngineError, EnginePolicy, ShardedEngine, TransactionRow};
+use std::collections::{BTreeMap, BTreeSet};
+use std::num::NonZeroUsize;
+use std::thread;
+
+// A client and, for inputs that name currencies, the currency of one of its accounts
+type Account = (u16, Option<Currency>);
+
+// At least two threads, so the check runs rows side by side even on a single core
+const MIN_THREADS: usize = 2;
+
+// This function applies the rows again on a ShardedEngine and compares its accounts with the serial engine's. It fails
+// with an invariant error naming how many accounts differ
+pub(crate) fn verify(args: &ProcessingArgs, policy: &EnginePolicy, rows: Vec<TransactionRow>, serial: &Engine) -> Result<(), EngineError> {
+    let threads = thread::available_parallelism().map_or(MIN_THREADS, NonZeroUsize::get).max(MIN_THREADS);
+    let shards = NonZeroUsize::new(threads).ok_or_else(|| EngineError::Invariant("no thread to verify with".to_string()))?;
+    let sharded = ShardedEngine::new(start_engine(args, policy)?, shards)?;
+
+    // Split the same way as the shards, so no two threads ever wait for the same shard
+    let mut parts: Vec<Vec<TransactionRow>> = (0..threads).map(|_| Vec::new()).collect();
+    for row in rows {
+        if let Some(part) = parts.get_mut(usize::from(row.client_id) % threads) {
+            part.push(row);
+        }
+    }
+    thread::scope(|scope| {
+        let workers: Vec<_> = parts.iter().map(|part| scope.spawn(|| apply(&sharded, part))).collect();
+        workers.into_iter().try_for_each(|worker| worker.join().unwrap_or_else(|_| Err(EngineError::Invariant("a --verify-parallel thread panicked".to_string()))))
+    })?;
+
+    let mut parallel = sharded.to_engine()?;
+    if let Some(client_id) = args.verify_parallel_divergence {
+        for client in parallel.accounts_mut().filter(|client| client.client_id == client_id) {
+            client.locked = !client.locked;
+        }
+    }
+
+    let serial = summaries(serial);
+    let parallel = summaries(&parallel);
+    let accounts: BTreeSet<Account> = serial.keys().chain(parallel.keys()).copied().collect();
+    let mut diverged = 0;
+    for account in &accounts {
+        let differences = match (serial.get(account), parallel.get(account)) {
+            (Some(serial), Some(parallel)) => differences(serial, parallel),
+            (Some(_), None) => vec!["only the serial run has the account".to_string()],
+            (None, Some(_)) => vec!["only the parallel run has the account".to_string()],
+            (None, None) => Vec::new(),
+        };
+        if !differences.is_empty() {
+            diverged += 1;
+            match account {
+                (client, Some(currency)) => error!(client = *client; "client {} {}: {}", client, currency.as_str(), differences.join("; ")),
+                (client, None) => error!(client = *client; "client {}: {}", client, differences.join("; ")),
+            }
+        }
+    }
+
+    if diverged > 0 {
+        return Err(EngineError::Invariant(format!("--verify-parallel: {} of {} accounts differ between the serial run and {} threads", diverged, accounts.len(), threads)));
+    }
+    info!("--verify-parallel: all {} accounts match between the serial run and {} threads", accounts.len(), threads);
+    Ok(())
+}
+
+// This function applies one thread's rows in order. Rejected rows are part of the run, as they are for the serial engine
+fn apply(sharded: &ShardedEngine, rows: &[TransactionRow]) -> Result<(), EngineError> {
+    for row in rows {
+        if let Err(e) = sharded.process(row) {
+            if e.rejection().is_none() {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn summaries(engine: &Engine) -> BTreeMap<Account, AccountSummary> {
+    engine.accounts().map(|client| ((client.client_id, client.currency), AccountSummary::from(client))).collect()
+}
+
+// This function describes every field in which the parallel run's account differs from the serial run's
+fn differences(serial: &AccountSummary, parallel: &AccountSummary) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (name, serial, parallel) in [
+        ("available", serial.available, parallel.available),
+        ("held", serial.held, parallel.held),
+        ("total", serial.total, parallel.total),
+    ] {
+        if serial != parallel {
+            differences.push(format!("{} is {} serially, {} in parallel", name, Places(serial, 4), Places(parallel, 4)));
+        }
+    }
+    if serial.debt != parallel.debt {
+        let debt = |debt: Option<_>| debt.map_or("untracked".to_string(), |debt| Places(debt, 4).to_string());
+        differences.push(format!("debt is {} serially, {} in parallel", debt(serial.debt), debt(parallel.debt)));
+    }
+    if serial.locked != parallel.locked {
+        differences.push(format!("locked is {} serially, {} in parallel", serial.locked, parallel.locked));
+    }
+    if serial.chargebacks != parallel.chargebacks {
+        differences.push(format!("chargebacks is {} serially, {} in parallel", serial.chargebacks, parallel.chargebacks));
+    }
+    differences
+}
@@ -0,0 +1,205 @@
+// A Storage that keeps the engine's state in PostgreSQL, for `serve --postgres-url`. Built with the postgres feature.
+//
+// The tables are created when they are missing:
+//
+//     accounts      client, currency, available, held, total, locked, and the whole account as JSON in `state`
+//     records       tx, client, currency, kind, amount, state: the stored deposits, withdrawals and opening balances
+//     admin_holds   tx, client, currency, amount
+//
+// A currency of '' is an account or record without one. The columns other than `state` are there for people querying
+// the tables, loading reads accounts back from `state` alone, which carries their open disputes as well.
+//
+// Each Change is written in one database transaction. A connection that fails or drops surfaces as EngineError::Storage,
+// and the client does not reconnect by itself: the caller connects again, and loads again if it cannot tell whether
+// the last change was written.
+
+use crate::money::Money;
+use crate::storage::{Change, Storage, Stored};
+use crate::{AdminHold, Client, Currency, EngineError, Record, RecordKind, RecordState};
+use ::postgres::{Config, NoTls, Row, Statement};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available NUMERIC NOT NULL,
+        held NUMERIC NOT NULL,
+        total NUMERIC NOT NULL,
+        locked BOOLEAN NOT NULL,
+        state JSONB NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    CREATE TABLE IF NOT EXISTS records (
+        tx BIGINT PRIMARY KEY,
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        amount NUMERIC NOT NULL,
+        state TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS admin_holds (
+        tx BIGINT PRIMARY KEY,
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        amount NUMERIC NOT NULL
+    );
+";
+
+// The statements a Change is written with, prepared once per connection
+struct Statements {
+    save_account: Statement,
+    save_record: Statement,
+    delete_record: Statement,
+    save_hold: Statement,
+    delete_hold: Statement,
+}
+
+pub struct PostgresStorage {
+    client: ::postgres::Client,
+    statements: Statements,
+}
+
+impl PostgresStorage {
+    // This function connects to a database by URL or key=value connection string, and creates the tables if they are missing
+    pub fn connect(url: &str) -> Result<PostgresStorage, EngineError> {
+        let config: Config = url.parse().map_err(|e| EngineError::Invalid(format!("invalid PostgreSQL connection string: {}", e)))?;
+        PostgresStorage::with_config(&config)
+    }
+
+    // This function is connect for a connection that is already configured, e.g. with a search_path in its options
+    pub fn with_config(config: &Config) -> Result<PostgresStorage, EngineError> {
+        let mut client = config.connect(NoTls).map_err(failed)?;
+        client.batch_execute(SCHEMA).map_err(failed)?;
+        let statements = Statements {
+            save_account: client.prepare(
+                "INSERT INTO accounts (client, currency, available, held, total, locked, state)
+                 VALUES ($1, $2, $3::text::numeric, $4::text::numeric, $5::text::numeric, $6, $7::text::jsonb)
+                 ON CONFLICT (client, currency) DO UPDATE SET available = EXCLUDED.available, held = EXCLUDED.held,
+                 total = EXCLUDED.total, locked = EXCLUDED.locked, state = EXCLUDED.state",
+            ).map_err(failed)?,
+            save_record: client.prepare(
+                "INSERT INTO records (tx, client, currency, kind, amount, state) VALUES ($1, $2, $3, $4, $5::text::numeric, $6)
+                 ON CONFLICT (tx) DO UPDATE SET client = EXCLUDED.client, currency = EXCLUDED.currency, kind = EXCLUDED.kind,
+                 amount = EXCLUDED.amount, state = EXCLUDED.state",
+            ).map_err(failed)?,
+            delete_record: client.prepare("DELETE FROM records WHERE tx = $1").map_err(failed)?,
+            save_hold: client.prepare(
+                "INSERT INTO admin_holds (tx, client, currency, amount) VALUES ($1, $2, $3, $4::text::numeric)
+                 ON CONFLICT (tx) DO UPDATE SET client = EXCLUDED.client, currency = EXCLUDED.currency, amount = EXCLUDED.amount",
+            ).map_err(failed)?,
+            delete_hold: client.prepare("DELETE FROM admin_holds WHERE tx = $1").map_err(failed)?,
+        };
+        Ok(PostgresStorage { client, statements })
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn load(&mut self) -> Result<Stored, EngineError> {
+        let mut stored = Stored::default();
+        for row in self.client.query("SELECT state::text FROM accounts", &[]).map_err(failed)? {
+            let state: String = row.try_get(0).map_err(failed)?;
+            let client: Client = serde_json::from_str(&state).map_err(|e| EngineError::Storage(format!("PostgreSQL: an account cannot be read: {}", e)))?;
+            stored.accounts.push(client);
+        }
+        for row in self.client.query("SELECT tx, client, currency, kind, amount::text, state FROM records", &[]).map_err(failed)? {
+            let record = Record {
+                state: parse_state(&row.try_get::<_, String>(5).map_err(failed)?)?,
+                currency: currency(&row)?,
+                ..Record::new(parse_kind(&row.try_get::<_, String>(3).map_err(failed)?)?, client_id(&row)?, amount(&row, 4)?)
+            };
+            stored.records.push((transaction_id(&row)?, record));
+        }
+        for row in self.client.query("SELECT tx, client, currency, amount::text FROM admin_holds", &[]).map_err(failed)? {
+            let hold = AdminHold { client_id: client_id(&row)?, amount: amount(&row, 3)?, currency: currency(&row)? };
+            stored.admin_holds.push((transaction_id(&row)?, hold));
+        }
+        Ok(stored)
+    }
+
+    fn save(&mut self, change: &Change) -> Result<(), EngineError> {
+        let statements = &self.statements;
+        let mut transaction = self.client.transaction().map_err(failed)?;
+        for account in &change.accounts {
+            let state = serde_json::to_string(account).map_err(|e| EngineError::Storage(format!("PostgreSQL: an account cannot be written: {}", e)))?;
+            transaction.execute(&statements.save_account, &[
+                &i32::from(account.client_id), &currency_name(account.currency), &account.available.to_string(),
+                &account.held.to_string(), &account.total.to_string(), &account.locked, &state,
+            ]).map_err(failed)?;
+        }
+        for (transaction_id, record) in &change.records {
+            let tx = i64::from(*transaction_id);
+            match record {
+                Some(record) => transaction.execute(&statements.save_record, &[
+                    &tx, &i32::from(record.client_id), &currency_name(record.currency), &kind_name(record.kind),
+                    &record.amount.to_string(), &record.state.name(),
+                ]),
+                None => transaction.execute(&statements.delete_record, &[&tx]),
+            }.map_err(failed)?;
+        }
+        for (transaction_id, hold) in &change.admin_holds {
+            let tx = i64::from(*transaction_id);
+            match hold {
+                Some(hold) => transaction.execute(&statements.save_hold, &[&tx, &i32::from(hold.client_id), &currency_name(hold.currency), &hold.amount.to_string()]),
+                None => transaction.execute(&statements.delete_hold, &[&tx]),
+            }.map_err(failed)?;
+        }
+        transaction.commit().map_err(failed)
+    }
+}
+
+// This function turns a database error into an engine error, with the cause the error itself leaves out, e.g. why a
+// connection was refused or which constraint a write broke
+fn failed(e: ::postgres::Error) -> EngineError {
+    match std::error::Error::source(&e) {
+        Some(cause) => EngineError::Storage(format!("PostgreSQL: {}: {}", e, cause)),
+        None => EngineError::Storage(format!("PostgreSQL: {}", e)),
+    }
+}
+
+fn unreadable(what: &str, value: impl std::fmt::Display) -> EngineError {
+    EngineError::Storage(format!("PostgreSQL: invalid {} {:?} in the database", what, value.to_string()))
+}
+
+fn currency_name(currency: Option<Currency>) -> String {
+    currency.map(|currency| currency.to_string()).unwrap_or_default()
+}
+
+fn kind_name(kind: RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Deposit => "deposit",
+        RecordKind::Withdrawal => "withdrawal",
+        RecordKind::OpeningBalance => "opening_balance",
+    }
+}
+
+fn parse_kind(name: &str) -> Result<RecordKind, EngineError> {
+    [RecordKind::Deposit, RecordKind::Withdrawal, RecordKind::OpeningBalance].into_iter().find(|&kind| kind_name(kind) == name).ok_or_else(|| unreadable("record kind", name))
+}
+
+fn parse_state(name: &str) -> Result<RecordState, EngineError> {
+    [RecordState::Normal, RecordState::Disputed, RecordState::Resolved, RecordState::ChargedBack].into_iter().find(|&state| state.name() == name).ok_or_else(|| unreadable("record state", name))
+}
+
+// This function reads the tx column of records and admin_holds
+fn transaction_id(row: &Row) -> Result<u32, EngineError> {
+    let tx: i64 = row.try_get("tx").map_err(failed)?;
+    u32::try_from(tx).map_err(|_| unreadable("tx id", tx))
+}
+
+fn client_id(row: &Row) -> Result<u16, EngineError> {
+    let client: i32 = row.try_get("client").map_err(failed)?;
+    u16::try_from(client).map_err(|_| unreadable("client id", client))
+}
+
+fn currency(row: &Row) -> Result<Option<Currency>, EngineError> {
+    let code: String = row.try_get("currency").map_err(failed)?;
+    if code.is_empty() {
+        return Ok(None);
+    }
+    code.parse().map(Some).map_err(|_| unreadable("currency", code))
+}
+
+fn amount(row: &Row, column: usize) -> Result<Money, EngineError> {
+    let text: String = row.try_get(column).map_err(failed)?;
+    text.parse().map_err(|_| unreadable("amount", text))
+}
@@ -0,0 +1,218 @@
+// CSV inputs read over HTTP, given as an input named http://host[:port]/path.
+//
+// The response body is streamed into the CSV parser as it arrives, without being stored on disk. A download thread reads
+// the body in chunks and hands them to the parser through a bounded channel, so the network and the engine work at the
+// same time while at most a few chunks are held in memory. The engine itself still applies one row at a time on the
+// main thread.
+//
+// If the connection drops part way through the body, the download is resumed once with a Range request from the first
+// byte not yet received. A second failure, or a server that does not answer the Range request with the missing bytes,
+// stops the run with an error naming the byte the body broke off at.
+//
+// Plain HTTP only: there is no TLS in this build. https:// inputs are still told apart from files, so that they are
+// refused up front with an error saying why rather than looked for on disk.
+
+use log::warn;
+use crate::EngineError;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+const SCHEME: &str = "http://";
+const TLS_SCHEME: &str = "https://";
+
+// Size of the body chunks passed to the parser, and how many may wait in the channel
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+// How long a read may wait for the server before the connection counts as dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// This function tells whether an input names a URL rather than a file
+pub fn is_url(input: &str) -> bool {
+    input.starts_with(SCHEME) || input.starts_with(TLS_SCHEME)
+}
+
+// The parts of an http URL a request needs
+#[derive(Debug, Clone)]
+struct Url {
+    text: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(text: &str) -> Result<Url, String> {
+        if text.starts_with(TLS_SCHEME) {
+            return Err(format!("cannot read {}: https:// URLs are not supported, this build has no TLS. Serve the input over http:// or download it first", text));
+        }
+        let rest = text.strip_prefix(SCHEME).ok_or_else(|| format!("cannot read {}: not an http:// URL", text))?;
+        let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("invalid port in {}", text))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {}", text));
+        }
+        Ok(Url { text: text.to_string(), host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+// The body of an HTTP response, as the download thread passes it on
+pub(crate) struct RemoteReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.offset = 0;
+                },
+                // The download thread is done and has sent everything
+                Err(_) => return Ok(0),
+            }
+        }
+        let rest = self.chunk.get(self.offset..).unwrap_or_default();
+        let n = rest.len().min(buf.len());
+        if let (Some(to), Some(from)) = (buf.get_mut(..n), rest.get(..n)) {
+            to.copy_from_slice(from);
+        }
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+// This function checks that a URL can be read, e.g. that it is not an https:// URL, without connecting to it
+pub(crate) fn check(input: &str) -> Result<(), EngineError> {
+    Url::parse(input)?;
+    Ok(())
+}
+
+// This function requests a URL and returns its body as a reader. The response headers are read before it returns, so a
+// server that cannot be reached or answers with an error stops the run before any row is applied
+pub(crate) fn open(input: &str) -> Result<RemoteReader, EngineError> {
+    let url = Url::parse(input)?;
+    let response = Response::request(&url, 0)?;
+
+    let (sender, chunks) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+    thread::spawn(move || download(url, response, sender));
+    Ok(RemoteReader { chunks, chunk: Vec::new(), offset: 0 })
+}
+
+// A response whose headers have been read, positioned at the start of its body
+struct Response {
+    body: BufReader<TcpStream>,
+    // Body bytes still to come, when the server said how many
+    remaining: Option<u64>,
+}
+
+impl Response {
+    // This function sends a GET for the URL from byte `from` of the body on, and reads the response headers
+    fn request(url: &Url, from: u64) -> io::Result<Response> {
+        let failed = |message: String| io::Error::other(format!("request for {} failed: {}", url.text, message));
+
+        let mut stream = TcpStream::connect((url.host.as_str(), url.port)).map_err(|e| io::Error::new(e.kind(), format!("could not connect to {}: {}", url.text, e)))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let range = if from > 0 { format!("Range: bytes={}-\r\n", from) } else { String::new() };
+        // HTTP/1.0 keeps the body free of chunked encoding, and the server closes the connection after it
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n{}Accept-Encoding: identity\r\n\r\n", url.path, url.host, range)?;
+        stream.flush()?;
+
+        let mut body = BufReader::new(stream);
+        let mut line = String::new();
+        body.read_line(&mut line)?;
+        let status = line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok()).ok_or_else(|| failed(format!("invalid status line {:?}", line.trim_end())))?;
+        let expected = if from > 0 { 206 } else { 200 };
+        if status != expected {
+            return Err(failed(format!("server answered {}, expected {}", line.trim_end(), expected)));
+        }
+
+        let mut remaining = None;
+        let mut range_start = None;
+        loop {
+            line.clear();
+            if body.read_line(&mut line)? == 0 {
+                return Err(failed("connection closed in the response headers".to_string()));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                remaining = value.parse::<u64>().ok();
+            } else if name.eq_ignore_ascii_case("content-range") {
+                // bytes 1000-4999/5000
+                range_start = value.strip_prefix("bytes ").and_then(|range| range.split_once('-')).and_then(|(start, _)| start.parse::<u64>().ok());
+            }
+        }
+        if from > 0 && range_start != Some(from) {
+            return Err(failed(format!("server did not resume the body at byte {}", from)));
+        }
+        Ok(Response { body, remaining })
+    }
+}
+
+// This function reads the body into the channel, resuming it once if the connection drops. It stops early when the
+// parser has gone away
+fn download(url: Url, mut response: Response, sender: SyncSender<io::Result<Vec<u8>>>) {
+    let mut received: u64 = 0;
+    let mut resumed = false;
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let result = match response.body.read(&mut chunk) {
+            // A body that ends before its announced length was cut off
+            Ok(0) if response.remaining.is_some_and(|remaining| remaining > 0) => Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                received += n as u64;
+                response.remaining = response.remaining.map(|remaining| remaining.saturating_sub(n as u64));
+                if sender.send(Ok(chunk)).is_err() {
+                    return;
+                }
+            },
+            Err(e) if !resumed && is_transient(&e) => {
+                resumed = true;
+                warn!("{}: connection dropped after {} bytes of the body ({}), resuming", url.text, received, e);
+                match Response::request(&url, received) {
+                    Ok(next) => response = next,
+                    Err(e) => {
+                        let _ = sender.send(Err(broken(&url, received, &e)));
+                        return;
+                    },
+                }
+            },
+            Err(e) => {
+                let _ = sender.send(Err(broken(&url, received, &e)));
+                return;
+            },
+        }
+    }
+}
+
+// Connection failures that a new request may get past
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof | ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+fn broken(url: &Url, received: u64, e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("reading {} broke off at byte {} of the body: {}", url.text, received, e))
+}
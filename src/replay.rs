@@ -0,0 +1,312 @@
+// Replay files, written with --record and checked with the `replay` subcommand.
+//
+// A replay file is JSON lines: a header with the engine version and the policy the run used, one line per row that
+// reached the transaction handlers with the decision taken for it, and a footer with the row count and a hash of
+// the final accounts. Replaying feeds the recorded rows through the current engine at their original row numbers
+// and reports every row whose decision changed, plus whether the final accounts still hash the same.
+
+use crate::heartbeat::ByteCounter;
+use crate::money::{Money, Places};
+use crate::args::{BalanceCaps, ProcessingArgs, RecordRetention};
+use crate::run::{process_transactions, Outcome};
+use crate::{Client, EngineError, LockPolicy, Rejection, TransactionRow, TransactionType};
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+const FORMAT_VERSION: u32 = 1;
+
+// The flags that change decisions, as they were for the recorded run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedPolicy {
+    chargeback_fee: Option<Money>,
+    track_debt: bool,
+    allow_forced_hold: bool,
+    require_opening_balances: bool,
+    abort_on_negative_held: bool,
+    // In rows, see RecordRetention
+    record_retention: Option<u64>,
+    // Missing from recordings made before balance caps existed
+    #[serde(default)]
+    max_balance: Option<Money>,
+    // Missing from recordings made before disputes could wait for funds
+    #[serde(default)]
+    dispute_requires_funds: bool,
+    // Caps from --limits-file are recorded here along with the flag's, so a replay does not need the file
+    #[serde(default)]
+    client_max_balance: Option<String>,
+    // Missing from recordings made before chargebacks could leave the account open, which all locked it
+    #[serde(default)]
+    lock_policy: Option<String>,
+    // Missing from recordings made before monthly fees existed
+    #[serde(default)]
+    monthly_fee: Option<Money>,
+    #[serde(default)]
+    fee_final_partial_month: bool,
+    // Missing from recordings made before currencies could have their own decimal places
+    #[serde(default)]
+    currency_scales: Option<String>,
+}
+
+impl RecordedPolicy {
+    fn from_args(args: &ProcessingArgs) -> Result<RecordedPolicy, EngineError> {
+        let caps = args.client_caps()?;
+        Ok(RecordedPolicy {
+            chargeback_fee: args.chargeback_fee,
+            track_debt: args.track_debt,
+            allow_forced_hold: args.allow_forced_hold,
+            require_opening_balances: args.require_opening_balances,
+            abort_on_negative_held: args.abort_on_negative_held,
+            record_retention: args.record_retention.map(|retention| retention.rows),
+            max_balance: args.max_balance,
+            dispute_requires_funds: args.dispute_requires_funds,
+            client_max_balance: (!caps.is_empty()).then(|| BalanceCaps { caps }.to_string()),
+            lock_policy: (args.lock_policy != LockPolicy::Account).then(|| args.lock_policy.to_string()),
+            monthly_fee: args.monthly_fee,
+            fee_final_partial_month: args.fee_final_partial_month,
+            currency_scales: args.currency_scales.as_ref().map(ToString::to_string),
+        })
+    }
+
+    // This function sets every recorded flag that was not given on the command line, so the flags given to replay
+    // replace the recorded ones. The balance caps of --client-max-balance and --limits-file are replaced together
+    fn apply_to(&self, args: &mut ProcessingArgs, matches: Option<&ArgMatches>) -> Result<(), EngineError> {
+        let given = |flag: &str| matches.is_some_and(|matches| matches.occurrences_of(flag) > 0);
+        if !given("chargeback-fee") {
+            args.chargeback_fee = self.chargeback_fee;
+        }
+        if !given("monthly-fee") {
+            args.monthly_fee = self.monthly_fee;
+        }
+        if !given("fee-final-partial-month") {
+            args.fee_final_partial_month = self.fee_final_partial_month;
+        }
+        if !given("track-debt") {
+            args.track_debt = self.track_debt;
+        }
+        if !given("allow-forced-hold") {
+            args.allow_forced_hold = self.allow_forced_hold;
+        }
+        if !given("require-opening-balances") {
+            args.require_opening_balances = self.require_opening_balances;
+        }
+        if !given("abort-on-negative-held") {
+            args.abort_on_negative_held = self.abort_on_negative_held;
+        }
+        if !given("record-retention") {
+            args.record_retention = self.record_retention.map(|rows| RecordRetention { rows });
+        }
+        if !given("max-balance") {
+            args.max_balance = self.max_balance;
+        }
+        if !given("dispute-requires-funds") {
+            args.dispute_requires_funds = self.dispute_requires_funds;
+        }
+        if !given("client-max-balance") && !given("limits-file") {
+            args.client_max_balance = self.client_max_balance.as_deref().map(str::parse).transpose()?;
+        }
+        if !given("currency-scales") {
+            args.currency_scales = self.currency_scales.as_deref().map(str::parse).transpose().map_err(|e| EngineError::parse(None, e))?;
+        }
+        if !given("lock-policy") {
+            args.lock_policy = self.lock_policy.as_deref().map(str::parse).transpose().map_err(|e| EngineError::parse(None, e))?.unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Line {
+    Header {
+        format: u32,
+        engine_version: String,
+        policy: RecordedPolicy,
+    },
+    Row {
+        row: u64,
+        #[serde(rename = "type")]
+        transaction_type: TransactionType,
+        client: u16,
+        tx: u32,
+        amount: Option<Money>,
+        // "applied", or the reason the row was rejected
+        decision: String,
+    },
+    Footer {
+        rows: u64,
+        state_hash: String,
+    },
+}
+
+// This function describes a decision the way replay files store it
+fn decision(result: Result<(), Rejection>) -> String {
+    match result {
+        Ok(()) => "applied".to_string(),
+        Err(rejection) => rejection.to_string(),
+    }
+}
+
+// This function hashes the final accounts in client order with FNV-1a, so the hash does not depend on the build
+pub(crate) fn state_hash<'a>(clients: impl Iterator<Item = &'a Client>) -> String {
+    let mut clients: Vec<&Client> = clients.collect();
+    clients.sort_unstable_by_key(|client| client.client_id);
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for client in clients {
+        let amount = |value: Money| Places(value, 4).to_string();
+        let line = format!(
+            "{},{},{},{},{},{}\n",
+            client.client_id, amount(client.available), amount(client.held), amount(client.total), client.locked,
+            client.debt.map(amount).unwrap_or_default(),
+        );
+        for byte in line.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+// Writes a replay file while process_transactions runs
+pub(crate) struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &str, args: &ProcessingArgs) -> Result<Recorder, EngineError> {
+        let mut recorder = Recorder { out: BufWriter::new(File::create(path)?) };
+        recorder.write_line(&Line::Header {
+            format: FORMAT_VERSION,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            policy: RecordedPolicy::from_args(args)?,
+        })?;
+        Ok(recorder)
+    }
+
+    pub(crate) fn write(&mut self, row: u64, transaction: &TransactionRow, result: Result<(), Rejection>) -> Result<(), EngineError> {
+        self.write_line(&Line::Row {
+            row,
+            transaction_type: transaction.transaction_type,
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            amount: transaction.amount,
+            decision: decision(result),
+        })
+    }
+
+    pub(crate) fn finish<'a>(mut self, rows: u64, clients: impl Iterator<Item = &'a Client>) -> Result<(), EngineError> {
+        self.write_line(&Line::Footer { rows, state_hash: state_hash(clients) })?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &Line) -> Result<(), EngineError> {
+        serde_json::to_writer(&mut self.out, line)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+// A recording read back from a replay file
+struct Recording {
+    engine_version: String,
+    policy: RecordedPolicy,
+    // Row number, transaction and recorded decision, in input order
+    rows: Vec<(u64, TransactionRow, String)>,
+    total_rows: u64,
+    state_hash: String,
+}
+
+// This function reads a replay file, refusing truncated files and files from a newer format
+fn read_recording(path: &str) -> Result<Recording, EngineError> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let first = lines.next().ok_or("replay file is empty")??;
+    let (engine_version, policy) = match serde_json::from_str(&first)? {
+        Line::Header { format, engine_version, policy } if format <= FORMAT_VERSION => (engine_version, policy),
+        Line::Header { format, .. } => return Err(format!("replay file format {} is newer than this build supports", format).into()),
+        _ => return Err("replay file does not start with a header".into()),
+    };
+
+    let mut rows = Vec::new();
+    for line in lines {
+        match serde_json::from_str(&line?)? {
+            Line::Row { row, transaction_type, client, tx, amount, decision } => {
+                let transaction = TransactionRow {
+                    transaction_type,
+                    client_id: client,
+                    transaction_id: tx,
+                    amount,
+                    expected_held_total: None,
+                    timestamp: None,
+                    currency: None,
+                };
+                rows.push((row, transaction, decision));
+            },
+            Line::Footer { rows: total_rows, state_hash } => {
+                return Ok(Recording { engine_version, policy, rows, total_rows, state_hash });
+            },
+            Line::Header { .. } => return Err("replay file has more than one header".into()),
+        }
+    }
+    Err("replay file has no footer, the recorded run did not finish".into())
+}
+
+// This function replays the recording at `path` and prints what diverged. Returns whether the replay matched the
+// recording. `processing` holds the flags of the replay subcommand and `matches` its arguments, to tell flags given on
+// the command line from their defaults
+pub fn run(path: &str, processing: &mut ProcessingArgs, matches: Option<&ArgMatches>) -> Result<bool, EngineError> {
+    let recording = read_recording(path)?;
+
+    recording.policy.apply_to(processing, matches)?;
+
+    // Rows that never reached the handlers are fed as skipped rows so every row keeps its original number
+    let mut transactions: Vec<Result<Option<TransactionRow>, Box<dyn Error>>> = Vec::new();
+    let mut expected = HashMap::new();
+    for (row, transaction, decision) in recording.rows {
+        while (transactions.len() as u64) + 1 < row {
+            transactions.push(Ok(None));
+        }
+        expected.insert(row, decision);
+        transactions.push(Ok(Some(transaction)));
+    }
+    while (transactions.len() as u64) < recording.total_rows {
+        transactions.push(Ok(None));
+    }
+
+    // Row number and description of every row whose decision changed
+    let mut divergences: Vec<(u64, String)> = Vec::new();
+    let mut observe = |outcome: Outcome| {
+        let recorded = expected.remove(&outcome.row).unwrap_or_default();
+        let replayed = decision(outcome.result);
+        if recorded != replayed {
+            divergences.push((outcome.row, format!(
+                "{} {} for client {} was recorded as {:?} but replayed as {:?}",
+                outcome.transaction.transaction_type, outcome.transaction.transaction_id,
+                outcome.transaction.client_id, recorded, replayed,
+            )));
+        }
+    };
+    let policy = processing.policy()?;
+    let state = process_transactions(Box::new(transactions.into_iter()), &ByteCounter::default(), processing, &policy, Some(&mut observe), None)?;
+
+    let state_hash = state_hash(state.engine.accounts());
+    println!("Replaying {} recorded by engine {} on engine {}.", path, recording.engine_version, env!("CARGO_PKG_VERSION"));
+    for (row, divergence) in &divergences {
+        println!("Divergence at row {}: {}", row, divergence);
+    }
+    let hash_matches = state_hash == recording.state_hash && state.rows == recording.total_rows;
+    if !hash_matches {
+        println!("Divergence: final state hash is {}, recorded {}", state_hash, recording.state_hash);
+    }
+
+    match divergences.first() {
+        None if hash_matches => println!("Replay matched: {} rows, state hash {}.", recording.total_rows, state_hash),
+        None => println!("Replay diverged in the final state only."),
+        Some((row, _)) => println!("Replay diverged first at row {} ({} divergent rows).", row, divergences.len()),
+    }
+    Ok(divergences.is_empty() && hash_matches)
+}
@@ -0,0 +1,819 @@
+// The account report and the sinks it can be written to.
+//
+// ReportWriter applies the client filter and totals row once, then hands each account to a ReportSink, either at the
+// end of the run through write_report or during the run with --streaming-output. A sink only has to know how to write
+// rows in its format; CSV, JSON and the aligned table are implemented here and Arrow in arrow_io. Any error a sink returns ends the report and is
+// surfaced to the caller. How amounts are written and whether accounts are sorted depends on the ReportSchema.
+
+use crate::money::{self, Money, Places};
+use crate::args::{ClientFilter, ReportSchema};
+use crate::{AccountSummary, Client, Currency, CurrencyScales, EngineError};
+use csv::WriterBuilder;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+// What a sink is told about the run before the first account. When accounts are streamed during the run neither
+// count is known yet
+pub struct RunMeta {
+    pub schema: ReportSchema,
+    // Decimal places of every amount, unless the scales name others for the account's currency
+    pub precision: u32,
+    pub scales: CurrencyScales,
+    #[cfg_attr(not(feature = "arrow"), allow(dead_code))]
+    pub rows: Option<u64>,
+    #[cfg_attr(not(feature = "arrow"), allow(dead_code))]
+    pub accounts: Option<usize>,
+    // Whether accounts are kept by currency, which adds a currency column after the client
+    pub currencies: bool,
+    // Whether totals follow the accounts. The JSON report is laid out differently then, before its first account
+    pub totals: bool,
+}
+
+// The sums over every account written. The CSV and table sinks write it like an account row with TOTAL in the client
+// column, and the JSON sink as the totals field after the accounts. With currencies there is one for each, since amounts
+// in different currencies do not add up
+#[derive(Debug, Default)]
+pub struct TotalsView {
+    pub currency: Option<Currency>,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub debt: Option<Money>,
+    pub chargebacks: u64,
+}
+
+impl TotalsView {
+    // This function adds one account to the totals, refusing rather than wrapping on overflow
+    fn add(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+        let overflow = || "totals row would overflow";
+        self.available = self.available.checked_add(account.available).ok_or_else(overflow)?;
+        self.held = self.held.checked_add(account.held).ok_or_else(overflow)?;
+        self.total = self.total.checked_add(account.total).ok_or_else(overflow)?;
+        if let Some(debt) = account.debt {
+            let sum = self.debt.unwrap_or(Money::ZERO).checked_add(debt).ok_or_else(overflow)?;
+            self.debt = Some(sum);
+        }
+        self.chargebacks += u64::from(account.chargebacks);
+        Ok(())
+    }
+}
+
+// A destination for the account report
+pub trait ReportSink {
+    fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError>;
+
+    fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError>;
+
+    // Formats without a place for a totals row keep this default
+    fn write_totals(&mut self, _totals: &TotalsView) -> Result<(), EngineError> {
+        Err("this report format does not support a totals row".into())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError>;
+}
+
+// Which accounts are written, in which layout and precision, and whether a TOTAL row follows them
+#[derive(Debug)]
+pub struct ReportOptions {
+    pub schema: ReportSchema,
+    pub precision: u32,
+    // Decimal places by currency from --currency-scales, which replace the precision for those currencies
+    pub scales: CurrencyScales,
+    pub clients: Option<ClientFilter>,
+    pub totals_row: bool,
+}
+
+impl Default for ReportOptions {
+    fn default() -> ReportOptions {
+        ReportOptions { schema: ReportSchema::default(), precision: money::DECIMAL_PLACES, scales: CurrencyScales::default(), clients: None, totals_row: false }
+    }
+}
+
+// Feeds accounts to a sink one at a time, applying the options and keeping the totals row up to date
+pub struct ReportWriter<'a> {
+    sink: &'a mut dyn ReportSink,
+    clients: Option<ClientFilter>,
+    totals_row: bool,
+    // By currency, a single one keyed by None for accounts without
+    totals: BTreeMap<Option<Currency>, TotalsView>,
+}
+
+impl<'a> ReportWriter<'a> {
+    pub fn begin(sink: &'a mut dyn ReportSink, options: &ReportOptions, meta: &RunMeta) -> Result<ReportWriter<'a>, EngineError> {
+        sink.begin(meta)?;
+        Ok(ReportWriter {
+            sink,
+            clients: options.clients.clone(),
+            totals_row: options.totals_row,
+            totals: BTreeMap::new(),
+        })
+    }
+
+    // This function writes one account, unless the options leave it out
+    pub fn account(&mut self, client: &Client) -> Result<(), EngineError> {
+        if self.clients.as_ref().is_some_and(|clients| !clients.contains(client.client_id)) {
+            return Ok(());
+        }
+        let account = AccountSummary::from(client);
+        self.sink.write_account(&account)?;
+        let currency = account.currency;
+        self.totals.entry(currency).or_insert_with(|| TotalsView { currency, ..TotalsView::default() }).add(&account)
+    }
+
+    // This function writes the totals rows if asked for and finishes the sink. A report without accounts still gets one
+    pub fn finish(mut self) -> Result<(), EngineError> {
+        if self.totals_row {
+            if self.totals.is_empty() {
+                self.totals.insert(None, TotalsView::default());
+            }
+            for totals in self.totals.values() {
+                self.sink.write_totals(totals)?;
+            }
+        }
+        self.sink.finish()
+    }
+}
+
+// This function writes the accounts to the sink in the order the schema asks for
+pub fn write_report<'a>(clients: impl Iterator<Item = &'a Client>, rows: u64, options: &ReportOptions, sink: &mut dyn ReportSink) -> Result<(), EngineError> {
+    let clients: Vec<&Client> = clients.collect();
+    let mut writer = ReportWriter::begin(sink, options, &RunMeta {
+        schema: options.schema,
+        precision: options.precision,
+        scales: options.scales.clone(),
+        rows: Some(rows),
+        accounts: Some(options.clients.as_ref().map_or(clients.len(), |filter| clients.iter().filter(|client| filter.contains(client.client_id)).count())),
+        currencies: clients.iter().any(|client| client.currency.is_some()),
+        totals: options.totals_row,
+    })?;
+    write_accounts(&mut writer, clients, options.schema)?;
+    writer.finish()
+}
+
+// This function writes every account given, sorted by client id and then currency unless the schema is v1
+pub fn write_accounts<'a>(writer: &mut ReportWriter, clients: impl IntoIterator<Item = &'a Client>, schema: ReportSchema) -> Result<(), EngineError> {
+    let mut clients: Vec<&Client> = clients.into_iter().collect();
+    if schema != ReportSchema::V1 {
+        clients.sort_unstable_by_key(|client| client.account());
+    }
+    for client in clients {
+        writer.account(client)?;
+    }
+    Ok(())
+}
+
+// How a text report writes its amounts, and whether it has a currency column, taken from the RunMeta
+#[derive(Debug, Clone)]
+struct AmountFormat {
+    schema: ReportSchema,
+    precision: u32,
+    scales: CurrencyScales,
+    currencies: bool,
+}
+
+impl AmountFormat {
+    fn of(meta: &RunMeta) -> AmountFormat {
+        AmountFormat { schema: meta.schema, precision: meta.precision, scales: meta.scales.clone(), currencies: meta.currencies }
+    }
+
+    // This function returns how the amounts of an account in the given currency are written
+    fn style(&self, currency: Option<Currency>) -> AmountStyle {
+        AmountStyle { schema: self.schema, precision: self.scales.places(currency).unwrap_or(self.precision) }
+    }
+}
+
+impl Default for AmountFormat {
+    fn default() -> AmountFormat {
+        AmountFormat { schema: ReportSchema::default(), precision: money::DECIMAL_PLACES, scales: CurrencyScales::default(), currencies: false }
+    }
+}
+
+// How the amounts of one row are written
+#[derive(Debug, Clone, Copy)]
+struct AmountStyle {
+    schema: ReportSchema,
+    precision: u32,
+}
+
+// An amount as written in a text report in the given style
+struct Amount(Money, AmountStyle);
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.1.schema {
+            ReportSchema::V1 => round_serialize(&self.0, self.1.precision, s),
+            ReportSchema::V2 => exact_serialize(&self.0, self.1.precision, s),
+        }
+    }
+}
+
+// The client column, a client id for an account and TOTAL for the totals row
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ClientColumn {
+    Id(u16),
+    Total(&'static str),
+}
+
+// One line of a text report, either an account or the TOTAL row
+#[derive(Serialize)]
+struct TextRow {
+    client: ClientColumn,
+    // Left out unless the report has currencies. An account without a currency among ones with leaves it empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debt: Option<Amount>,
+    // Only set for the JSON report, so the CSV and table columns stay as they were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chargebacks: Option<u64>,
+}
+
+impl TextRow {
+    fn account(account: &AccountSummary, format: &AmountFormat) -> TextRow {
+        let style = format.style(account.currency);
+        TextRow {
+            client: ClientColumn::Id(account.client),
+            currency: format.currencies.then_some(account.currency),
+            available: Amount(account.available, style),
+            held: Amount(account.held, style),
+            total: Amount(account.total, style),
+            locked: Some(account.locked),
+            debt: account.debt.map(|debt| Amount(debt, style)),
+            chargebacks: None,
+        }
+    }
+
+    fn totals(totals: &TotalsView, format: &AmountFormat) -> TextRow {
+        let style = format.style(totals.currency);
+        TextRow {
+            client: ClientColumn::Total("TOTAL"),
+            currency: format.currencies.then_some(totals.currency),
+            available: Amount(totals.available, style),
+            held: Amount(totals.held, style),
+            total: Amount(totals.total, style),
+            locked: None,
+            debt: totals.debt.map(|debt| Amount(debt, style)),
+            chargebacks: None,
+        }
+    }
+}
+
+// Writes the report as CSV with a header row. Rows are buffered and flushed once at the end
+pub struct CsvSink<W: Write> {
+    wtr: csv::Writer<W>,
+    format: AmountFormat,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(out: W) -> CsvSink<W> {
+        CsvSink { wtr: WriterBuilder::new().from_writer(out), format: AmountFormat::default() }
+    }
+
+    fn write_row(&mut self, row: TextRow) -> Result<(), EngineError> {
+        self.wtr.serialize(row)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ReportSink for CsvSink<W> {
+    fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+        self.format = AmountFormat::of(meta);
+        Ok(())
+    }
+
+    fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+        self.write_row(TextRow::account(account, &self.format))
+    }
+
+    fn write_totals(&mut self, totals: &TotalsView) -> Result<(), EngineError> {
+        self.write_row(TextRow::totals(totals, &self.format))
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+// The totals as the JSON report writes them, with the fields of an account that can be added up
+#[derive(Serialize)]
+struct JsonTotals {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debt: Option<Amount>,
+    chargebacks: u64,
+}
+
+// Writes the report as a JSON array with one object per account, keyed like the CSV header. With totals the array is
+// the accounts field of an object whose totals field holds them, one object or, with currencies, an array of one per
+// currency: {"accounts":[...],"totals":{...}}. Each account is written as soon as it arrives, so streamed accounts go
+// out during the run like they do for CSV. Give it a buffered writer, it only flushes at the end
+pub struct JsonSink<W: Write> {
+    out: W,
+    format: AmountFormat,
+    totals: bool,
+    rows: usize,
+    // Totals written so far, which come after every account
+    totals_written: usize,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(out: W) -> JsonSink<W> {
+        JsonSink { out, format: AmountFormat::default(), totals: false, rows: 0, totals_written: 0 }
+    }
+
+    fn write_row(&mut self, row: TextRow) -> Result<(), EngineError> {
+        self.out.write_all(if self.rows == 0 { b"\n  " } else { b",\n  " })?;
+        serde_json::to_writer(&mut self.out, &row)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    // This function closes the accounts array
+    fn end_accounts(&mut self) -> Result<(), EngineError> {
+        self.out.write_all(if self.rows == 0 { b"]" } else { b"\n]" })?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ReportSink for JsonSink<W> {
+    fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+        self.format = AmountFormat::of(meta);
+        self.totals = meta.totals;
+        self.out.write_all(if self.totals { b"{\"accounts\":[" } else { b"[" })?;
+        Ok(())
+    }
+
+    fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+        let chargebacks = Some(u64::from(account.chargebacks));
+        self.write_row(TextRow { chargebacks, ..TextRow::account(account, &self.format) })
+    }
+
+    fn write_totals(&mut self, totals: &TotalsView) -> Result<(), EngineError> {
+        if !self.totals {
+            return Err("the JSON report was begun without totals".into());
+        }
+        match (self.totals_written, self.format.currencies) {
+            (0, false) => {
+                self.end_accounts()?;
+                self.out.write_all(b",\"totals\":")?;
+            },
+            (0, true) => {
+                self.end_accounts()?;
+                self.out.write_all(b",\"totals\":[\n  ")?;
+            },
+            (_, true) => self.out.write_all(b",\n  ")?,
+            (_, false) => return Err("a report without currencies has one totals object".into()),
+        }
+        let row = TextRow::totals(totals, &self.format);
+        serde_json::to_writer(&mut self.out, &JsonTotals {
+            currency: row.currency,
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            debt: row.debt,
+            chargebacks: totals.chargebacks,
+        })?;
+        self.totals_written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        match (self.totals, self.totals_written) {
+            (false, _) => self.end_accounts()?,
+            (true, 0) => {
+                self.end_accounts()?;
+                self.out.write_all(b"}")?;
+            },
+            (true, _) if self.format.currencies => self.out.write_all(b"\n]}")?,
+            (true, _) => self.out.write_all(b"}")?,
+        }
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// This function renders one account as the object the JSON report writes for it
+pub fn account_json(client: &Client, options: &ReportOptions) -> Result<Vec<u8>, EngineError> {
+    let format = AmountFormat { schema: options.schema, precision: options.precision, scales: options.scales.clone(), currencies: false };
+    let account = AccountSummary::from(client);
+    let chargebacks = Some(u64::from(account.chargebacks));
+    Ok(serde_json::to_vec(&TextRow { chargebacks, ..TextRow::account(&account, &format) })?)
+}
+
+// Writes the report as columns padded to line up, for reading in a terminal. The widths depend on every row, so
+// nothing is written until the report is finished
+pub struct TableSink<W: Write> {
+    out: W,
+    format: AmountFormat,
+    rows: Vec<Vec<String>>,
+}
+
+impl<W: Write> TableSink<W> {
+    pub fn new(out: W) -> TableSink<W> {
+        TableSink { out, format: AmountFormat::default(), rows: Vec::new() }
+    }
+
+    // This function turns a row into its cells, written the same way as in the JSON report
+    fn push_row(&mut self, row: TextRow) -> Result<(), EngineError> {
+        let mut cells = vec![cell(&row.client)?];
+        if let Some(currency) = &row.currency {
+            cells.push(cell(currency)?);
+        }
+        cells.extend([cell(&row.available)?, cell(&row.held)?, cell(&row.total)?, cell(&row.locked)?]);
+        if let Some(debt) = &row.debt {
+            cells.push(cell(debt)?);
+        }
+        self.rows.push(cells);
+        Ok(())
+    }
+}
+
+// This function writes a value as the text of one table cell, leaving it empty when there is no value
+fn cell(value: &impl Serialize) -> Result<String, EngineError> {
+    Ok(match serde_json::to_value(value)? {
+        serde_json::Value::String(text) => text,
+        serde_json::Value::Null => String::new(),
+        other => other.to_string(),
+    })
+}
+
+impl<W: Write> ReportSink for TableSink<W> {
+    fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+        self.format = AmountFormat::of(meta);
+        Ok(())
+    }
+
+    fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+        self.push_row(TextRow::account(account, &self.format))
+    }
+
+    fn write_totals(&mut self, totals: &TotalsView) -> Result<(), EngineError> {
+        self.push_row(TextRow::totals(totals, &self.format))
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        let mut header: Vec<&str> = vec!["client"];
+        if self.format.currencies {
+            header.push("currency");
+        }
+        header.extend(["available", "held", "total", "locked"]);
+        if self.rows.iter().any(|row| row.len() > header.len()) {
+            header.push("debt");
+        }
+        let header: Vec<String> = header.into_iter().map(str::to_string).collect();
+        let left: Vec<bool> = header.iter().map(|name| matches!(name.as_str(), "client" | "currency" | "locked")).collect();
+
+        let mut widths: Vec<usize> = header.iter().map(String::len).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        // The client, currency and locked columns are left aligned and the amounts right aligned
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let line: Vec<String> = row.iter().zip(&widths).zip(&left).map(|((cell, &width), &left)| match left {
+                true => format!("{:<width$}", cell, width = width),
+                false => format!("{:>width$}", cell, width = width),
+            }).collect();
+            writeln!(self.out, "{}", line.join("  ").trim_end())?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// Writes a report file under a temporary name next to it and renames it into place once the report is complete, so a
+// run that fails part way never leaves a truncated report where downstream jobs look for it
+pub struct AtomicSink {
+    inner: Box<dyn ReportSink>,
+    file: File,
+    partial: PathBuf,
+    path: PathBuf,
+    done: bool,
+}
+
+impl AtomicSink {
+    // This function creates the temporary file, and any missing parent directories, and hands it to `sink`
+    pub fn create(path: &str, sink: impl FnOnce(Box<dyn Write>) -> Box<dyn ReportSink>) -> Result<AtomicSink, EngineError> {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let file = File::create(&partial)?;
+        let inner = sink(Box::new(BufWriter::new(file.try_clone()?)));
+        Ok(AtomicSink { inner, file, partial, path, done: false })
+    }
+}
+
+impl ReportSink for AtomicSink {
+    fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+        self.inner.begin(meta)
+    }
+
+    fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+        self.inner.write_account(account)
+    }
+
+    fn write_totals(&mut self, totals: &TotalsView) -> Result<(), EngineError> {
+        self.inner.write_totals(totals)
+    }
+
+    fn finish(&mut self) -> Result<(), EngineError> {
+        self.inner.finish()?;
+        self.file.sync_all()?;
+        fs::rename(&self.partial, &self.path)?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicSink {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+// This function rounds the Decimal units to `dp` significance places in the Bankers Rounding method and writes them through f32,
+// as the v1 report did. Amounts f32 cannot hold fail the serialization
+fn round_serialize<S>(x: &Money, dp: u32, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let value = money::to_f32(x.round_dp(dp)).ok_or_else(|| serde::ser::Error::custom(format!("amount {} does not fit in an f32", x)))?;
+    s.serialize_f32(value)
+}
+
+// This function writes the amount exactly, rounded to `dp` decimal places in the Bankers Rounding method and padded
+// with zeros to that many, e.g. 98765432.1001 for 4 or 1.50 for 2
+pub(crate) fn exact_serialize<S>(x: &Money, dp: u32, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    // Rounding can leave a negative zero, which is written as plain zero
+    let rounded = x.round_dp(dp);
+    let rounded = if rounded == Money::ZERO { Money::ZERO } else { rounded };
+    s.collect_str(&Places(rounded, dp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Engine, Transaction, TransactionRow, TransactionType};
+
+    // Everything a sink was handed, in order
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Begin { accounts: Option<usize>, totals: bool },
+        Account(u16, Money),
+        Totals(Money, Money, Money),
+        Finish,
+    }
+
+    // A sink that records what it is handed, and fails on the account of `fail_on` if set
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Vec<Event>,
+        fail_on: Option<u16>,
+    }
+
+    impl ReportSink for RecordingSink {
+        fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+            self.events.push(Event::Begin { accounts: meta.accounts, totals: meta.totals });
+            Ok(())
+        }
+
+        fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+            if self.fail_on == Some(account.client) {
+                return Err("the sink is full".into());
+            }
+            self.events.push(Event::Account(account.client, account.total));
+            Ok(())
+        }
+
+        fn write_totals(&mut self, totals: &TotalsView) -> Result<(), EngineError> {
+            self.events.push(Event::Totals(totals.available, totals.held, totals.total));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), EngineError> {
+            self.events.push(Event::Finish);
+            Ok(())
+        }
+    }
+
+    // This function returns an engine holding clients 5, 1, 4, 2 and 3, in that order of arrival, with client 2 holding
+    // part of theirs in a dispute
+    fn engine() -> Result<Engine, EngineError> {
+        let mut engine = Engine::new();
+        for (tx, (client, deposit)) in (1..).zip([(5, "50"), (1, "10"), (4, "40"), (2, "20"), (3, "30")]) {
+            engine.process_transaction(Transaction::Deposit { client, tx, amount: money(deposit) })?;
+        }
+        engine.process_transaction(Transaction::Deposit { client: 2, tx: 6, amount: money("2.5") })?;
+        engine.process_transaction(Transaction::Dispute { client: 2, tx: 6 })?;
+        Ok(engine)
+    }
+
+    fn money(text: &str) -> Money {
+        text.parse().unwrap_or_else(|_| panic!("invalid amount {}", text))
+    }
+
+    fn account(client: u16, total: &str) -> Event {
+        Event::Account(client, money(total))
+    }
+
+    fn totals(available: &str, held: &str, total: &str) -> Event {
+        Event::Totals(money(available), money(held), money(total))
+    }
+
+    #[test]
+    fn a_sink_gets_the_filtered_accounts_sorted_then_the_totals() -> Result<(), EngineError> {
+        let engine = engine()?;
+        let options = ReportOptions { clients: Some("2-4".parse()?), totals_row: true, ..ReportOptions::default() };
+        let mut sink = RecordingSink::default();
+        write_report(engine.accounts(), 7, &options, &mut sink)?;
+        assert_eq!(sink.events, [
+            Event::Begin { accounts: Some(3), totals: true },
+            account(2, "22.5"),
+            account(3, "30"),
+            account(4, "40"),
+            totals("90", "2.5", "92.5"),
+            Event::Finish,
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn a_sink_gets_every_account_sorted_without_a_filter() -> Result<(), EngineError> {
+        let engine = engine()?;
+        let mut sink = RecordingSink::default();
+        write_report(engine.accounts(), 7, &ReportOptions::default(), &mut sink)?;
+        assert_eq!(sink.events, [
+            Event::Begin { accounts: Some(5), totals: false },
+            account(1, "10"),
+            account(2, "22.5"),
+            account(3, "30"),
+            account(4, "40"),
+            account(5, "50"),
+            Event::Finish,
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn a_failing_sink_ends_the_report_with_its_error() -> Result<(), EngineError> {
+        let engine = engine()?;
+        let options = ReportOptions { totals_row: true, ..ReportOptions::default() };
+        let mut sink = RecordingSink { fail_on: Some(3), ..RecordingSink::default() };
+        let error = write_report(engine.accounts(), 7, &options, &mut sink).err().map(|e| e.to_string());
+        assert_eq!(error.as_deref(), Some("the sink is full"));
+        // Nothing after the failed account, neither the rest nor the totals nor finish
+        assert_eq!(sink.events, [Event::Begin { accounts: Some(5), totals: true }, account(1, "10"), account(2, "22.5")]);
+        Ok(())
+    }
+
+    // This function returns a path in the temporary directory that no other test uses
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("payment_engine_{}_{}", std::process::id(), name))
+    }
+
+    // A CSV sink that fails on the account of one client, after the accounts before it went to the file
+    struct FailingCsvSink {
+        inner: CsvSink<Box<dyn Write>>,
+        fail_on: u16,
+        partial: PathBuf,
+    }
+
+    impl ReportSink for FailingCsvSink {
+        fn begin(&mut self, meta: &RunMeta) -> Result<(), EngineError> {
+            self.inner.begin(meta)
+        }
+
+        fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+            if account.client == self.fail_on {
+                // The report so far is only ever in the temporary file
+                assert!(self.partial.exists(), "{} is missing", self.partial.display());
+                return Err("the disk is full".into());
+            }
+            self.inner.write_account(account)
+        }
+
+        fn finish(&mut self) -> Result<(), EngineError> {
+            self.inner.finish()
+        }
+    }
+
+    #[test]
+    fn a_complete_report_is_renamed_into_place() -> Result<(), EngineError> {
+        let dir = temp_path("atomic_complete");
+        let path = dir.join("nested").join("report.csv");
+        let name = path.to_str().ok_or("the path is not UTF-8")?;
+        let mut sink = AtomicSink::create(name, |out| Box::new(CsvSink::new(out)))?;
+        write_report(engine()?.accounts(), 7, &ReportOptions::default(), &mut sink)?;
+        drop(sink);
+
+        let written = fs::read_to_string(&path);
+        let partial_left = dir.join("nested").join("report.csv.partial").exists();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(written?.starts_with("client,available,held,total,locked\n1,10.0000,"));
+        assert!(!partial_left);
+        Ok(())
+    }
+
+    #[test]
+    fn a_failed_report_leaves_no_partial_file_and_the_old_report_in_place() -> Result<(), EngineError> {
+        let dir = temp_path("atomic_failed");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("report.csv");
+        let partial = dir.join("report.csv.partial");
+        fs::write(&path, "the report of the last run\n")?;
+
+        let name = path.to_str().ok_or("the path is not UTF-8")?;
+        let failing = partial.clone();
+        let mut sink = AtomicSink::create(name, move |out| Box::new(FailingCsvSink { inner: CsvSink::new(out), fail_on: 3, partial: failing }))?;
+        let error = write_report(engine()?.accounts(), 7, &ReportOptions::default(), &mut sink).err().map(|e| e.to_string());
+        drop(sink);
+
+        let kept = fs::read_to_string(&path);
+        let partial_left = partial.exists();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(error.as_deref(), Some("the disk is full"));
+        assert_eq!(kept?, "the report of the last run\n");
+        assert!(!partial_left);
+        Ok(())
+    }
+
+    // An amount an f32 would round is written to the report digit for digit
+    #[test]
+    fn large_balances_round_trip_exactly() -> Result<(), EngineError> {
+        let amount: Money = "98765432.1001".parse().map_err(|_| "invalid amount")?;
+        let mut engine = Engine::new();
+        engine.process(&TransactionRow::new(TransactionType::Deposit, 1, 1, Some(amount)))?;
+
+        let mut out = Vec::new();
+        write_report(engine.accounts(), 1, &ReportOptions::default(), &mut CsvSink::new(&mut out))?;
+        let report = String::from_utf8_lossy(&out).into_owned();
+        assert_eq!(report, "client,available,held,total,locked\n1,98765432.1001,0.0000,98765432.1001,false\n");
+        let written = report.lines().nth(1).and_then(|row| row.split(',').nth(1)).map(str::parse::<Money>);
+        assert!(matches!(written, Some(Ok(written)) if written == amount), "{:?}", written);
+        Ok(())
+    }
+
+    // The JSON report reads back as the accounts it was written from, with their totals after them
+    #[test]
+    fn json_reports_round_trip() -> Result<(), EngineError> {
+        let amount = |text: &str| text.parse::<Money>().map_err(|_| EngineError::from("invalid amount"));
+        let mut engine = Engine::new();
+        engine.process(&TransactionRow::new(TransactionType::Deposit, 2, 1, Some(amount("98765432.1001")?)))?;
+        engine.process(&TransactionRow::new(TransactionType::Deposit, 1, 2, Some(amount("10.5")?)))?;
+        engine.process(&TransactionRow::new(TransactionType::Deposit, 1, 3, Some(amount("4.25")?)))?;
+        engine.process(&TransactionRow::new(TransactionType::Dispute, 1, 3, None))?;
+
+        let mut out = Vec::new();
+        let options = ReportOptions { totals_row: true, ..ReportOptions::default() };
+        write_report(engine.accounts(), 4, &options, &mut JsonSink::new(&mut out))?;
+        let report: serde_json::Value = serde_json::from_slice(&out)?;
+        let field = |object: &serde_json::Value, name: &str| -> Result<Money, EngineError> {
+            amount(object.get(name).and_then(serde_json::Value::as_str).ok_or_else(|| format!("no {} in {}", name, object))?)
+        };
+
+        let accounts = report.get("accounts").and_then(serde_json::Value::as_array).ok_or("no accounts array")?;
+        let mut clients: Vec<&Client> = engine.accounts().collect();
+        clients.sort_unstable_by_key(|client| client.client_id);
+        assert_eq!(accounts.len(), clients.len());
+        for (written, client) in accounts.iter().zip(clients) {
+            assert_eq!(written.get("client").and_then(serde_json::Value::as_u64), Some(u64::from(client.client_id)));
+            assert_eq!(field(written, "available")?, client.available);
+            assert_eq!(field(written, "held")?, client.held);
+            assert_eq!(field(written, "total")?, client.total);
+            assert_eq!(written.get("locked").and_then(serde_json::Value::as_bool), Some(client.locked));
+        }
+
+        let totals = report.get("totals").ok_or("no totals")?;
+        assert_eq!(field(totals, "available")?, amount("98765442.6001")?);
+        assert_eq!(field(totals, "held")?, amount("4.25")?);
+        assert_eq!(field(totals, "total")?, amount("98765446.8501")?);
+        assert!(totals.get("client").is_none(), "{}", totals);
+        Ok(())
+    }
+}
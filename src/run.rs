@@ -0,0 +1,1811 @@
+// Processing an input from start to finish, as the payment_engine command line tool does it.
+//
+// process_input opens the inputs, reads their rows in the chosen format and feeds each to the Engine, keeping a State
+// with everything the end of run reports need: skipped and dangling rows, volumes, fees, dispute spans and lock causes.
+// Once the input is done the report functions here write the account report, the worst case after charging back every
+// open dispute, the volume report and the rest from that State, and print the summaries to stderr. The binary only
+// parses the flags, calls these in turn and turns their errors into exit statuses.
+
+use crate::args::{schema_name, AssertionMode, ClientFilter, Compression, Grouping, InputFormat, InputPrecision, MergeBy, OutputFormat, ProcessingArgs, RecordRetention, ReportArgs};
+use crate::diagnostics::Severity;
+use crate::heartbeat::{ByteCounter, CountingReader, Heartbeat};
+use crate::input::{self, OptionalColumns};
+use crate::money::{self, Money, Places};
+use crate::report::{self, exact_serialize, AtomicSink, CsvSink, JsonSink, ReportOptions, ReportSink, ReportWriter, TableSink};
+use crate::{audit, binlog, cache, checkpoint, diagnostics, merge, parallel, remote, replay, snapshot};
+use crate::{AccountKey, Client, IdMap, Currency, CurrencyScales, Engine, EngineError, EnginePolicy, FeeCounts, RecordState, Rejection, TransactionRow, TransactionType};
+use csv::{StringRecord, Trim, WriterBuilder};
+use log::{debug, error, trace, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// One row of an --opening-balances file. Amounts are text so they are parsed exactly, like amounts in the input
+#[derive(Debug, Deserialize)]
+struct OpeningRow {
+    client: u16,
+    available: String,
+    held: String,
+    locked: bool,
+}
+
+// This function opens the accounts of an --opening-balances file in the engine. A client listed twice, or one that
+// already has an account from --snapshot-in, is an error, as are negative balances unless they are allowed
+fn open_accounts(engine: &mut Engine, path: &str, allow_negative: bool) -> Result<(), EngineError> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open opening balances {}: {}", path, e)))?;
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(file);
+
+    let mut seen = HashSet::new();
+    for (index, row) in rdr.deserialize::<OpeningRow>().enumerate() {
+        let line = index + 2;
+        let row = row.map_err(|e| format!("{} line {}: {}", path, line, e))?;
+        let amount = |text: &str| match text.parse::<Money>() {
+            Ok(amount) if amount.round_dp(money::DECIMAL_PLACES) == amount => Ok(amount),
+            _ => Err(format!("{} line {}: invalid amount {:?}", path, line, text)),
+        };
+        let (available, held) = (amount(&row.available)?, amount(&row.held)?);
+        if !allow_negative && (available < Money::ZERO || held < Money::ZERO) {
+            return Err(format!("{} line {}: client {} has a negative balance, which needs --allow-negative-opening", path, line, row.client).into());
+        }
+        if !seen.insert(row.client) {
+            return Err(format!("{} line {}: client {} has more than one row", path, line, row.client).into());
+        }
+        engine.open_with_balances(row.client, available, held, row.locked).map_err(|e| match e {
+            Rejection::NotFirstTransaction => format!("{} line {}: client {} already has an account in the snapshot", path, line, row.client),
+            e => format!("{} line {}: client {}: {}", path, line, row.client, e),
+        })?;
+    }
+    Ok(())
+}
+
+// Everything the handlers read and update while processing an input
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    // The accounts, stored records and admin holds
+    pub engine: Engine,
+    // Stored records in arrival order with the row that stored them, only kept when a retention is set
+    record_rows: VecDeque<(u64, u32)>,
+    // Records dropped past the retention horizon with the row they were dropped at, so references to them can be told
+    // apart from unknown ones. Each is kept for one more retention, so memory stays bounded by the retention
+    evicted: HashMap<u32, u64>,
+    // The same records in the order they were dropped, to forget them again
+    evicted_rows: VecDeque<(u64, u32)>,
+    // What locked each currently locked account
+    lock_causes: HashMap<u16, LockCause>,
+    // Assert rows whose expected balances did not match
+    failed_assertions: Vec<AssertionFailure>,
+    // Every dispute opened so far, in the order they were opened
+    disputes: Vec<DisputeSpan>,
+    // Index into disputes of the open dispute for each disputed tx id
+    open_disputes: HashMap<u32, usize>,
+    // Accounts that have appeared in any row so far, applied or not, with the count and summed amount of the applied rows
+    // of each type. With --multi-currency each currency of a client is its own account, which can be opened by its own
+    // opening balance, and amounts in different currencies are never added up. Volumes are kept as rows are applied,
+    // since records may be evicted. Every row looks its account up here, so the map uses the engine's id hasher
+    seen_accounts: IdMap<AccountKey, TypeVolumes>,
+    // What happened to every row that was not simply applied, by row, only kept with --annotate-out
+    row_outcomes: Vec<(u64, RowOutcome)>,
+    // How many rows were not applied, by outcome and reason code. Parse errors are counted under one reason
+    outcome_counts: BTreeMap<(&'static str, String), u64>,
+    // The accounts already written out and dropped with --streaming-output
+    streamed: AccountTotals,
+    // Reference rows rejected for an unknown tx id. Once the input is done, only ids that never appeared are left
+    dangling: Vec<DanglingRef>,
+    // Rows that could not be parsed or had an unknown type, skipped unless --strict
+    skipped: Vec<SkippedRow>,
+    // Accounts whose available balance an applied row took below zero, by account
+    negative_available: BTreeMap<AccountKey, NegativeAvailable>,
+    // Count and summed amount of the chargeback fees debited, by account. Kept apart from volumes, which count rows
+    fees: BTreeMap<AccountKey, Volume>,
+    // Chargebacks whose fee could not be debited, in input order
+    failed_fees: Vec<FailedFee>,
+    // Count and summed amount of the monthly fees debited, by account
+    monthly_fees: BTreeMap<AccountKey, Volume>,
+    // Monthly fees that could not be debited, in the order they were charged
+    failed_monthly_fees: Vec<FailedFee>,
+    // Number of input rows read
+    pub rows: u64,
+    // When processing started and ended, for --stats
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    // The most accounts and stored records held at once. Both only shrink when accounts are streamed out or records
+    // expire, so note_peaks is called before those and once at the end
+    peak_clients: usize,
+    peak_records: usize,
+}
+
+impl State {
+    // This function returns the volume of every type applied to every account, sorted by account and type name. Types
+    // no row of the account was applied for are left out
+    fn volumes(&self) -> BTreeMap<(AccountKey, &'static str), Volume> {
+        self.seen_accounts.iter()
+            .flat_map(|(&account, volumes)| TransactionType::ALL.iter().zip(volumes.0).map(move |(kind, volume)| ((account, kind.name()), volume)))
+            .filter(|(_, volume)| volume.count > 0)
+            .collect()
+    }
+
+    // This function returns how many reference rows point at tx ids that never appeared in the input
+    pub fn dangling_refs(&self) -> usize {
+        self.dangling.len()
+    }
+}
+
+// The rows at which a dispute was opened and, once it is, closed
+#[derive(Debug, Clone)]
+struct DisputeSpan {
+    tx: u32,
+    client: u16,
+    opened_row: u64,
+    closed_row: Option<u64>,
+    outcome: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct DisputeAgingRow {
+    tx: u32,
+    client: u16,
+    opened_row: u64,
+    closed_row: Option<u64>,
+    outcome: &'static str,
+    // Rows between opening and closing, or up to the last input row if still open
+    age_rows: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AssertionFailure {
+    row: u64,
+    line: Option<u64>,
+    client: u16,
+    tx: u32,
+    reason: String,
+}
+
+// The outcome column of an annotated row, with the reason code or parse error that goes in the detail column
+#[derive(Debug, Clone)]
+struct RowOutcome {
+    outcome: &'static str,
+    detail: String,
+}
+
+// A dispute, resolve or chargeback row whose tx id no row of the input defines
+#[derive(Debug, Clone, Serialize)]
+struct DanglingRef {
+    row: u64,
+    line: Option<u64>,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+}
+
+// A row that was skipped because it could not be turned into a transaction the engine knows
+#[derive(Debug, Clone, Serialize)]
+struct SkippedRow {
+    row: u64,
+    line: Option<u64>,
+    // The row's fields as read, empty if the row could not be read at all
+    raw: String,
+    reason: String,
+}
+
+// A CSV row that could not be parsed. Reading can go on with the next row, unlike after an I/O error
+#[derive(Debug)]
+pub(crate) struct BadRow {
+    raw: String,
+    reason: String,
+    // The type column when that is what was wrong with the row, e.g. transfer
+    unknown_type: Option<String>,
+}
+
+impl fmt::Display for BadRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Error for BadRow {}
+
+// This function turns an error from reading the input that stops the run into an EngineError. Rows are read with
+// boxed errors, since most of them are bad rows that are skipped and only the reader knows how to tell them apart
+pub(crate) fn input_error(e: Box<dyn Error>, line: Option<u64>) -> EngineError {
+    let e = match e.downcast::<EngineError>() {
+        Ok(e) => return *e,
+        Err(e) => e,
+    };
+    let e = match e.downcast::<io::Error>() {
+        Ok(e) => return EngineError::Io(*e),
+        Err(e) => e,
+    };
+    match e.downcast::<csv::Error>() {
+        Ok(e) => EngineError::from(*e),
+        Err(e) => EngineError::parse(line, e.to_string()),
+    }
+}
+
+// A CSV row with no transaction in it: blank, or a comment with --allow-comments. It is counted, not reported
+#[derive(Debug)]
+pub(crate) struct IgnoredRow(&'static str);
+
+impl fmt::Display for IgnoredRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} row", self.0)
+    }
+}
+
+impl Error for IgnoredRow {}
+
+// This function names the kind of row a CSV record is if it holds no transaction: blank when every field is empty or
+// whitespace, as in a row of only commas, and comment when the first field starts with # and comments are allowed
+pub(crate) fn ignored_row(record: &csv::ByteRecord, allow_comments: bool) -> Option<&'static str> {
+    // Every row with a transaction in it is told apart by its first field alone
+    let first = record.get(0).map_or(&[][..], <[u8]>::trim_ascii);
+    if allow_comments && first.starts_with(b"#") {
+        Some("comment")
+    } else if first.is_empty() && record.iter().all(|field| field.trim_ascii().is_empty()) {
+        Some("blank")
+    } else {
+        None
+    }
+}
+
+// A chargeback that was applied without its fee, since debiting the fee would have overflowed a balance
+#[derive(Debug, Clone, Copy)]
+struct FailedFee {
+    account: AccountKey,
+    tx: u32,
+    row: u64,
+    fee: Money,
+}
+
+// How often a client's available balance went from zero or more to below zero, e.g. by a dispute of funds already
+// withdrawn, and the lowest it went
+#[derive(Debug, Clone, Copy)]
+struct NegativeAvailable {
+    events: u64,
+    lowest: Money,
+}
+
+// Counts and sums over a set of accounts
+#[derive(Debug, Clone, Copy, Default)]
+struct AccountTotals {
+    clients: u64,
+    locked: u64,
+    chargebacks: u64,
+    held: Money,
+    total: Money,
+    open_disputes: u64,
+    disputed: Money,
+}
+
+impl AccountTotals {
+    // This function adds one account, refusing rather than wrapping on overflow
+    fn add(&mut self, client: &Client) -> Result<(), EngineError> {
+        let overflow = |what: &str| format!("summary {} would overflow", what);
+        self.clients += 1;
+        self.locked += u64::from(client.locked);
+        self.chargebacks += u64::from(client.chargebacks);
+        self.held = self.held.checked_add(client.held).ok_or_else(|| overflow("held"))?;
+        self.total = self.total.checked_add(client.total).ok_or_else(|| overflow("total"))?;
+        self.open_disputes += client.open_disputes().len() as u64;
+        self.disputed = client.disputed().and_then(|disputed| self.disputed.checked_add(disputed)).ok_or_else(|| overflow("disputed"))?;
+        Ok(())
+    }
+}
+
+// How many rows of one type were applied to an account, and the amount they moved
+#[derive(Debug, Clone, Copy, Default)]
+struct Volume {
+    count: u64,
+    amount: Money,
+}
+
+// The volume of each transaction type applied to one account, in the order of TransactionType::ALL
+#[derive(Debug, Clone, Copy, Default)]
+struct TypeVolumes([Volume; TransactionType::ALL.len()]);
+
+#[derive(Debug, Serialize)]
+struct VolumeRow {
+    client: String,
+    // Left out unless some account has a currency, like the currency column of the account report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+    #[serde(rename = "type")]
+    transaction_type: &'static str,
+    count: u64,
+    amount: Money,
+}
+
+// The row that moved an account from unlocked to locked
+#[derive(Debug, Clone, Serialize)]
+struct LockCause {
+    client: u16,
+    cause: &'static str,
+    tx: u32,
+    row: u64,
+    line: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct WorstCaseRow {
+    client: u16,
+    // Left out unless some account has a currency, like the currency column of the account report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+    #[serde(serialize_with = "four_places_serialize")]
+    worst_available: Money,
+    #[serde(serialize_with = "four_places_serialize")]
+    worst_total: Money,
+    would_lock: bool,
+}
+
+// This function writes the amount like exact_serialize at four decimal places, for files that --precision does not apply to
+fn four_places_serialize<S>(x: &Money, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    exact_serialize(x, money::DECIMAL_PLACES, s)
+}
+
+// What happened to one input row, handed to the observer of process_input.
+// `before` and `after` are the client's account around the row, None if the client did not exist
+pub struct Outcome<'a> {
+    pub row: u64,
+    // Only known for CSV input, where row N is on line N + 1 after the header
+    pub line: Option<u64>,
+    pub transaction: &'a TransactionRow,
+    pub result: Result<(), Rejection>,
+    pub before: Option<Client>,
+    pub after: Option<&'a Client>,
+}
+
+// The rows of an input, with None for CSV rows skipped by the client filter
+pub(crate) type Rows<'a> = Box<dyn Iterator<Item = Result<Option<TransactionRow>, Box<dyn Error>>> + 'a>;
+
+// Input file name that stands for standard input
+pub const STDIN_INPUT: &str = "-";
+
+// The log target of the line that closes a run. It is filtered like a warning but written without the label
+pub const DONE_TARGET: &str = "payment_engine::done";
+
+// This function is the main logic that opens the inputs, feeds each transaction to its handler and, if asked, logs every accepted transaction to a binlog.
+// Several inputs are read one after another as if they were one, so later files can refer to transactions in earlier ones
+pub fn process_input(
+    inputs: &[String],
+    args: &ProcessingArgs,
+    policy: &EnginePolicy,
+    observer: Option<&mut dyn FnMut(Outcome)>,
+    stream: Option<&mut ReportWriter>,
+) -> Result<State, EngineError> {
+    let client_filter = args.clients.as_ref();
+    let bytes = ByteCounter::default();
+
+    // Binlogs, which caches are, and replay files have no room for a currency
+    if args.multi_currency && (args.cache.is_some() || args.export_binlog.is_some() || args.record.is_some()) {
+        return Err("--multi-currency does not support --cache, --export-binlog or --record".into());
+    }
+
+    // A URL that cannot be read at all is named before any other combination of flags is looked at
+    for input in inputs.iter().filter(|input| remote::is_url(input)) {
+        remote::check(input)?;
+    }
+
+    // The parallel run applies every row the serial run applied, kept in memory, so an input of unknown length is not
+    // taken. Accounts streamed out or charged monthly fees all at once cannot be compared between the runs
+    if args.verify_parallel && (inputs.iter().any(|input| input == STDIN_INPUT || remote::is_url(input)) || stream.is_some() || args.monthly_fee.is_some()) {
+        return Err("--verify-parallel needs input files and does not support standard input, URLs, --streaming-output or --monthly-fee".into());
+    }
+
+    // Standard input has no path and can only be read once, so it is always parsed straight from the stream
+    if inputs.iter().any(|input| input == STDIN_INPUT) {
+        if inputs.len() > 1 || args.input_format != InputFormat::Csv || args.cache.is_some() {
+            return Err("standard input can only be read on its own, as CSV and without --cache".into());
+        }
+        let mut transactions: Rows = Box::new(read_csv(decompress(io::stdin().lock(), args.compression, None)?, client_filter, args, &bytes));
+        if args.sort_by_timestamp {
+            transactions = sort_by_timestamp(transactions);
+        }
+        return process_transactions(transactions, &bytes, args, policy, observer, stream);
+    }
+    if args.cache.is_some() && inputs.len() > 1 {
+        return Err("--cache needs a single input file".into());
+    }
+    // Only CSV rows carry a timestamp, and a cache is a binlog, which has no room for one
+    if args.sort_by_timestamp && (args.input_format != InputFormat::Csv || args.cache.is_some()) {
+        return Err("--sort-by-timestamp needs CSV input and does not support --cache".into());
+    }
+
+    if args.merge_by == Some(MergeBy::Timestamp) {
+        if args.input_format != InputFormat::Csv || args.cache.is_some() || args.sort_by_timestamp || inputs.iter().any(|input| remote::is_url(input)) {
+            return Err("--merge-by timestamp needs CSV input files and does not support --cache or --sort-by-timestamp".into());
+        }
+        let transactions = merge::by_timestamp(inputs, args, &bytes)?;
+        return process_transactions(transactions, &bytes, args, policy, observer, stream);
+    }
+    if args.merge_max_skew.is_some() {
+        return Err("--merge-max-skew only applies with --merge-by".into());
+    }
+
+    // Every input row is yielded so rows can be numbered, with None for CSV rows skipped by the client filter. All files
+    // are opened up front, so a missing one stops the run before any row is applied
+    let mut files: Vec<Rows> = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if remote::is_url(input) {
+            if args.input_format != InputFormat::Csv || args.cache.is_some() {
+                return Err(format!("{} can only be read as CSV and without --cache", input).into());
+            }
+            files.push(Box::new(read_csv(decompress(remote::open(input)?, args.compression, Some(path))?, client_filter, args, &bytes)));
+            continue;
+        }
+        files.push(match args.input_format {
+            InputFormat::Csv => match &args.cache {
+                Some(cache) => {
+                    let file = decompress(open_input(path)?, args.compression, Some(path))?;
+                    cache::rows(cache, path, args.thousands_separator.0, &bytes, || Box::new(read_csv(file, None, args, &bytes)))?
+                },
+                None => Box::new(read_csv(decompress(open_input(path)?, args.compression, Some(path))?, client_filter, args, &bytes)),
+            },
+            _ if args.cache.is_some() => return Err("--cache only applies to CSV input".into()),
+            _ if args.compression != Compression::Auto && args.compression != Compression::None => {
+                return Err("--compression only applies to CSV input".into());
+            },
+            InputFormat::Binlog => Box::new(binlog::BinlogReader::open(path, &bytes)?.map(|t| t.map(Some))),
+            #[cfg(feature = "arrow")]
+            InputFormat::Arrow => Box::new(crate::arrow_io::ArrowReader::open(path, &bytes)?.map(|t| t.map(Some))),
+        });
+    }
+    let mut transactions: Rows = Box::new(files.into_iter().flatten());
+    if args.sort_by_timestamp {
+        transactions = sort_by_timestamp(transactions);
+    }
+    process_transactions(transactions, &bytes, args, policy, observer, stream)
+}
+
+// This function returns the line of a CSV input a row was read from. The header is line 1. Rows sorted by timestamp are
+// numbered in the order they are applied, which no longer follows the lines
+pub(crate) fn input_line(args: &ProcessingArgs, row: u64) -> Option<u64> {
+    (args.input_format == InputFormat::Csv && !args.multiple_inputs && !args.sort_by_timestamp).then(|| row + 1)
+}
+
+// This function reads every row and returns them in timestamp order. The sort is stable, so rows with equal timestamps
+// keep their input order. Rows without a timestamp, including the ones that could not be parsed, sort before all others
+pub(crate) fn sort_by_timestamp(rows: Rows) -> Rows {
+    let mut rows: Vec<_> = rows.collect();
+    rows.sort_by_key(|row| row.as_ref().ok().and_then(Option::as_ref).and_then(|transaction| transaction.timestamp));
+    Box::new(rows.into_iter())
+}
+
+// This function creates the engine a run starts from: the accounts of --snapshot-in or none, with the records spilled
+// to --spill-dir and the balances of --opening-balances added
+pub fn start_engine(args: &ProcessingArgs, policy: &EnginePolicy) -> Result<Engine, EngineError> {
+    let mut engine = match &args.snapshot_in {
+        Some(path) => {
+            let (engine, schema) = snapshot::load(path, policy)?;
+            if let Some(expected) = args.report_schema.filter(|&expected| expected != schema) {
+                return Err(EngineError::Invalid(format!(
+                    "snapshot {} was written by a run with --report-schema {}, this run has {}; resume with the same schema",
+                    path, schema_name(schema), schema_name(expected),
+                )));
+            }
+            engine
+        },
+        None => Engine::with_policy(policy.clone()),
+    };
+    if let Some(dir) = &args.spill_dir {
+        engine.spill_records(Path::new(dir), args.spill_keep)?;
+    }
+    if let Some(path) = &args.opening_balances {
+        open_accounts(&mut engine, path, args.allow_negative_opening)?;
+    }
+    Ok(engine)
+}
+
+// This function applies a stream of input rows and returns the resulting state. `bytes` counts the input consumed, for the heartbeat.
+// With --assume-grouped-by client and a `stream`, each client is written to it and dropped from the state once its rows end
+pub fn process_transactions(
+    transactions: Rows,
+    bytes: &ByteCounter,
+    args: &ProcessingArgs,
+    policy: &EnginePolicy,
+    mut observer: Option<&mut dyn FnMut(Outcome)>,
+    mut stream: Option<&mut ReportWriter>,
+) -> Result<State, EngineError> {
+    let mut state = State { engine: start_engine(args, policy)?, started: Some(Instant::now()), ..State::default() };
+    // Records from the snapshot age from the start of this input
+    if args.record_retention.is_some() {
+        let mut loaded: Vec<u32> = state.engine.records().map(|(id, _)| id).collect();
+        loaded.sort_unstable();
+        state.record_rows.extend(loaded.into_iter().map(|id| (0, id)));
+    }
+    let client_filter = args.clients.as_ref();
+    let mut heartbeat = args.heartbeat.map(|every| Heartbeat::new(every, bytes));
+    let mut row: u64 = 0;
+    let mut referenced = HashSet::<u16>::new();
+    let mut unknown_refs = HashSet::<u32>::new();
+    let mut defined_later = HashSet::<u32>::new();
+    // With grouped input, the client whose rows are being read and the clients whose rows have ended
+    let mut current_client: Option<u16> = None;
+    let mut finished_clients = HashSet::<u16>::new();
+
+    let mut binlog = match &args.export_binlog {
+        Some(path) => Some(binlog::BinlogWriter::create(path)?),
+        None => None,
+    };
+    let mut recorder = match &args.record {
+        Some(path) => Some(replay::Recorder::create(path, args)?),
+        None => None,
+    };
+    let mut audit = match &args.audit_log {
+        Some(path) => Some(audit::AuditLog::create(path, &args.inputs)?),
+        None => None,
+    };
+    let mut diagnostics = match &args.diagnostics_json {
+        Some(path) => Some(diagnostics::Diagnostics::create(path)?),
+        None => None,
+    };
+    let mut checkpoint = match (args.checkpoint_every, &args.checkpoint_out) {
+        (Some(every), Some(path)) => Some(checkpoint::Checkpoint::new(every, path)),
+        _ => None,
+    };
+    // The rows handed to the engine, in order, for --verify-parallel to apply again
+    let mut engine_rows = args.verify_parallel.then(Vec::new);
+
+    for transaction in transactions {
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            checkpoint.rows_done(row, &state.engine)?;
+        }
+        if args.until_row.is_some_and(|last| row >= last) {
+            break;
+        }
+        row += 1;
+        let transaction = transaction.and_then(|transaction| check_max_amount(transaction, args.max_amount));
+        let transaction = match transaction {
+            Ok(transaction) => transaction,
+            Err(e) if e.is::<IgnoredRow>() => {
+                let kind = e.downcast_ref::<IgnoredRow>().map_or("blank", |ignored| ignored.0);
+                if let Some(log) = audit.as_mut() {
+                    log.write(row, input_line(args, row), None, "ignored", kind, None)?;
+                }
+                annotate(&mut state, args, row, "ignored", kind);
+                continue;
+            },
+            Err(e) if args.annotate_out.is_some() || args.keep_outcomes || e.downcast_ref::<BadRow>().is_some_and(|bad| !(args.strict || (args.strict_types && bad.unknown_type.is_some()))) => {
+                let (raw, unknown_type) = e.downcast_ref::<BadRow>().map(|bad| (bad.raw.clone(), bad.unknown_type.clone())).unwrap_or_default();
+                let outcome = if unknown_type.is_some() { "unknown_type" } else { "parse_error" };
+                if let Some(log) = audit.as_mut() {
+                    log.write(row, input_line(args, row), None, outcome, &e.to_string(), None)?;
+                }
+                if let Some(out) = diagnostics.as_mut() {
+                    out.write(Severity::Warning, outcome, Some(row), None, &e.to_string())?;
+                }
+                skip_row(&mut state, args, row, raw, e.to_string(), unknown_type);
+                continue;
+            },
+            Err(e) => return Err(input_error(e, input_line(args, row))),
+        };
+        let Some(mut transaction) = transaction else {
+            if let Some(log) = audit.as_mut() {
+                log.write(row, input_line(args, row), None, "skipped", "client_filter", None)?;
+            }
+            annotate(&mut state, args, row, "skipped", "client_filter");
+            continue;
+        };
+        let line = input_line(args, row);
+        // Finer amounts are brought to four decimal places before anything looks at them, so the balances and every
+        // report agree. Rejected ones reach the engine as they are, which refuses them
+        if let Some(amount) = transaction.amount {
+            transaction.amount = Some(match args.input_precision {
+                InputPrecision::Round => amount.round_dp(money::DECIMAL_PLACES),
+                InputPrecision::Truncate => money::truncate_dp(amount, money::DECIMAL_PLACES),
+                InputPrecision::Reject => amount,
+            });
+        }
+        trace!(line, row, client = transaction.client_id, tx = transaction.transaction_id; "row {}: {} {} for client {}, amount {:?}", row, transaction.transaction_type, transaction.transaction_id, transaction.client_id, transaction.amount);
+
+        // CSV rows are filtered while parsing, other formats are filtered here
+        if client_filter.is_some_and(|f| !f.contains(transaction.client_id)) {
+            if let Some(log) = audit.as_mut() {
+                log.write(row, line, Some(&transaction), "skipped", "client_filter", None)?;
+            }
+            annotate(&mut state, args, row, "skipped", "client_filter");
+            continue;
+        }
+
+        // Ignored types are dropped as if the row had never been in the input
+        if args.ignore_types.contains(&transaction.transaction_type) {
+            if let Some(log) = audit.as_mut() {
+                log.write(row, line, Some(&transaction), "ignored", "ignore_types", state.engine.account_for(&transaction))?;
+            }
+            annotate(&mut state, args, row, "ignored", "ignore_types");
+            continue;
+        }
+
+        // A client is finished once the next client's rows start. Nothing may touch it afterwards, since it may already be written out
+        if args.assume_grouped_by == Some(Grouping::Client) {
+            let place = line.map_or(format!("row {}", row), |line| format!("line {}", line));
+            if finished_clients.contains(&transaction.client_id) {
+                return Err(format!("input is not grouped by client: {} is for client {}, whose rows ended earlier", place, transaction.client_id).into());
+            }
+            if let Some(record) = state.engine.record(transaction.transaction_id).filter(|r| finished_clients.contains(&r.client_id)) {
+                return Err(format!(
+                    "{} {} at {} references a transaction of client {}, whose rows ended earlier",
+                    transaction.transaction_type, transaction.transaction_id, place, record.client_id,
+                ).into());
+            }
+            if let Some(previous) = current_client.filter(|&c| c != transaction.client_id) {
+                finished_clients.insert(previous);
+                if let Some(writer) = stream.as_deref_mut() {
+                    stream_client(&mut state, &mut referenced, previous, policy, writer)?;
+                }
+            }
+            current_client = Some(transaction.client_id);
+        }
+
+        // Assertions only look at the state, they are not transactions and never reach the handlers
+        if transaction.transaction_type == TransactionType::Assert {
+            let failed = check_assertion(&state, &transaction).err();
+            if let Some(log) = audit.as_mut() {
+                let (outcome, reason) = if failed.is_some() { ("rejected", "assertion_failed") } else { ("applied", "") };
+                log.write(row, line, Some(&transaction), outcome, reason, state.engine.account_for(&transaction))?;
+            }
+            if let Some(reason) = failed {
+                if args.assertions == AssertionMode::Strict {
+                    return Err(format!("assert {} at row {} failed for client {}: {}", transaction.transaction_id, row, transaction.client_id, reason).into());
+                }
+                warn!(line, row, client = transaction.client_id, tx = transaction.transaction_id, reason = "assertion_failed";
+                    "assert {} for client {} failed: {}.", transaction.transaction_id, transaction.client_id, reason);
+                if let Some(out) = diagnostics.as_mut() {
+                    out.write(Severity::Warning, "assertion_failed", Some(row), Some(&transaction), &reason)?;
+                }
+                annotate(&mut state, args, row, "rejected", "assertion_failed");
+                state.failed_assertions.push(AssertionFailure {
+                    row,
+                    line,
+                    client: transaction.client_id,
+                    tx: transaction.transaction_id,
+                    reason,
+                });
+            }
+            continue;
+        }
+
+        if args.include_referenced_clients {
+            referenced.insert(transaction.client_id);
+        }
+
+        // A reference rejected as unknown only dangles if its id is not defined further down either
+        if !unknown_refs.is_empty() && matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::OpeningBalance)
+            && unknown_refs.remove(&transaction.transaction_id) {
+            defined_later.insert(transaction.transaction_id);
+        }
+
+        if let Some(retention) = args.record_retention {
+            evict_expired_records(&mut state, retention, row);
+        }
+
+        // A row that was already applied, e.g. by the run that wrote the loaded snapshot, is dropped rather than rejected
+        if args.idempotent_replay {
+            if let Some(reason) = already_applied(&state, &transaction) {
+                if let Some(log) = audit.as_mut() {
+                    log.write(row, line, Some(&transaction), "ignored_duplicate_tx", reason, state.engine.account_for(&transaction))?;
+                }
+                debug!(line, row, client = transaction.client_id, tx = transaction.transaction_id, reason;
+                    "{} {} for client {} ignored: already applied.", transaction.transaction_type, transaction.transaction_id, transaction.client_id);
+                annotate(&mut state, args, row, "ignored_duplicate_tx", reason);
+                continue;
+            }
+        }
+
+        // A dispute row pointing at an evicted record gets its own reason rather than looking like an unknown transaction
+        let references_evicted = transaction.transaction_type.is_dispute_lifecycle()
+            && state.engine.record(transaction.transaction_id).is_none()
+            && state.evicted.contains_key(&transaction.transaction_id);
+        // The account the row moves, looked up once since a row never moves it to another
+        let account = state.engine.account_key(&transaction).unwrap_or(transaction.account());
+        let before = match observer {
+            Some(_) => state.engine.account(account).cloned(),
+            None => None,
+        };
+        let account_before = state.engine.account(account).map(|c| (c.locked, c.held, c.available));
+        let was_locked = account_before.is_some_and(|(locked, _, _)| locked);
+        let held_before = account_before.map(|(_, held, _)| held);
+        let available_before = account_before.map(|(_, _, available)| available);
+        let moved = moved_amount(&state, &transaction);
+        let fees_before = state.engine.fee_counts();
+
+        // An opening balance has to be the account's first row, and with --require-opening-balances nothing else can be
+        let first_row_of_account = match state.seen_accounts.entry(account) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(TypeVolumes::default());
+                true
+            },
+        };
+        let is_opening_balance = transaction.transaction_type == TransactionType::OpeningBalance;
+        let result = if references_evicted {
+            Err(Rejection::ExpiredReference)
+        } else if is_opening_balance && !first_row_of_account {
+            Err(Rejection::NotFirstTransaction)
+        } else if args.require_opening_balances && !is_opening_balance && account_before.is_none() {
+            Err(Rejection::MissingOpeningBalance)
+        } else {
+            if let Some(rows) = engine_rows.as_mut() {
+                rows.push(transaction.clone());
+            }
+            let result = state.engine.process(&transaction);
+            // A row in a later month than the one before it ends that month, whose fees come before the row itself
+            if policy.monthly_fee().is_some() {
+                record_monthly_fees(&mut state, audit.as_mut(), row, line)?;
+            }
+            match result {
+                Ok(()) => Ok(()),
+                Err(e) => match e.rejection() {
+                    Some(rejection) => Err(rejection),
+                    None => return Err(e),
+                },
+            }
+        };
+        // The rest of the row only reads the account, so it is looked up once more after the engine is done with it
+        let account_after = state.engine.account(account).map(|c| (c.locked, c.held, c.available));
+        if args.abort_on_negative_held && matches!(transaction.transaction_type, TransactionType::Resolve | TransactionType::Chargeback) {
+            if let (Some(before), Some((_, after, _))) = (held_before, account_after) {
+                if after < Money::ZERO {
+                    let place = line.map_or(format!("row {}", row), |line| format!("line {}", line));
+                    return Err(EngineError::Invariant(format!(
+                        "{} {} at {} left client {} with negative held funds (held was {}, now {})",
+                        transaction.transaction_type, transaction.transaction_id, place, transaction.client_id, before, after,
+                    )));
+                }
+            }
+        }
+
+        let is_locked = account_after.is_some_and(|(locked, _, _)| locked);
+        if !was_locked && is_locked {
+            state.lock_causes.insert(transaction.client_id, LockCause {
+                client: transaction.client_id,
+                cause: lock_cause(transaction.transaction_type),
+                tx: transaction.transaction_id,
+                row,
+                line,
+            });
+        } else if was_locked && !is_locked {
+            state.lock_causes.remove(&transaction.client_id);
+        }
+
+        if result.is_ok() {
+            track_dispute_span(&mut state, &transaction, row);
+            add_volume(&mut state, account, &transaction, moved)?;
+            track_negative_available(&mut state, account, available_before, account_after.map(|(_, _, available)| available));
+        }
+        if result == Err(Rejection::UnknownTransaction) && transaction.transaction_type.is_dispute_lifecycle() {
+            unknown_refs.insert(transaction.transaction_id);
+            state.dangling.push(DanglingRef {
+                row,
+                line,
+                transaction_type: transaction.transaction_type,
+                client: transaction.client_id,
+                tx: transaction.transaction_id,
+            });
+        }
+
+        if args.record_retention.is_some() && result.is_ok() && state.engine.record(transaction.transaction_id).is_some()
+            && matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+            state.record_rows.push_back((row, transaction.transaction_id));
+        }
+        if let Some(log) = audit.as_mut() {
+            let (outcome, reason) = match result {
+                Ok(()) => ("applied", ""),
+                Err(e) => (rejection_outcome(e), e.code()),
+            };
+            log.write(row, line, Some(&transaction), outcome, reason, state.engine.account(account))?;
+        }
+        if let Some(fee) = policy.chargeback_fee() {
+            // The engine rounds the fee to the decimal places of the account's currency
+            let fee = fee.round_dp(policy.scale_for(state.engine.account_for(&transaction).and_then(|client| client.currency)));
+            record_fee(&mut state, audit.as_mut(), row, line, &transaction, fee, fees_before)?;
+        }
+        if let Err(e) = result {
+            warn!(line, row, client = transaction.client_id, tx = transaction.transaction_id, reason = e.code();
+                "{} {} for client {} rejected: {}.", transaction.transaction_type, transaction.transaction_id, transaction.client_id, e);
+            if let Some(out) = diagnostics.as_mut() {
+                out.write(Severity::Warning, e.code(), Some(row), Some(&transaction), &e.to_string())?;
+            }
+            annotate(&mut state, args, row, rejection_outcome(e), e.code());
+        } else if log::log_enabled!(log::Level::Debug) {
+            if let Some(client) = state.engine.account(account) {
+                debug!(line, row, client = transaction.client_id, tx = transaction.transaction_id;
+                    "{} {} for client {} applied: available {}, held {}, total {}, locked {}",
+                    transaction.transaction_type, transaction.transaction_id, transaction.client_id, client.available, client.held, client.total, client.locked);
+            }
+        }
+        if let Some(beat) = heartbeat.as_mut() {
+            beat.row(result.is_err());
+        }
+        if let Some(observe) = observer.as_mut() {
+            observe(Outcome {
+                row,
+                line,
+                transaction: &transaction,
+                result,
+                before,
+                after: state.engine.account(account),
+            });
+        }
+
+        if let Some(log) = binlog.as_mut() {
+            if result.is_ok() {
+                log.write(&transaction)?;
+            }
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.write(row, &transaction, result)?;
+        }
+    }
+
+    if let Some(beat) = &heartbeat {
+        beat.finish();
+    }
+    // Compared before referenced clients are listed and other report touches, which only the serial run gets
+    if let Some(rows) = engine_rows {
+        parallel::verify(args, policy, rows, &state.engine)?;
+    }
+    // The month the input ends in is usually still going on, so it is only charged when asked for
+    if args.fee_final_partial_month && policy.monthly_fee().is_some() {
+        state.engine.close_month();
+        record_monthly_fees(&mut state, audit.as_mut(), row, None)?;
+    }
+    if let Some(checkpoint) = checkpoint.as_mut() {
+        checkpoint.rows_done(row, &state.engine)?;
+    }
+
+    if let (Some(last), Some(writer)) = (current_client, stream) {
+        stream_client(&mut state, &mut referenced, last, policy, writer)?;
+    }
+    // With currencies a client that has an account in any of them is listed already
+    let listed: HashSet<u16> = state.engine.accounts().map(|client| client.client_id).collect();
+    for client_id in referenced.into_iter().filter(|client_id| !listed.contains(client_id)) {
+        state.engine.account_or_new(client_id);
+    }
+    state.dangling.retain(|dangling| !defined_later.contains(&dangling.tx));
+
+    // Every row of the report needs the debt column once debt is tracked
+    if policy.track_debt() {
+        for client in state.engine.accounts_mut() {
+            client.debt.get_or_insert(Money::ZERO);
+        }
+    }
+
+    if let Some(log) = binlog {
+        log.finish()?;
+    }
+    if let Some(recorder) = recorder {
+        recorder.finish(row, state.engine.accounts())?;
+    }
+
+    state.rows = row;
+    note_peaks(&mut state);
+    state.finished = Some(Instant::now());
+    Ok(state)
+}
+
+// This function records a row that is skipped instead of stopping the run
+fn skip_row(state: &mut State, args: &ProcessingArgs, row: u64, raw: String, reason: String, unknown_type: Option<String>) {
+    let line = input_line(args, row);
+    let code = if unknown_type.is_some() { "unknown_type" } else { "parse_error" };
+    warn!(line, row, reason = code; "skipping {}: {}.", line.map_or(format!("row {}", row), |line| format!("line {}", line)), reason);
+    // Unknown types are counted by the type they name, so a new row type from upstream shows up as one line in the summary
+    match unknown_type {
+        Some(name) => annotate(state, args, row, "unknown_type", &name),
+        None => annotate(state, args, row, "parse_error", &reason),
+    }
+    state.skipped.push(SkippedRow { row, line, raw, reason });
+}
+
+// This function tells whether a row's effect is already in the state, for --idempotent-replay, and if so returns the code
+// of the rejection the row would otherwise get. A deposit, withdrawal or opening balance is applied once its tx id is
+// stored, and a dispute, resolve or chargeback once the client's record is in the state the step leads to or a later
+// one, so a dispute of a resolved record is not opened again. A record disputed a second time looks like one disputed
+// once, so a replayed resolve of its first dispute resolves the second
+fn already_applied(state: &State, transaction: &TransactionRow) -> Option<&'static str> {
+    let record = state.engine.record(transaction.transaction_id)?;
+    let reached = match transaction.transaction_type {
+        TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::OpeningBalance => return Some(Rejection::DuplicateTransaction.code()),
+        TransactionType::Dispute => RecordState::Disputed,
+        TransactionType::Resolve => RecordState::Resolved,
+        TransactionType::Chargeback => RecordState::ChargedBack,
+        _ => return None,
+    };
+    if record.client_id != transaction.client_id || lifecycle_position(record.state) < lifecycle_position(reached) {
+        return None;
+    }
+    Some(record.state.next(transaction.transaction_type).err().unwrap_or(Rejection::AlreadyDisputed).code())
+}
+
+// This function orders the record states along the dispute lifecycle. A charged back record has passed every other state
+fn lifecycle_position(state: RecordState) -> u8 {
+    match state {
+        RecordState::Normal => 0,
+        RecordState::Disputed => 1,
+        RecordState::Resolved => 2,
+        RecordState::ChargedBack => 3,
+    }
+}
+
+// This function names the outcome of a row the engine rejected, as annotated rows and the audit log show it
+fn rejection_outcome(rejection: Rejection) -> &'static str {
+    match rejection {
+        Rejection::DuplicateTransaction => "duplicate",
+        Rejection::Overflow => "error_overflow",
+        Rejection::BalanceCapExceeded => "ignored_limit_exceeded",
+        _ => "rejected",
+    }
+}
+
+// This function refuses a row whose amount is above --max-amount as a bad row, in any input format
+fn check_max_amount(transaction: Option<TransactionRow>, max_amount: Option<Money>) -> Result<Option<TransactionRow>, Box<dyn Error>> {
+    match (&transaction, max_amount) {
+        (Some(t), Some(max)) if t.amount.is_some_and(|amount| amount > max) => Err(BadRow {
+            raw: format!("{},{},{},{}", t.transaction_type, t.client_id, t.transaction_id, t.amount.unwrap_or(max)),
+            reason: format!("amount {} is above --max-amount {}", t.amount.unwrap_or(max), max),
+            unknown_type: None,
+        }.into()),
+        _ => Ok(transaction),
+    }
+}
+
+// This function remembers what happened to a row that was not applied, when the input is being annotated
+fn annotate(state: &mut State, args: &ProcessingArgs, row: u64, outcome: &'static str, detail: &str) {
+    let reason = if outcome == "parse_error" { "unparseable" } else { detail };
+    *state.outcome_counts.entry((outcome, reason.to_string())).or_default() += 1;
+    if args.annotate_out.is_some() || args.keep_outcomes {
+        state.row_outcomes.push((row, RowOutcome { outcome, detail: detail.to_string() }));
+    }
+}
+
+// This function writes a copy of the CSV input with the outcome and detail of every row appended. Fields are copied
+// byte for byte, and every row is padded to the widest row so the two new columns line up. Blank and comment rows
+// before the header are rows like any other, so they are numbered the same way the input was read
+fn write_annotated(input: &Path, state: &State, allow_comments: bool, path: &str) -> Result<(), EngineError> {
+    let reader = || -> Result<csv::Reader<File>, EngineError> {
+        Ok(csv::ReaderBuilder::new().flexible(true).has_headers(false).from_path(input)?)
+    };
+
+    let mut rdr = reader()?;
+    let mut width = 0;
+    for record in rdr.byte_records() {
+        width = width.max(record?.len());
+    }
+
+    let mut rdr = reader()?;
+    let mut wtr = WriterBuilder::new().flexible(true).from_path(path)?;
+    let mut outcomes = state.row_outcomes.iter().peekable();
+    let mut header_seen = false;
+    let mut row = 0;
+    for record in rdr.byte_records() {
+        let mut record = record?;
+        while record.len() < width {
+            record.push_field(b"");
+        }
+        if !header_seen && ignored_row(&record, allow_comments).is_none() {
+            header_seen = true;
+            record.push_field(b"outcome");
+            record.push_field(b"detail");
+            wtr.write_byte_record(&record)?;
+            continue;
+        }
+
+        row += 1;
+        match outcomes.next_if(|(outcome_row, _)| *outcome_row == row) {
+            Some((_, annotation)) => {
+                record.push_field(annotation.outcome.as_bytes());
+                record.push_field(annotation.detail.as_bytes());
+            },
+            None => {
+                record.push_field(b"applied");
+                record.push_field(b"");
+            },
+        }
+        wtr.write_byte_record(&record)?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function writes a finished client's account and drops it from the state
+fn stream_client(state: &mut State, referenced: &mut HashSet<u16>, client_id: u16, policy: &EnginePolicy, writer: &mut ReportWriter) -> Result<(), EngineError> {
+    note_peaks(state);
+    let client = state.engine.remove_account(client_id).or_else(|| referenced.contains(&client_id).then(|| Client::new(client_id)));
+    referenced.remove(&client_id);
+    if let Some(mut client) = client {
+        if policy.track_debt() {
+            client.debt.get_or_insert(Money::ZERO);
+        }
+        state.streamed.add(&client)?;
+        writer.account(&client)?;
+    }
+    Ok(())
+}
+
+// This function writes the end of run reports the flags ask for and prints the summaries to stderr, once the input is
+// done. A report that cannot be written is logged and the others are still written, since the account report and the
+// exit status do not depend on them
+pub fn write_reports(state: &State, inputs: &[String], args: &ProcessingArgs, report: &ReportArgs) {
+    if let Some(path) = &report.simulate_chargebacks {
+        if let Err(e) = write_worst_case(&simulate_chargebacks(state), path) {
+            error!("could not write worst case report: {}", e);
+        }
+    }
+
+    if let Some(path) = &report.assertion_report {
+        if let Err(e) = write_assertion_report(state, path) {
+            error!("could not write assertion report: {}", e);
+        }
+    }
+
+    if let Some(path) = &report.why_locked {
+        if let Err(e) = write_why_locked(state, path) {
+            error!("could not write lock causes: {}", e);
+        }
+    }
+
+    if let Some(path) = &report.dispute_aging {
+        if let Err(e) = write_dispute_aging(state, path) {
+            error!("could not write dispute aging report: {}", e);
+        }
+    }
+
+    if let Some(path) = &report.volume_report {
+        if let Err(e) = write_volume_report(state, path) {
+            error!("could not write volume report: {}", e);
+        }
+    }
+
+    // Checked by the caller to be a single input file
+    if let (Some(path), [input]) = (&args.annotate_out, inputs) {
+        if let Err(e) = write_annotated(Path::new(input), state, args.allow_comments, path) {
+            error!("could not write annotated input: {}", e);
+        }
+    }
+
+    if report.report_anomalies {
+        print_anomalies(state, report);
+    }
+    if report.summary {
+        if let Err(e) = print_summary(state, report) {
+            error!("could not print summary: {}", e);
+        }
+    }
+    if report.stats {
+        print_stats(state);
+    }
+
+    if !state.skipped.is_empty() {
+        warn!("processed {} rows, skipped {}.", state.rows, state.skipped.len());
+    }
+    if let Some(path) = &report.skipped_rows {
+        if let Err(e) = write_skipped_rows(state, path) {
+            error!("could not write skipped rows: {}", e);
+        }
+    }
+
+    // Many of these usually mean the input was paired with the wrong file
+    if !state.dangling.is_empty() {
+        warn!("{} dispute, resolve or chargeback rows reference transactions that are not in the input.", state.dangling.len());
+    }
+    if let Some(path) = &report.dangling_refs {
+        if let Err(e) = write_dangling_refs(state, path) {
+            error!("could not write dangling references: {}", e);
+        }
+    }
+}
+
+// This function compares a client's balances against an assert row, describing every mismatch
+fn check_assertion(state: &State, transaction: &TransactionRow) -> Result<(), String> {
+    let client = state.engine.account_for(transaction).ok_or_else(|| Rejection::UnknownClient.to_string())?;
+
+    let mut mismatches = Vec::new();
+    let mut compare = |name: &str, actual: Money, expected: Money| {
+        if actual != expected {
+            mismatches.push(format!("{} is {}, expected {}", name, actual, expected));
+        }
+    };
+    if let Some(available) = transaction.amount {
+        compare("available", client.available, available);
+    }
+    if let Some((held, total)) = transaction.expected_held_total {
+        compare("held", client.held, held);
+        compare("total", client.total, total);
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches.join("; "))
+    }
+}
+
+// This function writes the failed assertions in the order they occurred
+fn write_assertion_report(state: &State, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+    for failure in &state.failed_assertions {
+        wtr.serialize(failure)?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function opens or closes the dispute span of an applied dispute, resolve or chargeback
+fn track_dispute_span(state: &mut State, transaction: &TransactionRow, row: u64) {
+    let outcome = match transaction.transaction_type {
+        TransactionType::Dispute => {
+            state.open_disputes.insert(transaction.transaction_id, state.disputes.len());
+            state.disputes.push(DisputeSpan {
+                tx: transaction.transaction_id,
+                client: transaction.client_id,
+                opened_row: row,
+                closed_row: None,
+                outcome: None,
+            });
+            return;
+        },
+        TransactionType::Resolve => "resolved",
+        TransactionType::Chargeback => "charged_back",
+        _ => return,
+    };
+
+    if let Some(span) = state.open_disputes.remove(&transaction.transaction_id).and_then(|index| state.disputes.get_mut(index)) {
+        span.closed_row = Some(row);
+        span.outcome = Some(outcome);
+    }
+}
+
+// This function writes one row per dispute opened during the run, in the order they were opened
+fn write_dispute_aging(state: &State, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+    for span in &state.disputes {
+        let end = span.closed_row.unwrap_or(state.rows);
+        wtr.serialize(DisputeAgingRow {
+            tx: span.tx,
+            client: span.client,
+            opened_row: span.opened_row,
+            closed_row: span.closed_row,
+            outcome: span.outcome.unwrap_or("open"),
+            age_rows: end.saturating_sub(span.opened_row),
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function names the amount a row moves, looked up before it is applied. Reference rows move the amount of the
+// record they point at, and a release moves the amount of its hold
+fn moved_amount(state: &State, transaction: &TransactionRow) -> Option<Money> {
+    match transaction.transaction_type {
+        kind if kind.is_dispute_lifecycle() => state.engine.record(transaction.transaction_id).map(|record| record.amount),
+        TransactionType::AdminRelease => state.engine.admin_hold(transaction.transaction_id).map(|hold| hold.amount),
+        _ => transaction.amount,
+    }
+}
+
+// This function records the fee of a chargeback the row applied, if the engine tried to debit one since `before`. The
+// fee gets its own line in the audit log after the chargeback's. A debited fee counts towards the client's fee volume,
+// and one that could not be debited is kept for --report-anomalies
+fn record_fee(state: &mut State, audit: Option<&mut audit::AuditLog>, row: u64, line: Option<u64>, transaction: &TransactionRow, fee: Money, before: FeeCounts) -> Result<(), EngineError> {
+    let after = state.engine.fee_counts();
+    let account = state.engine.account_key(transaction).unwrap_or(transaction.account());
+    let outcome = if after.charged > before.charged {
+        let volume = state.fees.entry(account).or_default();
+        volume.count += 1;
+        volume.amount = volume.amount.checked_add(fee).ok_or_else(|| format!("chargeback fees of client {} overflowed", transaction.client_id))?;
+        ("fee_charged", "")
+    } else if after.failed > before.failed {
+        state.failed_fees.push(FailedFee { account, tx: transaction.transaction_id, row, fee });
+        ("fee_failed", Rejection::Overflow.code())
+    } else {
+        return Ok(());
+    };
+    if let Some(log) = audit {
+        let fee_row = TransactionRow { amount: Some(fee), ..transaction.clone() };
+        log.write(row, line, Some(&fee_row), outcome.0, outcome.1, state.engine.account_for(transaction))?;
+    }
+    Ok(())
+}
+
+// This function records the monthly fees the engine charged since they were last taken, with a line each in the audit
+// log. A debited fee counts towards the client's monthly fee volume, and one that could not be debited is kept for
+// --report-anomalies
+fn record_monthly_fees(state: &mut State, mut audit: Option<&mut audit::AuditLog>, row: u64, line: Option<u64>) -> Result<(), EngineError> {
+    for fee in state.engine.take_monthly_fees() {
+        match fee.result {
+            Ok(()) => {
+                let volume = state.monthly_fees.entry(fee.account).or_default();
+                volume.count += 1;
+                volume.amount = volume.amount.checked_add(fee.fee).ok_or_else(|| format!("monthly fees of client {} overflowed", fee.account.client_id))?;
+            },
+            Err(_) => state.failed_monthly_fees.push(FailedFee { account: fee.account, tx: fee.tx, row, fee: fee.fee }),
+        }
+        if let Some(log) = audit.as_deref_mut() {
+            log.write_monthly_fee(row, line, &fee)?;
+        }
+    }
+    Ok(())
+}
+
+// This function counts an applied row and its amount towards the volume of the account it moved and its type
+fn add_volume(state: &mut State, account: AccountKey, transaction: &TransactionRow, amount: Option<Money>) -> Result<(), EngineError> {
+    // TransactionType is declared in the order of ALL, so its discriminant is its index there
+    let volumes = state.seen_accounts.entry(account).or_default();
+    if let Some(volume) = volumes.0.get_mut(transaction.transaction_type as usize) {
+        volume.count += 1;
+        volume.amount = volume.amount.checked_add(amount.unwrap_or(Money::ZERO))
+            .ok_or_else(|| format!("{} volume of client {} overflowed", transaction.transaction_type, transaction.client_id))?;
+    }
+    Ok(())
+}
+
+// This function notes an applied row that left the client's available balance below zero. Going below zero counts as
+// one event, rows that take it lower still only update the lowest balance
+fn track_negative_available(state: &mut State, account: AccountKey, available_before: Option<Money>, available_after: Option<Money>) {
+    let Some(available) = available_after.filter(|&available| available < Money::ZERO) else {
+        return;
+    };
+    let entry = state.negative_available.entry(account).or_insert(NegativeAvailable { events: 0, lowest: available });
+    if available_before.is_none_or(|before| before >= Money::ZERO) {
+        entry.events += 1;
+    }
+    entry.lowest = entry.lowest.min(available);
+}
+
+// This function prints one line per account whose available balance went below zero, one per chargeback whose fee could
+// not be debited, then one per account that still has disputes open at the end of the run, each sorted by client id and
+// currency. Amounts have the decimal places the account report gives their currency
+fn print_anomalies(state: &State, report: &ReportArgs) {
+    let amount = |value: Money, currency: Option<Currency>| Places(value, places(state, report, currency));
+    for (account, negative) in &state.negative_available {
+        eprintln!("anomaly client={}{} kind=negative_available events={} lowest_available={}", account.client_id, currency_field(account.currency), negative.events, amount(negative.lowest, account.currency));
+    }
+    for failed in &state.failed_fees {
+        eprintln!("anomaly client={}{} kind=chargeback_fee_failed tx={} row={} fee={}", failed.account.client_id, currency_field(failed.account.currency), failed.tx, failed.row, amount(failed.fee, failed.account.currency));
+    }
+    for failed in &state.failed_monthly_fees {
+        eprintln!("anomaly client={}{} kind=monthly_fee_failed tx={} row={} fee={}", failed.account.client_id, currency_field(failed.account.currency), failed.tx, failed.row, amount(failed.fee, failed.account.currency));
+    }
+
+    let mut disputing: Vec<&Client> = state.engine.accounts().filter(|client| !client.open_disputes().is_empty()).collect();
+    disputing.sort_unstable_by_key(|client| client.account());
+    for client in disputing {
+        let txs: Vec<String> = client.open_disputes().keys().map(u32::to_string).collect();
+        let account = format!("{}{}", client.client_id, currency_field(client.currency));
+        match client.disputed() {
+            Some(disputed) => eprintln!("anomaly client={} kind=open_disputes count={} held={} txs={}", account, txs.len(), amount(disputed, client.currency), txs.join(",")),
+            None => eprintln!("anomaly client={} kind=open_disputes count={} held=overflow txs={}", account, txs.len(), txs.join(",")),
+        }
+    }
+
+    // Disputes that are still waiting for funds under --dispute-requires-funds hold nothing
+    let mut waiting: Vec<&Client> = state.engine.accounts().filter(|client| !client.pending_disputes().is_empty()).collect();
+    waiting.sort_unstable_by_key(|client| client.account());
+    for client in waiting {
+        let txs: Vec<String> = client.pending_disputes().iter().map(|(transaction_id, _)| transaction_id.to_string()).collect();
+        let account = format!("{}{}", client.client_id, currency_field(client.currency));
+        let available = amount(client.available, client.currency);
+        match client.pending_disputes().iter().try_fold(Money::ZERO, |sum, &(_, amount)| sum.checked_add(amount)) {
+            Some(pending) => eprintln!("anomaly client={} kind=pending_disputes count={} amount={} available={} txs={}", account, txs.len(), amount(pending, client.currency), available, txs.join(",")),
+            None => eprintln!("anomaly client={} kind=pending_disputes count={} amount=overflow available={} txs={}", account, txs.len(), available, txs.join(",")),
+        }
+    }
+}
+
+// This function returns the decimal places the account report writes amounts in the currency with: its scale from
+// --currency-scales if one applies, and --precision otherwise
+fn places(state: &State, report: &ReportArgs, currency: Option<Currency>) -> u32 {
+    state.engine.policy().currency_scales().places(currency).unwrap_or(report.precision)
+}
+
+// This function returns the currency=... field of a summary or anomaly line, empty for an account without a currency
+fn currency_field(currency: Option<Currency>) -> String {
+    currency.map_or_else(String::new, |currency| format!(" currency={}", currency))
+}
+
+// This function lists every row that was not applied, other than rows ignored or skipped on purpose, followed by their
+// count. It returns the count
+pub fn print_issues(state: &State, args: &ProcessingArgs) -> u64 {
+    let mut issues = 0;
+    for (row, outcome) in &state.row_outcomes {
+        if matches!(outcome.outcome, "ignored" | "ignored_duplicate_tx" | "skipped") {
+            continue;
+        }
+        issues += 1;
+        match input_line(args, *row) {
+            Some(line) => eprintln!("issue row={} line={} outcome={} detail={}", row, line, outcome.outcome, outcome.detail),
+            None => eprintln!("issue row={} outcome={} detail={}", row, outcome.outcome, outcome.detail),
+        }
+    }
+    eprintln!("dry-run: {} issues in {} rows", issues, state.rows);
+    issues
+}
+
+// This function prints how many rows were applied by type and not applied by outcome and reason, then the number of
+// clients, locked accounts and chargebacks, the funds held and in total across them and the disputes still open. Counts were kept
+// during the run, and accounts already streamed out are included. Amounts in different currencies are not added up,
+// so with currencies the account, dispute and fee lines come once for each
+fn print_summary(state: &State, report: &ReportArgs) -> Result<(), EngineError> {
+    let mut applied = BTreeMap::<&str, u64>::new();
+    for ((_, transaction_type), volume) in state.volumes() {
+        *applied.entry(transaction_type).or_default() += volume.count;
+    }
+    let not_applied: u64 = state.outcome_counts.values().sum();
+    eprintln!("summary report schema={}", schema_name(report.report_schema));
+    eprintln!("summary rows={} applied={} not_applied={}", state.rows, applied.values().sum::<u64>(), not_applied);
+    for (transaction_type, count) in applied {
+        eprintln!("summary applied type={} count={}", transaction_type, count);
+    }
+    for ((outcome, reason), count) in &state.outcome_counts {
+        eprintln!("summary not_applied outcome={} reason={} count={}", outcome, reason, count);
+    }
+
+    // Streamed accounts never have a currency, and a run without accounts still gets its lines of zeros
+    let mut accounts = BTreeMap::<Option<Currency>, AccountTotals>::new();
+    if state.streamed.clients > 0 || state.engine.accounts().len() == 0 {
+        accounts.insert(None, state.streamed);
+    }
+    for client in state.engine.accounts() {
+        accounts.entry(client.currency).or_default().add(client)?;
+    }
+    for (&currency, accounts) in &accounts {
+        let (field, places) = (currency_field(currency), places(state, report, currency));
+        eprintln!("summary accounts{} clients={} locked={} chargebacks={} held={} total={}", field, accounts.clients, accounts.locked, accounts.chargebacks, Places(accounts.held, places), Places(accounts.total, places));
+        eprintln!("summary open_disputes{} count={} held={}", field, accounts.open_disputes, Places(accounts.disputed, places));
+    }
+    for (name, fees, failed) in [("chargeback_fees", &state.fees, &state.failed_fees), ("monthly_fees", &state.monthly_fees, &state.failed_monthly_fees)] {
+        for (currency, totals) in fee_totals(fees, failed)? {
+            eprintln!("summary {}{} count={} amount={} failed={}", name, currency_field(currency), totals.count, Places(totals.amount, places(state, report, currency)), totals.failed);
+        }
+    }
+    Ok(())
+}
+
+// How many fees of one kind were debited in a currency, their sum, and how many could not be debited
+#[derive(Debug, Clone, Copy, Default)]
+struct FeeTotals {
+    count: u64,
+    amount: Money,
+    failed: usize,
+}
+
+// This function adds up the fees debited and failed across all accounts, by currency
+fn fee_totals(fees: &BTreeMap<AccountKey, Volume>, failed: &[FailedFee]) -> Result<BTreeMap<Option<Currency>, FeeTotals>, EngineError> {
+    let mut totals = BTreeMap::<Option<Currency>, FeeTotals>::new();
+    for (account, volume) in fees {
+        let total = totals.entry(account.currency).or_default();
+        total.count += volume.count;
+        total.amount = total.amount.checked_add(volume.amount).ok_or("total fees overflowed")?;
+    }
+    for fee in failed {
+        totals.entry(fee.account.currency).or_default().failed += 1;
+    }
+    Ok(totals)
+}
+
+// This function prints the line that closes a run, e.g. done: 120000 rows, 119990 applied, 7 rejected, 2 skipped, 1 ignored,
+// 371 clients, 4 locked. Rejected counts the rows the engine refused, skipped the rows that could not be read, as in the
+// "processed N rows, skipped M" warning, and ignored the rows left out on purpose, e.g. comments, rows of filtered
+// clients and rows already applied, so the four add up to the rows. Like the warnings it is left out below the warn level
+pub fn print_done(state: &State) {
+    if !log::log_enabled!(target: DONE_TARGET, log::Level::Warn) {
+        return;
+    }
+    let applied: u64 = state.seen_accounts.values().flat_map(|volumes| volumes.0).map(|volume| volume.count).sum();
+    let skipped = state.skipped.len() as u64;
+    let (mut rejected, mut ignored) = (0, 0);
+    for (&(outcome, _), &count) in &state.outcome_counts {
+        match outcome {
+            // The rows in state.skipped
+            "parse_error" | "unknown_type" => {},
+            "ignored" | "ignored_duplicate_tx" | "skipped" => ignored += count,
+            _ => rejected += count,
+        }
+    }
+    let (mut clients, mut locked) = (state.streamed.clients, state.streamed.locked);
+    for client in state.engine.accounts() {
+        clients += 1;
+        locked += u64::from(client.locked);
+    }
+    warn!(target: DONE_TARGET, rows = state.rows, applied, rejected, skipped, ignored, clients, locked;
+        "done: {} rows, {} applied, {} rejected, {} skipped, {} ignored, {} clients, {} locked", state.rows, applied, rejected, skipped, ignored, clients, locked);
+}
+
+// This function remembers how many accounts and stored records the engine holds, if that is more than before
+fn note_peaks(state: &mut State) {
+    state.peak_clients = state.peak_clients.max(state.engine.accounts().len());
+    state.peak_records = state.peak_records.max(state.engine.transactions().len());
+}
+
+// This function prints the --stats line, e.g. stats wall_secs=2.401 rows=3000000 rows_per_sec=1249479 peak_clients=65535 peak_records=2400000
+fn print_stats(state: &State) {
+    let wall = match (state.started, state.finished) {
+        (Some(started), Some(finished)) => finished.duration_since(started),
+        _ => Duration::ZERO,
+    };
+    let rate = if wall.is_zero() { 0 } else { (state.rows as f64 / wall.as_secs_f64()) as u64 };
+    eprintln!("stats wall_secs={:.3} rows={} rows_per_sec={} peak_clients={} peak_records={}", wall.as_secs_f64(), state.rows, rate, state.peak_clients, state.peak_records);
+}
+
+// This function writes the volume of every account and type, sorted by client id, currency and type, followed by a TOTAL
+// row per type. With currencies the report gets a currency column and every currency its own TOTAL rows
+fn write_volume_report(state: &State, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+
+    // Chargeback and monthly fees are listed as two more types, in account order with the rest
+    let mut volumes = state.volumes();
+    volumes.extend(state.fees.iter().map(|(&account, &volume)| ((account, "chargeback_fee"), volume)));
+    volumes.extend(state.monthly_fees.iter().map(|(&account, &volume)| ((account, "monthly_fee"), volume)));
+    let currencies = volumes.keys().any(|(account, _)| account.currency.is_some());
+
+    let mut totals = BTreeMap::<(&str, Option<Currency>), Volume>::new();
+    for ((account, transaction_type), volume) in volumes {
+        let total = totals.entry((transaction_type, account.currency)).or_default();
+        total.count += volume.count;
+        total.amount = total.amount.checked_add(volume.amount).ok_or_else(|| format!("total {} volume overflowed", transaction_type))?;
+        wtr.serialize(VolumeRow {
+            client: account.client_id.to_string(),
+            currency: currencies.then_some(account.currency),
+            transaction_type,
+            count: volume.count,
+            amount: volume.amount,
+        })?;
+    }
+    for ((transaction_type, currency), total) in totals {
+        wtr.serialize(VolumeRow {
+            client: "TOTAL".to_string(),
+            currency: currencies.then_some(currency),
+            transaction_type,
+            count: total.count,
+            amount: total.amount,
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function writes one row per reference to a tx id that never appeared in the input
+fn write_dangling_refs(state: &State, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+    for dangling in &state.dangling {
+        wtr.serialize(dangling)?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function writes every skipped row with its content and the reason it was skipped
+fn write_skipped_rows(state: &State, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+    for skipped in &state.skipped {
+        wtr.serialize(skipped)?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function names what a row of the given type does when it locks an account
+fn lock_cause(transaction_type: TransactionType) -> &'static str {
+    match transaction_type {
+        TransactionType::Chargeback => "chargeback",
+        TransactionType::AdminLock => "admin_lock",
+        _ => "other",
+    }
+}
+
+// This function writes the lock cause of every locked client, sorted by client id
+fn write_why_locked(state: &State, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+
+    let mut causes: Vec<&LockCause> = state.lock_causes.values().collect();
+    causes.sort_unstable_by_key(|cause| cause.client);
+    for cause in causes {
+        wtr.serialize(cause)?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function drops records that are past the retention horizon at the given row.
+// Records under dispute are kept and looked at again one horizon later. Dropped records are remembered for one more
+// horizon, so a late reference is rejected as expired rather than unknown
+fn evict_expired_records(state: &mut State, retention: RecordRetention, row: u64) {
+    note_peaks(state);
+    while let Some(&(stored, transaction_id)) = state.record_rows.front() {
+        if stored.saturating_add(retention.rows) >= row {
+            break;
+        }
+        state.record_rows.pop_front();
+
+        if state.engine.record(transaction_id).is_some_and(|record| record.state.is_disputed()) {
+            state.record_rows.push_back((row, transaction_id));
+        } else if state.engine.remove_record(transaction_id).is_some() {
+            state.evicted.insert(transaction_id, row);
+            state.evicted_rows.push_back((row, transaction_id));
+        }
+    }
+
+    // A tx id stored and dropped again later was dropped at a later row, and is only forgotten once that one is old
+    while let Some(&(evicted, transaction_id)) = state.evicted_rows.front() {
+        if evicted.saturating_add(retention.rows) >= row {
+            break;
+        }
+        state.evicted_rows.pop_front();
+        if state.evicted.get(&transaction_id) == Some(&evicted) {
+            state.evicted.remove(&transaction_id);
+        }
+    }
+}
+
+// This function charges back every dispute still open on a copy of the state, showing the worst case if all of them were lost.
+// The real state is left untouched. Accounts are keyed by client and currency, so each currency of a client is its own
+fn simulate_chargebacks(state: &State) -> BTreeMap<AccountKey, Client> {
+    let mut simulated = state.engine.clone();
+
+    let mut open_disputes: Vec<(u32, u16)> = simulated.records()
+        .filter(|(_, record)| record.state.is_disputed())
+        .map(|(transaction_id, record)| (transaction_id, record.client_id))
+        .collect();
+    open_disputes.sort_unstable();
+
+    for (transaction_id, client_id) in open_disputes {
+        if let Err(e) = simulated.issue_chargeback(transaction_id, client_id) {
+            warn!("simulated chargeback {} for client {} rejected: {}.", transaction_id, client_id, e);
+        }
+    }
+
+    simulated.accounts().map(|client| (client.account(), client.clone())).collect()
+}
+
+// This function writes the worst case balances of every account, sorted by client id and currency. With currencies the
+// file gets a currency column, like the account report
+fn write_worst_case(worst_case: &BTreeMap<AccountKey, Client>, path: &str) -> Result<(), EngineError> {
+    let mut wtr = WriterBuilder::new().from_path(path)?;
+
+    let currencies = worst_case.keys().any(|account| account.currency.is_some());
+    for client in worst_case.values() {
+        wtr.serialize(WorstCaseRow {
+            client: client.client_id,
+            currency: currencies.then_some(client.currency),
+            worst_available: client.available,
+            worst_total: client.total,
+            would_lock: client.locked,
+        })?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+// This function opens a CSV input file, which is taken as given: absolute or relative to the working directory
+pub(crate) fn open_input(path: &Path) -> Result<File, EngineError> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open input {}: {}", path.display(), e)).into())
+}
+
+// This function wraps a CSV input in a streaming decoder for its compression, if it has one. With auto the compression
+// is picked from the extension of `path`, and input without a path is read as is
+pub(crate) fn decompress<'a, R: io::Read + 'a>(reader: R, compression: Compression, path: Option<&Path>) -> Result<Box<dyn io::Read + 'a>, EngineError> {
+    let compression = match compression {
+        Compression::Auto => match path.and_then(Path::extension).and_then(|extension| extension.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        },
+        compression => compression,
+    };
+
+    match compression {
+        Compression::Auto | Compression::None => Ok(Box::new(reader)),
+        #[cfg(feature = "compression")]
+        Compression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(reader))),
+        #[cfg(feature = "compression")]
+        Compression::Zstd => Ok(Box::new(zstd::Decoder::new(reader)?)),
+        #[cfg(not(feature = "compression"))]
+        Compression::Gzip | Compression::Zstd => Err("reading compressed input needs a build with the compression feature".into()),
+    }
+}
+
+// This function returns an iterator parsing each CSV row read from `reader` into a transaction, with None for rows of
+// clients outside the filter and an IgnoredRow error for blank and comment rows, which may also come before the header
+pub(crate) fn read_csv<'a, R: io::Read + 'a>(reader: R, client_filter: Option<&'a ClientFilter>, args: &ProcessingArgs, bytes: &ByteCounter) -> impl Iterator<Item = Result<Option<TransactionRow>, Box<dyn Error>>> + 'a {
+    let mut rdr = csv::ReaderBuilder::new()
+                    .trim(Trim::All)
+                    .flexible(true)
+                    .has_headers(false)
+                    .from_reader(CountingReader::new(reader, bytes));
+    let separator = args.thousands_separator;
+    let allow_comments = args.allow_comments;
+
+    // The header is the first row that is not blank or a comment, and any rows before it are passed on as ignored.
+    // Columns are looked up by their header name in any case, so their order does not matter and unknown columns are
+    // ignored, as is the currency column unless --multi-currency is given
+    // Every row is read into the same record, so reading a row does not allocate
+    let mut record = StringRecord::new();
+    let mut leading = Vec::new();
+    let headers = loop {
+        match rdr.read_record(&mut record) {
+            Ok(true) => match ignored_row(record.as_byte_record(), allow_comments) {
+                Some(kind) => leading.push(kind),
+                None => break Ok(record.iter().map(str::to_ascii_lowercase).collect::<StringRecord>()),
+            },
+            Ok(false) => break Ok(StringRecord::new()),
+            Err(e) => break Err(format!("could not read the CSV header: {}", e)),
+        }
+    };
+    let headers = headers.and_then(|headers| match input::missing_column(&headers) {
+        Some(missing) => Err(format!("the CSV header has no {} column", missing)),
+        None => Ok(headers),
+    });
+    let columns = headers.as_ref().map_or(OptionalColumns::default(), |headers| OptionalColumns::find(headers, args.multi_currency));
+    let keep_client = move |client_id: u16| client_filter.is_none_or(|filter| filter.contains(client_id));
+
+    let leading = leading.into_iter().map(|kind| Err(IgnoredRow(kind).into()));
+    leading.chain(std::iter::from_fn(move || {
+        let result = rdr.read_record(&mut record);
+        if matches!(result, Ok(false)) {
+            return None;
+        }
+        if let Some(kind) = result.as_ref().ok().and_then(|_| ignored_row(record.as_byte_record(), allow_comments)) {
+            return Some(Err(IgnoredRow(kind).into()));
+        }
+        Some(headers.as_ref().map_err(|e| e.as_str().into()).and_then(|headers| match result {
+            Ok(_) => input::parse_row(&record, headers, columns, keep_client, separator.0).map_err(|e| {
+                let raw = record.iter().collect::<Vec<_>>().join(",");
+                let unknown_type = match e.downcast_ref::<EngineError>() {
+                    Some(EngineError::UnknownTransactionType(name)) => Some(name.clone()),
+                    _ => None,
+                };
+                BadRow { raw, reason: e.to_string(), unknown_type }.into()
+            }),
+            // A row that is not valid UTF-8 or cannot be split into fields still leaves the reader at the next row
+            Err(e) if !e.is_io_error() => Err(BadRow { raw: String::new(), reason: e.to_string(), unknown_type: None }.into()),
+            Err(e) => Err(e.into()),
+        }))
+    }))
+}
+
+// This function opens the account report in the requested format, to stdout unless an output file is given
+pub fn open_report(args: &ReportArgs) -> Result<Box<dyn ReportSink>, EngineError> {
+    let format = args.format;
+    let sink = move |out: Box<dyn Write>| -> Box<dyn ReportSink> {
+        match format {
+            OutputFormat::Csv => Box::new(CsvSink::new(out)),
+            OutputFormat::Json => Box::new(JsonSink::new(out)),
+            OutputFormat::Table => Box::new(TableSink::new(out)),
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => Box::new(crate::arrow_io::ArrowSink::new(out)),
+        }
+    };
+
+    Ok(match &args.output {
+        Some(path) => Box::new(AtomicSink::create(path, sink)?),
+        None => sink(Box::new(io::stdout())),
+    })
+}
+
+// This function writes the account report once the input has been processed
+pub fn write_report(state: &State, args: &ReportArgs) -> Result<(), EngineError> {
+    let mut sink = open_report(args)?;
+    report::write_report(state.engine.accounts(), state.rows, &report_options(args, state.engine.policy().currency_scales()), sink.as_mut())
+}
+
+pub fn report_options(args: &ReportArgs, scales: &CurrencyScales) -> ReportOptions {
+    ReportOptions { schema: args.report_schema, precision: args.precision, scales: scales.clone(), clients: ClientFilter::union(&args.client), totals_row: args.totals_row }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::RunMeta;
+    use crate::AccountSummary;
+    use clap::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn input_errors_keep_their_kind() {
+        let io: Box<dyn Error> = Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "cut short"));
+        assert!(matches!(input_error(io, Some(4)), EngineError::Io(_)));
+        let unknown: Box<dyn Error> = Box::new(EngineError::UnknownTransactionType("transfer".to_string()));
+        assert!(matches!(input_error(unknown, Some(4)), EngineError::UnknownTransactionType(_)));
+        let bad: Box<dyn Error> = "line 4 has no client".into();
+        assert!(matches!(input_error(bad, Some(4)), EngineError::Parse { line: Some(4), .. }));
+    }
+
+    // The flags of the command line tool that a test runs with, without its subcommands
+    #[derive(Parser)]
+    struct Args {
+        input_files: Vec<String>,
+
+        #[clap(flatten)]
+        processing: ProcessingArgs,
+
+        #[clap(flatten)]
+        report: ReportArgs,
+    }
+
+    // A sink that notes each account it is handed in a log it shares with the row observer, so the log shows when
+    // during the input each account was written
+    struct LoggingSink(Rc<RefCell<Vec<String>>>);
+
+    impl ReportSink for LoggingSink {
+        fn begin(&mut self, _meta: &RunMeta) -> Result<(), EngineError> {
+            Ok(())
+        }
+
+        fn write_account(&mut self, account: &AccountSummary) -> Result<(), EngineError> {
+            self.0.borrow_mut().push(format!("account {} total={}", account.client, account.total));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), EngineError> {
+            self.0.borrow_mut().push("finish".to_string());
+            Ok(())
+        }
+    }
+
+    // This function runs a fixture with --streaming-output --assume-grouped-by client into a LoggingSink, returning the
+    // log of applied rows and written accounts
+    fn stream_fixture(name: &str) -> Result<Vec<String>, EngineError> {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let args = Args::try_parse_from(["payment_engine", path.as_str(), "--streaming-output", "--assume-grouped-by", "client"]).map_err(|e| e.to_string())?;
+        let policy = args.processing.policy()?;
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut sink = LoggingSink(Rc::clone(&log));
+        let mut writer = ReportWriter::begin(&mut sink, &report_options(&args.report, policy.currency_scales()), &RunMeta {
+            schema: args.report.report_schema, precision: args.report.precision, scales: policy.currency_scales().clone(),
+            rows: None, accounts: None, currencies: false, totals: false,
+        })?;
+        let rows = Rc::clone(&log);
+        let mut observer = |outcome: Outcome| rows.borrow_mut().push(format!("row {}", outcome.row));
+        let processed = process_input(&args.input_files, &args.processing, &policy, Some(&mut observer), Some(&mut writer));
+        processed.and_then(|state| {
+            // Streamed clients are gone from the state by the end of the input
+            assert_eq!(state.engine.accounts().count(), 0);
+            writer.finish()
+        })?;
+        let log = log.borrow().clone();
+        Ok(log)
+    }
+
+    // Each client is written as soon as the next client's first row is read, before that row is applied
+    #[test]
+    fn grouped_input_writes_each_client_once_its_rows_end() -> Result<(), EngineError> {
+        let total = |amount: &str| amount.parse::<Money>().map(|amount| amount.to_string()).map_err(|_| EngineError::from("invalid amount"));
+        assert_eq!(stream_fixture("streaming_grouped.csv")?, [
+            "row 1".to_string(),
+            "row 2".to_string(),
+            format!("account 1 total={}", total("6")?),
+            "row 3".to_string(),
+            "row 4".to_string(),
+            format!("account 2 total={}", total("5")?),
+            "row 5".to_string(),
+            format!("account 3 total={}", total("1")?),
+            "finish".to_string(),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn ungrouped_input_fails_at_the_row_for_a_finished_client() {
+        let error = stream_fixture("streaming_ungrouped.csv").err().map(|e| e.to_string());
+        assert_eq!(error.as_deref(), Some("input is not grouped by client: line 5 is for client 1, whose rows ended earlier"));
+    }
+}
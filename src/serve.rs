@@ -0,0 +1,570 @@
+// The `serve` subcommand: keep an engine in memory and apply transactions posted to it over HTTP.
+//
+//     POST /transactions              {"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}
+//     GET  /accounts                  every account, as an array in the layout of the JSON report
+//     GET  /accounts/{client}         one account
+//     POST /accounts/{client}/lock    lock the account, as an admin_lock row would
+//     POST /accounts/{client}/unlock  unlock it again, as an admin_unlock row would
+//     GET  /ready                     200 while serving, 503 once shutting down, for readiness probes
+//
+// Locks and unlocks go through the engine like posted transactions, so they take the same lock and count in its
+// statistics. Their body may be empty, or name the {"currency"} of the account with --multi-currency.
+// Each connection handles one request. Accepted connections wait in a queue of --queue-size for one of --max-in-flight
+// worker threads, so no more requests than that are read or applied at once. While the queue is full no connection is
+// accepted, and the callers are pushed back by TCP until a worker takes one; a warning is logged each time this starts
+// and an info line when it ends. The engine is split by client into --shards shards, see ShardedEngine, so workers apply
+// transactions for clients in different shards at the same time, and those for one shard one at a time in the order
+// their requests take its lock. A rejected transaction is answered with a 4xx status and a JSON body carrying the
+// rejection code, e.g. {"error": "insufficient_funds", "message": "..."}.
+// With --max-pending, a connection that arrives while that many are queued or being handled is answered with 429
+// straight away instead of waiting.
+// The engine applies the same policy as the main command, from the same flags, except that --monthly-fee needs
+// --shards 1, and starts from the accounts of
+// --snapshot-in and --opening-balances if given. With --multi-currency a transaction may name a "currency", and
+// /accounts/{client} answers with an array of the client's accounts, one per currency. Flags that only change how
+// input files are read, like --input-format or --strict, have no effect on posted transactions.
+// With --api-keys-file every request needs an Authorization: Bearer header with one of the keys, see api_keys. A missing
+// or unknown key is answered with 401, and a read key asking to POST with 403.
+// With --postgres-url, built with the postgres feature, the accounts live in that database instead: the engine starts from
+// what it holds rather than from --snapshot-in or --opening-balances, and each applied request is written to it before it
+// is answered. A request that cannot be written is undone and answered with 503, see storage.rs.
+// SIGTERM or SIGINT starts a graceful shutdown: /ready answers 503 straight away, new connections are answered 503
+// without being queued, and the requests already queued or being handled are finished, waiting --drain-timeout seconds
+// at most. The accounts are then written to --output and --snapshot-out if given, a done line is printed and the
+// process exits with 0. A second signal exits at once with EXIT_FORCED.
+// A worker waits --request-timeout seconds at most for a request to arrive and for its response to be taken, and answers
+// a request that does not arrive in time with 408. A request line longer than 8 KiB is answered with 400, and headers
+// longer than 16 KiB together with 431, without reading the rest.
+// This is a small HTTP/1.1 server for trusted callers on a local network, not one to expose to the internet.
+
+use crate::api_keys::{ApiKeys, Role};
+use crate::ServeArgs;
+use payment_engine::args::{ClientFilter, ReportSchema};
+use payment_engine::money::Money;
+use payment_engine::report::{self, AtomicSink, CsvSink, JsonSink, ReportOptions};
+use payment_engine::run::start_engine;
+use payment_engine::{snapshot, Currency, EngineError, Rejection, ShardedEngine, TransactionRow, TransactionType};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Largest request body read, far above any single transaction
+const MAX_BODY: usize = 64 * 1024;
+
+// Longest request line read, answered with 400 beyond it
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+
+// Most bytes read for all the headers of a request together, answered with 431 beyond it
+const MAX_HEADERS: usize = 16 * 1024;
+
+// How long a request that is turned away may take to arrive, since it is read on the thread accepting connections
+const REFUSED_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How often a draining server looks for new connections and checks whether the requests in flight are done
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+// A transaction as posted, named like the CSV columns
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransactionBody {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(default)]
+    amount: Option<Money>,
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+// The body of a lock or unlock, which only names a currency
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LockBody {
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: String,
+}
+
+// A request as read from the connection. The bearer token is kept as bytes and only ever compared, never printed
+struct Request {
+    method: String,
+    path: String,
+    token: Option<Vec<u8>>,
+    body: Vec<u8>,
+}
+
+// What every connection thread shares
+struct Shared {
+    engine: ShardedEngine,
+    multi_currency: bool,
+    keys: Option<ApiKeys>,
+    // How long a worker waits on a connection's reads and writes
+    request_timeout: Duration,
+    // Set by the first SIGTERM or SIGINT
+    draining: AtomicBool,
+}
+
+// A response ready to be written, always with a JSON body
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: Vec<u8>) -> Response {
+        Response { status, body }
+    }
+
+    fn error(status: u16, error: &str, message: impl ToString) -> Response {
+        let body = serde_json::to_vec(&ErrorBody { error, message: message.to_string() }).unwrap_or_default();
+        Response { status, body }
+    }
+}
+
+// This function binds the port and serves requests until the process is stopped
+pub(crate) fn run(args: &ServeArgs) -> Result<(), EngineError> {
+    let shared = Arc::new(Shared {
+        engine: start(args)?,
+        multi_currency: args.processing.multi_currency,
+        keys: args.api_keys_file.as_deref().map(ApiKeys::read).transpose()?,
+        request_timeout: Duration::from_secs(args.request_timeout.get()),
+        draining: AtomicBool::new(false),
+    });
+
+    let pending = Arc::new(AtomicUsize::new(0));
+
+    let (queue, connections) = mpsc::sync_channel::<(TcpStream, PendingSlot)>(args.queue_size.get());
+    let connections = Arc::new(Mutex::new(connections));
+    for _ in 0..args.max_in_flight.get() {
+        let (shared, connections) = (Arc::clone(&shared), Arc::clone(&connections));
+        thread::spawn(move || work(&shared, &connections));
+    }
+
+    let listener = TcpListener::bind((args.host.as_str(), args.port))?;
+    let address = listener.local_addr()?;
+    #[cfg(unix)]
+    on_signals(Arc::clone(&shared), listener.try_clone()?, address)?;
+    eprintln!("serve: listening on {}", address);
+    for stream in listener.incoming() {
+        if shared.draining.load(Ordering::Acquire) {
+            // The listener no longer blocks, so this is the wake-up connection, another one or WouldBlock
+            if let Ok(stream) = stream {
+                turn_away_draining(stream);
+            }
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("could not accept a connection: {}.", e);
+                continue;
+            },
+        };
+        if args.max_pending.is_some_and(|max| pending.load(Ordering::Acquire) >= max.get()) {
+            if let Err(e) = refuse(stream) {
+                warn!("could not turn away a request: {}.", e);
+            }
+            continue;
+        }
+        let connection = (stream, PendingSlot::take(&pending));
+        let waiting = match queue.try_send(connection) {
+            Ok(()) => continue,
+            Err(TrySendError::Full(connection)) => connection,
+            Err(TrySendError::Disconnected(_)) => break,
+        };
+        warn!("serve: backpressure, {} connections are waiting for {} workers, accepting no more until one is taken.", args.queue_size, args.max_in_flight);
+        if queue.send(waiting).is_err() {
+            break;
+        }
+        info!("serve: backpressure released, accepting connections again.");
+    }
+    if !shared.draining.load(Ordering::Acquire) {
+        return Err("every serve worker stopped".into());
+    }
+
+    drain(&listener, &pending, Duration::from_secs(args.drain_timeout));
+    // Idle workers stop, and any still holding a connection after a timeout are not waited for
+    drop(queue);
+    shut_down(&shared, args)
+}
+
+// This function starts the engine from --snapshot-in and --opening-balances, or from the database of --postgres-url, which
+// it then writes every applied request to
+fn start(args: &ServeArgs) -> Result<ShardedEngine, EngineError> {
+    let policy = args.processing.policy()?;
+    #[cfg(feature = "postgres")]
+    if let Some(url) = &args.postgres_url {
+        if args.processing.snapshot_in.is_some() || args.processing.opening_balances.is_some() {
+            return Err("--postgres-url starts from the database and cannot be combined with --snapshot-in or --opening-balances".into());
+        }
+        let mut storage = payment_engine::postgres::PostgresStorage::connect(url)?;
+        let engine = payment_engine::Engine::load(&mut storage, policy)?;
+        return Ok(ShardedEngine::new(engine, args.shards)?.with_storage(Box::new(storage)));
+    }
+    ShardedEngine::new(start_engine(&args.processing, &policy)?, args.shards)
+}
+
+// This function handles SIGTERM and SIGINT on a thread of its own. The first one starts draining and wakes the accept
+// loop with a connection of its own, the second one exits at once
+#[cfg(unix)]
+fn on_signals(shared: Arc<Shared>, listener: TcpListener, address: std::net::SocketAddr) -> Result<(), EngineError> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        for (count, signal) in signals.forever().enumerate() {
+            if count > 0 {
+                eprintln!("serve: second signal, exiting without draining");
+                std::process::exit(crate::EXIT_FORCED);
+            }
+            eprintln!("serve: signal {}, draining", signal);
+            shared.draining.store(true, Ordering::Release);
+            // A thread waiting in accept stays there, so the connection below is the last one it returns. From then on
+            // the drain loop polls
+            if let Err(e) = listener.set_nonblocking(true).and_then(|()| TcpStream::connect(address)) {
+                warn!("serve: could not wake the accept loop: {}.", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// This function answers the connections that arrive while draining with 503 until no request is queued or being
+// handled, or until the timeout
+fn drain(listener: &TcpListener, pending: &AtomicUsize, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let in_flight = pending.load(Ordering::Acquire);
+        if in_flight == 0 {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!("serve: {} requests were still in flight after {}s, shutting down without them.", in_flight, timeout.as_secs());
+            return;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => turn_away_draining(stream),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(DRAIN_POLL),
+            Err(e) => {
+                warn!("could not accept a connection: {}.", e);
+                thread::sleep(DRAIN_POLL);
+            },
+        }
+    }
+}
+
+// This function writes the accounts to --output and --snapshot-out and prints the done line
+fn shut_down(shared: &Shared, args: &ServeArgs) -> Result<(), EngineError> {
+    let mut engine = shared.engine.to_engine()?;
+    if let Some(path) = &args.output {
+        let options = ReportOptions { scales: engine.policy().currency_scales().clone(), ..ReportOptions::default() };
+        let mut sink = AtomicSink::create(path, |out| Box::new(CsvSink::new(out)))?;
+        report::write_report(engine.accounts(), engine.report().rows, &options, &mut sink)?;
+    }
+    if let Some(path) = &args.snapshot_out {
+        // --output is written in the default schema, which the snapshot records
+        snapshot::save(&engine, ReportSchema::default(), path)?;
+    }
+    let done = engine.complete();
+    let applied: u64 = done.applied.values().sum();
+    let locked = done.accounts.iter().filter(|account| account.locked).count();
+    eprintln!("serve: done: {} transactions, {} applied, {} rejected, {} clients, {} locked", done.rows, applied, done.rejected, done.accounts.len(), locked);
+    Ok(())
+}
+
+// This function answers the queued connections one at a time until the queue is closed
+fn work(shared: &Shared, connections: &Mutex<Receiver<(TcpStream, PendingSlot)>>) {
+    loop {
+        // The lock is only held while waiting for a connection, so the other workers keep handling theirs
+        let next = match connections.lock() {
+            Ok(connections) => connections.recv(),
+            Err(_) => return,
+        };
+        let Ok((stream, slot)) = next else {
+            return;
+        };
+        if let Err(e) = handle(stream, shared) {
+            warn!("could not answer a request: {}.", e);
+        }
+        drop(slot);
+    }
+}
+
+// One connection counted against --max-pending until it is dropped
+struct PendingSlot(Arc<AtomicUsize>);
+
+impl PendingSlot {
+    fn take(pending: &Arc<AtomicUsize>) -> PendingSlot {
+        pending.fetch_add(1, Ordering::AcqRel);
+        PendingSlot(Arc::clone(pending))
+    }
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// This function answers a connection over the --max-pending limit with 429
+fn refuse(stream: TcpStream) -> Result<(), EngineError> {
+    turn_away(stream, Response::error(429, "too_many_requests", "too many requests are pending, try again later"))
+}
+
+// This function answers a connection that arrives while draining with 503
+fn turn_away_draining(stream: TcpStream) {
+    if let Err(e) = turn_away(stream, Response::error(503, "shutting_down", "the server is shutting down")) {
+        warn!("could not turn away a request: {}.", e);
+    }
+}
+
+// This function answers a connection without queueing it. The request is read first, as closing a connection with
+// unread data resets it and the caller might never see the answer
+fn turn_away(stream: TcpStream, response: Response) -> Result<(), EngineError> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REFUSED_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let _ = read_request(&mut reader);
+    write_response(stream, &response)?;
+    Ok(())
+}
+
+// This function reads one request from the connection and writes its response
+fn handle(stream: TcpStream, shared: &Shared) -> Result<(), EngineError> {
+    stream.set_read_timeout(Some(shared.request_timeout))?;
+    stream.set_write_timeout(Some(shared.request_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader) {
+        // Probes carry no key, and a draining server is not ready whoever asks
+        Ok(request) if request.method == "GET" && request.path == "/ready" => match shared.draining.load(Ordering::Acquire) {
+            false => Response::json(200, br#"{"ready":true}"#.to_vec()),
+            true => Response::error(503, "shutting_down", "the server is shutting down"),
+        },
+        Ok(request) => match authorize(&request, shared.keys.as_ref()) {
+            Ok(()) => route(&request.method, &request.path, &request.body, &shared.engine, shared.multi_currency),
+            Err(refused) => refused,
+        },
+        Err(refused) => refused,
+    };
+    write_response(stream, &response)?;
+    Ok(())
+}
+
+// This function checks the request's API key when there are keys. Reading takes the read role, anything else the admin role
+fn authorize(request: &Request, keys: Option<&ApiKeys>) -> Result<(), Response> {
+    let Some(keys) = keys else {
+        return Ok(());
+    };
+    let Some(role) = request.token.as_deref().and_then(|token| keys.role(token)) else {
+        return Err(Response::error(401, "unauthorized", "a valid API key is needed in an Authorization: Bearer header"));
+    };
+    let needed = if request.method == "GET" { Role::Read } else { Role::Admin };
+    if role < needed {
+        return Err(Response::error(403, "forbidden", format!("{} needs an admin key", request.method)));
+    }
+    Ok(())
+}
+
+// This function reads the request line, the headers and a body of Content-Length bytes, or gives the response to a
+// request that cannot be read
+fn read_request(reader: &mut impl BufRead) -> Result<Request, Response> {
+    let Some(line) = read_bounded_line(reader, MAX_REQUEST_LINE).map_err(unreadable)? else {
+        return Err(Response::error(400, "bad_request", format!("request line is longer than {} bytes", MAX_REQUEST_LINE)));
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(Response::error(400, "bad_request", "malformed request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut length = 0;
+    let mut token = None;
+    let mut left = MAX_HEADERS;
+    loop {
+        let Some(header) = read_bounded_line(reader, left).map_err(unreadable)? else {
+            return Err(Response::error(431, "headers_too_large", format!("request headers are longer than {} bytes", MAX_HEADERS)));
+        };
+        if header.is_empty() {
+            return Err(Response::error(400, "bad_request", "connection closed inside the headers"));
+        }
+        left -= header.len();
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().map_err(|_| Response::error(400, "bad_request", "invalid Content-Length"))?;
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                token = value.trim().strip_prefix("Bearer ").map(|token| token.trim().as_bytes().to_vec());
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(Response::error(400, "bad_request", format!("request body is larger than {} bytes", MAX_BODY)));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(unreadable)?;
+    Ok(Request { method, path, token, body })
+}
+
+// This function reads one line of at most `limit` bytes with its line ending, or None when it goes on past that
+fn read_bounded_line(reader: &mut impl BufRead, limit: usize) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let limit = u64::try_from(limit).unwrap_or(u64::MAX);
+    let read = io::Read::take(&mut *reader, limit.saturating_add(1)).read_line(&mut line)?;
+    Ok((u64::try_from(read).unwrap_or(u64::MAX) <= limit).then_some(line))
+}
+
+// This function answers a request that could not be read, with 408 when it did not arrive in time
+fn unreadable(e: io::Error) -> Response {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Response::error(408, "request_timeout", "the request did not arrive in time"),
+        _ => Response::error(400, "bad_request", e),
+    }
+}
+
+// This function picks the handler for a request
+fn route(method: &str, path: &str, body: &[u8], engine: &ShardedEngine, multi_currency: bool) -> Response {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("POST", ["transactions"]) => post_transaction(engine, body, multi_currency),
+        ("POST", ["accounts", client, action @ ("lock" | "unlock")]) => match client.parse::<u16>() {
+            Ok(client) => post_lock(engine, client, *action == "lock", body, multi_currency),
+            Err(_) => Response::error(400, "bad_request", format!("invalid client id {:?}", client)),
+        },
+        ("GET", ["accounts"]) => get_accounts(engine, None),
+        ("GET", ["accounts", client]) => match client.parse::<u16>() {
+            Ok(client) if multi_currency => get_client_accounts(engine, client),
+            Ok(client) => get_accounts(engine, Some(client)),
+            Err(_) => Response::error(400, "bad_request", format!("invalid client id {:?}", client)),
+        },
+        (_, ["transactions"] | ["accounts"] | ["accounts", _] | ["accounts", _, "lock" | "unlock"]) => Response::error(405, "method_not_allowed", format!("{} is not supported here", method)),
+        _ => Response::error(404, "not_found", format!("no such resource {}", path)),
+    }
+}
+
+// This function applies a posted transaction and answers with the account it moved as it is afterwards
+fn post_transaction(engine: &ShardedEngine, body: &[u8], multi_currency: bool) -> Response {
+    let body: TransactionBody = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, "bad_request", e),
+    };
+    if body.transaction_type == TransactionType::Assert {
+        return Response::error(400, "bad_request", "assert rows are only supported in input files");
+    }
+    if body.currency.is_some() && !multi_currency {
+        return Response::error(400, "bad_request", "a currency is only accepted with --multi-currency");
+    }
+
+    let transaction = TransactionRow { currency: body.currency, ..TransactionRow::new(body.transaction_type, body.client, body.tx, body.amount) };
+    apply(engine, &transaction)
+}
+
+// This function locks or unlocks a client's account and answers with the account as it is afterwards
+fn post_lock(engine: &ShardedEngine, client: u16, lock: bool, body: &[u8], multi_currency: bool) -> Response {
+    let body = if body.is_empty() { Ok(LockBody::default()) } else { serde_json::from_slice::<LockBody>(body) };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, "bad_request", e),
+    };
+    if body.currency.is_some() && !multi_currency {
+        return Response::error(400, "bad_request", "a currency is only accepted with --multi-currency");
+    }
+    let transaction_type = if lock { TransactionType::AdminLock } else { TransactionType::AdminUnlock };
+    apply(engine, &TransactionRow { currency: body.currency, ..TransactionRow::new(transaction_type, client, 0, None) })
+}
+
+// This function applies a transaction and answers with the account it moved, or with why it was rejected
+fn apply(engine: &ShardedEngine, transaction: &TransactionRow) -> Response {
+    match engine.process(transaction) {
+        Ok(Some(account)) => render(report::account_json(&account, &ReportOptions::default())),
+        Ok(None) => Response::error(500, "internal_error", "the applied transaction left no account"),
+        Err(e @ EngineError::Invariant(_)) => Response::error(500, "internal_error", e),
+        Err(e @ EngineError::Storage(_)) => Response::error(503, "storage_unavailable", e),
+        Err(e) => match e.rejection() {
+            Some(rejection) => Response::error(rejection_status(rejection), rejection.code(), rejection),
+            None => Response::error(400, "bad_request", e),
+        },
+    }
+}
+
+// This function answers with every account, or with one client's account
+fn get_accounts(engine: &ShardedEngine, client: Option<u16>) -> Response {
+    let rendered = match client {
+        None => engine.accounts().and_then(|accounts| {
+            let mut out = Vec::new();
+            report::write_report(accounts.iter(), 0, &ReportOptions::default(), &mut JsonSink::new(&mut out)).map(|()| out)
+        }),
+        Some(id) => match engine.account(id) {
+            Ok(Some(account)) => report::account_json(&account, &ReportOptions::default()),
+            Ok(None) => return Response::error(404, Rejection::UnknownClient.code(), format!("client {} does not exist", id)),
+            Err(e) => Err(e),
+        },
+    };
+    render(rendered)
+}
+
+// This function answers with every account of one client, one per currency, in the layout of the JSON report
+fn get_client_accounts(engine: &ShardedEngine, client: u16) -> Response {
+    let accounts = match engine.client_accounts(client) {
+        Ok(accounts) if accounts.is_empty() => return Response::error(404, Rejection::UnknownClient.code(), format!("client {} does not exist", client)),
+        Ok(accounts) => accounts,
+        Err(e) => return Response::error(500, "internal_error", e),
+    };
+    let options = ReportOptions { clients: Some(ClientFilter::single(client)), ..ReportOptions::default() };
+    let mut out = Vec::new();
+    render(report::write_report(accounts.iter(), 0, &options, &mut JsonSink::new(&mut out)).map(|()| out))
+}
+
+fn render(rendered: Result<Vec<u8>, EngineError>) -> Response {
+    match rendered {
+        Ok(body) => Response::json(200, body),
+        Err(e) => Response::error(500, "internal_error", e),
+    }
+}
+
+// This function maps a rejection to the status it is answered with
+fn rejection_status(rejection: Rejection) -> u16 {
+    match rejection {
+        Rejection::UnknownClient | Rejection::UnknownTransaction => 404,
+        Rejection::MissingAmount | Rejection::InvalidAmount => 422,
+        _ => 409,
+    }
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let challenge = if response.status == 401 { "WWW-Authenticate: Bearer\r\n" } else { "" };
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status, reason, response.body.len(), challenge)?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
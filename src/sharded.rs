@@ -0,0 +1,366 @@
+// An engine split by client across shards, each behind a lock of its own, so that threads applying rows for different
+// clients do not wait for each other. `serve` uses one so its workers can apply requests side by side.
+//
+// A client's accounts, stored transactions and admin holds all live in the shard its client id maps to, so a row only
+// needs that shard. Tx ids are taken once across every shard though, and a row may name another client's tx, so the tx
+// ids each shard holds are also kept by tx id in `owners`. Locks are always taken in the same order, which is what
+// keeps two threads from waiting on each other:
+//
+//   1. shards before owners, and never a shard while holding an owners lock
+//   2. at most one shard and one owners lock per row
+//   3. reads of the whole engine lock every shard in ascending order, and so does a transfer lock its two shards
+//   4. the storage, if any, last
+//
+// Rows are applied as Engine::process applies them, with the same rejections, so rows fed one at a time leave a
+// ShardedEngine with the same accounts and report as a single Engine. Monthly fees are charged to every account at once
+// when a month ends, which no shard can do on its own, so a policy with a monthly fee needs a single shard. An observer
+// set on the engine is not carried over.
+//
+// A transfer moves funds between the accounts of two clients, which may be in different shards. It is not a row and
+// takes no tx id, so it cannot be disputed and is not counted in the report.
+//
+// With a storage, see with_storage, every applied row and transfer is written to it under the locks it took, one at a
+// time across every shard, and one that cannot be written is undone and fails as with Engine::process_stored.
+
+use crate::money::Money;
+use crate::storage::{Change, Storage};
+use crate::{AccountKey, Client, Engine, EngineError, EnginePolicy, IdMap, Rejection, RunCounts, Transaction, TransactionRow, TransactionType};
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// The clients a tx id belongs to, as a stored transaction and as an admin hold. An opening balance only checks stored
+// transactions, so one tx id can be both
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Foreign {
+    pub(crate) record: Option<u16>,
+    pub(crate) hold: Option<u16>,
+}
+
+impl Foreign {
+    // This function returns how Engine::apply refuses a row naming this tx id when the engine holds the tx itself, for
+    // a shard that does not. `holds_tx` says whether the shard has an admin hold of that tx id. None when the shard can
+    // decide on its own
+    pub(crate) fn rejection(self, transaction: &Transaction, has_account: bool, holds_tx: bool) -> Option<Rejection> {
+        let (record, hold) = (self.record.is_some(), self.hold.is_some());
+        Some(match transaction {
+            Transaction::OpeningBalance { .. } if has_account => Rejection::NotFirstTransaction,
+            Transaction::OpeningBalance { .. } if record => Rejection::DuplicateTransaction,
+            Transaction::Deposit { .. } | Transaction::Withdrawal { .. } | Transaction::AdminHold { .. } if record || hold => Rejection::DuplicateTransaction,
+            // The dispute lifecycle refuses admin holds before it looks for a stored transaction
+            Transaction::Dispute { .. } | Transaction::Resolve { .. } | Transaction::Chargeback { .. } if hold => Rejection::NotDisputable,
+            Transaction::Dispute { .. } | Transaction::Resolve { .. } | Transaction::Chargeback { .. } if record && !holds_tx => Rejection::ClientMismatch,
+            Transaction::Correction { .. } if record => Rejection::ClientMismatch,
+            Transaction::AdminRelease { .. } if hold => Rejection::ClientMismatch,
+            _ => return None,
+        })
+    }
+
+    // This function keeps only the clients that live in another shard than `index`
+    fn elsewhere(self, index: usize, count: usize) -> Option<Foreign> {
+        let foreign = Foreign {
+            record: self.record.filter(|&client_id| shard_of(client_id, count) != index),
+            hold: self.hold.filter(|&client_id| shard_of(client_id, count) != index),
+        };
+        (foreign != Foreign::default()).then_some(foreign)
+    }
+}
+
+impl Engine {
+    // This function returns who holds a tx id in this engine
+    fn tx_owner(&self, transaction_id: u32) -> Foreign {
+        Foreign {
+            record: self.records.get(transaction_id).map(|record| record.client_id),
+            hold: self.admin_holds.get(&transaction_id).map(|hold| hold.client_id),
+        }
+    }
+
+    // This function returns an account as it would be once a transfer took `amount` out of it, refused as a withdrawal
+    // of the amount would be
+    fn debited(&self, key: AccountKey, amount: Money) -> Result<Client, Rejection> {
+        self.check_transfer(key, amount)?;
+        // A client without an account has nothing to transfer
+        let mut client = self.clients.get(&key).cloned().ok_or(Rejection::InsufficientFunds)?;
+        if client.locked {
+            return Err(Rejection::AccountLocked);
+        }
+        if client.debt.is_some_and(|debt| debt > Money::ZERO) {
+            return Err(Rejection::InDebt);
+        }
+        client.withdraw(amount)?;
+        Ok(client)
+    }
+
+    // This function returns an account as it would be once a transfer paid `amount` into it, refused as a deposit of
+    // the amount would be. A client without an account gets one
+    fn credited(&self, key: AccountKey, amount: Money) -> Result<Client, Rejection> {
+        self.check_transfer(key, amount)?;
+        let current = self.clients.get(&key);
+        if current.is_some_and(|client| client.locked) {
+            return Err(Rejection::AccountLocked);
+        }
+        self.policy.check_balance_cap(key.client_id, current, amount)?;
+        let mut client = current.cloned().unwrap_or_else(|| Client::for_account(key));
+        client.deposit(amount)?;
+        // As after a deposit row, the new funds settle debt first and then hold the disputes waiting for them
+        if self.policy.track_debt {
+            client.settle_debt();
+        }
+        if self.policy.dispute_requires_funds {
+            client.hold_pending_disputes();
+        }
+        Ok(client)
+    }
+
+    // This function refuses a transfer for a forgotten client, or of an amount finer than the account's currency allows
+    fn check_transfer(&self, key: AccountKey, amount: Money) -> Result<(), Rejection> {
+        if self.forgotten.contains(&key.client_id) {
+            return Err(Rejection::ForgottenClient);
+        }
+        if amount.round_dp(self.policy.scale_for(key.currency)) != amount {
+            return Err(Rejection::InvalidAmount);
+        }
+        Ok(())
+    }
+}
+
+impl RunCounts {
+    fn add(&mut self, other: &RunCounts) {
+        self.rows += other.rows;
+        self.rejected += other.rejected;
+        for (transaction_type, count) in &other.applied {
+            *self.applied.entry(*transaction_type).or_default() += count;
+        }
+        self.locked.extend(other.locked.iter().copied());
+        self.fees.charged += other.fees.charged;
+        self.fees.failed += other.fees.failed;
+    }
+}
+
+// This function tells whether a row's tx id means anything, i.e. whether the row must look it up in `owners`
+fn refers_to_tx(transaction_type: TransactionType) -> bool {
+    !matches!(transaction_type, TransactionType::AdminLock | TransactionType::AdminUnlock | TransactionType::Assert)
+}
+
+// This function tells whether an applied row can have taken its tx id or given it back
+fn takes_or_frees_tx(transaction_type: TransactionType) -> bool {
+    matches!(transaction_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::OpeningBalance | TransactionType::AdminHold | TransactionType::AdminRelease)
+}
+
+#[derive(Debug)]
+pub struct ShardedEngine {
+    shards: Box<[RwLock<Engine>]>,
+    // Which client holds each tx id any shard holds, split by tx id into as many maps as there are shards
+    owners: Box<[Mutex<IdMap<u32, Foreign>>]>,
+    policy: EnginePolicy,
+    storage: Option<Mutex<Box<dyn Storage>>>,
+}
+
+impl ShardedEngine {
+    // This function splits an engine into `shards` shards by client. An engine that spills its records makes every shard
+    // spill to the same directory, each keeping its share of the records in memory
+    pub fn new(mut engine: Engine, shards: NonZeroUsize) -> Result<ShardedEngine, EngineError> {
+        let count = shards.get();
+        if count > 1 && engine.policy.monthly_fee.is_some() {
+            return Err(EngineError::Invalid("monthly fees are charged to every account at once and need a single shard".to_string()));
+        }
+        let mut parts: Vec<Engine> = (0..count).map(|_| Engine::with_policy(engine.policy.clone())).collect();
+        let mut owners: Vec<IdMap<u32, Foreign>> = (0..count).map(|_| IdMap::default()).collect();
+
+        for (key, client) in engine.clients.drain() {
+            if let Some(part) = parts.get_mut(shard_of(key.client_id, count)) {
+                part.clients.insert(key, client);
+            }
+        }
+        for (transaction_id, record) in engine.records.iter() {
+            if let Some(part) = parts.get_mut(shard_of(record.client_id, count)) {
+                part.records.insert(transaction_id, record)?;
+            }
+            if let Some(owners) = owners.get_mut(tx_index(transaction_id, count)) {
+                owners.entry(transaction_id).or_default().record = Some(record.client_id);
+            }
+        }
+        for (transaction_id, hold) in engine.admin_holds.drain() {
+            if let Some(owners) = owners.get_mut(tx_index(transaction_id, count)) {
+                owners.entry(transaction_id).or_default().hold = Some(hold.client_id);
+            }
+            if let Some(part) = parts.get_mut(shard_of(hold.client_id, count)) {
+                part.admin_holds.insert(transaction_id, hold);
+            }
+        }
+        for client_id in std::mem::take(&mut engine.forgotten) {
+            if let Some(part) = parts.get_mut(shard_of(client_id, count)) {
+                part.forgotten.insert(client_id);
+            }
+        }
+        // The first shard keeps what belongs to the engine as a whole, for to_engine to put back
+        if let Some(first) = parts.first_mut() {
+            first.fee_month = std::mem::take(&mut engine.fee_month);
+            first.counts = std::mem::take(&mut engine.counts);
+        }
+        if let Some(spill) = engine.records.spill.as_ref() {
+            for part in &mut parts {
+                part.spill_records(spill.dir(), spill.keep().div_ceil(count))?;
+            }
+        }
+
+        Ok(ShardedEngine {
+            shards: parts.into_iter().map(RwLock::new).collect(),
+            owners: owners.into_iter().map(Mutex::new).collect(),
+            policy: engine.policy,
+            storage: None,
+        })
+    }
+
+    // This function writes every row and transfer applied from now on to a storage, which should hold the state the
+    // engine was split from, see Engine::load
+    pub fn with_storage(self, storage: Box<dyn Storage>) -> ShardedEngine {
+        ShardedEngine { storage: Some(Mutex::new(storage)), ..self }
+    }
+
+    pub fn policy(&self) -> &EnginePolicy {
+        &self.policy
+    }
+
+    // This function applies a row as Engine::process does and returns the row's account as the row left it, which
+    // another thread may have changed again by the time the caller looks at it
+    pub fn process(&self, transaction: &TransactionRow) -> Result<Option<Client>, EngineError> {
+        let index = self.index(transaction.client_id);
+        let mut shard = self.write(index)?;
+        let result = if refers_to_tx(transaction.transaction_type) {
+            let transaction_id = transaction.transaction_id;
+            let mut owners = self.owners(transaction_id)?;
+            // What this shard holds is its own business, only what other shards hold needs saying
+            let foreign = owners.get(&transaction_id).and_then(|owner| owner.elsewhere(index, self.shards.len()));
+            let result = self.apply(&mut shard, transaction, foreign);
+            if result.is_ok() && takes_or_frees_tx(transaction.transaction_type) {
+                let here = shard.tx_owner(transaction_id);
+                let owner = owners.entry(transaction_id).or_default();
+                owner.record = here.record.or(owner.record.filter(|&client_id| self.index(client_id) != index));
+                owner.hold = here.hold.or(owner.hold.filter(|&client_id| self.index(client_id) != index));
+                if *owner == Foreign::default() {
+                    owners.remove(&transaction_id);
+                }
+            }
+            result
+        } else {
+            self.apply(&mut shard, transaction, None)
+        };
+        result.map(|()| shard.account_for(transaction).cloned())
+    }
+
+    // This function applies a row to its shard, writing it to the storage if there is one
+    fn apply(&self, shard: &mut Engine, transaction: &TransactionRow, foreign: Option<Foreign>) -> Result<(), EngineError> {
+        match &self.storage {
+            Some(storage) => shard.process_row_stored(transaction, foreign, lock_storage(storage)?.as_mut()),
+            None => shard.process_row(transaction, foreign),
+        }
+    }
+
+    // This function moves `amount` from the available funds of one account to those of another, as a withdrawal from
+    // the first and a deposit into the second would, and returns both accounts as the transfer left them. Either both
+    // move or neither does. The two shards are locked in ascending order whichever way the funds go, so transfers in
+    // opposite directions never wait on each other for good
+    pub fn transfer(&self, from: impl Into<AccountKey>, to: impl Into<AccountKey>, amount: Money) -> Result<(Client, Client), EngineError> {
+        let (from, to) = (from.into(), to.into());
+        if from == to {
+            return Err(EngineError::Invalid(format!("client {} cannot transfer to the same account", from.client_id)));
+        }
+        if from.currency != to.currency {
+            return Err(Rejection::CurrencyMismatch.into());
+        }
+        let (source, target) = (self.index(from.client_id), self.index(to.client_id));
+        let low = source.min(target);
+        let mut shards = vec![self.write(low)?];
+        if source != target {
+            shards.push(self.write(source.max(target))?);
+        }
+        // Where each shard's guard is in `shards`
+        let slot = |index: usize| usize::from(index != low);
+        let debited = shards.get(slot(source)).ok_or_else(|| unavailable(source))?.debited(from, amount)?;
+        let credited = shards.get(slot(target)).ok_or_else(|| unavailable(target))?.credited(to, amount)?;
+        if let Some(storage) = &self.storage {
+            lock_storage(storage)?.save(&Change { accounts: vec![debited.clone(), credited.clone()], ..Change::default() })?;
+        }
+        shards.get_mut(slot(source)).ok_or_else(|| unavailable(source))?.clients.insert(from, debited.clone());
+        shards.get_mut(slot(target)).ok_or_else(|| unavailable(target))?.clients.insert(to, credited.clone());
+        Ok((debited, credited))
+    }
+
+    // This function returns an account by client id, or by client id and currency as an AccountKey
+    pub fn account(&self, key: impl Into<AccountKey>) -> Result<Option<Client>, EngineError> {
+        let key = key.into();
+        Ok(self.read(self.index(key.client_id))?.account(key).cloned())
+    }
+
+    // This function returns every account of one client, one per currency for inputs that name currencies
+    pub fn client_accounts(&self, client_id: u16) -> Result<Vec<Client>, EngineError> {
+        Ok(self.read(self.index(client_id))?.accounts().filter(|client| client.client_id == client_id).cloned().collect())
+    }
+
+    // This function returns every account as it was at one moment, in no particular order
+    pub fn accounts(&self) -> Result<Vec<Client>, EngineError> {
+        let shards = self.read_all()?;
+        Ok(shards.iter().flat_map(|shard| shard.accounts().cloned()).collect())
+    }
+
+    // This function puts the shards back together into one engine as they are at one moment, e.g. to report on or to
+    // save as a snapshot
+    pub fn to_engine(&self) -> Result<Engine, EngineError> {
+        let shards = self.read_all()?;
+        let mut engine = Engine::with_policy(self.policy.clone());
+        for shard in &shards {
+            engine.clients.extend(shard.clients.iter().map(|(key, client)| (*key, client.clone())));
+            for (transaction_id, record) in shard.records.iter() {
+                engine.records.insert(transaction_id, record)?;
+            }
+            engine.admin_holds.extend(shard.admin_holds.iter().map(|(transaction_id, hold)| (*transaction_id, hold.clone())));
+            engine.forgotten.extend(shard.forgotten.iter().copied());
+            engine.counts.add(&shard.counts);
+        }
+        if let Some(first) = shards.first() {
+            engine.fee_month = first.fee_month.clone();
+        }
+        Ok(engine)
+    }
+
+    fn index(&self, client_id: u16) -> usize {
+        shard_of(client_id, self.shards.len())
+    }
+
+    fn read(&self, index: usize) -> Result<RwLockReadGuard<'_, Engine>, EngineError> {
+        self.shards.get(index).and_then(|shard| shard.read().ok()).ok_or_else(|| unavailable(index))
+    }
+
+    fn write(&self, index: usize) -> Result<RwLockWriteGuard<'_, Engine>, EngineError> {
+        self.shards.get(index).and_then(|shard| shard.write().ok()).ok_or_else(|| unavailable(index))
+    }
+
+    // This function locks every shard for reading, in ascending order
+    fn read_all(&self) -> Result<Vec<RwLockReadGuard<'_, Engine>>, EngineError> {
+        (0..self.shards.len()).map(|index| self.read(index)).collect()
+    }
+
+    fn owners(&self, transaction_id: u32) -> Result<MutexGuard<'_, IdMap<u32, Foreign>>, EngineError> {
+        let index = tx_index(transaction_id, self.owners.len());
+        self.owners.get(index).and_then(|owners| owners.lock().ok())
+            .ok_or_else(|| EngineError::Invariant(format!("the tx ids of shard {} are unavailable after a thread panicked while holding them", index)))
+    }
+}
+
+// This function returns the shard of a client
+fn shard_of(client_id: u16, count: usize) -> usize {
+    usize::from(client_id) % count
+}
+
+// This function returns the owners map of a tx id
+fn tx_index(transaction_id: u32, count: usize) -> usize {
+    transaction_id as usize % count
+}
+
+fn lock_storage(storage: &Mutex<Box<dyn Storage>>) -> Result<MutexGuard<'_, Box<dyn Storage>>, EngineError> {
+    storage.lock().map_err(|_| EngineError::Invariant("the storage is unavailable after a thread panicked while writing to it".to_string()))
+}
+
+fn unavailable(index: usize) -> EngineError {
+    EngineError::Invariant(format!("shard {} is unavailable after a thread panicked while holding it", index))
+}
@@ -0,0 +1,199 @@
+// Engine state saved at the end of a run with --snapshot-out and loaded before the next one with --snapshot-in, so a
+// daily input continues from the balances of the day before and yesterday's deposits can still be disputed.
+//
+//   "PESN" | version u8 | report schema u8 | engine state as MessagePack
+//
+// The report schema is the --report-schema of the run that wrote the snapshot, 1 for v1 and 2 for v2. A run that
+// continues from the snapshot has to write the same one, so the reports of one series of runs never change layout
+// part way.
+//
+// The state is every account with its open disputes, every stored record with its place in the dispute lifecycle and
+// every open admin hold, along with the month that --monthly-fee is collecting, the accounts that were positive in it and
+// the clients removed by `forget`.
+// The policy is not saved, the run that loads the snapshot applies its own flags. A snapshot with a different version
+// is refused rather than read into the wrong fields.
+
+use crate::args::ReportSchema;
+use crate::{Engine, EngineError, EnginePolicy};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+const MAGIC: &[u8; 4] = b"PESN";
+const VERSION: u8 = 5;
+
+// This function writes the engine state and the report schema of the run. It goes to a temporary file first, so an
+// existing snapshot is only replaced by a complete one
+pub fn save(engine: &Engine, schema: ReportSchema, path: &str) -> Result<(), EngineError> {
+    let mut partial = PathBuf::from(path).into_os_string();
+    partial.push(".partial");
+
+    let mut out = BufWriter::new(File::create(&partial)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION, match schema {
+        ReportSchema::V1 => 1,
+        ReportSchema::V2 => 2,
+    }])?;
+    rmp_serde::encode::write(&mut out, engine).map_err(|e| e.to_string())?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+// This function reads a snapshot into an engine that applies the given policy, and returns it with the report schema
+// the snapshot was written with
+pub fn load(path: &str, policy: &EnginePolicy) -> Result<(Engine, ReportSchema), EngineError> {
+    let bytes = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("could not read snapshot {}: {}", path, e)))?;
+    let (schema, state) = match bytes.strip_prefix(MAGIC.as_slice()).and_then(|rest| rest.split_first()) {
+        Some((&VERSION, rest)) => match rest.split_first() {
+            Some((1, state)) => (ReportSchema::V1, state),
+            Some((2, state)) => (ReportSchema::V2, state),
+            _ => return Err(format!("snapshot {} is damaged: unknown report schema", path).into()),
+        },
+        Some((version, _)) => return Err(format!("snapshot {} has format version {}, this build only reads version {}", path, version, VERSION).into()),
+        None => return Err(format!("{} is not a snapshot file", path).into()),
+    };
+
+    let mut engine: Engine = rmp_serde::from_slice(state).map_err(|e| format!("snapshot {} is damaged: {}", path, e))?;
+    engine.set_policy(policy.clone());
+    Ok((engine, schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::state_hash;
+    use crate::money::Money;
+    use crate::{LockPolicy, Transaction};
+    use proptest::prelude::*;
+    use std::num::NonZeroU32;
+
+    // Few clients and tx ids, so rows of the second part often refer to records of the first
+    const CLIENTS: u16 = 3;
+    const TX_IDS: u32 = 16;
+
+    // This function returns a path in the temporary directory that no other test uses
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("payment_engine_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    // This function describes everything a later row can depend on: every account with its disputes, every record with
+    // its place in the dispute lifecycle and every admin hold
+    fn fingerprint(engine: &Engine) -> String {
+        let mut accounts: Vec<String> = engine.accounts().map(|client| format!("{:?}", client)).collect();
+        accounts.sort();
+        let mut records: Vec<String> = engine.records().map(|record| format!("{:?}", record)).collect();
+        records.sort();
+        let holds: Vec<String> = (1..=TX_IDS).filter_map(|tx| engine.admin_hold(tx)).map(|hold| format!("{:?}", hold)).collect();
+        format!("{:?}\n{:?}\n{:?}", accounts, records, holds)
+    }
+
+    fn transaction() -> impl Strategy<Value = Transaction> {
+        let amount = (1..10_000u32).prop_map(|cents| format!("{}.{:02}", cents / 100, cents % 100).parse::<Money>().unwrap_or_else(|_| panic!("invalid amount of {} cents", cents)));
+        prop_oneof![
+            3 => (1..=CLIENTS, 1..=TX_IDS, amount.clone()).prop_map(|(client, tx, amount)| Transaction::Deposit { client, tx, amount }),
+            2 => (1..=CLIENTS, 1..=TX_IDS, amount.clone()).prop_map(|(client, tx, amount)| Transaction::Withdrawal { client, tx, amount }),
+            1 => (1..=CLIENTS, 1..=TX_IDS, amount).prop_map(|(client, tx, amount)| Transaction::AdminHold { client, tx, amount }),
+            1 => (1..=CLIENTS, 1..=TX_IDS).prop_map(|(client, tx)| Transaction::AdminRelease { client, tx }),
+            4 => (0..3u8, 1..=CLIENTS, 1..=TX_IDS).prop_map(|(kind, client, tx)| match kind {
+                0 => Transaction::Dispute { client, tx },
+                1 => Transaction::Resolve { client, tx },
+                _ => Transaction::Chargeback { client, tx },
+            }),
+        ]
+    }
+
+    fn policy() -> impl Strategy<Value = EnginePolicy> {
+        (any::<bool>(), 0..3u32).prop_map(|(requires_funds, threshold)| {
+            let policy = EnginePolicy::builder().dispute_requires_funds(requires_funds);
+            match NonZeroU32::new(threshold) {
+                Some(threshold) => policy.lock_policy(LockPolicy::Threshold(threshold)).build(),
+                None => policy.build(),
+            }
+        })
+    }
+
+    fn process(engine: &mut Engine, transactions: &[Transaction]) {
+        for transaction in transactions {
+            let _ = engine.process_transaction(*transaction);
+        }
+    }
+
+    #[test]
+    fn a_snapshot_round_trips_with_the_same_state_hash() -> Result<(), EngineError> {
+        let amount = |text: &str| text.parse::<Money>().map_err(|_| EngineError::from("invalid amount"));
+        let mut engine = Engine::new();
+        for transaction in [
+            Transaction::Deposit { client: 1, tx: 1, amount: amount("10.5")? },
+            Transaction::Deposit { client: 1, tx: 2, amount: amount("4")? },
+            Transaction::Deposit { client: 2, tx: 3, amount: amount("7.25")? },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::AdminHold { client: 2, tx: 4, amount: amount("1")? },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Chargeback { client: 2, tx: 3 },
+        ] {
+            engine.process_transaction(transaction)?;
+        }
+
+        let path = temp_path("round_trip.snapshot");
+        save(&engine, ReportSchema::V1, &path)?;
+        let loaded = load(&path, &EnginePolicy::default());
+        let _ = fs::remove_file(&path);
+        let (mut loaded, schema) = loaded?;
+        assert_eq!(schema, ReportSchema::V1);
+        assert_eq!(state_hash(loaded.accounts()), state_hash(engine.accounts()));
+        assert_eq!(fingerprint(&loaded), fingerprint(&engine));
+
+        // The disputed deposit can still be resolved after loading, and the locked client stays locked
+        loaded.process_transaction(Transaction::Resolve { client: 1, tx: 1 })?;
+        assert_eq!(loaded.account(1).map(|client| client.held), Some(Money::ZERO));
+        let locked = loaded.process_transaction(Transaction::Deposit { client: 2, tx: 5, amount: amount("1")? });
+        assert!(matches!(locked, Err(EngineError::AccountLocked { client: 2 })), "{:?}", locked);
+        assert_ne!(state_hash(loaded.accounts()), state_hash(engine.accounts()));
+        Ok(())
+    }
+
+    #[test]
+    fn other_versions_and_other_files_are_refused() -> Result<(), EngineError> {
+        let path = temp_path("version.snapshot");
+        save(&Engine::new(), ReportSchema::V2, &path)?;
+        let mut bytes = fs::read(&path)?;
+        if let Some(version) = bytes.get_mut(MAGIC.len()) {
+            *version = VERSION + 1;
+        }
+        fs::write(&path, &bytes)?;
+        let newer = load(&path, &EnginePolicy::default()).err().map(|e| e.to_string());
+        fs::write(&path, "type,client,tx,amount\n")?;
+        let csv = load(&path, &EnginePolicy::default()).err().map(|e| e.to_string());
+        let _ = fs::remove_file(&path);
+
+        assert!(newer.as_deref().is_some_and(|e| e.contains(&format!("has format version {}", VERSION + 1))), "{:?}", newer);
+        assert!(csv.as_deref().is_some_and(|e| e.ends_with("is not a snapshot file")), "{:?}", csv);
+        Ok(())
+    }
+
+    proptest! {
+        // Running A, saving a snapshot and running B from it ends where running A and B in one go does
+        #[test]
+        fn resuming_from_a_snapshot_equals_one_run(
+            policy in policy(),
+            first in prop::collection::vec(transaction(), 0..40),
+            second in prop::collection::vec(transaction(), 0..40),
+        ) {
+            let mut whole = Engine::with_policy(policy.clone());
+            process(&mut whole, &first);
+            process(&mut whole, &second);
+
+            let path = temp_path("resume.snapshot");
+            let mut before = Engine::with_policy(policy.clone());
+            process(&mut before, &first);
+            save(&before, ReportSchema::V2, &path)?;
+            let resumed = load(&path, &policy);
+            let _ = fs::remove_file(&path);
+            let (mut resumed, _) = resumed?;
+            process(&mut resumed, &second);
+
+            prop_assert_eq!(fingerprint(&resumed), fingerprint(&whole));
+        }
+    }
+}
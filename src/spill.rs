@@ -0,0 +1,255 @@
+// Records a TransactionStore has moved out of memory, for Engine::spill_records.
+//
+// The store keeps the records it stored or changed most recently in memory, up to a limit, and writes the rest to an
+// append-only file. Every spilled record takes one fixed-size slot:
+//
+//   client u16 | kind u8 | state u8 | scale u8 | currency [u8; 3] | mantissa i128
+//
+// little endian, 24 bytes. A record without a currency has three zero bytes for it. Only the offset of each record's slot stays in memory. A spilled record that is changed
+// moves back into memory, and its slot is left behind unused, so the file only grows. It is removed when the store is
+// dropped.
+
+use crate::money;
+use crate::{Currency, IdMap, Record, RecordKind, RecordState};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+const SLOT_LEN: usize = 24;
+
+// Numbers the files of the stores spilling in this process, so two of them never share one
+static FILES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub(crate) struct Spill {
+    // Reads seek behind shared references, which threads reading one engine at once must not interleave
+    file: Mutex<File>,
+    path: PathBuf,
+    // Where each spilled record's slot starts
+    offsets: IdMap<u32, u64>,
+    end: u64,
+    // How many records stay in memory
+    keep: usize,
+    // Tx ids in the order they were stored or changed, oldest first. A tx id that was touched again is in here more
+    // than once, and `queued` counts how often, so only its last entry lets it be spilled
+    order: VecDeque<u32>,
+    queued: IdMap<u32, u32>,
+    // The first read or write of the file that failed, for Engine::process to stop the run with. Reads happen behind
+    // shared references, so it is kept in a mutex
+    failure: Mutex<Option<String>>,
+}
+
+impl Spill {
+    // This function creates an empty spill file in `dir`
+    pub(crate) fn create(dir: &Path, keep: usize) -> io::Result<Spill> {
+        let path = dir.join(format!("records-{}-{}.spill", std::process::id(), FILES.fetch_add(1, Ordering::Relaxed)));
+        let file = fs::create_dir_all(dir).and_then(|()| OpenOptions::new().read(true).write(true).create_new(true).open(&path))
+            .map_err(|e| io::Error::new(e.kind(), format!("could not create spill file {}: {}", path.display(), e)))?;
+        Ok(Spill {
+            file: Mutex::new(file),
+            path,
+            offsets: IdMap::default(),
+            end: 0,
+            keep,
+            order: VecDeque::new(),
+            queued: IdMap::default(),
+            failure: Mutex::new(None),
+        })
+    }
+
+    pub(crate) fn keep(&self) -> usize {
+        self.keep
+    }
+
+    // This function returns the directory the file is in
+    pub(crate) fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub(crate) fn contains(&self, transaction_id: u32) -> bool {
+        self.offsets.contains_key(&transaction_id)
+    }
+
+    // This function returns the tx ids of every spilled record, in no particular order
+    pub(crate) fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.offsets.keys().copied()
+    }
+
+    // This function marks a record in memory as just stored or changed
+    pub(crate) fn touch(&mut self, transaction_id: u32) {
+        if self.order.len() > 2 * self.queued.len() + 1024 {
+            self.compact();
+        }
+        self.order.push_back(transaction_id);
+        *self.queued.entry(transaction_id).or_default() += 1;
+    }
+
+    // This function drops every entry of the order but the last one for each tx id, so records that are touched over
+    // and over before any is spilled do not grow it without bound
+    fn compact(&mut self) {
+        let mut order = VecDeque::with_capacity(self.queued.len());
+        for &transaction_id in self.order.iter().rev() {
+            if let Some(count) = self.queued.get_mut(&transaction_id).filter(|count| **count > 0) {
+                *count = 0;
+                order.push_front(transaction_id);
+            }
+        }
+        for count in self.queued.values_mut() {
+            *count = 1;
+        }
+        self.order = order;
+    }
+
+    // This function returns the tx id of the record in memory that was stored or changed longest ago, taking it out
+    // of the order. Tx ids that were touched again later are passed over
+    pub(crate) fn oldest(&mut self) -> Option<u32> {
+        while let Some(transaction_id) = self.order.pop_front() {
+            let Some(count) = self.queued.get_mut(&transaction_id) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                self.queued.remove(&transaction_id);
+                return Some(transaction_id);
+            }
+        }
+        None
+    }
+
+    // This function puts a tx id that `oldest` returned back at the front, for when it could not be spilled after all
+    pub(crate) fn untake(&mut self, transaction_id: u32) {
+        self.order.push_front(transaction_id);
+        *self.queued.entry(transaction_id).or_default() += 1;
+    }
+
+    // This function forgets a tx id that was removed from memory some other way
+    pub(crate) fn forget(&mut self, transaction_id: u32) {
+        self.queued.remove(&transaction_id);
+    }
+
+    // This function reads a spilled record. A failed read is recorded and the record treated as missing
+    pub(crate) fn read(&self, transaction_id: u32) -> Option<Record> {
+        let offset = *self.offsets.get(&transaction_id)?;
+        match self.read_slot(offset) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                self.fail(format!("could not read tx {} from spill file {}: {}", transaction_id, self.path.display(), e));
+                None
+            },
+        }
+    }
+
+    // This function takes a spilled record back out of the file, leaving its slot unused
+    pub(crate) fn take(&mut self, transaction_id: u32) -> Option<Record> {
+        let record = self.read(transaction_id)?;
+        self.offsets.remove(&transaction_id);
+        Some(record)
+    }
+
+    // This function appends a record to the file. On failure nothing is spilled and the failure is recorded
+    pub(crate) fn write(&mut self, transaction_id: u32, record: &Record) -> bool {
+        let (mantissa, scale) = money::to_parts(record.amount);
+        let [c0, c1] = record.client_id.to_le_bytes();
+        let [a, b, c] = record.currency.map_or([0; 3], Currency::to_bytes);
+        let mut slot = Vec::with_capacity(SLOT_LEN);
+        slot.extend_from_slice(&[c0, c1, kind_code(record.kind), state_code(record.state), scale as u8, a, b, c]);
+        slot.extend_from_slice(&mantissa.to_le_bytes());
+
+        let file = self.file.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let written = file.seek(SeekFrom::Start(self.end)).and_then(|_| file.write_all(&slot));
+        match written {
+            Ok(()) => {
+                self.offsets.insert(transaction_id, self.end);
+                self.end += SLOT_LEN as u64;
+                true
+            },
+            Err(e) => {
+                self.fail(format!("could not write tx {} to spill file {}: {}", transaction_id, self.path.display(), e));
+                false
+            },
+        }
+    }
+
+    // This function returns the failure recorded since the last call, if any
+    pub(crate) fn take_failure(&self) -> Option<String> {
+        self.failure.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+
+    fn fail(&self, message: String) {
+        self.failure.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert(message);
+    }
+
+    fn read_slot(&self, offset: u64) -> io::Result<Record> {
+        let mut slot = [0u8; SLOT_LEN];
+        {
+            let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut slot)?;
+        }
+        let corrupt = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("slot at byte {} has {}", offset, what));
+
+        let (head, tail) = slot.split_at(8);
+        let [c0, c1, kind, state, scale, a, b, c] = <[u8; 8]>::try_from(head).map_err(|_| corrupt("no header"))?;
+        let currency = match [a, b, c] {
+            [0, 0, 0] => None,
+            code => Some(Currency::from_bytes(code).ok_or_else(|| corrupt("an invalid currency"))?),
+        };
+        let mantissa = i128::from_le_bytes(<[u8; 16]>::try_from(tail).map_err(|_| corrupt("no amount"))?);
+        Ok(Record {
+            kind: kind_from_code(kind).ok_or_else(|| corrupt("an unknown kind"))?,
+            client_id: u16::from_le_bytes([c0, c1]),
+            amount: money::from_parts(mantissa, u32::from(scale)).ok_or_else(|| corrupt("an amount out of range"))?,
+            state: state_from_code(state).ok_or_else(|| corrupt("an unknown state"))?,
+            currency,
+        })
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn kind_code(kind: RecordKind) -> u8 {
+    match kind {
+        RecordKind::Deposit => 1,
+        RecordKind::Withdrawal => 2,
+        RecordKind::OpeningBalance => 3,
+    }
+}
+
+fn kind_from_code(code: u8) -> Option<RecordKind> {
+    match code {
+        1 => Some(RecordKind::Deposit),
+        2 => Some(RecordKind::Withdrawal),
+        3 => Some(RecordKind::OpeningBalance),
+        _ => None,
+    }
+}
+
+fn state_code(state: RecordState) -> u8 {
+    match state {
+        RecordState::Normal => 1,
+        RecordState::Disputed => 2,
+        RecordState::Resolved => 3,
+        RecordState::ChargedBack => 4,
+    }
+}
+
+fn state_from_code(code: u8) -> Option<RecordState> {
+    match code {
+        1 => Some(RecordState::Normal),
+        2 => Some(RecordState::Disputed),
+        3 => Some(RecordState::Resolved),
+        4 => Some(RecordState::ChargedBack),
+        _ => None,
+    }
+}
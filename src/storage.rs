@@ -0,0 +1,136 @@
+// Durable state for an engine that must survive restarts, e.g. `serve` keeping its accounts in PostgreSQL with
+// --postgres-url, see postgres.rs.
+//
+// An engine starts from what a Storage loads with Engine::load, and Engine::process_stored then hands the storage what
+// each applied row changed as one Change, to write all at once or not at all. A row whose change cannot be written is
+// undone in the engine and fails with the storage's error, so the engine and the storage never disagree and the caller
+// can try the row again once the storage is back. Nothing is retried here. An observer has been told about the row by
+// then, and is not told that it was undone.
+//
+// Only the accounts, the stored transactions and the admin holds are kept. The counts of a run start from zero on every
+// start, and monthly fees, which charge every account at once, cannot be used with a storage.
+
+use crate::{AccountKey, AdminHold, Client, Engine, EngineError, EnginePolicy, Foreign, Record, RunCounts, TransactionRow, TransactionType};
+use std::fmt;
+
+pub trait Storage: Send {
+    // This function returns everything stored, to start an engine from
+    fn load(&mut self) -> Result<Stored, EngineError>;
+
+    // This function writes what one row changed, all of it or none of it
+    fn save(&mut self, change: &Change) -> Result<(), EngineError>;
+}
+
+impl fmt::Debug for dyn Storage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Storage")
+    }
+}
+
+// Everything a storage holds
+#[derive(Debug, Clone, Default)]
+pub struct Stored {
+    pub accounts: Vec<Client>,
+    pub records: Vec<(u32, Record)>,
+    pub admin_holds: Vec<(u32, AdminHold)>,
+}
+
+// What one applied row, or a ShardedEngine transfer, changed
+#[derive(Debug, Clone, Default)]
+pub struct Change {
+    // The accounts as the row left them
+    pub accounts: Vec<Client>,
+    // The record stored under the row's tx id afterwards, or None when there is none any more
+    pub records: Vec<(u32, Option<Record>)>,
+    // The admin hold under the row's tx id afterwards, or None when there is none any more
+    pub admin_holds: Vec<(u32, Option<AdminHold>)>,
+}
+
+// What a row may change, as it was before the row, to put back when its change cannot be written
+struct Undo {
+    account: Option<(AccountKey, Option<Client>)>,
+    transaction: Option<(u32, Option<Record>, Option<AdminHold>)>,
+    counts: RunCounts,
+}
+
+impl Engine {
+    // This function starts an engine under `policy` from what a storage holds
+    pub fn load(storage: &mut dyn Storage, policy: EnginePolicy) -> Result<Engine, EngineError> {
+        check_policy(&policy)?;
+        let stored = storage.load()?;
+        let mut engine = Engine::with_policy(policy);
+        for client in stored.accounts {
+            engine.clients.insert(AccountKey { client_id: client.client_id, currency: client.currency }, client);
+        }
+        for (transaction_id, record) in stored.records {
+            engine.records.insert(transaction_id, record)?;
+        }
+        engine.admin_holds.extend(stored.admin_holds);
+        Ok(engine)
+    }
+
+    // This function is process for an engine whose state is kept in a storage. An applied row's change is written to the
+    // storage before this returns, and a row whose change cannot be written is undone and fails with the storage's error
+    pub fn process_stored(&mut self, transaction: &TransactionRow, storage: &mut dyn Storage) -> Result<(), EngineError> {
+        self.process_row_stored(transaction, None, storage)
+    }
+
+    // This function is process_stored for one shard of a ShardedEngine
+    pub(crate) fn process_row_stored(&mut self, transaction: &TransactionRow, foreign: Option<Foreign>, storage: &mut dyn Storage) -> Result<(), EngineError> {
+        check_policy(&self.policy)?;
+        let undo = self.undo_for(transaction);
+        self.process_row(transaction, foreign)?;
+        let change = Change {
+            accounts: undo.account.as_ref().and_then(|(key, _)| self.clients.get(key)).cloned().into_iter().collect(),
+            records: undo.transaction.iter().map(|&(transaction_id, _, _)| (transaction_id, self.records.get(transaction_id))).collect(),
+            admin_holds: undo.transaction.iter().map(|&(transaction_id, _, _)| (transaction_id, self.admin_holds.get(&transaction_id).cloned())).collect(),
+        };
+        if let Err(e) = storage.save(&change) {
+            self.undo(undo);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // This function notes what a row may change, before it is applied
+    fn undo_for(&self, transaction: &TransactionRow) -> Undo {
+        let refers_to_tx = !matches!(transaction.transaction_type, TransactionType::AdminLock | TransactionType::AdminUnlock | TransactionType::Assert);
+        let transaction_id = transaction.transaction_id;
+        Undo {
+            // A row whose account cannot be told is refused before it moves any
+            account: self.account_key(transaction).ok().map(|key| (key, self.clients.get(&key).cloned())),
+            transaction: refers_to_tx.then(|| (transaction_id, self.records.get(transaction_id), self.admin_holds.get(&transaction_id).cloned())),
+            counts: self.counts.clone(),
+        }
+    }
+
+    // This function puts back what a row changed
+    fn undo(&mut self, undo: Undo) {
+        if let Some((key, client)) = undo.account {
+            match client {
+                Some(client) => self.clients.insert(key, client),
+                None => self.clients.remove(&key),
+            };
+        }
+        if let Some((transaction_id, record, hold)) = undo.transaction {
+            self.records.remove(transaction_id);
+            if let Some(record) = record {
+                // The tx id was just freed, so this cannot be refused
+                let _ = self.records.insert(transaction_id, record);
+            }
+            match hold {
+                Some(hold) => self.admin_holds.insert(transaction_id, hold),
+                None => self.admin_holds.remove(&transaction_id),
+            };
+        }
+        self.counts = undo.counts;
+    }
+}
+
+// This function refuses a policy a storage cannot keep up with
+fn check_policy(policy: &EnginePolicy) -> Result<(), EngineError> {
+    if policy.monthly_fee.is_some() {
+        return Err(EngineError::Invalid("monthly fees are charged to every account at once and cannot be used with a storage".to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+// The `verify` subcommand: process an input and check that an account report is what it yields.
+//
+// The input is processed with the same flags as the main command, so a report written with e.g. --lock-policy or
+// --multi-currency is checked against the run that wrote it. The report is read as a CSV account report in the default
+// layout, with a currency column for inputs that name currencies. Its columns are looked up by header and its rows
+// may come in any order. Amounts are compared as numbers, so 1.5 and 1.5000 match, and a TOTAL row is ignored. Every
+// client whose account differs, or that only one side has, is printed to stderr with what differs, e.g.
+//
+//   client 7: available is 10.0000 in the report, 12.5000 from the input
+//
+// and the run reports a mismatch.
+
+use crate::VerifyArgs;
+use payment_engine::money::{Money, Places};
+use payment_engine::run::process_input;
+use payment_engine::{AccountSummary, Currency, EngineError};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+
+// One row of an account report as written. The client is text so the TOTAL row can be told apart, and its locked
+// column is empty
+#[derive(Debug, Deserialize)]
+struct ReportRow {
+    client: String,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(deserialize_with = "exact_money")]
+    available: Money,
+    #[serde(deserialize_with = "exact_money")]
+    held: Money,
+    #[serde(deserialize_with = "exact_money")]
+    total: Money,
+    locked: Option<bool>,
+}
+
+// Amounts are parsed from their text, since serde would hand a CSV number to Decimal through f64. Zeros a report written
+// with a higher --precision pads with are dropped first, which fixed-point builds need to read it at all
+fn exact_money<'de, D: Deserializer<'de>>(d: D) -> Result<Money, D::Error> {
+    let text = String::deserialize(d)?;
+    let digits = if text.contains('.') { text.trim_end_matches('0').trim_end_matches('.') } else { text.as_str() };
+    digits.parse::<Money>().map_err(|_| serde::de::Error::custom(format!("invalid amount {:?}", text)))
+}
+
+// This function processes the input and compares the accounts with the report. It returns whether every account matched
+pub(crate) fn run(args: &VerifyArgs) -> Result<bool, EngineError> {
+    let claimed = read_report(&args.accounts)?;
+
+    let state = process_input(std::slice::from_ref(&args.input), &args.processing, &args.processing.policy()?, None, None)?;
+    let actual: BTreeMap<Account, AccountSummary> = state.engine.accounts().map(|client| ((client.client_id, client.currency), AccountSummary::from(client))).collect();
+
+    let mut mismatched = 0;
+    let clients: BTreeSet<Account> = claimed.keys().chain(actual.keys()).copied().collect();
+    for client in &clients {
+        let differences = match (claimed.get(client), actual.get(client)) {
+            (Some(claimed), Some(actual)) => differences(claimed, actual),
+            (Some(_), None) => vec!["in the report, but the input gives it no account".to_string()],
+            (None, Some(_)) => vec!["missing from the report".to_string()],
+            (None, None) => Vec::new(),
+        };
+        if !differences.is_empty() {
+            mismatched += 1;
+            match client {
+                (client, Some(currency)) => eprintln!("client {} {}: {}", client, currency.as_str(), differences.join("; ")),
+                (client, None) => eprintln!("client {}: {}", client, differences.join("; ")),
+            }
+        }
+    }
+
+    if mismatched == 0 {
+        eprintln!("verify: all {} accounts in {} match {}", clients.len(), args.accounts, args.input);
+    } else {
+        eprintln!("verify: {} of {} accounts in {} do not match {}", mismatched, clients.len(), args.accounts, args.input);
+    }
+    Ok(mismatched == 0)
+}
+
+// A client and, for inputs that name currencies, the currency of one of its accounts
+type Account = (u16, Option<Currency>);
+
+// This function reads the accounts of a report by client and currency. An account listed twice is an error, since it
+// cannot be compared
+fn read_report(path: &str) -> Result<BTreeMap<Account, AccountSummary>, EngineError> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("could not open accounts {}: {}", path, e)))?;
+    let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(file);
+
+    let mut accounts = BTreeMap::new();
+    for (index, row) in rdr.deserialize::<ReportRow>().enumerate() {
+        let line = index + 2;
+        let row = row.map_err(|e| format!("{} line {}: {}", path, line, e))?;
+        if row.client == "TOTAL" {
+            continue;
+        }
+
+        let client = row.client.parse::<u16>().map_err(|_| format!("{} line {}: invalid client {:?}", path, line, row.client))?;
+        let currency = match row.currency.as_deref() {
+            None | Some("") => None,
+            Some(code) => Some(code.parse::<Currency>().map_err(|e| format!("{} line {}: {}", path, line, e))?),
+        };
+        let locked = row.locked.ok_or_else(|| format!("{} line {}: client {} has no locked value", path, line, client))?;
+        let account = AccountSummary { client, currency, available: row.available, held: row.held, total: row.total, locked, debt: None, chargebacks: 0 };
+        if accounts.insert((client, currency), account).is_some() {
+            return Err(format!("{} line {}: client {} is listed more than once", path, line, client).into());
+        }
+    }
+    Ok(accounts)
+}
+
+// This function describes every column in which the report differs from the processed account
+fn differences(claimed: &AccountSummary, actual: &AccountSummary) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (name, claimed, actual) in [
+        ("available", claimed.available, actual.available),
+        ("held", claimed.held, actual.held),
+        ("total", claimed.total, actual.total),
+    ] {
+        if claimed != actual {
+            differences.push(format!("{} is {} in the report, {} from the input", name, claimed, Places(actual, 4)));
+        }
+    }
+    if claimed.locked != actual.locked {
+        differences.push(format!("locked is {} in the report, {} from the input", claimed.locked, actual.locked));
+    }
+    differences
+}
@@ -0,0 +1,52 @@
+// Runs the command line tool with --annotate-out on tests/fixtures/annotate_messy.csv, whose rows are applied, rejected,
+// duplicated, skipped by --clients, dropped by --ignore-types, too short to parse, of an unknown type or carry an amount
+// that is not a number. The annotated copy must have a row for every input row, in order, starting with that row exactly
+// as it was in the input and ending with its outcome and detail.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+
+#[test]
+fn every_input_row_is_copied_with_its_outcome() {
+    let path = temp_path("annotated_messy.csv");
+    let input = fixture("annotate_messy.csv");
+    let output = command([input.as_str(), "-q", "--clients", "1-2", "--ignore-types", "chargeback", "--annotate-out", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let annotated = read(&path);
+
+    // Parse errors are described in the words of the amount parser, which differs between the money types
+    let expected = [
+        ("outcome", "detail"),
+        ("applied", ""),
+        ("applied", ""),
+        ("rejected", "insufficient_funds"),
+        ("duplicate", "duplicate_transaction"),
+        ("parse_error", "CSV deserialize error: record 5 (line: 6, byte: 93): expected field, but got end of row"),
+        ("unknown_type", "refund"),
+        ("ignored", "ignore_types"),
+        ("applied", ""),
+        ("skipped", "client_filter"),
+        ("rejected", "unknown_transaction"),
+        ("parse_error", "line 12: amount \"abc\" is not a valid number"),
+        ("applied", ""),
+    ];
+    let input_lines: Vec<String> = std::fs::read_to_string(&input).expect("the fixture can be read").lines().map(str::to_string).collect();
+    let annotated_lines: Vec<&str> = annotated.lines().collect();
+    assert_eq!(annotated_lines.len(), input_lines.len(), "{}", annotated);
+    assert_eq!(annotated_lines.len(), expected.len(), "{}", annotated);
+
+    let records: Vec<csv::StringRecord> = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(annotated.as_bytes())
+        .records().collect::<Result<_, _>>().expect("the annotated copy is CSV");
+    for (((record, line), input_line), (outcome, detail)) in records.iter().zip(&annotated_lines).zip(&input_lines).zip(expected) {
+        // Every row is padded to the widest one, so a short row gets empty fields before the two new columns
+        assert!(line.starts_with(input_line.as_str()), "{:?} does not start with {:?}", line, input_line);
+        let fields: Vec<&str> = record.iter().collect();
+        assert_eq!(fields.len(), 6, "{:?}", line);
+        assert_eq!(fields[4], outcome, "{:?}", line);
+        assert!(fields[5].starts_with(detail), "{:?} should have detail {:?}", line, detail);
+        assert_eq!(fields[5].is_empty(), detail.is_empty(), "{:?}", line);
+    }
+    // The short row keeps its two fields, padded with empty ones
+    assert_eq!(annotated_lines[5], "deposit,2,,,parse_error,\"CSV deserialize error: record 5 (line: 6, byte: 93): expected field, but got end of row\"");
+}
@@ -0,0 +1,106 @@
+// Runs the command line tool on Arrow IPC input written here from tests/fixtures/basic.csv, as a file and as a stream,
+// and reads its Arrow report back. Only built with the arrow feature.
+#![cfg(feature = "arrow")]
+
+mod common;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::Decimal128Type;
+use arrow_array::{ArrayRef, Decimal128Array, RecordBatch, StringArray, UInt16Array, UInt64Array};
+use arrow_ipc::reader::FileReader;
+use arrow_ipc::writer::{FileWriter, StreamWriter};
+use arrow_schema::{DataType, Field, Schema};
+use common::{command, temp_path};
+use std::fs::File;
+use std::sync::Arc;
+
+const BASIC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/basic.csv");
+const BASIC_REPORT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/basic.expected.csv");
+
+// This function turns the rows of basic.csv into one batch, amounts at four decimal places
+fn basic_batch() -> RecordBatch {
+    let rows = std::fs::read_to_string(BASIC).expect("the fixture is readable");
+    let rows: Vec<Vec<&str>> = rows.lines().skip(1).map(|line| line.split(',').collect()).collect();
+    fn field<'a>(row: &[&'a str], i: usize) -> &'a str {
+        row.get(i).copied().expect("every fixture row has four fields")
+    }
+    let amount = |text: &str| {
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        format!("{}{:0<4}", whole, fraction).parse::<i128>().expect("fixture amounts are decimal")
+    };
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("type", DataType::Utf8, false),
+        Field::new("client", DataType::UInt16, false),
+        Field::new("tx", DataType::UInt64, false),
+        Field::new("amount", DataType::Decimal128(38, 4), true),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(rows.iter().map(|row| field(row, 0)).collect::<Vec<_>>())),
+        Arc::new(UInt16Array::from(rows.iter().map(|row| field(row, 1).parse().expect("client ids are u16")).collect::<Vec<u16>>())),
+        Arc::new(UInt64Array::from(rows.iter().map(|row| field(row, 2).parse().expect("tx ids are u64")).collect::<Vec<u64>>())),
+        Arc::new(Decimal128Array::from(rows.iter().map(|row| amount(field(row, 3))).collect::<Vec<_>>()).with_precision_and_scale(38, 4).expect("the scale fits")),
+    ];
+    RecordBatch::try_new(schema, columns).expect("the columns match the schema")
+}
+
+#[test]
+fn file_and_stream_input_match_the_csv_report() {
+    let batch = basic_batch();
+    let (file, stream) = (temp_path("basic.arrow"), temp_path("basic.arrows"));
+    let mut writer = FileWriter::try_new(File::create(&file).expect("the file is created"), &batch.schema()).expect("the writer starts");
+    writer.write(&batch).expect("the batch is written");
+    writer.finish().expect("the file is finished");
+    let mut writer = StreamWriter::try_new(File::create(&stream).expect("the stream is created"), &batch.schema()).expect("the writer starts");
+    writer.write(&batch).expect("the batch is written");
+    writer.finish().expect("the stream is finished");
+
+    let expected = std::fs::read_to_string(BASIC_REPORT).expect("the expected report is readable");
+    for path in [&file, &stream] {
+        let output = command(["--input-format", "arrow", path.to_str().expect("utf-8 path")]);
+        let _ = std::fs::remove_file(path);
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    }
+}
+
+#[test]
+fn a_wrong_column_type_is_named() {
+    let schema = Schema::new(vec![
+        Field::new("type", DataType::Utf8, false),
+        Field::new("client", DataType::UInt64, false),
+        Field::new("tx", DataType::UInt64, false),
+        Field::new("amount", DataType::Decimal128(38, 4), true),
+    ]);
+    let path = temp_path("wrong_type.arrow");
+    FileWriter::try_new(File::create(&path).expect("the file is created"), &schema).and_then(|mut writer| writer.finish()).expect("the file is written");
+
+    let output = command(["--input-format", "arrow", path.to_str().expect("utf-8 path")]);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("arrow field \"client\" has type UInt64, expected UInt16"), "{}", stderr);
+}
+
+#[test]
+fn the_report_is_written_as_arrow() {
+    let path = temp_path("report.arrow");
+    let output = command([BASIC, "--format", "arrow", "--output", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let reader = FileReader::try_new(File::open(&path).expect("the report was written"), None).expect("the report is an arrow file");
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(reader.schema().metadata().get("rows").map(String::as_str), Some("6"));
+    let batches: Vec<RecordBatch> = reader.collect::<Result<_, _>>().expect("the report batches are readable");
+    assert_eq!(batches.len(), 1);
+    let batch = batches.first().expect("one batch");
+    let column = |name: &str| batch.column_by_name(name).unwrap_or_else(|| panic!("the report has a {} column", name)).as_primitive::<Decimal128Type>();
+    // 5.7501 at four decimal places for client 1, nothing for client 2
+    assert_eq!(column("available").values().to_vec(), [57501, 0]);
+    assert_eq!(column("total").values().to_vec(), [57501, 0]);
+    assert_eq!(column("held").values().to_vec(), [0, 0]);
+
+    let to_stdout = command([BASIC, "--format", "arrow"]);
+    assert_eq!(to_stdout.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&to_stdout.stderr).contains("--format arrow needs --output"));
+}
@@ -0,0 +1,65 @@
+// Writes an audit log for tests/fixtures/chargeback_fee.csv and checks its hash chain with `audit verify`: intact as
+// written, and broken at the right line after a one byte edit or a removed line.
+
+mod common;
+
+use common::{command, temp_path};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+const CHARGEBACK_FEE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/chargeback_fee.csv");
+
+// This function writes the audit log of the fixture to a path no other test uses and returns it with its text
+fn audit_log(name: &str) -> (PathBuf, String) {
+    let path = temp_path(name);
+    let output = command([CHARGEBACK_FEE, "--chargeback-fee", "2.5", "--audit-log", path.to_str().expect("utf-8 path")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let text = std::fs::read_to_string(&path).expect("the audit log was written");
+    (path, text)
+}
+
+// This function runs `audit verify` on a log and returns its exit code and standard output
+fn verify(path: &PathBuf) -> (Option<i32>, String) {
+    let output = command(["audit", "verify", path.to_str().expect("utf-8 path")]);
+    let _ = std::fs::remove_file(path);
+    (output.status.code(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn an_intact_log_passes() {
+    let (path, text) = audit_log("audit_intact.csv");
+    let digest = format!("{:x}", Sha256::digest(std::fs::read(CHARGEBACK_FEE).expect("the fixture is readable")));
+    let header = text.lines().nth(1).expect("the log has a run header");
+    assert!(header.starts_with(&format!("0,,,,,,run_header,engine={} input={} sha256={},", env!("CARGO_PKG_VERSION"), CHARGEBACK_FEE, digest)), "{}", header);
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(0), "{}", stdout);
+    assert!(stdout.ends_with(": the hash chain of 10 lines is intact\n"), "{}", stdout);
+}
+
+#[test]
+fn a_one_byte_edit_is_found_at_its_line() {
+    let (path, text) = audit_log("audit_edited.csv");
+    // Line 7 is the fee of row 4's chargeback, 2.5000, made 2.6000
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let line = lines.get_mut(6).expect("the log has seven lines");
+    assert!(line.starts_with("4,5,chargeback,1,2,2.5000,fee_charged,"), "{}", line);
+    *line = line.replacen("2.5000", "2.6000", 1);
+    std::fs::write(&path, lines.join("\n") + "\n").expect("the edited log is written");
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(3), "{}", stdout);
+    assert!(stdout.ends_with(": broken link at line 7 (row 4): the hash does not match the line\n"), "{}", stdout);
+}
+
+#[test]
+fn a_removed_line_is_found_at_the_line_after_it() {
+    let (path, text) = audit_log("audit_removed.csv");
+    // Line 4 is row 2, so row 3 follows line 3 once it is gone
+    let lines: Vec<&str> = text.lines().enumerate().filter(|&(index, _)| index != 3).map(|(_, line)| line).collect();
+    std::fs::write(&path, lines.join("\n") + "\n").expect("the shortened log is written");
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(3), "{}", stdout);
+    assert!(stdout.ends_with(": broken link at line 4 (row 3): prev_hash is not the hash of the line before\n"), "{}", stdout);
+}
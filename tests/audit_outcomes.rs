@@ -0,0 +1,51 @@
+// Runs the command line tool with --audit-log on tests/fixtures/audit_outcomes.csv, which has a row for each way a row can
+// end: applied, each kind of rejection, a reused tx id, a deposit over --max-balance, a row that does not parse, an
+// unknown type, a type left out with --ignore-types and a client left out with --clients. The audit log must have one
+// line per input row, in order, each with its outcome code and reason.
+
+mod common;
+
+use common::{command, fixture, read_audit, temp_path};
+
+#[test]
+fn the_audit_log_has_every_outcome_code() {
+    let path = temp_path("audit_outcomes.csv");
+    let output = command([
+        fixture("audit_outcomes.csv").as_str(), "-q", "--max-balance", "1000", "--ignore-types", "admin_lock", "--clients", "1-2",
+        "--audit-log", path.to_str().expect("utf-8 path"),
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let audit = read_audit(&path);
+
+    // Rows that did not parse have no type, client or tx, and their reason is the parser's own message
+    let expected = [
+        (1, "deposit", "1", "1", "applied", ""),
+        (2, "withdrawal", "1", "2", "rejected", "insufficient_funds"),
+        (3, "deposit", "1", "1", "duplicate", "duplicate_transaction"),
+        (4, "dispute", "1", "99", "rejected", "unknown_transaction"),
+        (5, "dispute", "2", "1", "rejected", "client_mismatch"),
+        (6, "resolve", "1", "1", "rejected", "not_disputed"),
+        (7, "dispute", "1", "1", "applied", ""),
+        (8, "dispute", "1", "1", "rejected", "already_disputed"),
+        (9, "chargeback", "1", "1", "applied", ""),
+        (10, "deposit", "1", "3", "rejected", "account_locked"),
+        (11, "deposit", "2", "4", "ignored_limit_exceeded", "balance_cap_exceeded"),
+        (12, "deposit", "2", "5", "rejected", "missing_amount"),
+        (13, "deposit", "2", "6", "rejected", "invalid_amount"),
+        (14, "", "", "", "parse_error", "line 15: amount \"abc\" is not a valid number"),
+        (15, "", "", "", "unknown_type", "invalid transaction type \"refund\""),
+        (16, "admin_lock", "2", "10", "ignored", "ignore_types"),
+        (17, "", "", "", "skipped", "client_filter"),
+        (18, "deposit", "2", "12", "applied", ""),
+    ];
+    let mut reader = csv::Reader::from_reader(audit.as_bytes());
+    let records: Vec<csv::StringRecord> = reader.records().collect::<Result<_, _>>().expect("the audit log is CSV");
+    assert_eq!(records.len(), expected.len(), "{}", audit);
+    for (record, (row, kind, client, tx, outcome, reason)) in records.iter().zip(expected) {
+        assert_eq!(record[0].parse::<u64>().ok(), Some(row), "{:?}", record);
+        assert_eq!(record[1].parse::<u64>().ok(), Some(row + 1), "{:?}", record);
+        assert_eq!((&record[2], &record[3], &record[4]), (kind, client, tx), "{:?}", record);
+        assert_eq!(&record[6], outcome, "{:?}", record);
+        assert!(record[7].starts_with(reason) && record[7].is_empty() == reason.is_empty(), "{:?} should have reason {:?}", record, reason);
+    }
+}
@@ -0,0 +1,54 @@
+// Runs the command line tool on tests/fixtures/balance_cap.csv with --max-balance 100, and a limits file that caps client
+// 2 at 50. A deposit landing exactly on a cap is applied and one going past it by the smallest amount is rejected, while
+// a dispute and resolve of a capped account move nothing in or out and are applied.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+
+#[test]
+fn deposits_may_reach_the_cap_but_not_cross_it() {
+    let path = temp_path("balance_cap_annotated.csv");
+    let limits = fixture("balance_cap_limits.csv");
+    let output = command([
+        fixture("balance_cap.csv").as_str(), "--max-balance", "100", "--limits-file", limits.as_str(),
+        "--annotate-out", path.to_str().expect("utf-8 path"),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("deposit 3 for client 1 rejected: balance would exceed the client's cap."), "{}", stderr);
+
+    assert_eq!(read(&path), "type,client,tx,amount,outcome,detail\n\
+        deposit,1,1,60,applied,\n\
+        deposit,1,2,40,applied,\n\
+        deposit,1,3,0.0001,ignored_limit_exceeded,balance_cap_exceeded\n\
+        dispute,1,1,,applied,\n\
+        resolve,1,1,,applied,\n\
+        deposit,2,4,50,applied,\n\
+        deposit,2,5,1,ignored_limit_exceeded,balance_cap_exceeded\n\
+        deposit,3,6,100.0001,ignored_limit_exceeded,balance_cap_exceeded\n\
+        withdrawal,1,7,10,applied,\n\
+        deposit,1,8,10,applied,\n");
+    // Client 3's only deposit was over the cap, so they have no account
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client,available,held,total,locked\n\
+        1,100.0000,0.0000,100.0000,false\n\
+        2,50.0000,0.0000,50.0000,false\n");
+}
+
+#[test]
+fn a_client_cap_replaces_the_global_one() {
+    // Without the limits file client 2 may go up to 100 like everyone else
+    let output = command([fixture("balance_cap.csv").as_str(), "-q", "--max-balance", "100"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(report.contains("\n2,51.0000,0.0000,51.0000,false\n"), "{}", report);
+
+    // And the limits file may raise a client's cap above the global one as well as lower it
+    let limits = temp_path("balance_cap_raised.csv");
+    std::fs::write(&limits, "client,max_total\n3,200\n").expect("the limits file can be written");
+    let output = command([fixture("balance_cap.csv").as_str(), "-q", "--max-balance", "100", "--limits-file", limits.to_str().expect("utf-8 path")]);
+    let _ = std::fs::remove_file(&limits);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(report.ends_with("\n3,100.0001,0.0000,100.0001,false\n"), "{}", report);
+}
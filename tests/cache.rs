@@ -0,0 +1,66 @@
+// Runs the command line tool with --cache over a copy of tests/fixtures/basic.csv. The first run parses the input and
+// writes the cache, later runs read the rows from it without parsing the input and must write the same report, under
+// any policy, until the input changes.
+
+mod common;
+
+use common::{command, fixture, temp_path};
+use std::path::Path;
+use std::process::Output;
+
+// This function runs the tool on `input` with the cache at `cache`, checking that it succeeded
+fn cached_run(input: &Path, cache: &Path, flags: &[&str]) -> Output {
+    let args = [&[input.to_str().expect("utf-8 path"), "--cache", cache.to_str().expect("utf-8 path")][..], flags].concat();
+    let output = command(&args);
+    assert_eq!(output.status.code(), Some(0), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+#[test]
+fn a_second_run_reads_the_cache_until_the_input_changes() {
+    let input = temp_path("cache_input.csv");
+    let cache = temp_path("cache_input.bin");
+    std::fs::copy(fixture("basic.csv"), &input).expect("the fixture can be copied");
+    let reading = format!("cache: reading parsed input from {}", cache.display());
+    let parsing = format!("cache: parsing {} and rebuilding {}, because", input.display(), cache.display());
+
+    let first = cached_run(&input, &cache, &[]);
+    let stderr = String::from_utf8_lossy(&first.stderr);
+    assert!(stderr.contains(&format!("{} it does not exist yet", parsing)), "{}", stderr);
+    assert!(stderr.contains(&format!("cache: wrote {}", cache.display())), "{}", stderr);
+    assert!(!stderr.contains(&reading), "{}", stderr);
+    assert_eq!(first.stdout, command([fixture("basic.csv")]).stdout);
+
+    // The parser is skipped, and the input file is only read to check it is the one the cache was built from
+    let second = cached_run(&input, &cache, &[]);
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(stderr.contains(&reading), "{}", stderr);
+    assert!(!stderr.contains(&parsing), "{}", stderr);
+    assert_eq!(second.stdout, first.stdout);
+
+    // The cache holds rows, not what the policy made of them
+    let tracked = cached_run(&input, &cache, &["--track-debt"]);
+    let stderr = String::from_utf8_lossy(&tracked.stderr);
+    assert!(stderr.contains(&reading), "{}", stderr);
+    assert_eq!(tracked.stdout, command([fixture("basic.csv").as_str(), "--track-debt"]).stdout);
+    assert_ne!(tracked.stdout, first.stdout);
+
+    let mut changed = std::fs::read_to_string(&input).expect("the input can be read");
+    changed.push_str("deposit,3,7,2.5\n");
+    std::fs::write(&input, changed).expect("the input can be changed");
+    let third = cached_run(&input, &cache, &[]);
+    let stderr = String::from_utf8_lossy(&third.stderr);
+    assert!(stderr.contains(&format!("{} the input or --thousands-separator changed since it was written", parsing)), "{}", stderr);
+    assert!(!stderr.contains(&reading), "{}", stderr);
+    let report = String::from_utf8_lossy(&third.stdout);
+    assert!(report.ends_with("3,2.5000,0.0000,2.5000,false\n"), "{}", report);
+
+    // The rebuilt cache holds the changed input
+    let fourth = cached_run(&input, &cache, &[]);
+    assert!(String::from_utf8_lossy(&fourth.stderr).contains(&reading));
+    assert_eq!(fourth.stdout, third.stdout);
+
+    for path in [input, cache] {
+        let _ = std::fs::remove_file(path);
+    }
+}
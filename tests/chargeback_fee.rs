@@ -0,0 +1,76 @@
+// Runs the command line tool with --chargeback-fee and checks where the fee shows up: the report balances, its own line in
+// the audit log, its own type in the volume report, the summary and, when it takes a client negative or cannot be debited
+// at all, the anomalies.
+
+mod common;
+
+use common::{command, fixture, read, read_audit, temp_path};
+use std::process::Output;
+
+// A fee that can be debited once from an account near zero, but not a second time
+#[cfg(not(feature = "fixed-point"))]
+const HUGE_FEE: &str = "50000000000000000000000000000";
+#[cfg(feature = "fixed-point")]
+const HUGE_FEE: &str = "500000000000000";
+
+fn run(input: &str, args: &[&str]) -> Output {
+    command([&[fixture(input).as_str()][..], args].concat())
+}
+
+#[test]
+fn the_fee_is_debited_with_and_without_enough_funds() {
+    let (audit, volume) = (temp_path("fee_audit.csv"), temp_path("fee_volume.csv"));
+    let output = run("chargeback_fee.csv", &[
+        "--chargeback-fee", "2.5", "--summary", "--report-anomalies",
+        "--audit-log", audit.to_str().expect("utf-8 path"), "--volume-report", volume.to_str().expect("utf-8 path"),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Client 1 has 6 left after the chargeback of 4 to pay the fee from, client 2 has nothing and goes negative
+    assert!(stdout.contains("1,7.5000,0.0000,7.5000,true"), "{}", stdout);
+    assert!(stdout.contains("2,-2.5000,0.0000,-2.5000,true"), "{}", stdout);
+    assert!(stderr.contains("anomaly client=2 kind=negative_available events=1 lowest_available=-2.5000"), "{}", stderr);
+    assert!(!stderr.contains("anomaly client=1 "), "{}", stderr);
+    assert!(stderr.contains("summary chargeback_fees count=2 amount=5.0000 failed=0"), "{}", stderr);
+
+    // The chargeback's own line leaves the disputed amount out, the fee line after it has the fee
+    let audit = read_audit(&audit);
+    assert!(audit.contains("4,5,chargeback,1,2,,applied,,7.5000,0.0000,7.5000,\n4,5,chargeback,1,2,2.5000,fee_charged,,7.5000,0.0000,7.5000,\n"), "{}", audit);
+    assert!(audit.contains("7,8,chargeback,2,3,2.5000,fee_charged,,-2.5000,0.0000,-2.5000,\n"), "{}", audit);
+
+    let volume = read(&volume);
+    // Amounts are written as the money type displays them, 2.5 or 2.5000
+    for line in ["\n1,chargeback,1,4.0", "\n1,chargeback_fee,1,2.5", "\n2,chargeback_fee,1,2.5", "\nTOTAL,chargeback_fee,2,5.0"] {
+        assert!(volume.contains(line), "{} in {}", line, volume);
+    }
+}
+
+#[test]
+fn a_fee_that_cannot_be_debited_is_an_anomaly() {
+    let audit = temp_path("fee_failed_audit.csv");
+    let output = run("chargeback_fee_overflow.csv", &[
+        "--chargeback-fee", HUGE_FEE, "--lock-policy", "threshold:2", "--summary", "--report-anomalies",
+        "--audit-log", audit.to_str().expect("utf-8 path"),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Both chargebacks are applied, only one of their fees fits
+    assert!(stderr.contains("summary applied type=chargeback count=2"), "{}", stderr);
+    assert!(stderr.contains("summary chargeback_fees count=1 "), "{}", stderr);
+    assert!(stderr.contains(" failed=1"), "{}", stderr);
+    assert_eq!(stderr.matches("anomaly client=3 kind=chargeback_fee_failed").count(), 1, "{}", stderr);
+
+    let audit = read_audit(&audit);
+    assert_eq!(audit.matches(",fee_charged,,").count(), 1, "{}", audit);
+    assert_eq!(audit.matches(",fee_failed,overflow,").count(), 1, "{}", audit);
+}
+
+#[test]
+fn a_negative_fee_is_refused() {
+    let output = run("chargeback_fee.csv", &["--chargeback-fee=-1"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("must be zero or more"));
+}
@@ -0,0 +1,37 @@
+// Runs the command line tool with --checkpoint-every on tests/fixtures/basic.csv, six rows. The checkpoint file must hold
+// the accounts as of the last multiple of N, not the end of the input, and each checkpoint gets a progress line.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+
+#[test]
+fn the_last_checkpoint_holds_the_accounts_after_row_4() {
+    let path = temp_path("checkpoint_every_4.csv");
+    let output = command([fixture("basic.csv").as_str(), "-q", "--checkpoint-every", "4", "--checkpoint-out", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    // Row 5 withdraws client 2's 5.5, after the checkpoint
+    assert_eq!(read(&path), "client,available,held,total,locked\n\
+        1,5.7501,0.0000,5.7501,false\n\
+        2,5.5000,0.0000,5.5000,false\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\n2,0.0000,0.0000,0.0000,false\n"));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().filter(|line| line.starts_with("checkpoint ")).collect();
+    assert_eq!(lines.len(), 1, "{}", stderr);
+    assert!(lines[0].starts_with("checkpoint rows=4 rows_per_sec="), "{}", stderr);
+    assert!(lines[0].ends_with(&format!(" accounts=2 path={}", path.display())), "{}", stderr);
+}
+
+#[test]
+fn every_multiple_of_n_gets_a_progress_line() {
+    let path = temp_path("checkpoint_every_2.csv");
+    let output = command([fixture("basic.csv").as_str(), "-q", "--checkpoint-every", "2", "--checkpoint-out", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    // Row 6 is a multiple of 2, so the last checkpoint is the end of the input
+    assert_eq!(read(&path).as_bytes(), output.stdout);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let rows: Vec<&str> = stderr.lines().filter_map(|line| line.strip_prefix("checkpoint rows=")).filter_map(|rest| rest.split(' ').next()).collect();
+    assert_eq!(rows, ["2", "4", "6"], "{}", stderr);
+}
@@ -0,0 +1,38 @@
+// Runs the command line tool on tests/fixtures/error_heavy.csv, where nearly every row takes a different error path:
+// insufficient funds, a reused tx id, unknown and mismatched references, resolving and charging back what is not
+// disputed, amounts that are not numbers or negative, an unknown type, a short row and a locked account. Whatever the
+// tool logs about them, at any verbosity and with every end of run report on stderr, stdout must be the account report
+// alone: the header and one well formed row per client.
+
+mod common;
+
+use common::{command, fixture};
+use payment_engine::money::Money;
+
+const HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+#[test]
+fn stdout_holds_only_the_csv_report() {
+    let input = fixture("error_heavy.csv");
+    for flags in [&[][..], &["-vvv"], &["-vvv", "--log-format", "json"], &["--summary", "--stats", "--report-anomalies"]] {
+        let output = command([&[input.as_str()][..], flags].concat());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(0), "{:?}: {}", flags, stderr);
+        assert!(stderr.contains("rejected: insufficient funds"), "{:?}: {}", flags, stderr);
+
+        let mut reader = csv::Reader::from_reader(output.stdout.as_slice());
+        let header = reader.headers().expect("stdout starts with a header").clone();
+        assert_eq!(header.iter().collect::<Vec<_>>(), HEADER, "{:?}", flags);
+        let mut clients = Vec::new();
+        for record in reader.records() {
+            let record = record.unwrap_or_else(|e| panic!("{:?}: stdout is not clean CSV: {}", flags, e));
+            assert_eq!(record.len(), HEADER.len(), "{:?}: {:?}", flags, record);
+            clients.push(record[0].parse::<u16>().unwrap_or_else(|_| panic!("{:?}: invalid client in {:?}", flags, record)));
+            for field in 1..4 {
+                assert!(record[field].parse::<Money>().is_ok(), "{:?}: invalid amount in {:?}", flags, record);
+            }
+            assert!(["true", "false"].contains(&&record[4]), "{:?}: invalid locked in {:?}", flags, record);
+        }
+        assert_eq!(clients, [1, 2, 3], "{:?}", flags);
+    }
+}
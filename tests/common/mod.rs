@@ -0,0 +1,50 @@
+// Helpers the integration tests share: running the command line tool, paths in the temporary directory no other test
+// uses, and reading back the files a run writes. Every test file is its own crate and uses only some of them.
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+pub const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+// This function returns the path of a file under tests/fixtures
+pub fn fixture(name: &str) -> String {
+    format!("{}/{}", FIXTURES, name)
+}
+
+// This function runs the tool with RUST_LOG removed, so only the flags decide what it logs
+pub fn command<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(args: I) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(args)
+        .env_remove("RUST_LOG")
+        .output()
+        .expect("the payment_engine binary runs")
+}
+
+// This function runs the tool and returns its standard output, checking that it succeeded
+pub fn stdout<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(args: I) -> String {
+    let args: Vec<S> = args.into_iter().collect();
+    let output = command(&args);
+    let shown: Vec<_> = args.iter().map(|arg| arg.as_ref().to_string_lossy()).collect();
+    assert!(output.status.success(), "{:?}: {}", shown, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// This function returns a path in the temporary directory that no other test uses
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("payment_engine_{}_{}", std::process::id(), name))
+}
+
+// This function reads a file a run wrote and removes it
+pub fn read(path: &Path) -> String {
+    let text = std::fs::read_to_string(path).expect("the output file was written");
+    let _ = std::fs::remove_file(path);
+    text
+}
+
+// This function reads an audit log without its run header and hash chain columns, which depend on the input file and
+// the engine version rather than on the rows
+pub fn read_audit(path: &Path) -> String {
+    read(path).lines().filter(|line| !line.contains(",run_header,")).map(|line| format!("{}\n", line.rsplitn(3, ',').last().unwrap_or(line))).collect()
+}
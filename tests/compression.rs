@@ -0,0 +1,39 @@
+// Runs the command line tool on gzip and zstd compressed copies of tests/fixtures/basic.csv, picked by extension and by
+// --compression, and checks the report is that of the plain file. Only built with the compression feature.
+#![cfg(feature = "compression")]
+
+mod common;
+
+use common::{stdout, temp_path};
+use std::io::Write;
+use std::path::Path;
+
+const BASIC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/basic.csv");
+const BASIC_REPORT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/basic.expected.csv");
+
+// This function runs the tool on the input and returns its report, checking that it succeeded
+fn report(input: &Path, args: &[&str]) -> String {
+    stdout([&[input.to_str().expect("utf-8 path")][..], args].concat())
+}
+
+#[test]
+fn compressed_input_gives_the_plain_report() {
+    let csv = std::fs::read(BASIC).expect("the fixture is readable");
+    let expected = std::fs::read_to_string(BASIC_REPORT).expect("the expected report is readable");
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(&csv).expect("the fixture is compressed");
+    let gzip = gzip.finish().expect("the fixture is compressed");
+    let zstd = zstd::encode_all(csv.as_slice(), 0).expect("the fixture is compressed");
+
+    for (name, bytes, compression) in [("basic.csv.gz", &gzip, "gzip"), ("basic.csv.zst", &zstd, "zstd")] {
+        let (by_extension, by_flag) = (temp_path(name), temp_path(&format!("{}.bin", name)));
+        std::fs::write(&by_extension, bytes).expect("the compressed copy is written");
+        std::fs::write(&by_flag, bytes).expect("the compressed copy is written");
+        let reports = (report(&by_extension, &[]), report(&by_flag, &["--compression", compression]));
+        let _ = std::fs::remove_file(&by_extension);
+        let _ = std::fs::remove_file(&by_flag);
+        assert_eq!(reports.0, expected, "{}", name);
+        assert_eq!(reports.1, expected, "{} with --compression {}", name, compression);
+    }
+}
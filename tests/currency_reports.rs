@@ -0,0 +1,60 @@
+// Runs the command line tool with --multi-currency over tests/fixtures/currency_reports.csv, where client 1 has a JPY and
+// a USD account with a dispute open in each and client 2 a USD one, and checks that the summary, the anomalies, the volume
+// report and the worst case keep the currencies apart, with the amounts of each at its own decimal places.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+
+#[test]
+fn summary_and_anomalies_come_once_per_currency_at_its_scale() {
+    let output = command([&fixture("currency_reports.csv"), "--multi-currency", "--currency-scales", "JPY=0,USD=2", "--summary", "--report-anomalies"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in [
+        "anomaly client=1 currency=JPY kind=negative_available events=1 lowest_available=-40\n",
+        "anomaly client=1 currency=JPY kind=open_disputes count=1 held=100 txs=1\n",
+        "anomaly client=1 currency=USD kind=open_disputes count=1 held=10.50 txs=2\n",
+        "summary accounts currency=JPY clients=1 locked=0 chargebacks=0 held=100 total=60\n",
+        "summary open_disputes currency=JPY count=1 held=100\n",
+        "summary accounts currency=USD clients=2 locked=0 chargebacks=0 held=10.50 total=17.75\n",
+        "summary open_disputes currency=USD count=1 held=10.50\n",
+    ] {
+        assert!(stderr.contains(line), "{} in {}", line, stderr);
+    }
+    assert!(!stderr.contains("summary accounts clients="), "{}", stderr);
+}
+
+// Currencies --currency-scales does not list take --precision, like in the account report
+#[test]
+fn anomalies_follow_the_precision() {
+    let output = command([&fixture("currency_reports.csv"), "--multi-currency", "--precision", "2", "--report-anomalies"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("anomaly client=1 currency=JPY kind=negative_available events=1 lowest_available=-40.00\n"), "{}", stderr);
+    assert!(stderr.contains("anomaly client=1 currency=JPY kind=open_disputes count=1 held=100.00 txs=1\n"), "{}", stderr);
+}
+
+#[test]
+fn the_worst_case_has_a_row_per_currency() {
+    let path = temp_path("currency_worst_case.csv");
+    let output = command([&fixture("currency_reports.csv"), "--multi-currency", "--simulate-chargebacks", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(read(&path), "client,currency,worst_available,worst_total,would_lock\n\
+        1,JPY,-40.0000,-40.0000,true\n\
+        1,USD,0.0000,0.0000,true\n\
+        2,USD,7.2500,7.2500,false\n");
+}
+
+#[test]
+fn the_volume_report_totals_each_currency_apart() {
+    let path = temp_path("currency_volume.csv");
+    let output = command([&fixture("currency_reports.csv"), "--multi-currency", "--volume-report", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0));
+    let volume = read(&path);
+    assert!(volume.starts_with("client,currency,type,count,amount\n"), "{}", volume);
+    // Amounts are written as the money type displays them, 17.75 or 17.7500
+    for line in ["\n1,JPY,deposit,1,100", "\n1,USD,deposit,1,10.5", "\n2,USD,deposit,1,7.25", "\nTOTAL,JPY,deposit,1,100", "\nTOTAL,USD,deposit,2,17.75", "\nTOTAL,JPY,withdrawal,1,40"] {
+        assert!(volume.contains(line), "{} in {}", line, volume);
+    }
+    assert!(!volume.contains("TOTAL,USD,withdrawal"), "{}", volume);
+}
@@ -0,0 +1,58 @@
+// Runs the command line tool with --currency-scales over tests/fixtures/currency_scales.csv, whose rows are in JPY, USD and
+// EUR. JPY has no decimal places and USD two, so the fractional yen deposit and the tenth of a cent withdrawal are
+// rejected, EUR takes DEFAULT, and client 4's chargeback fee is rounded to whole yen.
+
+mod common;
+
+use common::command;
+use std::process::Output;
+
+const CURRENCY_SCALES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/currency_scales.csv");
+
+fn run(args: &[&str]) -> Output {
+    command([&[CURRENCY_SCALES, "--multi-currency"][..], args].concat())
+}
+
+#[test]
+fn amounts_are_validated_and_written_with_their_currency_scale() {
+    let output = run(&["--currency-scales", "JPY=0,USD=2,DEFAULT=4", "--chargeback-fee", "1.5", "--summary"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(stderr.contains("deposit 2 for client 1 rejected: amount is not valid"), "{}", stderr);
+    assert!(stderr.contains("withdrawal 6 for client 2 rejected: amount is not valid"), "{}", stderr);
+    assert!(stderr.contains("summary not_applied outcome=rejected reason=invalid_amount count=2"), "{}", stderr);
+    assert_eq!(stdout, "client,currency,available,held,total,locked\n\
+        1,EUR,1.2345,0.0000,1.2345,false\n\
+        1,JPY,100,0,100,false\n\
+        1,USD,12.34,0.00,12.34,false\n\
+        2,USD,5.50,0.00,5.50,false\n\
+        3,EUR,1.5000,0.0000,1.5000,false\n\
+        4,JPY,998,0,998,true\n");
+    // The fee of 1.5 is charged as 2 yen, rounded half to even
+    assert!(stderr.contains("summary chargeback_fees currency=JPY count=1 amount=2 failed=0"), "{}", stderr);
+}
+
+#[test]
+fn unlisted_currencies_take_the_default_scale() {
+    let output = run(&["--currency-scales", "JPY=0,USD=2,DEFAULT=3"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("deposit 4 for client 1 rejected: amount is not valid"), "{}", stderr);
+    assert!(!stdout.contains("1,EUR"), "{}", stdout);
+    assert!(stdout.contains("\n3,EUR,1.500,0.000,1.500,false\n"), "{}", stdout);
+
+    // Without DEFAULT an unlisted currency keeps four places
+    let stdout = String::from_utf8_lossy(&run(&["--currency-scales", "JPY=0"]).stdout).into_owned();
+    assert!(stdout.contains("\n1,EUR,1.2345,0.0000,1.2345,false\n"), "{}", stdout);
+    assert!(stdout.contains("\n1,USD,12.3400,0.0000,12.3400,false\n"), "{}", stdout);
+}
+
+#[test]
+fn a_scale_beyond_four_places_or_given_twice_is_refused() {
+    for spec in ["USD=5", "JPY=0,jpy=2", "DEFAULT=2,DEFAULT=3", "YENS=0", "USD"] {
+        assert_eq!(run(&["--currency-scales", spec]).status.code(), Some(2), "{}", spec);
+    }
+}
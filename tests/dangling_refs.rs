@@ -0,0 +1,30 @@
+// Runs the command line tool on tests/fixtures/dangling_refs.csv, which has two disputes and a chargeback of tx ids the
+// input never defines between rows that do refer to known ones. --dangling-refs must list exactly those three, and
+// --max-dangling-refs must fail the run when they are more than it allows.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+
+#[test]
+fn the_report_lists_each_dangling_reference_once() {
+    let path = temp_path("dangling_refs.csv");
+    let output = command([&fixture("dangling_refs.csv"), "-q", "--dangling-refs", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(read(&path), "row,line,type,client,tx\n\
+        2,3,dispute,1,7\n\
+        5,6,dispute,2,8\n\
+        6,7,chargeback,1,9\n");
+}
+
+#[test]
+fn more_dangling_references_than_the_limit_fail_the_run() {
+    let over = command([&fixture("dangling_refs.csv"), "--max-dangling-refs", "2"]);
+    assert_eq!(over.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&over.stderr);
+    assert!(stderr.contains("3 rows reference unknown transactions, more than the --max-dangling-refs limit of 2."), "{}", stderr);
+    assert!(over.stdout.is_empty(), "{}", String::from_utf8_lossy(&over.stdout));
+
+    let at = command([&fixture("dangling_refs.csv"), "-q", "--max-dangling-refs", "3"]);
+    assert_eq!(at.status.code(), Some(0), "{}", String::from_utf8_lossy(&at.stderr));
+}
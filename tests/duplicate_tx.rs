@@ -0,0 +1,26 @@
+// Runs the command line tool on tests/fixtures/duplicate_tx.csv, where tx 5 is a deposit of 10 for client 1 and then
+// comes again as a deposit of 99, a withdrawal of 3 and a deposit of 7 for client 2. Only the first is applied, each
+// reuse is rejected with a warning naming the tx id and client, and the dispute of tx 5 holds the first amount.
+
+mod common;
+
+use common::{command, fixture};
+
+#[test]
+fn a_reused_tx_id_is_rejected_and_the_dispute_holds_the_first_amount() {
+    let output = command([fixture("duplicate_tx.csv")]);
+    assert_eq!(output.status.code(), Some(0));
+    // Client 1 has 10 held by the dispute of tx 5 and the 2.5 of tx 6, and client 2 never got an account
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client,available,held,total,locked\n\
+        1,2.5000,10.0000,12.5000,false\n");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for warning in [
+        "deposit 5 for client 1 rejected: transaction id is already in use.",
+        "withdrawal 5 for client 1 rejected: transaction id is already in use.",
+        "deposit 5 for client 2 rejected: transaction id is already in use.",
+    ] {
+        assert!(stderr.contains(warning), "{} in {}", warning, stderr);
+    }
+    assert!(stderr.contains("done: 6 rows, 3 applied, 3 rejected"), "{}", stderr);
+}
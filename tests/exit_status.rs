@@ -0,0 +1,52 @@
+// Runs the command line tool on inputs that stop it in different ways and checks the exit status each one gets.
+
+mod common;
+
+use common::command;
+
+const DIAGNOSTICS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/diagnostics.csv");
+const PENDING_DISPUTES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pending_disputes.csv");
+const BAD_ROWS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/bad_rows.csv");
+
+#[test]
+fn a_completed_run_exits_0_despite_rejections() {
+    assert_eq!(command([DIAGNOSTICS, "-q"]).status.code(), Some(0));
+}
+
+#[test]
+fn a_missing_input_exits_1() {
+    let output = command(["does/not/exist.csv"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("could not open input"));
+}
+
+#[test]
+fn an_unknown_type_under_strict_exits_2() {
+    let output = command([DIAGNOSTICS, "--strict"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid transaction type \"transfer\""));
+}
+
+#[test]
+fn a_failed_assert_exits_2() {
+    assert_eq!(command([PENDING_DISPUTES]).status.code(), Some(2));
+}
+
+// Two rows that cannot be parsed sit between good ones. By default both are skipped and the good rows around them
+// applied, --strict stops at the first one without writing a report
+#[test]
+fn bad_rows_are_skipped_unless_strict() {
+    let output = command([BAD_ROWS]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client,available,held,total,locked\n1,12.5000,0.0000,12.5000,false\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("skipping line 3:") && stderr.contains("skipping line 4:"), "{}", stderr);
+    assert!(stderr.contains("processed 4 rows, skipped 2"), "{}", stderr);
+    // The done line counts the same rows as skipped, and none of them as rejected
+    assert!(stderr.contains("done: 4 rows, 2 applied, 0 rejected, 2 skipped, 0 ignored, 1 clients, 0 locked"), "{}", stderr);
+
+    let output = command([BAD_ROWS, "--strict"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 3: amount \"oops\" is not a valid number"));
+}
@@ -0,0 +1,48 @@
+// Runs an input with --snapshot-out, forgets client 1 in the snapshot and runs a later input from the new snapshot.
+// Every later row of client 1 must be rejected as forgotten_client without bringing back an account for them, and
+// client 2 must be left as it was.
+
+mod common;
+
+use common::{command, temp_path, FIXTURES};
+use std::path::PathBuf;
+use std::process::Output;
+
+// This function runs the tool, checking that it succeeded
+fn run(args: &[&str]) -> Output {
+    let output = command(args);
+    assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+#[test]
+fn a_forgotten_client_stays_forgotten() {
+    let (state, export, state_out) = (temp_path("forget.snapshot"), temp_path("forget_client_1.json"), temp_path("forget_out.snapshot"));
+    let path = |path: &PathBuf| path.to_str().expect("utf-8 path").to_string();
+
+    run(&[&format!("{}/forget.csv", FIXTURES), "--snapshot-out", &path(&state)]);
+    run(&["forget", "--state", &path(&state), "--client", "1", "--export", &path(&export), "--state-out", &path(&state_out)]);
+    let later = run(&[&format!("{}/forget_later.csv", FIXTURES), "--snapshot-in", &path(&state_out), "--summary"]);
+    let exported = std::fs::read_to_string(&export).expect("the export was written");
+    for file in [&state, &export, &state_out] {
+        let _ = std::fs::remove_file(file);
+    }
+
+    // The export has the account with its open dispute and both deposits
+    for part in [
+        r#""client":1"#,
+        r#""available":"10.0000","held":"5.0000","total":"15.0000""#,
+        r#""open_disputes":[2]"#,
+        r#"{"tx":1,"type":"deposit","amount":"10.0000","state":"normal","currency":null}"#,
+        r#"{"tx":2,"type":"deposit","amount":"5.0000","state":"disputed","currency":null}"#,
+    ] {
+        assert!(exported.contains(part), "{} in {}", part, exported);
+    }
+
+    let stdout = String::from_utf8_lossy(&later.stdout);
+    let stderr = String::from_utf8_lossy(&later.stderr);
+    assert_eq!(stdout, "client,available,held,total,locked\n2,8.0000,0.0000,8.0000,false\n");
+    assert!(stderr.contains("summary not_applied outcome=rejected reason=forgotten_client count=3"), "{}", stderr);
+    // Client 1's deposit is gone, so client 2 has nothing to dispute under its tx id
+    assert!(stderr.contains("summary not_applied outcome=rejected reason=unknown_transaction count=1"), "{}", stderr);
+}
@@ -0,0 +1,49 @@
+// Inputs the parse_and_apply fuzz target found to panic, kept under tests/fuzz_regressions/ as plain CSV. Each may be
+// rejected in any way but must not panic, run through the command line tool with and without the flags that change
+// the handlers, and with the fuzzing feature through the fuzz target's own entry point under every options byte.
+
+mod common;
+
+use common::command;
+use std::fs;
+use std::path::PathBuf;
+
+const FLAG_SETS: [&[&str]; 3] = [
+    &[],
+    &["--track-debt", "--allow-forced-hold", "--dispute-requires-funds"],
+    &["--chargeback-fee", "2.5", "--lock-policy", "threshold:2", "--multi-currency"],
+];
+
+fn inputs() -> Vec<PathBuf> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fuzz_regressions");
+    let mut inputs = fs::read_dir(dir).expect("the fuzz_regressions directory is readable")
+        .map(|entry| entry.expect("the fuzz_regressions directory is readable").path())
+        .collect::<Vec<_>>();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no inputs in {}", dir);
+    inputs
+}
+
+#[test]
+fn fuzz_regressions_do_not_panic() {
+    for input in inputs() {
+        for flags in FLAG_SETS {
+            let output = command([&[input.to_str().expect("utf-8 path")][..], flags].concat());
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(!stderr.contains("panicked"), "{} with {:?} panicked:\n{}", input.display(), flags, stderr);
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+#[test]
+fn fuzz_regressions_do_not_panic_in_process() {
+    for input in inputs() {
+        let csv = fs::read(&input).expect("the input is readable");
+        for options in 0..=u8::MAX {
+            let mut data = vec![options];
+            data.extend_from_slice(&csv);
+            payment_engine::fuzzing::parse_and_apply(&data);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+// Runs an input, snapshots the engine and runs the same input again from the snapshot with --idempotent-replay. Every row
+// of tests/fixtures/idempotent.csv was applied by the first run, including disputes whose records have since been
+// resolved or charged back, so the second run must skip them all and leave the accounts as they were.
+
+mod common;
+
+use common::{stdout, temp_path};
+
+const IDEMPOTENT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/idempotent.csv");
+
+// This function runs the tool and returns its standard output with the account lines sorted
+fn run(args: &[&str]) -> String {
+    let mut lines: Vec<String> = stdout([&[IDEMPOTENT][..], args].concat()).lines().map(str::to_string).collect();
+    lines.sort();
+    lines.join("\n")
+}
+
+#[test]
+fn running_an_input_twice_equals_running_it_once() {
+    let (snapshot, volume) = (temp_path("idempotent.snapshot"), temp_path("idempotent_volume.csv"));
+    let (snapshot_path, volume_path) = (snapshot.to_str().expect("utf-8 path"), volume.to_str().expect("utf-8 path"));
+
+    let once = run(&["--snapshot-out", snapshot_path]);
+    let twice = run(&["--snapshot-in", snapshot_path, "--idempotent-replay", "--volume-report", volume_path]);
+    let volume_report = std::fs::read_to_string(&volume).expect("the volume report was written");
+    let _ = std::fs::remove_file(&snapshot);
+    let _ = std::fs::remove_file(&volume);
+
+    assert_eq!(twice, once);
+    assert!(once.contains("1,13.0000,0.0000,13.0000,false"), "{}", once);
+    // Nothing was applied the second time, so there is no volume to report
+    assert!(volume_report.lines().all(|line| line.starts_with("client,")), "{}", volume_report);
+}
@@ -0,0 +1,57 @@
+// Runs the command line tool on tests/fixtures/basic.csv given by its absolute path and by paths relative to the working
+// directory the tool is started in, which is never the directory of the executable, and checks the error a missing
+// input gets.
+
+mod common;
+
+use common::{command, fixture, FIXTURES};
+use std::path::Path;
+use std::process::{Command, Output};
+
+// This function runs the tool in `dir` with RUST_LOG removed, like common::command
+fn command_in(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(args)
+        .current_dir(dir)
+        .env_remove("RUST_LOG")
+        .output()
+        .expect("the payment_engine binary runs")
+}
+
+fn expected() -> String {
+    std::fs::read_to_string(fixture("basic.expected.csv")).expect("the expected report can be read")
+}
+
+#[test]
+fn an_absolute_path_is_read_from_anywhere() {
+    let path = fixture("basic.csv");
+    assert!(Path::new(&path).is_absolute(), "{}", path);
+    let output = command_in(&std::env::temp_dir(), &[path.as_str(), "-q"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected());
+}
+
+#[test]
+fn a_relative_path_is_taken_from_the_working_directory() {
+    let fixtures = Path::new(FIXTURES);
+    for (dir, path) in [(fixtures, "basic.csv"), (fixtures.parent().expect("tests/fixtures has a parent"), "fixtures/basic.csv"), (fixtures, "../fixtures/./basic.csv")] {
+        let output = command_in(dir, &[path, "-q"]);
+        assert_eq!(output.status.code(), Some(0), "{} in {}: {}", path, dir.display(), String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected(), "{} in {}", path, dir.display());
+    }
+}
+
+#[test]
+fn a_missing_input_is_named_with_the_reason() {
+    let output = command_in(&std::env::temp_dir(), &["payment_engine_no_such_input.csv"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("could not open input payment_engine_no_such_input.csv: No such file or directory"), "{}", stderr);
+
+    let missing = fixture("no_such_input.csv");
+    let output = command([missing.as_str()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("could not open input {}: No such file or directory", missing)), "{}", stderr);
+}
@@ -0,0 +1,103 @@
+// Property tests of the engine's balance invariants. Random sequences of transactions, with disputes of random and
+// reused tx ids, rows by the wrong client and rows for locked accounts, are fed to an engine one at a time, and after
+// every one each account must still have total == available + held. When only deposits move funds in, no total may
+// ever go negative either. Failing cases proptest finds are kept in proptest-regressions/ and rerun first.
+
+use payment_engine::money::Money;
+use payment_engine::{Engine, EnginePolicy, LockPolicy, Transaction};
+use proptest::prelude::*;
+use std::num::NonZeroU32;
+
+// Few clients and tx ids, so rows often refer to each other's transactions
+const CLIENTS: u16 = 4;
+const TX_IDS: u32 = 24;
+
+// This function turns whole cents into an amount
+fn cents(cents: u32) -> Money {
+    format!("{}.{:02}", cents / 100, cents % 100).parse().unwrap_or_else(|_| panic!("invalid amount of {} cents", cents))
+}
+
+fn deposit() -> impl Strategy<Value = Transaction> {
+    (1..=CLIENTS, 1..=TX_IDS, 1..100_000u32).prop_map(|(client, tx, amount)| Transaction::Deposit { client, tx, amount: cents(amount) })
+}
+
+// Disputes, resolves and chargebacks, by any client for any tx id
+fn dispute_lifecycle() -> impl Strategy<Value = Transaction> {
+    (0..3u8, 1..=CLIENTS, 1..=TX_IDS).prop_map(|(kind, client, tx)| match kind {
+        0 => Transaction::Dispute { client, tx },
+        1 => Transaction::Resolve { client, tx },
+        _ => Transaction::Chargeback { client, tx },
+    })
+}
+
+fn any_transaction() -> impl Strategy<Value = Transaction> {
+    prop_oneof![
+        3 => deposit(),
+        2 => (1..=CLIENTS, 1..=TX_IDS, 1..100_000u32).prop_map(|(client, tx, amount)| Transaction::Withdrawal { client, tx, amount: cents(amount) }),
+        4 => dispute_lifecycle(),
+        1 => (1..=CLIENTS, 1..=TX_IDS, 1..100_000u32).prop_map(|(client, tx, amount)| Transaction::Correction { client, tx, amount: cents(amount) }),
+        1 => (1..=CLIENTS, 1..=TX_IDS, 1..100_000u32).prop_map(|(client, tx, amount)| Transaction::AdminHold { client, tx, amount: cents(amount) }),
+        1 => (1..=CLIENTS, 1..=TX_IDS).prop_map(|(client, tx)| Transaction::AdminRelease { client, tx }),
+    ]
+}
+
+// The policies that keep total == available + held, which is every one without debt tracking
+fn policy() -> impl Strategy<Value = EnginePolicy> {
+    (any::<bool>(), any::<bool>(), 0..3u32, prop::option::of(1..500u32)).prop_map(|(forced_hold, requires_funds, threshold, fee)| {
+        let mut policy = EnginePolicy::builder().allow_forced_hold(forced_hold).dispute_requires_funds(requires_funds);
+        if let Some(threshold) = NonZeroU32::new(threshold) {
+            policy = policy.lock_policy(LockPolicy::Threshold(threshold));
+        }
+        if let Some(fee) = fee {
+            policy = policy.chargeback_fee(cents(fee));
+        }
+        policy.build()
+    })
+}
+
+// This function checks total == available + held for every account, naming the step that broke it
+fn check_totals(engine: &Engine, step: usize, transaction: &Transaction) -> Result<(), TestCaseError> {
+    for client in engine.accounts() {
+        let sum = client.available.checked_add(client.held);
+        prop_assert_eq!(sum, Some(client.total), "client {} after step {} ({:?})", client.client_id, step, transaction);
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn total_is_available_plus_held_after_every_step(policy in policy(), transactions in prop::collection::vec(any_transaction(), 1..80)) {
+        let mut engine = Engine::with_policy(policy);
+        for (step, transaction) in transactions.iter().enumerate() {
+            let _ = engine.process_transaction(*transaction);
+            check_totals(&engine, step, transaction)?;
+        }
+    }
+
+    #[test]
+    fn deposits_alone_never_leave_a_negative_total(transactions in prop::collection::vec(prop_oneof![deposit(), dispute_lifecycle()], 1..80)) {
+        let mut engine = Engine::new();
+        for (step, transaction) in transactions.iter().enumerate() {
+            let _ = engine.process_transaction(*transaction);
+            check_totals(&engine, step, transaction)?;
+            for client in engine.accounts() {
+                prop_assert!(client.total >= Money::ZERO, "client {} has total {} after step {} ({:?})", client.client_id, client.total, step, transaction);
+            }
+        }
+    }
+
+    // Disputes are only ever let through for an open account, whatever happened to it before
+    #[test]
+    fn locked_accounts_never_change(transactions in prop::collection::vec(any_transaction(), 1..80)) {
+        let mut engine = Engine::new();
+        for transaction in &transactions {
+            let before = engine.account(transaction.client()).filter(|client| client.locked).map(|client| (client.available, client.held, client.total));
+            let result = engine.process_transaction(*transaction);
+            if let Some(before) = before {
+                prop_assert!(result.is_err(), "{:?} was applied to a locked account", transaction);
+                let after = engine.account(transaction.client()).map(|client| (client.available, client.held, client.total));
+                prop_assert_eq!(after, Some(before));
+            }
+        }
+    }
+}
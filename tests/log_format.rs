@@ -0,0 +1,48 @@
+// Runs the command line tool with --log-format json on tests/fixtures/diagnostics.csv, whose rows are rejected for one reason
+// each, and checks that every line on stderr is a JSON object carrying the level, timestamp and message of one event
+// and the line, client, tx and reason it is about.
+
+mod common;
+
+use common::command;
+use serde_json::Value;
+
+const DIAGNOSTICS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/diagnostics.csv");
+
+// This function runs the tool with JSON logs and returns every event it wrote, checking that each line parses
+fn events(args: &[&str]) -> Vec<Value> {
+    let output = command([&[DIAGNOSTICS, "--log-format", "json"][..], args].concat());
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr.lines().map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{:?} is not JSON: {}", line, e))).collect()
+}
+
+#[test]
+fn every_event_is_a_json_object() {
+    let events = events(&[]);
+    assert!(!events.is_empty());
+    for event in &events {
+        assert!(event["level"].is_string() && event["timestamp"].is_string() && event["message"].is_string(), "{}", event);
+    }
+
+    let reasons: Vec<&str> = events.iter().filter_map(|event| event["reason"].as_str()).collect();
+    assert_eq!(reasons, [
+        "insufficient_funds", "duplicate_transaction", "client_mismatch", "unknown_transaction", "not_disputed",
+        "unknown_type", "parse_error", "account_locked",
+    ]);
+
+    // The withdrawal of 20 on line 4 is the first rejection
+    let first = events.iter().find(|event| event["reason"] == "insufficient_funds").expect("the withdrawal is rejected");
+    assert_eq!((&first["level"], &first["line"], &first["client"], &first["tx"]), (&Value::from("warn"), &Value::from(4), &Value::from(1), &Value::from(3)));
+    assert!(first["message"].as_str().is_some_and(|message| message.contains("rejected: insufficient funds")), "{}", first);
+
+    let done = events.last().expect("the run writes a done event");
+    let counts = ["rows", "applied", "rejected", "skipped", "ignored"].map(|field| &done[field]);
+    assert_eq!(counts, [12, 4, 6, 2, 0].map(Value::from).each_ref());
+}
+
+#[test]
+fn the_level_still_applies() {
+    assert!(events(&["-vv"]).iter().any(|event| event["level"] == "debug" && event["client"].is_u64()));
+    assert!(events(&["-q"]).is_empty());
+}
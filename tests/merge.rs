@@ -0,0 +1,81 @@
+// Runs the command line tool with --merge-by timestamp over three inputs whose timestamps interleave, one per region, and
+// checks that the result equals one run over the same rows sorted by hand. A dispute in one input refers to a deposit in
+// another, and two inputs have rows with equal timestamps, which go in the order the inputs were given.
+
+mod common;
+
+use common::{command, fixture, temp_path};
+use std::process::{Command, Output};
+
+fn run(inputs: &[&str], args: &[&str]) -> Output {
+    command(inputs.iter().map(|input| fixture(input)).chain(args.iter().map(|arg| arg.to_string())))
+}
+
+// This function returns the standard output of a successful run
+fn report(inputs: &[&str], args: &[&str]) -> String {
+    let output = run(inputs, args);
+    assert!(output.status.success(), "{:?}: {}", inputs, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+const REGIONS: [&str; 3] = ["merge_eu.csv", "merge_us.csv", "merge_asia.csv"];
+
+#[test]
+fn merged_inputs_equal_their_sorted_concatenation() {
+    let merged = report(&REGIONS, &["--merge-by", "timestamp"]);
+    assert_eq!(merged, report(&["merge_sorted.csv"], &[]));
+    // Read one after another the withdrawal of client 2 in the first input comes before its deposit
+    assert_ne!(merged, report(&REGIONS, &[]));
+    for line in ["1,-60.0000,0.0000,-60.0000,true", "2,20.0000,30.0000,50.0000,false", "3,20.0000,0.0000,20.0000,false"] {
+        assert!(merged.contains(line), "{} in {}", line, merged);
+    }
+}
+
+#[test]
+fn the_skew_needs_a_merge() {
+    let output = run(&REGIONS, &["--merge-max-skew", "1"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--merge-max-skew only applies with --merge-by"));
+}
+
+// A named pipe whose writer holds back its only row stalls the merge. With --merge-max-skew the other input is applied
+// without waiting, so the pipe's earlier deposit comes too late for the withdrawal
+#[cfg(unix)]
+#[test]
+fn a_stalled_input_is_left_behind_after_the_skew() {
+    use std::io::Write;
+    use std::time::Duration;
+
+    let run_with_pipe = |args: &[&str]| {
+        let pipe = temp_path(&format!("merge_{}.csv", args.len()));
+        let _ = std::fs::remove_file(&pipe);
+        let status = Command::new("mkfifo").arg(&pipe).status().expect("mkfifo runs");
+        assert!(status.success());
+        let writer = {
+            let pipe = pipe.clone();
+            std::thread::spawn(move || {
+                let mut fifo = std::fs::OpenOptions::new().write(true).open(&pipe).expect("the pipe opens");
+                fifo.write_all(b"type,client,tx,amount,timestamp\n").expect("the header is written");
+                fifo.flush().expect("the header is flushed");
+                std::thread::sleep(Duration::from_secs(3));
+                fifo.write_all(b"deposit,1,1,10,2024-03-01T08:00:00Z\n").expect("the row is written");
+            })
+        };
+        let file = temp_path(&format!("merge_file_{}.csv", args.len()));
+        std::fs::write(&file, "type,client,tx,amount,timestamp\nwithdrawal,1,2,4,2024-03-01T09:00:00Z\n").expect("the input is written");
+        let paths = [&pipe, &file].map(|path| path.to_str().expect("utf-8 path"));
+        let output = command([&paths[..], &["--merge-by", "timestamp"], args].concat());
+        writer.join().expect("the writer finishes");
+        let _ = std::fs::remove_file(&pipe);
+        let _ = std::fs::remove_file(&file);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        (String::from_utf8_lossy(&output.stdout).into_owned(), String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    let (waited, _) = run_with_pipe(&[]);
+    assert!(waited.contains("1,6.0000,0.0000,6.0000,false"), "{}", waited);
+    let (skewed, warnings) = run_with_pipe(&["--merge-max-skew", "1"]);
+    assert!(skewed.contains("1,10.0000,0.0000,10.0000,false"), "{}", skewed);
+    assert!(warnings.contains("merging the other inputs without it"), "{}", warnings);
+    assert!(warnings.contains("older than rows already applied"), "{}", warnings);
+}
@@ -0,0 +1,72 @@
+// Runs the command line tool with --monthly-fee over tests/fixtures/monthly_fee.csv, whose rows span January and part of
+// February. Client 1 stays positive, client 2 withdraws everything mid-January, client 3 is locked by a chargeback and
+// client 4 only appears in February, so each month charges a different set of accounts.
+
+mod common;
+
+use common::{command, fixture, read, read_audit, temp_path};
+use std::process::Output;
+
+fn run(args: &[&str]) -> Output {
+    command([&[fixture("monthly_fee.csv").as_str()][..], args].concat())
+}
+
+#[test]
+fn the_fee_is_charged_when_a_month_ends() {
+    let (audit, volume) = (temp_path("monthly_audit.csv"), temp_path("monthly_volume.csv"));
+    let output = run(&[
+        "--monthly-fee", "1", "--summary",
+        "--audit-log", audit.to_str().expect("utf-8 path"), "--volume-report", volume.to_str().expect("utf-8 path"),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Client 2 was positive for part of January, so it pays although it ended the month at zero. The locked client 3
+    // pays nothing, and February is not over
+    for line in ["1,10.0000,0.0000,10.0000,false", "2,-1.0000,0.0000,-1.0000,false", "3,2.0000,0.0000,2.0000,true", "4,3.0000,0.0000,3.0000,false"] {
+        assert!(stdout.contains(line), "{} in {}", line, stdout);
+    }
+    assert!(stderr.contains("summary monthly_fees count=2 amount=2.0000 failed=0"), "{}", stderr);
+    // Rows may not store a record under an id the fees use
+    assert!(stderr.contains("summary not_applied outcome=rejected reason=reserved_transaction_id count=1"), "{}", stderr);
+
+    // January's fees come before the February row that ended the month, with ids from the reserved range
+    let audit = read_audit(&audit);
+    assert!(audit.contains(concat!(
+        "8,9,monthly_fee,1,4293918720,1.0000,fee_charged,,9.0000,0.0000,9.0000,\n",
+        "8,9,monthly_fee,2,4293918721,1.0000,fee_charged,,-1.0000,0.0000,-1.0000,\n",
+        "8,9,deposit,1,6,1.0000,applied,,10.0000,0.0000,10.0000,2024-02-10T09:00:00Z\n",
+    )), "{}", audit);
+    assert_eq!(audit.matches("monthly_fee").count(), 2, "{}", audit);
+
+    let volume = read(&volume);
+    for line in ["\n1,monthly_fee,1,1", "\n2,monthly_fee,1,1", "\nTOTAL,monthly_fee,2,2"] {
+        assert!(volume.contains(line), "{} in {}", line, volume);
+    }
+}
+
+#[test]
+fn the_last_month_is_only_charged_when_asked_for() {
+    let audit = temp_path("monthly_final_audit.csv");
+    let output = run(&["--monthly-fee", "1", "--fee-final-partial-month", "--audit-log", audit.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // Client 2 stayed below zero all February
+    for line in ["1,9.0000,0.0000,9.0000,false", "2,-1.0000,0.0000,-1.0000,false", "3,2.0000,0.0000,2.0000,true", "4,2.0000,0.0000,2.0000,false"] {
+        assert!(stdout.contains(line), "{} in {}", line, stdout);
+    }
+    let audit = read_audit(&audit);
+    assert!(audit.ends_with(concat!(
+        "10,,monthly_fee,1,4293918722,1.0000,fee_charged,,9.0000,0.0000,9.0000,\n",
+        "10,,monthly_fee,4,4293918723,1.0000,fee_charged,,2.0000,0.0000,2.0000,\n",
+    )), "{}", audit);
+}
+
+#[test]
+fn a_negative_fee_is_refused() {
+    let output = run(&["--monthly-fee=-1"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("must be zero or more"));
+}
@@ -0,0 +1,33 @@
+// Runs the command line tool on tests/fixtures/basic.csv with and without --output. Without it the report goes to stdout
+// as it always has, with it the report goes only to the file, in directories created for it, and no temporary file is
+// left next to it. That a report which fails part way never replaces the file is tested on AtomicSink in report.rs.
+
+mod common;
+
+use common::{command, fixture, temp_path};
+
+fn expected() -> String {
+    std::fs::read_to_string(fixture("basic.expected.csv")).expect("the expected report can be read")
+}
+
+#[test]
+fn the_report_goes_to_stdout_without_output() {
+    let output = command([fixture("basic.csv").as_str(), "-q"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected());
+}
+
+#[test]
+fn the_report_goes_only_to_the_output_file() {
+    let dir = temp_path("output_file");
+    let path = dir.join("reports").join("accounts.csv");
+    let output = command([fixture("basic.csv").as_str(), "-q", "--output", path.to_str().expect("utf-8 path")]);
+    let written = std::fs::read_to_string(&path);
+    let entries: Vec<_> = std::fs::read_dir(dir.join("reports")).map(|entries| entries.filter_map(Result::ok).map(|entry| entry.file_name()).collect()).unwrap_or_default();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    assert_eq!(written.expect("the report was written"), expected());
+    assert_eq!(entries, ["accounts.csv"]);
+}
@@ -0,0 +1,81 @@
+// Runs the command line tool on fixtures that write warnings, checking that -q leaves stderr empty without changing the
+// report, that -v and --log-level pick the level, that RUST_LOG takes env_logger filters when neither is given, and that
+// -q and -v together are refused.
+
+mod common;
+
+use common::{command, fixture};
+use std::process::Command;
+
+const DIAGNOSTICS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/diagnostics.csv");
+const LOCK_THRESHOLD: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/lock_threshold.csv");
+
+#[test]
+fn quiet_leaves_stderr_empty() {
+    for args in [&[DIAGNOSTICS][..], &[LOCK_THRESHOLD, "--lock-policy", "threshold:2"][..]] {
+        let loud = command(args);
+        assert!(String::from_utf8_lossy(&loud.stderr).contains("Warning: "), "{:?} writes no warnings", args);
+
+        for flag in ["-q", "--quiet"] {
+            let quiet = command([args, &[flag]].concat());
+            assert!(quiet.stderr.is_empty(), "{:?} with {} wrote to stderr:\n{}", args, flag, String::from_utf8_lossy(&quiet.stderr));
+            assert_eq!(quiet.stdout, loud.stdout);
+            assert_eq!(quiet.status.code(), loud.status.code());
+        }
+    }
+}
+
+#[test]
+fn runs_end_with_one_done_line() {
+    let output = command([DIAGNOSTICS]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let done: Vec<&str> = stderr.lines().filter(|line| line.starts_with("done: ")).collect();
+    assert_eq!(done, ["done: 12 rows, 4 applied, 6 rejected, 2 skipped, 0 ignored, 2 clients, 1 locked"]);
+    assert_eq!(stderr.lines().last(), done.first().copied());
+}
+
+// Comments and blank lines are ignored rather than rejected or skipped, so the four counts add up to the rows
+#[test]
+fn the_done_line_counts_ignored_rows_apart() {
+    let output = command([&fixture("comments.csv"), "--allow-comments"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.lines().last(), Some("done: 9 rows, 4 applied, 1 rejected, 0 skipped, 4 ignored, 2 clients, 0 locked"), "{}", stderr);
+}
+
+#[test]
+fn verbose_raises_the_level_and_log_level_wins() {
+    let stderr = |args: &[&str]| String::from_utf8_lossy(&command(args).stderr).into_owned();
+    assert!(!stderr(&[DIAGNOSTICS]).contains("Info: "));
+    assert!(stderr(&[DIAGNOSTICS, "-v"]).contains("Info: "));
+    assert!(!stderr(&[DIAGNOSTICS, "-v"]).contains("Debug: "));
+    assert!(stderr(&[DIAGNOSTICS, "-vv"]).contains("Debug: "));
+    assert!(stderr(&[DIAGNOSTICS, "-vv", "--log-level", "warn"]).lines().all(|line| !line.starts_with("Debug: ")));
+    assert!(stderr(&[DIAGNOSTICS, "-q", "--log-level", "warn"]).contains("Warning: "));
+}
+
+#[test]
+fn quiet_and_verbose_conflict() {
+    let output = command([DIAGNOSTICS, "-q", "-v"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'--quiet' cannot be used with '--verbose'"));
+}
+
+#[test]
+fn rust_log_filters_by_module() {
+    let stderr = |filters: &str, args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .arg(DIAGNOSTICS)
+            .args(args)
+            .env("RUST_LOG", filters)
+            .output()
+            .expect("the payment_engine binary runs");
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    assert!(stderr("payment_engine=debug", &[]).contains("Debug: "));
+    assert!(stderr("debug", &[]).contains("Debug: "));
+    // A filter for another crate leaves this one at the default level
+    let other = stderr("other_crate=debug", &[]);
+    assert!(other.contains("Warning: ") && !other.contains("Debug: "), "{}", other);
+    assert!(!stderr("payment_engine=debug", &["--log-level", "warn"]).contains("Debug: "));
+    assert!(stderr("payment_engine=error", &[]).lines().all(|line| !line.starts_with("Warning: ")));
+}
@@ -0,0 +1,21 @@
+// Runs the command line tool with --record-retention rows=2 on tests/fixtures/record_retention.csv and checks the
+// outcome of every row in its audit log against tests/fixtures/record_retention.audit.csv.
+//
+// tx 1 is stored at row 1 and disputed at row 3, the last row it can be referenced at. tx 2 is stored at row 2 and
+// dropped at row 5, the first row past its horizon, so disputes of it at rows 5 and 7 are rejected as expired. At row 8
+// it has been dropped for longer than the retention and is rejected as unknown.
+
+mod common;
+
+use common::{command, fixture, read_audit, temp_path};
+
+#[test]
+fn references_inside_and_outside_the_horizon() {
+    let audit = temp_path("record_retention.csv");
+    let output = command([&fixture("record_retention.csv"), "--record-retention", "rows=2", "--audit-log", audit.to_str().expect("utf-8 path")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let written = read_audit(&audit);
+    let expected = std::fs::read_to_string(fixture("record_retention.audit.csv")).expect("the expected audit log is readable");
+    assert_eq!(written, expected);
+}
@@ -0,0 +1,86 @@
+// Reads a fixture over HTTP from a server on a local port: in one piece, and resumed with a Range request after the
+// connection drops part way through the body. https:// inputs are refused, as this build has no TLS.
+
+mod common;
+
+use common::command;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+const DISPUTE_CHARGEBACK: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/dispute_chargeback.csv");
+
+// This function reads a request up to its blank line and returns its Range header, if it has one
+fn read_request(stream: &TcpStream) -> Option<String> {
+    let mut reader = BufReader::new(stream);
+    let mut range = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line).expect("the request is readable") > 0 && line != "\r\n" {
+        if let Some(value) = line.strip_prefix("Range: ") {
+            range = Some(value.trim_end().to_string());
+        }
+        line.clear();
+    }
+    range
+}
+
+// This function serves `body` once for every entry of `cut_at` on a local port and returns the URL of the body. An entry
+// of Some(n) sends the whole Content-Length but only the body up to byte n before closing the connection. Requests after
+// the first must resume from where the one before broke off
+fn serve(body: Vec<u8>, cut_at: Vec<Option<usize>>) -> (String, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("a local port is free");
+    let url = format!("http://{}/dispute_chargeback.csv", listener.local_addr().expect("the listener has an address"));
+    let server = thread::spawn(move || {
+        let mut from = 0;
+        for cut in cut_at {
+            let (mut stream, _) = listener.accept().expect("the engine connects");
+            let range = read_request(&stream);
+            let head = match range {
+                None => format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()),
+                Some(range) => {
+                    assert_eq!(range, format!("bytes={}-", from));
+                    format!("HTTP/1.0 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n", from, body.len() - 1, body.len(), body.len() - from)
+                },
+            };
+            let end = cut.unwrap_or(body.len());
+            stream.write_all(head.as_bytes()).expect("the headers are sent");
+            stream.write_all(&body[from..end]).expect("the body is sent");
+            from = end;
+        }
+    });
+    (url, server)
+}
+
+#[test]
+fn a_csv_input_is_streamed_over_http() {
+    let body = std::fs::read(DISPUTE_CHARGEBACK).expect("the fixture is readable");
+    let (url, server) = serve(body, vec![None]);
+    let output = command([&url]);
+    server.join().expect("the server does not panic");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, command([DISPUTE_CHARGEBACK]).stdout);
+}
+
+#[test]
+fn a_dropped_connection_is_resumed_from_the_byte_it_broke_off_at() {
+    let body = std::fs::read(DISPUTE_CHARGEBACK).expect("the fixture is readable");
+    let half = body.len() / 2;
+    let (url, server) = serve(body, vec![Some(half), None]);
+    let output = command([&url]);
+    server.join().expect("the server does not panic");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains(&format!("connection dropped after {} bytes of the body", half)), "{}", stderr);
+    assert_eq!(output.stdout, command([DISPUTE_CHARGEBACK]).stdout);
+}
+
+// Nothing listens on port 1, so the run would fail differently if it tried to connect
+#[test]
+fn https_urls_are_refused_without_connecting() {
+    for flags in [&[][..], &["--cache", "unused.bin"][..]] {
+        let output = command([&["https://127.0.0.1:1/txns.csv"][..], flags].concat());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{}", stderr);
+        assert!(stderr.contains("cannot read https://127.0.0.1:1/txns.csv: https:// URLs are not supported, this build has no TLS"), "{}", stderr);
+    }
+}
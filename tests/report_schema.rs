@@ -0,0 +1,66 @@
+// Runs the command line tool with --report-schema v1 and v2. v1 output is pinned byte for byte against
+// tests/fixtures/basic.v1.csv, and v2 may only differ from it in the ways ReportSchema documents: amounts written
+// exactly at four decimal places and accounts sorted by client id. The schema is recorded in the --summary output and in
+// snapshots, and a run cannot continue from a snapshot written with the other one.
+
+mod common;
+
+use common::{command, fixture, temp_path};
+
+// This function writes an amount the way v2 does, from the way v1 wrote it
+fn four_places(amount: &str) -> String {
+    let (units, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    format!("{}.{:0<4}", units, fraction)
+}
+
+#[test]
+fn v1_output_is_pinned_byte_for_byte() {
+    let output = command([&fixture("basic.csv"), "--report-schema", "v1"]);
+    assert_eq!(output.status.code(), Some(0));
+    let golden = std::fs::read(fixture("basic.v1.csv")).expect("the golden report is readable");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&golden));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--report-schema v1 is deprecated"));
+}
+
+#[test]
+fn v2_differs_only_in_amounts_and_order() {
+    let v1 = String::from_utf8_lossy(&command([&fixture("basic.csv"), "--report-schema", "v1"]).stdout).into_owned();
+    let v2 = String::from_utf8_lossy(&command([&fixture("basic.csv")]).stdout).into_owned();
+    let (header, rows) = v1.split_once('\n').expect("the v1 report has a header");
+    let mut rows: Vec<String> = rows.lines().map(|row| {
+        let cells: Vec<String> = row.split(',').enumerate().map(|(i, cell)| match i {
+            1..=3 => four_places(cell),
+            _ => cell.to_string(),
+        }).collect();
+        cells.join(",")
+    }).collect();
+    rows.sort_by_key(|row| row.split(',').next().and_then(|client| client.parse::<u16>().ok()));
+    assert_eq!(v2, format!("{}\n{}\n", header, rows.join("\n")));
+}
+
+#[test]
+fn the_summary_names_the_schema() {
+    for schema in ["v1", "v2"] {
+        let output = command([&fixture("basic.csv"), "--report-schema", schema, "--summary"]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(&format!("summary report schema={}\n", schema)), "{}", stderr);
+    }
+}
+
+#[test]
+fn a_snapshot_only_resumes_with_its_own_schema() {
+    let snapshot = temp_path("report_schema.snapshot");
+    let snapshot = snapshot.to_str().expect("utf-8 path");
+    let written = command([&fixture("basic.csv"), "--report-schema", "v1", "--snapshot-out", snapshot]);
+    assert_eq!(written.status.code(), Some(0), "{}", String::from_utf8_lossy(&written.stderr));
+
+    let other = command([&fixture("dispute_resolve.csv"), "--snapshot-in", snapshot]);
+    let same = command([&fixture("dispute_resolve.csv"), "--snapshot-in", snapshot, "--report-schema", "v1"]);
+    let _ = std::fs::remove_file(snapshot);
+
+    let stderr = String::from_utf8_lossy(&other.stderr);
+    assert_eq!(other.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("was written by a run with --report-schema v1, this run has v2"), "{}", stderr);
+    assert!(other.stdout.is_empty());
+    assert_eq!(same.status.code(), Some(0), "{}", String::from_utf8_lossy(&same.stderr));
+}
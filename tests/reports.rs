@@ -0,0 +1,138 @@
+// Runs the command line tool on fixture inputs and compares its report with the expected one kept next to it under
+// tests/fixtures/ as <name>.expected.csv. Report rows may come in any order, so both sides are compared with the header
+// first and the remaining rows sorted. The scenario inputs and the fixtures the README describes live in tests/fixtures/
+// as well, and each is run with the flags it is meant for. src/sample.csv is the sample the others are compared with.
+
+mod common;
+
+use common::{stdout, temp_path, FIXTURES};
+
+const SRC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+
+// This function splits a report into its header and its rows in sorted order
+fn rows(report: &str) -> (String, Vec<String>) {
+    let mut lines = report.lines().map(str::to_string);
+    let header = lines.next().unwrap_or_default();
+    let mut rows: Vec<String> = lines.collect();
+    rows.sort();
+    (header, rows)
+}
+
+// This function runs the tool on the input and checks its report against <expected>.expected.csv
+fn check(input: &str, flags: &[&str], expected: &str) {
+    let report = stdout([&[input][..], flags].concat());
+    let expected_path = format!("{}/{}.expected.csv", FIXTURES, expected);
+    let expected_report = std::fs::read_to_string(&expected_path).expect("the expected report is readable");
+    assert_eq!(rows(&report), rows(&expected_report), "{} with {:?} against {}", input, flags, expected_path);
+}
+
+fn scenario(name: &str) {
+    check(&format!("{}/{}.csv", FIXTURES, name), &[], name);
+}
+
+#[test]
+fn deposits_and_withdrawals() {
+    scenario("basic");
+}
+
+#[test]
+fn dispute_then_resolve() {
+    scenario("dispute_resolve");
+}
+
+#[test]
+fn dispute_then_chargeback() {
+    scenario("dispute_chargeback");
+}
+
+#[test]
+fn references_to_unknown_transactions() {
+    scenario("unknown_tx");
+}
+
+// A withdrawal by a client with no account yet, as the very first row, is rejected and leaves no account behind
+#[test]
+fn withdrawal_before_any_deposit() {
+    scenario("unknown_client_withdrawal");
+}
+
+#[test]
+fn disputes_by_the_wrong_client() {
+    scenario("client_mismatch");
+}
+
+#[test]
+fn locked_accounts_refuse_rows() {
+    scenario("locked_account");
+}
+
+// Rows that need no amount may leave out its column, rows that need one are rejected without it and the file goes on
+#[test]
+fn rows_with_and_without_an_amount_column() {
+    scenario("mixed_columns");
+}
+
+// A rejected row is not enough for a client to get an account, only --include-referenced-clients lists it
+#[test]
+fn rejected_rows_list_their_client_only_when_asked() {
+    let input = format!("{}/referenced_clients.csv", FIXTURES);
+    check(&input, &[], "referenced_clients");
+    check(&input, &["--include-referenced-clients"], "referenced_clients_included");
+}
+
+#[test]
+fn readme_fixtures() {
+    let opening_balances = format!("{}/opening_balances.csv", FIXTURES);
+    let cases: [(&str, &[&str], &str); 10] = [
+        ("redispute", &[], "redispute"),
+        ("pending_disputes", &["--dispute-requires-funds"], "pending_disputes"),
+        ("comments", &["--allow-comments"], "comments"),
+        ("currencies", &["--multi-currency"], "currencies"),
+        ("timestamps", &[], "timestamps"),
+        ("timestamps", &["--sort-by-timestamp"], "timestamps_sorted"),
+        ("lock_threshold", &["--lock-policy", "threshold:2"], "lock_threshold"),
+        ("opening_balances_rows", &["--opening-balances", &opening_balances], "opening_balances_rows"),
+        ("sample_shuffled", &[], "sample_shuffled"),
+        ("sample_shuffled", &["--multi-currency"], "sample_shuffled_currencies"),
+    ];
+    for (input, flags, expected) in cases {
+        check(&format!("{}/{}.csv", FIXTURES, input), flags, expected);
+    }
+}
+
+#[test]
+fn comments_and_shuffled_columns_match_the_sample() {
+    check(&format!("{}/sample.csv", SRC), &[], "comments");
+    check(&format!("{}/sample.csv", SRC), &[], "sample_shuffled");
+}
+
+// check compares rows sorted, so the order accounts are written in is checked on the raw output: clients 5, 1 and 3
+// come out as 1, 3 and 5, and so do the hundreds of clients of a generated input
+#[test]
+fn accounts_are_written_in_client_order() {
+    assert_eq!(stdout([&format!("{}/unsorted_clients.csv", FIXTURES)]), "client,available,held,total,locked\n\
+        1,1.0000,0.0000,1.0000,false\n\
+        3,3.0000,0.0000,3.0000,false\n\
+        5,4.5000,0.0000,4.5000,false\n");
+
+    let generated = temp_path("unsorted_generated.csv");
+    let generated = generated.to_str().expect("utf-8 path");
+    stdout(["generate", "--rows", "5000", "--clients", "500", "--out", generated]);
+    let report = stdout([generated]);
+    let _ = std::fs::remove_file(generated);
+    let clients: Vec<u16> = report.lines().skip(1).map(|row| row.split(',').next().and_then(|client| client.parse().ok()).expect("every row starts with a client id")).collect();
+    assert!(clients.len() > 100, "{}", report);
+    assert!(clients.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", clients);
+}
+
+#[test]
+fn row_order_does_not_matter() {
+    assert_eq!(rows("client,total\n2,1\n1,3\n"), rows("client,total\n1,3\n2,1\n"));
+    assert_ne!(rows("client,total\n2,1\n1,3\n"), rows("total,client\n1,3\n2,1\n"));
+}
+
+// Each currency of a client is its own account, so each can start with an opening balance, but only once
+#[test]
+fn every_currency_of_a_client_can_have_an_opening_balance() {
+    check(&format!("{}/opening_balance_currencies.csv", FIXTURES), &["--multi-currency"], "opening_balance_currencies");
+}
@@ -0,0 +1,319 @@
+// Starts the `serve` subcommand on a free port and talks HTTP to it, checking that the served engine applies the same
+// processing flags as the main command.
+
+mod common;
+
+use common::{command, temp_path};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// A running server, killed when dropped
+struct Server {
+    child: Child,
+    address: String,
+    // What the server wrote to stderr after its address, collected as it arrives
+    log: Arc<Mutex<String>>,
+}
+
+impl Server {
+    fn start(flags: &[&str]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .args(["serve", "--port", "0"])
+            .args(flags)
+            .env_remove("RUST_LOG")
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("the payment_engine binary runs");
+        let mut stderr = BufReader::new(child.stderr.take().expect("stderr is piped"));
+        let mut line = String::new();
+        stderr.read_line(&mut line).expect("the server reports its address");
+        let address = line.trim().strip_prefix("serve: listening on ").unwrap_or_else(|| panic!("unexpected first line {:?}", line)).to_string();
+        let log = Arc::new(Mutex::new(String::new()));
+        let collected = Arc::clone(&log);
+        thread::spawn(move || {
+            for line in stderr.lines().map_while(Result::ok) {
+                collected.lock().expect("the log is not poisoned").push_str(&(line + "\n"));
+            }
+        });
+        Server { child, address, log }
+    }
+
+    fn log(&self) -> String {
+        self.log.lock().expect("the log is not poisoned").clone()
+    }
+
+    // This function sends one request and returns the status and body of the response
+    fn request(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+        self.request_with_key(None, method, path, body)
+    }
+
+    // This function sends one request with an API key, if given, in its Authorization header
+    fn request_with_key(&self, key: Option<&str>, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(&self.address).expect("the server accepts connections");
+        let authorization = key.map(|key| format!("Authorization: Bearer {}\r\n", key)).unwrap_or_default();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: test\r\n{}Content-Length: {}\r\n\r\n{}", method, path, authorization, body.len(), body).expect("the request is sent");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("the response is read");
+        let status = response.split_whitespace().nth(1).and_then(|status| status.parse().ok()).unwrap_or_else(|| panic!("malformed response {:?}", response));
+        let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+        (status, body)
+    }
+
+    fn post(&self, body: &str) -> (u16, String) {
+        self.request("POST", "/transactions", body)
+    }
+
+    // This function sends the server a signal by name, e.g. TERM
+    #[cfg(unix)]
+    fn signal(&self, name: &str) {
+        let status = Command::new("kill").args([&format!("-{}", name), &self.child.id().to_string()]).status().expect("kill runs");
+        assert!(status.success());
+    }
+
+    // This function waits until /ready says the server is draining
+    #[cfg(unix)]
+    fn wait_until_draining(&self) {
+        for _ in 0..200 {
+            if self.request("GET", "/ready", "").0 == 503 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the server did not start draining: {}", self.log());
+    }
+
+    // This function waits for the server to exit and returns its exit code
+    #[cfg(unix)]
+    fn exit_code(&mut self) -> Option<i32> {
+        for _ in 0..500 {
+            if let Some(status) = self.child.try_wait().expect("the server can be waited for") {
+                return status.code();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the server did not exit: {}", self.log());
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// This function charges back one of two deposits of client 1 and returns the status of a deposit after it
+fn deposit_after_a_chargeback(server: &Server) -> u16 {
+    for body in [
+        r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#,
+        r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "20"}"#,
+        r#"{"type": "dispute", "client": 1, "tx": 1}"#,
+        r#"{"type": "chargeback", "client": 1, "tx": 1}"#,
+    ] {
+        assert_eq!(server.post(body).0, 200, "{}", body);
+    }
+    server.post(r#"{"type": "deposit", "client": 1, "tx": 3, "amount": "5"}"#).0
+}
+
+#[test]
+fn the_lock_policy_applies_to_posted_transactions() {
+    assert_eq!(deposit_after_a_chargeback(&Server::start(&[])), 409);
+    assert_eq!(deposit_after_a_chargeback(&Server::start(&["--lock-policy", "threshold:2"])), 200);
+}
+
+#[test]
+fn currencies_need_multi_currency() {
+    let server = Server::start(&[]);
+    let (status, body) = server.post(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10", "currency": "EUR"}"#);
+    assert_eq!(status, 400);
+    assert!(body.contains("--multi-currency"), "{}", body);
+
+    let server = Server::start(&["--multi-currency"]);
+    assert_eq!(server.post(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10", "currency": "EUR"}"#).0, 200);
+    assert_eq!(server.post(r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "3", "currency": "USD"}"#).0, 200);
+    let (status, accounts) = server.request("GET", "/accounts/1", "");
+    assert_eq!(status, 200);
+    assert!(accounts.contains("\"EUR\"") && accounts.contains("\"USD\""), "{}", accounts);
+    assert_eq!(server.request("GET", "/accounts/2", "").0, 404);
+}
+
+#[test]
+fn a_client_locked_mid_run_has_their_next_deposit_rejected() {
+    let server = Server::start(&[]);
+    assert_eq!(server.post(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#).0, 200);
+    let (status, account) = server.request("POST", "/accounts/1/lock", "");
+    assert_eq!(status, 200);
+    assert!(account.contains("\"locked\":true"), "{}", account);
+
+    let (status, body) = server.post(r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "5"}"#);
+    assert_eq!(status, 409);
+    assert!(body.contains("account_locked"), "{}", body);
+    assert_eq!(server.request("POST", "/accounts/2/lock", "").0, 404);
+
+    assert_eq!(server.request("POST", "/accounts/1/unlock", "").0, 200);
+    let (status, account) = server.post(r#"{"type": "deposit", "client": 1, "tx": 3, "amount": "5"}"#);
+    assert_eq!(status, 200);
+    assert!(account.contains("\"total\":\"15.0000\""), "{}", account);
+    assert_eq!(server.request("GET", "/accounts/1/lock", "").0, 405);
+}
+
+#[test]
+fn api_keys_are_checked_and_post_needs_the_admin_role() {
+    let keys = temp_path("api_keys");
+    std::fs::write(&keys, "# support\nreader-key\nadmin-key:admin\n").expect("the keys file is written");
+    let server = Server::start(&["--api-keys-file", keys.to_str().expect("utf-8 path")]);
+    let _ = std::fs::remove_file(&keys);
+    let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#;
+
+    // Accepted
+    assert_eq!(server.request_with_key(Some("admin-key"), "POST", "/transactions", deposit).0, 200);
+    assert_eq!(server.request_with_key(Some("admin-key"), "POST", "/accounts/1/lock", "").0, 200);
+    assert_eq!(server.request_with_key(Some("reader-key"), "GET", "/accounts/1", "").0, 200);
+
+    // Rejected, with the key left out of the answer
+    let (status, body) = server.request("GET", "/accounts", "");
+    assert_eq!(status, 401);
+    assert!(body.contains("unauthorized"), "{}", body);
+    let (status, body) = server.request_with_key(Some("admin-kez"), "GET", "/accounts", "");
+    assert_eq!(status, 401);
+    assert!(!body.contains("admin-kez"), "{}", body);
+    assert_eq!(server.request_with_key(Some("admin-key:admin"), "GET", "/accounts", "").0, 401);
+
+    // Role insufficient
+    let (status, body) = server.request_with_key(Some("reader-key"), "POST", "/accounts/1/unlock", "");
+    assert_eq!(status, 403);
+    assert!(body.contains("forbidden"), "{}", body);
+    assert_eq!(server.request_with_key(Some("reader-key"), "POST", "/transactions", deposit).0, 403);
+}
+
+#[test]
+fn a_keys_file_with_an_unknown_role_is_refused() {
+    let keys = temp_path("bad_api_keys");
+    std::fs::write(&keys, "secret-key:owner\n").expect("the keys file is written");
+    let output = command(["serve", "--port", "0", "--api-keys-file", keys.to_str().expect("utf-8 path")]);
+    let _ = std::fs::remove_file(&keys);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("line 1: the role must be read or admin"), "{}", stderr);
+    assert!(!stderr.contains("secret-key"), "{}", stderr);
+}
+
+// Connections that send nothing yet keep both workers reading, so the rest fill the queue and the server stops accepting.
+// Every request is still answered once they are sent
+#[test]
+fn a_flood_of_connections_waits_in_a_bounded_queue() {
+    let server = Server::start(&["--max-in-flight", "2", "--queue-size", "4"]);
+    let mut streams: Vec<TcpStream> = (0..16).map(|_| TcpStream::connect(&server.address).expect("the connection is queued by the kernel")).collect();
+    thread::sleep(Duration::from_millis(300));
+    assert!(server.log().contains("serve: backpressure, 4 connections are waiting for 2 workers"), "{}", server.log());
+
+    for (tx, stream) in streams.iter_mut().enumerate() {
+        let body = format!(r#"{{"type": "deposit", "client": 1, "tx": {}, "amount": "1"}}"#, tx + 1);
+        write!(stream, "POST /transactions HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).expect("the request is sent");
+    }
+    for mut stream in streams {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("the response is read");
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    }
+    let (status, account) = server.request("GET", "/accounts/1", "");
+    assert_eq!(status, 200);
+    assert!(account.contains("\"total\":\"16.0000\""), "{}", account);
+}
+
+// This function sends raw bytes and reads the whole response, giving its status and body
+fn raw_request(server: &Server, request: &[u8]) -> (u16, String) {
+    let mut stream = TcpStream::connect(&server.address).expect("the server accepts connections");
+    stream.write_all(request).expect("the request is sent");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("the response is read");
+    let status = response.split_whitespace().nth(1).and_then(|status| status.parse().ok()).unwrap_or_else(|| panic!("malformed response {:?}", response));
+    (status, response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default())
+}
+
+// Each request stops one byte past its limit, which is all the server reads, so closing leaves nothing unread
+#[test]
+fn an_oversized_request_line_or_headers_are_answered_without_reading_on() {
+    let server = Server::start(&["--max-in-flight", "1"]);
+    let line = format!("GET /{}", "a".repeat(8 * 1024 - 4));
+    let (status, body) = raw_request(&server, line.as_bytes());
+    assert_eq!(status, 400);
+    assert!(body.contains("request line is longer than 8192 bytes"), "{}", body);
+
+    let headers = format!("GET /accounts HTTP/1.1\r\nX-Padding: {}", "a".repeat(16 * 1024 - 10));
+    let (status, body) = raw_request(&server, headers.as_bytes());
+    assert_eq!(status, 431);
+    assert!(body.contains("headers_too_large"), "{}", body);
+
+    // Just under the limits is still a request
+    let headers = format!("GET /accounts HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(16 * 1024 - 15));
+    assert_eq!(raw_request(&server, headers.as_bytes()), (200, "[]\n".to_string()));
+}
+
+// With one worker, a caller that stops mid-request would hold up everyone else without the timeout
+#[test]
+fn a_stalled_request_times_out_and_frees_its_worker() {
+    let server = Server::start(&["--max-in-flight", "1", "--request-timeout", "1"]);
+    let mut stalled = TcpStream::connect(&server.address).expect("the server accepts connections");
+    stalled.write_all(b"POST /transactions HTTP/1.1\r\nContent-Length: 10\r\n\r\n{").expect("the request is sent");
+    assert_eq!(server.post(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#).0, 200);
+
+    let mut response = String::new();
+    stalled.read_to_string(&mut response).expect("the response is read");
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", response);
+    assert!(response.contains("request_timeout"), "{}", response);
+}
+
+// This function opens a connection whose request is sent up to the last byte of its body, so a worker holds it in flight
+#[cfg(unix)]
+fn unfinished_request(server: &Server, body: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(&server.address).expect("the server accepts connections");
+    let (sent, _) = body.split_at(body.len() - 1);
+    write!(stream, "POST /transactions HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}", body.len(), sent).expect("the request is sent");
+    thread::sleep(Duration::from_millis(100));
+    stream
+}
+
+#[test]
+#[cfg(unix)]
+fn sigterm_drains_the_request_in_flight_and_writes_the_accounts() {
+    let (report, snapshot) = (temp_path("serve_drain_report.csv"), temp_path("serve_drain.snapshot"));
+    let mut server = Server::start(&["--output", report.to_str().expect("utf-8 path"), "--snapshot-out", snapshot.to_str().expect("utf-8 path")]);
+    assert_eq!(server.request("GET", "/ready", ""), (200, r#"{"ready":true}"#.to_string()));
+    assert_eq!(server.post(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#).0, 200);
+    let body = r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "5"}"#;
+    let mut in_flight = unfinished_request(&server, body);
+
+    server.signal("TERM");
+    server.wait_until_draining();
+    let (status, refused) = server.post(r#"{"type": "deposit", "client": 2, "tx": 3, "amount": "1"}"#);
+    assert_eq!(status, 503);
+    assert!(refused.contains("shutting_down"), "{}", refused);
+
+    in_flight.write_all(b"}").expect("the rest of the body is sent");
+    let mut response = String::new();
+    in_flight.read_to_string(&mut response).expect("the response is read");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+    assert_eq!(server.exit_code(), Some(0), "{}", server.log());
+    assert!(server.log().contains("serve: done: 2 transactions, 2 applied, 0 rejected, 1 clients, 0 locked"), "{}", server.log());
+    assert_eq!(std::fs::read_to_string(&report).expect("the report was written"), "client,available,held,total,locked\n1,15.0000,0.0000,15.0000,false\n");
+    assert!(snapshot.exists());
+    let _ = std::fs::remove_file(report);
+    let _ = std::fs::remove_file(snapshot);
+}
+
+#[test]
+#[cfg(unix)]
+fn a_second_signal_exits_without_waiting() {
+    let mut server = Server::start(&[]);
+    let _stuck = unfinished_request(&server, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "5"}"#);
+    server.signal("INT");
+    server.wait_until_draining();
+    server.signal("TERM");
+    assert_eq!(server.exit_code(), Some(130), "{}", server.log());
+}
@@ -0,0 +1,289 @@
+// Tests of ShardedEngine. Random sequences of rows fed one at a time must leave it exactly as they leave one Engine,
+// with the same rejection for every row. Threads hammering one client must leave its balances consistent, threads whose
+// rows name each other's tx ids while others read the whole engine must never wait on each other for good, and a tx id
+// raced for by clients in different shards must be taken only once. Transfers between shards move both accounts or
+// neither, and transfers racing in opposite directions must not deadlock.
+
+use payment_engine::money::Money;
+use payment_engine::{Engine, EngineError, EnginePolicy, Rejection, ShardedEngine, Transaction, TransactionRow, TransactionType};
+use proptest::prelude::*;
+use std::num::NonZeroUsize;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// More clients than shards, and few tx ids, so rows often name a tx held by a client in another shard
+const CLIENTS: u16 = 6;
+const TX_IDS: u32 = 24;
+
+// How long the threaded tests may take before they count as deadlocked
+const DEADLINE: Duration = Duration::from_secs(30);
+
+fn shards(count: usize) -> NonZeroUsize {
+    NonZeroUsize::new(count).expect("a shard count above zero")
+}
+
+fn amount(text: &str) -> Money {
+    text.parse().unwrap_or_else(|_| panic!("invalid amount {}", text))
+}
+
+fn cents(cents: u32) -> Money {
+    amount(&format!("{}.{:02}", cents / 100, cents % 100))
+}
+
+fn row(transaction: Transaction) -> TransactionRow {
+    TransactionRow::from(transaction)
+}
+
+// This function runs `work` on a thread of its own and fails the test if it has not finished by the deadline
+fn within_deadline(work: impl FnOnce() + Send + 'static) {
+    let (done, finished) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        work();
+        let _ = done.send(());
+    });
+    match finished.recv_timeout(DEADLINE) {
+        Ok(()) => worker.join().expect("the work does not panic"),
+        // The work panicked, and join passes the panic on
+        Err(mpsc::RecvTimeoutError::Disconnected) => worker.join().expect("the work does not panic"),
+        Err(mpsc::RecvTimeoutError::Timeout) => panic!("the threads did not finish within {:?}, they are deadlocked", DEADLINE),
+    }
+}
+
+fn any_transaction() -> impl Strategy<Value = Transaction> {
+    let amounts = || 1..100_000u32;
+    prop_oneof![
+        3 => (1..=CLIENTS, 1..=TX_IDS, amounts()).prop_map(|(client, tx, amount)| Transaction::Deposit { client, tx, amount: cents(amount) }),
+        2 => (1..=CLIENTS, 1..=TX_IDS, amounts()).prop_map(|(client, tx, amount)| Transaction::Withdrawal { client, tx, amount: cents(amount) }),
+        4 => (0..3u8, 1..=CLIENTS, 1..=TX_IDS).prop_map(|(kind, client, tx)| match kind {
+            0 => Transaction::Dispute { client, tx },
+            1 => Transaction::Resolve { client, tx },
+            _ => Transaction::Chargeback { client, tx },
+        }),
+        1 => (1..=CLIENTS, 1..=TX_IDS, amounts()).prop_map(|(client, tx, amount)| Transaction::Correction { client, tx, amount: cents(amount) }),
+        1 => (1..=CLIENTS, 1..=TX_IDS, amounts()).prop_map(|(client, tx, amount)| Transaction::OpeningBalance { client, tx, amount: cents(amount) }),
+        1 => (1..=CLIENTS, 1..=TX_IDS, amounts()).prop_map(|(client, tx, amount)| Transaction::AdminHold { client, tx, amount: cents(amount) }),
+        1 => (1..=CLIENTS, 1..=TX_IDS).prop_map(|(client, tx)| Transaction::AdminRelease { client, tx }),
+        1 => (1..=CLIENTS).prop_map(|client| Transaction::AdminLock { client, tx: 0 }),
+        1 => (1..=CLIENTS).prop_map(|client| Transaction::AdminUnlock { client, tx: 0 }),
+    ]
+}
+
+#[test]
+fn engines_can_be_shared_between_threads() {
+    fn send_and_sync<T: Send + Sync>() {}
+    send_and_sync::<Engine>();
+    send_and_sync::<ShardedEngine>();
+}
+
+proptest! {
+    #[test]
+    fn rows_fed_one_at_a_time_leave_the_same_engine(transactions in prop::collection::vec(any_transaction(), 1..80), count in 1..5usize, forced_hold in any::<bool>()) {
+        let policy = EnginePolicy::builder().allow_forced_hold(forced_hold).chargeback_fee(cents(150)).build();
+        let mut engine = Engine::with_policy(policy.clone());
+        let sharded = ShardedEngine::new(Engine::with_policy(policy), shards(count)).expect("the policy can be sharded");
+        for (step, transaction) in transactions.into_iter().enumerate() {
+            let row = row(transaction);
+            let expected = engine.process(&row).map(|()| engine.account_for(&row).cloned());
+            let got = sharded.process(&row);
+            prop_assert_eq!(format!("{:?}", got.as_ref().err()), format!("{:?}", expected.as_ref().err()), "step {} ({:?})", step, row);
+            prop_assert_eq!(format!("{:?}", got.ok()), format!("{:?}", expected.ok()), "step {} ({:?})", step, row);
+        }
+        let merged = sharded.to_engine().expect("no shard is poisoned");
+        prop_assert_eq!(serde_json::to_string(&merged.report()).expect("the report serializes"), serde_json::to_string(&engine.report()).expect("the report serializes"));
+    }
+}
+
+// Every thread deposits into the same client, disputes and resolves its deposit, then withdraws half of it, while one
+// more thread keeps reading the account. Each thread's withdrawal is covered by its own deposit whatever the others do,
+// so every row is applied and the end balance is known
+#[test]
+fn threads_hammering_one_client_keep_its_balances_consistent() {
+    const THREADS: u32 = 8;
+    const ROUNDS: u32 = 500;
+    let engine = Arc::new(ShardedEngine::new(Engine::new(), shards(4)).expect("the default policy can be sharded"));
+    let shared = Arc::clone(&engine);
+    within_deadline(move || {
+        let writers: Vec<_> = (0..THREADS).map(|thread| {
+            let engine = Arc::clone(&shared);
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let tx = (thread * ROUNDS + round) * 2 + 1;
+                    for transaction in [
+                        Transaction::Deposit { client: 7, tx, amount: amount("1") },
+                        Transaction::Dispute { client: 7, tx },
+                        Transaction::Resolve { client: 7, tx },
+                        Transaction::Withdrawal { client: 7, tx: tx + 1, amount: amount("0.5") },
+                    ] {
+                        let account = engine.process(&row(transaction)).unwrap_or_else(|e| panic!("{:?} was rejected: {}", transaction, e)).expect("the row has an account");
+                        assert_eq!(account.available.checked_add(account.held), Some(account.total), "after {:?}", transaction);
+                    }
+                }
+            })
+        }).collect();
+        let reader = {
+            let engine = Arc::clone(&shared);
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    for account in engine.accounts().expect("no shard is poisoned") {
+                        assert_eq!(account.available.checked_add(account.held), Some(account.total));
+                        assert!(account.available >= Money::ZERO && account.held >= Money::ZERO, "{:?}", account);
+                    }
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().expect("no writer panics");
+        }
+        reader.join().expect("the reader does not panic");
+    });
+
+    let account = engine.account(7).expect("no shard is poisoned").expect("client 7 has an account");
+    assert_eq!((account.available, account.held, account.total), (amount("2000"), Money::ZERO, amount("2000")));
+    let report = engine.to_engine().expect("no shard is poisoned").report();
+    assert_eq!((report.rows, report.rejected), (u64::from(THREADS * ROUNDS * 4), 0));
+    assert_eq!(report.applied.get(&TransactionType::Dispute), Some(&u64::from(THREADS * ROUNDS)));
+}
+
+// Two clients in different shards keep disputing each other's deposits, which locks each one's shard and then the
+// owners of the other's tx id, while a third thread reads every shard in turn
+#[test]
+fn opposing_rows_and_whole_engine_reads_do_not_deadlock() {
+    const ROUNDS: u32 = 2000;
+    let engine = Arc::new(ShardedEngine::new(Engine::new(), shards(2)).expect("the default policy can be sharded"));
+    for (client, tx) in [(1, 1), (2, 2)] {
+        engine.process(&row(Transaction::Deposit { client, tx, amount: amount("10") })).expect("the deposit is applied");
+    }
+    let shared = Arc::clone(&engine);
+    within_deadline(move || {
+        let opposing: Vec<_> = [(1u16, 2u32), (2, 1)].into_iter().map(|(client, theirs)| {
+            let engine = Arc::clone(&shared);
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let error = engine.process(&row(Transaction::Dispute { client, tx: theirs })).expect_err("another client's tx cannot be disputed");
+                    assert!(matches!(error, EngineError::ClientMismatch { tx, expected, got } if tx == theirs && expected == 3 - client && got == client), "{:?}", error);
+                    let tx = 100 + round * 2 + u32::from(client);
+                    engine.process(&row(Transaction::Deposit { client, tx, amount: amount("1") })).expect("the deposit is applied");
+                }
+            })
+        }).collect();
+        let reader = {
+            let engine = Arc::clone(&shared);
+            thread::spawn(move || {
+                for _ in 0..ROUNDS / 10 {
+                    engine.to_engine().expect("no shard is poisoned");
+                }
+            })
+        };
+        for thread in opposing {
+            thread.join().expect("no thread panics");
+        }
+        reader.join().expect("the reader does not panic");
+    });
+
+    for client in [1, 2] {
+        let account = engine.account(client).expect("no shard is poisoned").expect("the client has an account");
+        assert_eq!(account.total, amount("2010"));
+    }
+}
+
+// Clients in every shard deposit under the same tx ids at once. One Engine would take each tx id once and refuse the
+// rest as duplicates, and so must the shards between them
+#[test]
+fn a_tx_id_raced_for_across_shards_is_taken_once() {
+    const TX_IDS: u32 = 200;
+    let engine = Arc::new(ShardedEngine::new(Engine::new(), shards(8)).expect("the default policy can be sharded"));
+    let shared = Arc::clone(&engine);
+    within_deadline(move || {
+        let racers: Vec<_> = (1..=8u16).map(|client| {
+            let engine = Arc::clone(&shared);
+            thread::spawn(move || (1..=TX_IDS).filter(|&tx| engine.process(&row(Transaction::Deposit { client, tx, amount: amount("1") })).is_ok()).count())
+        }).collect();
+        let taken: usize = racers.into_iter().map(|racer| racer.join().expect("no racer panics")).sum();
+        assert_eq!(taken, TX_IDS as usize);
+    });
+
+    let merged = engine.to_engine().expect("no shard is poisoned");
+    assert_eq!(merged.records().count(), TX_IDS as usize);
+    assert_eq!(merged.report().rejected, u64::from(TX_IDS) * 7);
+}
+
+fn balances(engine: &ShardedEngine, client: u16) -> (Money, Money, Money, bool) {
+    let account = engine.account(client).expect("no shard is poisoned").expect("the client has an account");
+    (account.available, account.held, account.total, account.locked)
+}
+
+#[test]
+fn a_transfer_between_shards_moves_both_accounts_or_neither() {
+    let engine = ShardedEngine::new(Engine::new(), shards(2)).expect("the default policy can be sharded");
+    engine.process(&row(Transaction::Deposit { client: 1, tx: 1, amount: amount("10") })).expect("the deposit is applied");
+
+    let (from, to) = engine.transfer(1, 2, amount("4")).expect("the transfer is applied");
+    assert_eq!((from.available, from.total, to.available, to.total), (amount("6"), amount("6"), amount("4"), amount("4")));
+    assert_eq!(balances(&engine, 2), (amount("4"), Money::ZERO, amount("4"), false));
+
+    let refusals = [
+        (engine.transfer(1, 2, amount("6.0001")), Rejection::InsufficientFunds),
+        (engine.transfer(1, 2, Money::ZERO), Rejection::InvalidAmount),
+        (engine.transfer(1, 2, amount("-1")), Rejection::InvalidAmount),
+        (engine.transfer(3, 2, amount("1")), Rejection::InsufficientFunds),
+    ];
+    for (result, rejection) in refusals {
+        assert_eq!(result.err().and_then(|e| e.rejection()), Some(rejection));
+    }
+    engine.process(&row(Transaction::AdminLock { client: 2, tx: 0 })).expect("the lock is applied");
+    assert_eq!(engine.transfer(1, 2, amount("1")).err().and_then(|e| e.rejection()), Some(Rejection::AccountLocked));
+    assert_eq!(engine.transfer(2, 1, amount("1")).err().and_then(|e| e.rejection()), Some(Rejection::AccountLocked));
+    assert!(matches!(engine.transfer(1, 1, amount("1")), Err(EngineError::Invalid(_))));
+
+    // Nothing refused moved either account, and a transfer is no row
+    assert_eq!(balances(&engine, 1), (amount("6"), Money::ZERO, amount("6"), false));
+    assert_eq!(balances(&engine, 2), (amount("4"), Money::ZERO, amount("4"), true));
+    assert!(engine.account(3).expect("no shard is poisoned").is_none());
+    assert_eq!(engine.to_engine().expect("no shard is poisoned").report().rows, 2);
+}
+
+// Threads move funds from client 1 to client 2 while as many move them back, each transfer locking both shards, and
+// another thread keeps reading the whole engine. Taking the shards in the order the funds go would deadlock here
+#[test]
+fn opposing_transfers_between_shards_do_not_deadlock() {
+    const THREADS: u16 = 4;
+    const ROUNDS: u32 = 2000;
+    let engine = Arc::new(ShardedEngine::new(Engine::new(), shards(2)).expect("the default policy can be sharded"));
+    for (client, tx) in [(1, 1), (2, 2)] {
+        engine.process(&row(Transaction::Deposit { client, tx, amount: amount("1000") })).expect("the deposit is applied");
+    }
+    let shared = Arc::clone(&engine);
+    within_deadline(move || {
+        let transfers: Vec<_> = (0..THREADS * 2).map(|thread| {
+            let engine = Arc::clone(&shared);
+            let (from, to) = if thread % 2 == 0 { (1u16, 2u16) } else { (2, 1) };
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    let (debited, credited) = engine.transfer(from, to, amount("0.1")).unwrap_or_else(|e| panic!("{} to {} was refused: {}", from, to, e));
+                    assert_eq!((debited.client_id, credited.client_id), (from, to));
+                }
+            })
+        }).collect();
+        let reader = {
+            let engine = Arc::clone(&shared);
+            thread::spawn(move || {
+                for _ in 0..ROUNDS / 10 {
+                    let accounts = engine.accounts().expect("no shard is poisoned");
+                    let total = accounts.iter().try_fold(Money::ZERO, |sum, account| sum.checked_add(account.total));
+                    assert_eq!(total, Some(amount("2000")), "{:?}", accounts);
+                }
+            })
+        };
+        for thread in transfers {
+            thread.join().expect("no thread panics");
+        }
+        reader.join().expect("the reader does not panic");
+    });
+
+    // As many transfers went each way
+    for client in [1, 2] {
+        assert_eq!(balances(&engine, client), (amount("1000"), Money::ZERO, amount("1000"), false));
+    }
+}
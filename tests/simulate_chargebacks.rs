@@ -0,0 +1,46 @@
+// Runs the command line tool with --simulate-chargebacks on tests/fixtures/simulate.csv, where client 1 has two disputes
+// still open at the end, client 2 one that was resolved and client 3 none. The worst case must charge back both of client
+// 1's, and the account report and the snapshot must be those of a run without the simulation.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+
+#[test]
+fn the_worst_case_charges_back_every_open_dispute() {
+    let path = temp_path("simulate_worst_case.csv");
+    let output = command([&fixture("simulate.csv"), "-q", "--simulate-chargebacks", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    // Client 1 loses both disputed deposits, 10 and 5, and keeps the undisputed 2.5
+    assert_eq!(read(&path), "client,worst_available,worst_total,would_lock\n\
+        1,2.5000,2.5000,true\n\
+        2,4.0000,4.0000,false\n\
+        3,1.0000,1.0000,false\n");
+}
+
+#[test]
+fn the_simulation_leaves_the_report_and_the_snapshot_as_they_were() {
+    let worst_case = temp_path("simulate_untouched_worst_case.csv");
+    let simulated_snapshot = temp_path("simulate_untouched_simulated.snapshot");
+    let plain_snapshot = temp_path("simulate_untouched_plain.snapshot");
+
+    let simulated = command([
+        fixture("simulate.csv").as_str(), "-q", "--simulate-chargebacks", worst_case.to_str().expect("utf-8 path"),
+        "--snapshot-out", simulated_snapshot.to_str().expect("utf-8 path"),
+    ]);
+    let plain = command([fixture("simulate.csv").as_str(), "-q", "--snapshot-out", plain_snapshot.to_str().expect("utf-8 path")]);
+    assert_eq!(simulated.status.code(), Some(0), "{}", String::from_utf8_lossy(&simulated.stderr));
+    assert_eq!(plain.status.code(), Some(0), "{}", String::from_utf8_lossy(&plain.stderr));
+
+    assert_eq!(String::from_utf8_lossy(&simulated.stdout), "client,available,held,total,locked\n\
+        1,2.5000,15.0000,17.5000,false\n\
+        2,4.0000,0.0000,4.0000,false\n\
+        3,1.0000,0.0000,1.0000,false\n");
+    assert_eq!(simulated.stdout, plain.stdout);
+    // Snapshots are binary, so they are compared as bytes
+    let snapshots = [&simulated_snapshot, &plain_snapshot].map(|path| std::fs::read(path).expect("the snapshot was written"));
+    assert_eq!(snapshots[0], snapshots[1]);
+    for path in [worst_case, simulated_snapshot, plain_snapshot] {
+        let _ = std::fs::remove_file(path);
+    }
+}
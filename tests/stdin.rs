@@ -0,0 +1,30 @@
+// Pipes tests/fixtures/dispute_chargeback.csv into the command line tool's standard input, both with no input argument
+// and with -, and checks that the report is the one written for the file itself.
+
+mod common;
+
+use assert_cmd::Command;
+use common::fixture;
+
+// This function returns the tool, run with RUST_LOG removed like in common::command
+fn payment_engine() -> Command {
+    let mut command = Command::cargo_bin("payment_engine").expect("the payment_engine binary is built");
+    command.env_remove("RUST_LOG");
+    command
+}
+
+#[test]
+fn standard_input_is_read_without_an_input_argument_or_with_a_dash() {
+    let input = std::fs::read(fixture("dispute_chargeback.csv")).expect("the fixture can be read");
+    let expected = std::fs::read_to_string(fixture("dispute_chargeback.expected.csv")).expect("the expected report can be read");
+
+    payment_engine().arg("-q").write_stdin(input.clone()).assert().success().stdout(expected.clone());
+    payment_engine().args(["-", "-q"]).write_stdin(input).assert().success().stdout(expected);
+}
+
+#[test]
+fn standard_input_cannot_be_read_with_other_inputs() {
+    let assert = payment_engine().args([fixture("basic.csv").as_str(), "-"]).write_stdin("type,client,tx,amount\n").assert().code(2);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).into_owned();
+    assert!(stderr.contains("standard input can only be read on its own"), "{}", stderr);
+}
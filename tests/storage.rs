@@ -0,0 +1,295 @@
+// Tests of engines kept in a Storage. Against a storage held in memory: each applied row hands it exactly what the row
+// changed, a row it cannot write is undone and can be tried again, and an engine loaded from it carries on where the
+// last one stopped, alone or split into shards. With the postgres feature the same runs go through PostgreSQL, against
+// the database PAYMENT_ENGINE_POSTGRES_URL names; those tests are ignored unless asked for with --ignored.
+
+use payment_engine::money::Money;
+use payment_engine::storage::{Change, Storage, Stored};
+use payment_engine::{AccountKey, AdminHold, Client, Engine, EngineError, EnginePolicy, Record, RecordState, ShardedEngine, Transaction, TransactionRow};
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+fn amount(text: &str) -> Money {
+    text.parse().unwrap_or_else(|_| panic!("invalid amount {}", text))
+}
+
+fn row(transaction: Transaction) -> TransactionRow {
+    TransactionRow::from(transaction)
+}
+
+// What the storage below holds, shared with the test that handed the storage to an engine
+#[derive(Default)]
+struct Memory {
+    accounts: BTreeMap<AccountKey, Client>,
+    records: BTreeMap<u32, Record>,
+    admin_holds: BTreeMap<u32, AdminHold>,
+    changes: Vec<Change>,
+}
+
+// A storage held in memory that writes nothing while `down` is set
+#[derive(Clone, Default)]
+struct MemoryStorage {
+    memory: Arc<Mutex<Memory>>,
+    down: Arc<AtomicBool>,
+}
+
+impl MemoryStorage {
+    fn changes(&self) -> Vec<Change> {
+        std::mem::take(&mut self.memory.lock().expect("the memory is not poisoned").changes)
+    }
+
+    fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::SeqCst);
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> Result<Stored, EngineError> {
+        let memory = self.memory.lock().expect("the memory is not poisoned");
+        Ok(Stored {
+            accounts: memory.accounts.values().cloned().collect(),
+            records: memory.records.iter().map(|(transaction_id, record)| (*transaction_id, *record)).collect(),
+            admin_holds: memory.admin_holds.iter().map(|(transaction_id, hold)| (*transaction_id, hold.clone())).collect(),
+        })
+    }
+
+    fn save(&mut self, change: &Change) -> Result<(), EngineError> {
+        if self.down.load(Ordering::SeqCst) {
+            return Err(EngineError::Storage("the storage is down".to_string()));
+        }
+        let mut memory = self.memory.lock().expect("the memory is not poisoned");
+        for account in &change.accounts {
+            memory.accounts.insert(AccountKey { client_id: account.client_id, currency: account.currency }, account.clone());
+        }
+        for (transaction_id, record) in &change.records {
+            match record {
+                Some(record) => memory.records.insert(*transaction_id, *record),
+                None => memory.records.remove(transaction_id),
+            };
+        }
+        for (transaction_id, hold) in &change.admin_holds {
+            match hold {
+                Some(hold) => memory.admin_holds.insert(*transaction_id, hold.clone()),
+                None => memory.admin_holds.remove(transaction_id),
+            };
+        }
+        memory.changes.push(change.clone());
+        Ok(())
+    }
+}
+
+// Rows that open disputes and holds in two clients, for a loaded engine to carry on from
+fn first_rows() -> Vec<Transaction> {
+    vec![
+        Transaction::Deposit { client: 1, tx: 1, amount: amount("10") },
+        Transaction::Deposit { client: 1, tx: 2, amount: amount("5") },
+        Transaction::Dispute { client: 1, tx: 1 },
+        Transaction::Deposit { client: 2, tx: 3, amount: amount("8") },
+        Transaction::AdminHold { client: 2, tx: 4, amount: amount("3") },
+        Transaction::Withdrawal { client: 2, tx: 5, amount: amount("100") },
+    ]
+}
+
+// Rows that only work on from where first_rows left off
+fn later_rows() -> Vec<Transaction> {
+    vec![
+        Transaction::Resolve { client: 1, tx: 1 },
+        Transaction::Dispute { client: 1, tx: 2 },
+        Transaction::Chargeback { client: 1, tx: 2 },
+        Transaction::AdminRelease { client: 2, tx: 4 },
+        Transaction::Withdrawal { client: 2, tx: 6, amount: amount("8") },
+        Transaction::Deposit { client: 2, tx: 3, amount: amount("1") },
+    ]
+}
+
+// This function returns the accounts of an engine fed every row at once, to compare against
+fn expected_accounts() -> String {
+    let mut engine = Engine::new();
+    for transaction in first_rows().into_iter().chain(later_rows()) {
+        let _ = engine.process(&row(transaction));
+    }
+    format!("{:?}", engine.report().accounts)
+}
+
+#[test]
+fn applied_rows_hand_the_storage_what_they_changed() {
+    let mut storage = MemoryStorage::default();
+    let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("an empty storage loads");
+
+    engine.process_stored(&row(Transaction::Deposit { client: 1, tx: 1, amount: amount("10") }), &mut storage).expect("the deposit is applied");
+    engine.process_stored(&row(Transaction::Dispute { client: 1, tx: 1 }), &mut storage).expect("the dispute is applied");
+    engine.process_stored(&row(Transaction::AdminLock { client: 1, tx: 0 }), &mut storage).expect("the lock is applied");
+    let refused = engine.process_stored(&row(Transaction::Deposit { client: 1, tx: 2, amount: amount("1") }), &mut storage);
+    assert!(matches!(refused, Err(EngineError::AccountLocked { client: 1 })), "{:?}", refused);
+
+    let changes = storage.changes();
+    assert_eq!(changes.len(), 3, "{:?}", changes);
+    let [deposit, dispute, lock] = [&changes[0], &changes[1], &changes[2]];
+    assert_eq!(deposit.accounts.iter().map(|account| (account.client_id, account.available, account.held)).collect::<Vec<_>>(), [(1, amount("10"), Money::ZERO)]);
+    assert!(matches!(deposit.records[..], [(1, Some(Record { client_id: 1, state: RecordState::Normal, .. }))]), "{:?}", deposit.records);
+    assert!(matches!(deposit.admin_holds[..], [(1, None)]), "{:?}", deposit.admin_holds);
+    assert_eq!(dispute.accounts.iter().map(|account| (account.available, account.held)).collect::<Vec<_>>(), [(Money::ZERO, amount("10"))]);
+    assert!(matches!(dispute.records[..], [(1, Some(Record { state: RecordState::Disputed, .. }))]), "{:?}", dispute.records);
+    // A lock names no tx, so it only writes the account
+    assert!(lock.accounts.iter().all(|account| account.locked) && lock.accounts.len() == 1);
+    assert!(lock.records.is_empty() && lock.admin_holds.is_empty());
+}
+
+#[test]
+fn a_row_that_cannot_be_written_is_undone_and_can_be_tried_again() {
+    let mut storage = MemoryStorage::default();
+    let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("an empty storage loads");
+    engine.process_stored(&row(Transaction::Deposit { client: 1, tx: 1, amount: amount("10") }), &mut storage).expect("the deposit is applied");
+    let before = format!("{:?}", engine.report());
+
+    storage.set_down(true);
+    for transaction in [
+        Transaction::Dispute { client: 1, tx: 1 },
+        Transaction::Withdrawal { client: 1, tx: 2, amount: amount("4") },
+        Transaction::Deposit { client: 2, tx: 3, amount: amount("1") },
+        Transaction::AdminHold { client: 1, tx: 4, amount: amount("1") },
+    ] {
+        let result = engine.process_stored(&row(transaction), &mut storage);
+        assert!(matches!(result, Err(EngineError::Storage(_))), "{:?} gave {:?}", transaction, result);
+    }
+    assert_eq!(format!("{:?}", engine.report()), before);
+    assert_eq!(engine.record(1).map(|record| record.state), Some(RecordState::Normal));
+    assert!(engine.record(2).is_none() && engine.admin_hold(4).is_none() && engine.account(2).is_none());
+
+    storage.set_down(false);
+    engine.process_stored(&row(Transaction::Withdrawal { client: 1, tx: 2, amount: amount("4") }), &mut storage).expect("the withdrawal is applied");
+    let account = engine.account(1).expect("client 1 has an account");
+    assert_eq!((account.available, account.total), (amount("6"), amount("6")));
+    assert_eq!(engine.report().rows, 2);
+}
+
+#[test]
+fn an_engine_loaded_from_its_storage_carries_on_where_it_stopped() {
+    let mut storage = MemoryStorage::default();
+    let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("an empty storage loads");
+    for transaction in first_rows() {
+        let _ = engine.process_stored(&row(transaction), &mut storage);
+    }
+    drop(engine);
+
+    let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("the storage loads");
+    for transaction in later_rows() {
+        let _ = engine.process_stored(&row(transaction), &mut storage);
+    }
+    assert_eq!(format!("{:?}", engine.report().accounts), expected_accounts());
+    // The storage holds what the engine does
+    let reloaded = Engine::load(&mut storage, EnginePolicy::default()).expect("the storage loads");
+    assert_eq!(format!("{:?}", reloaded.report().accounts), expected_accounts());
+    assert_eq!(reloaded.records().count(), 4);
+}
+
+#[test]
+fn a_sharded_engine_writes_rows_and_transfers_to_its_storage() {
+    let mut storage = MemoryStorage::default();
+    let engine = Engine::load(&mut storage, EnginePolicy::default()).expect("an empty storage loads");
+    let shards = NonZeroUsize::new(4).expect("a shard count above zero");
+    let engine = ShardedEngine::new(engine, shards).expect("the default policy can be sharded").with_storage(Box::new(storage.clone()));
+    for transaction in first_rows() {
+        let _ = engine.process(&row(transaction));
+    }
+    engine.transfer(1, 2, amount("2")).expect("the transfer is applied");
+    storage.set_down(true);
+    assert!(matches!(engine.transfer(1, 2, amount("1")), Err(EngineError::Storage(_))));
+    assert!(matches!(engine.process(&row(Transaction::Resolve { client: 1, tx: 1 })), Err(EngineError::Storage(_))));
+    storage.set_down(false);
+
+    let reloaded = Engine::load(&mut storage.clone(), EnginePolicy::default()).expect("the storage loads");
+    let balances = |engine: &Engine| engine.accounts().map(|account| (account.client_id, (account.available, account.held, account.total))).collect::<BTreeMap<_, _>>();
+    let merged = engine.to_engine().expect("no shard is poisoned");
+    assert_eq!(balances(&reloaded), balances(&merged));
+    assert_eq!(balances(&merged).get(&1), Some(&(amount("3"), amount("10"), amount("13"))));
+    assert_eq!(balances(&merged).get(&2), Some(&(amount("7"), amount("3"), amount("10"))));
+}
+
+#[test]
+fn monthly_fees_cannot_be_used_with_a_storage() {
+    let policy = EnginePolicy::builder().monthly_fee(amount("1")).build();
+    let loaded = Engine::load(&mut MemoryStorage::default(), policy);
+    assert!(matches!(loaded, Err(EngineError::Invalid(_))), "{:?}", loaded.map(|_| ()));
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::*;
+    use payment_engine::postgres::PostgresStorage;
+
+    // This function connects to the test database in a schema of the test's own, emptied first, and returns a plain
+    // connection to look at the tables with
+    fn connect(schema: &str) -> (::postgres::Config, ::postgres::Client) {
+        let url = std::env::var("PAYMENT_ENGINE_POSTGRES_URL").expect("PAYMENT_ENGINE_POSTGRES_URL names a database to test against");
+        let mut config: ::postgres::Config = url.parse().expect("a valid connection string");
+        let mut client = config.connect(::postgres::NoTls).expect("the test database accepts connections");
+        client.batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}", schema)).expect("the schema is created");
+        config.options(&format!("-c search_path={}", schema));
+        let client = config.connect(::postgres::NoTls).expect("the test database accepts connections");
+        (config, client)
+    }
+
+    #[test]
+    fn an_unreachable_database_is_a_storage_error() {
+        let result = PostgresStorage::connect("host=127.0.0.1 port=1 user=nobody connect_timeout=5");
+        assert!(matches!(result, Err(EngineError::Storage(_))), "{:?}", result.map(|_| ()));
+        let result = PostgresStorage::connect("postgres://[not a url");
+        assert!(matches!(result, Err(EngineError::Invalid(_))), "{:?}", result.map(|_| ()));
+    }
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in PAYMENT_ENGINE_POSTGRES_URL"]
+    fn an_engine_kept_in_postgres_survives_a_restart() {
+        let (config, mut tables) = connect("storage_restart");
+        let mut storage = PostgresStorage::with_config(&config).expect("the storage connects");
+        let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("an empty database loads");
+        for transaction in first_rows() {
+            let _ = engine.process_stored(&row(transaction), &mut storage);
+        }
+        drop((engine, storage));
+
+        let mut storage = PostgresStorage::with_config(&config).expect("the storage connects again");
+        let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("the database loads");
+        for transaction in later_rows() {
+            let _ = engine.process_stored(&row(transaction), &mut storage);
+        }
+        assert_eq!(format!("{:?}", engine.report().accounts), expected_accounts());
+        let reloaded = Engine::load(&mut storage, EnginePolicy::default()).expect("the database loads");
+        assert_eq!(format!("{:?}", reloaded.report().accounts), expected_accounts());
+
+        let accounts: Vec<(i32, String, Money, bool)> = tables.query("SELECT client, currency, total::text, locked FROM accounts ORDER BY client", &[])
+            .expect("the accounts are read").iter().map(|row| (row.get(0), row.get(1), amount(row.get(2)), row.get(3))).collect();
+        assert_eq!(accounts, [(1, String::new(), amount("10"), true), (2, String::new(), Money::ZERO, false)]);
+        let states: Vec<(i64, String)> = tables.query("SELECT tx, state FROM records ORDER BY tx", &[])
+            .expect("the records are read").iter().map(|row| (row.get(0), row.get(1))).collect();
+        let expected = [(1, "resolved"), (2, "charged_back"), (3, "normal"), (6, "normal")].map(|(tx, state)| (tx, state.to_string()));
+        assert_eq!(states, expected);
+        let holds: i64 = tables.query_one("SELECT count(*) FROM admin_holds", &[]).expect("the holds are counted").get(0);
+        assert_eq!(holds, 0);
+    }
+
+    #[test]
+    #[ignore = "needs a PostgreSQL database in PAYMENT_ENGINE_POSTGRES_URL"]
+    fn a_row_postgres_refuses_is_undone() {
+        let (config, mut tables) = connect("storage_refused");
+        let mut storage = PostgresStorage::with_config(&config).expect("the storage connects");
+        let mut engine = Engine::load(&mut storage, EnginePolicy::default()).expect("an empty database loads");
+        engine.process_stored(&row(Transaction::Deposit { client: 1, tx: 1, amount: amount("10") }), &mut storage).expect("the deposit is applied");
+        // The database stands in for one that is down by refusing any total above 10
+        tables.batch_execute("ALTER TABLE accounts ADD CHECK (total <= 10)").expect("the check is added");
+
+        let result = engine.process_stored(&row(Transaction::Deposit { client: 1, tx: 2, amount: amount("5") }), &mut storage);
+        assert!(matches!(&result, Err(EngineError::Storage(message)) if message.starts_with("PostgreSQL: ")), "{:?}", result);
+        assert!(engine.record(2).is_none());
+        assert_eq!(engine.account(1).map(|account| account.total), Some(amount("10")));
+        let records: i64 = tables.query_one("SELECT count(*) FROM records", &[]).expect("the records are counted").get(0);
+        assert_eq!(records, 1);
+
+        engine.process_stored(&row(Transaction::Withdrawal { client: 1, tx: 2, amount: amount("5") }), &mut storage).expect("the withdrawal is applied");
+        let reloaded = Engine::load(&mut storage, EnginePolicy::default()).expect("the database loads");
+        assert_eq!(reloaded.account(1).map(|account| account.total), Some(amount("5")));
+    }
+}
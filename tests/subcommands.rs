@@ -0,0 +1,49 @@
+// Runs the subcommands that process an input on their own, `at`, `explain`, `history` and `replay`, and checks that
+// they apply the same processing flags as the main command. tests/fixtures/lock_threshold.csv only keeps client 1 open
+// after its first chargeback under --lock-policy threshold:2, so every subcommand sees a different run without the flag.
+
+mod common;
+
+use common::{command, stdout, temp_path};
+
+const LOCK_THRESHOLD: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/lock_threshold.csv");
+
+#[test]
+fn at_applies_the_lock_policy() {
+    let at = ["at", "--input", LOCK_THRESHOLD, "--row", "5", "--client", "1"];
+    assert_eq!(stdout(at), "client,available,held,total,locked\n1,20.0000,0.0000,20.0000,true\n");
+    assert_eq!(stdout([&at[..], &["--lock-policy", "threshold:2"]].concat()), "client,available,held,total,locked\n1,25.0000,0.0000,25.0000,false\n");
+}
+
+#[test]
+fn explain_applies_the_lock_policy() {
+    // Without the threshold the assert after the deposit fails, so it is only recorded here
+    let explain = ["explain", "--input", LOCK_THRESHOLD, "--tx", "3"];
+    assert!(stdout([&explain[..], &["--assertions", "lenient"]].concat()).contains("rejected: account is locked"));
+    let explanation = stdout([&explain[..], &["--lock-policy", "threshold:2"]].concat());
+    assert!(explanation.contains(" for client 1\n  applied\n"), "{}", explanation);
+}
+
+#[test]
+fn history_applies_the_lock_policy() {
+    let history = stdout(["history", LOCK_THRESHOLD, "--client", "1", "--lock-policy", "threshold:2"]);
+    assert!(history.contains("\n3,deposit,5.0000,applied,25.0000,0.0000,25.0000,\n"), "{}", history);
+    assert!(history.contains("\n4,deposit,7.0000,account_locked,5.0000,0.0000,5.0000,\n"), "{}", history);
+    assert!(history.ends_with("\n,,,final,5.0000,0.0000,5.0000,\n"), "{}", history);
+}
+
+// A replay uses the recorded lock policy unless it is given another one
+#[test]
+fn replay_applies_the_recorded_policy_unless_overridden() {
+    let recording = temp_path("lock_threshold.jsonl");
+    let recording = recording.to_str().expect("utf-8 path");
+    stdout([LOCK_THRESHOLD, "--lock-policy", "threshold:2", "--record", recording]);
+
+    let replayed = command(["replay", recording]);
+    let overridden = command(["replay", recording, "--lock-policy", "account"]);
+    let _ = std::fs::remove_file(recording);
+    assert_eq!(replayed.status.code(), Some(0), "{}", String::from_utf8_lossy(&replayed.stdout));
+    assert_eq!(overridden.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&overridden.stdout);
+    assert!(stdout.contains("Divergence at row 8: chargeback 2 for client 1 was recorded as \"applied\" but replayed as \"account is locked\""), "{}", stdout);
+}
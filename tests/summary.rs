@@ -0,0 +1,40 @@
+// Runs the command line tool with --summary on tests/fixtures/error_heavy.csv and checks the whole summary block on
+// stderr, every count worked out from the rows: 17 rows, of which 3 deposits, a dispute and a chargeback are applied,
+// and the other 12 are bucketed by outcome and reason.
+
+mod common;
+
+use common::{command, fixture};
+
+#[test]
+fn the_summary_has_the_exact_counts() {
+    let output = command([fixture("error_heavy.csv").as_str(), "-q", "--summary"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "summary report schema=v2\n\
+        summary rows=17 applied=5 not_applied=12\n\
+        summary applied type=chargeback count=1\n\
+        summary applied type=deposit count=3\n\
+        summary applied type=dispute count=1\n\
+        summary not_applied outcome=duplicate reason=duplicate_transaction count=1\n\
+        summary not_applied outcome=parse_error reason=unparseable count=2\n\
+        summary not_applied outcome=rejected reason=account_locked count=1\n\
+        summary not_applied outcome=rejected reason=client_mismatch count=1\n\
+        summary not_applied outcome=rejected reason=insufficient_funds count=2\n\
+        summary not_applied outcome=rejected reason=invalid_amount count=1\n\
+        summary not_applied outcome=rejected reason=not_disputed count=2\n\
+        summary not_applied outcome=rejected reason=unknown_transaction count=1\n\
+        summary not_applied outcome=unknown_type reason=refund count=1\n\
+        summary accounts clients=3 locked=1 chargebacks=1 held=0.0000 total=13.0000\n\
+        summary open_disputes count=0 held=0.0000\n");
+}
+
+// Disputes still open at the end are counted with the funds they hold
+#[test]
+fn the_summary_counts_open_disputes() {
+    let output = command([fixture("simulate.csv").as_str(), "-q", "--summary"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("summary rows=9 applied=9 not_applied=0\n"), "{}", stderr);
+    assert!(stderr.contains("summary accounts clients=3 locked=0 chargebacks=0 held=15.0000 total=22.5000\n"), "{}", stderr);
+    assert!(stderr.contains("summary open_disputes count=2 held=15.0000\n"), "{}", stderr);
+}
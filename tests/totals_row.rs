@@ -0,0 +1,53 @@
+// Runs the command line tool with --totals-row on tests/fixtures/totals.csv, four clients with available, held and a
+// locked account between them. The TOTAL row must be the sum of each column of the rows above it, and --client filters
+// must change it to the sum of just the accounts written.
+
+mod common;
+
+use common::{fixture, stdout};
+use payment_engine::money::Money;
+
+// This function sums the available, held and total columns of a CSV report's account rows and returns them with the
+// TOTAL row's, as written
+fn sums_and_totals(report: &str) -> ([Money; 3], Vec<String>) {
+    let mut sums = [Money::ZERO; 3];
+    let mut totals = Vec::new();
+    for line in report.lines().skip(1) {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields[0] == "TOTAL" {
+            totals = fields[1..4].iter().map(|field| field.to_string()).collect();
+            continue;
+        }
+        for (sum, field) in sums.iter_mut().zip(&fields[1..4]) {
+            let amount: Money = field.parse().unwrap_or_else(|_| panic!("invalid amount {:?} in {:?}", field, line));
+            *sum = sum.checked_add(amount).expect("the sums fit");
+        }
+    }
+    (sums, totals)
+}
+
+#[test]
+fn the_totals_row_is_the_sum_of_each_column() {
+    let report = stdout([fixture("totals.csv").as_str(), "--totals-row", "-q"]);
+    assert_eq!(report.lines().last(), Some("TOTAL,9.0001,3.2500,12.2501,"), "{}", report);
+    let (sums, totals) = sums_and_totals(&report);
+    assert_eq!(totals, sums.map(|sum| format!("{:.4}", sum)), "{}", report);
+}
+
+#[test]
+fn client_filters_change_the_totals_to_the_accounts_written() {
+    let input = fixture("totals.csv");
+    for (clients, expected) in [("2-3", "TOTAL,0.0000,3.2500,3.2500,"), ("1,4", "TOTAL,9.0001,0.0000,9.0001,"), ("3", "TOTAL,0.0000,0.0000,0.0000,")] {
+        let report = stdout([input.as_str(), "--totals-row", "-q", "--client", clients]);
+        assert_eq!(report.lines().last(), Some(expected), "--client {}: {}", clients, report);
+        let (sums, totals) = sums_and_totals(&report);
+        assert_eq!(totals, sums.map(|sum| format!("{:.4}", sum)), "--client {}: {}", clients, report);
+    }
+
+    let json = stdout([input.as_str(), "--totals-row", "-q", "--client", "1,4", "--format", "json"]);
+    let parsed: serde_json::Value = serde_json::from_str(&json).expect("the report is JSON");
+    assert_eq!(parsed["accounts"].as_array().map(Vec::len), Some(2), "{}", json);
+    assert_eq!(parsed["totals"]["available"], "9.0001", "{}", json);
+    assert_eq!(parsed["totals"]["held"], "0.0000", "{}", json);
+    assert_eq!(parsed["totals"]["total"], "9.0001", "{}", json);
+}
@@ -0,0 +1,47 @@
+// Runs the `verify` subcommand on inputs and account reports that match and that do not, with the processing flags
+// the reports were written with.
+
+mod common;
+
+use common::{command, temp_path, FIXTURES};
+use std::process::Output;
+
+fn verify(input: &str, accounts: &str, flags: &[&str]) -> Output {
+    command([&["verify", input, accounts][..], flags].concat())
+}
+
+#[test]
+fn a_matching_report_passes() {
+    let output = verify(&format!("{}/redispute.csv", FIXTURES), &format!("{}/redispute_accounts.csv", FIXTURES), &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("verify: all 2 accounts"));
+}
+
+#[test]
+fn a_tampered_balance_fails_naming_the_client() {
+    let accounts = std::fs::read_to_string(format!("{}/redispute_accounts.csv", FIXTURES)).expect("the report is readable");
+    let tampered = accounts.replace("2,20.0000,0.0000,20.0000,true", "2,20.0001,0.0000,20.0000,true");
+    assert_ne!(tampered, accounts);
+    let path = temp_path("tampered_accounts.csv");
+    std::fs::write(&path, tampered).expect("the tampered report is written");
+
+    let output = verify(&format!("{}/redispute.csv", FIXTURES), path.to_str().expect("utf-8 path"), &[]);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("client 2: available is 20.0001 in the report, 20.0000 from the input"), "{}", stderr);
+    assert!(!stderr.contains("client 1:"), "{}", stderr);
+    assert!(stderr.contains("verify: 1 of 2 accounts"), "{}", stderr);
+}
+
+// The reports below were written with a lock threshold and with currencies, which verify has to apply as well
+#[test]
+fn the_processing_flags_of_the_run_are_applied() {
+    let lock_threshold = (format!("{}/lock_threshold.csv", FIXTURES), format!("{}/lock_threshold.expected.csv", FIXTURES));
+    assert_eq!(verify(&lock_threshold.0, &lock_threshold.1, &["--lock-policy", "threshold:2"]).status.code(), Some(0));
+    assert_ne!(verify(&lock_threshold.0, &lock_threshold.1, &[]).status.code(), Some(0));
+
+    let currencies = (format!("{}/currencies.csv", FIXTURES), format!("{}/currencies.expected.csv", FIXTURES));
+    let output = verify(&currencies.0, &currencies.1, &["--multi-currency"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+}
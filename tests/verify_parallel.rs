@@ -0,0 +1,62 @@
+// Runs inputs with --verify-parallel: clean inputs pass and write the same report as a plain run, a divergence put into
+// the parallel run through its test hook stops the run naming the client, and inputs that cannot be kept are refused.
+
+mod common;
+
+use common::{command, fixture, temp_path};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn a_clean_fixture_passes_with_the_serial_report() {
+    let input = fixture("dispute_chargeback.csv");
+    let plain = command([&input]);
+    let verified = command([&input, "--verify-parallel", "--log-level", "info"]);
+    assert_eq!(verified.status.code(), Some(0), "{}", String::from_utf8_lossy(&verified.stderr));
+    assert_eq!(String::from_utf8_lossy(&verified.stdout), String::from_utf8_lossy(&plain.stdout));
+    let stderr = String::from_utf8_lossy(&verified.stderr);
+    assert!(stderr.contains("--verify-parallel: all 2 accounts match"), "{}", stderr);
+}
+
+// Generated rows spread over many clients keep every thread busy, with disputes and chargebacks among them
+#[test]
+fn a_generated_input_over_many_clients_passes() {
+    let path = temp_path("verify_parallel_generated.csv");
+    let path = path.to_str().expect("utf-8 path");
+    let generated = command(["generate", "--rows", "20000", "--clients", "300", "--out", path]);
+    assert!(generated.status.success(), "{}", String::from_utf8_lossy(&generated.stderr));
+
+    let output = command([path, "--verify-parallel", "--log-level", "info"]);
+    let _ = std::fs::remove_file(path);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("--verify-parallel: all 300 accounts match"), "{}", stderr);
+}
+
+#[test]
+fn an_injected_divergence_is_listed_by_client() {
+    let input = fixture("dispute_chargeback.csv");
+    let output = command([&input, "--verify-parallel", "--verify-parallel-divergence", "2"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("client 2: locked is true serially, false in parallel"), "{}", stderr);
+    assert!(!stderr.contains("client 1:"), "{}", stderr);
+    assert!(stderr.contains("--verify-parallel: 1 of 2 accounts differ"), "{}", stderr);
+}
+
+#[test]
+fn standard_input_is_refused() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(["-", "--verify-parallel"])
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("the payment_engine binary runs");
+    child.stdin.take().expect("stdin is piped").write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n").expect("the input is written");
+    let output = child.wait_with_output().expect("the run finishes");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--verify-parallel needs input files"));
+}
@@ -0,0 +1,58 @@
+// Runs the command line tool with --volume-report on tests/fixtures/volume_matrix.csv and checks every cell against the
+// matrix worked out by hand from the rows:
+//
+//     client 1   deposits 10, 2.5 and 1.25; withdraws 7; disputes tx 1 with only 5.5 available, so the dispute holds all
+//                10 and leaves available at -4.5; resolves it; disputes tx 2, which holds 2.5
+//     client 2   deposits 20; corrects it to 12; disputes it, holding the corrected 12; charges it back, taking that 12
+//     client 3   deposits 4; a withdrawal of 5 is rejected and a dispute of unknown tx 99 too, so neither counts
+//
+// A dispute counts the amount it held when it was opened, a chargeback the amount it took, and a correction the amount
+// it set.
+
+mod common;
+
+use common::{command, fixture, read, temp_path};
+use payment_engine::money::Money;
+
+#[test]
+fn the_volume_matrix_matches_the_hand_computed_one() {
+    let path = temp_path("volume_matrix.csv");
+    let output = command([&fixture("volume_matrix.csv"), "-q", "--volume-report", path.to_str().expect("utf-8 path")]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let volumes = read(&path);
+    assert!(volumes.starts_with("client,type,count,amount\n"), "{}", volumes);
+
+    // Amounts are written as the money type displays them, 13.75 or 13.7500, so they are compared as amounts
+    let expected = [
+        ("1", "deposit", 3, "13.75"),
+        ("1", "dispute", 2, "12.5"),
+        ("1", "resolve", 1, "10"),
+        ("1", "withdrawal", 1, "7"),
+        ("2", "chargeback", 1, "12"),
+        ("2", "correction", 1, "12"),
+        ("2", "deposit", 1, "20"),
+        ("2", "dispute", 1, "12"),
+        ("3", "deposit", 1, "4"),
+        ("TOTAL", "chargeback", 1, "12"),
+        ("TOTAL", "correction", 1, "12"),
+        ("TOTAL", "deposit", 5, "37.75"),
+        ("TOTAL", "dispute", 3, "24.5"),
+        ("TOTAL", "resolve", 1, "10"),
+        ("TOTAL", "withdrawal", 1, "7"),
+    ];
+    let rows: Vec<(String, String, u64, Money)> = volumes.lines().skip(1).map(|line| {
+        let fields: Vec<&str> = line.split(',').collect();
+        assert_eq!(fields.len(), 4, "{}", line);
+        (fields[0].to_string(), fields[1].to_string(), fields[2].parse().expect("a count"), fields[3].parse().expect("an amount"))
+    }).collect();
+    let expected: Vec<(String, String, u64, Money)> = expected.iter()
+        .map(|&(client, kind, count, amount)| (client.to_string(), kind.to_string(), count, amount.parse().expect("an amount")))
+        .collect();
+    assert_eq!(rows, expected, "{}", volumes);
+
+    // The balances the volumes lead to
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client,available,held,total,locked\n\
+        1,4.2500,2.5000,6.7500,false\n\
+        2,0.0000,0.0000,0.0000,true\n\
+        3,4.0000,0.0000,4.0000,false\n");
+}